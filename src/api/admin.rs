@@ -0,0 +1,466 @@
+//! Admin API to register servers at runtime.
+//!
+//! Requires `Authorization: Bearer <token>`.
+//!
+//! - `GET /admin/servers` lists the registered servers.
+//! - `PUT /admin/servers/{name}` registers or replaces the server.
+//! - `DELETE /admin/servers/{name}` unregisters the server.
+//! - `POST /admin/servers/{name}/handover` moves the sessions to new servers with `--standby`.
+//! - `GET /admin/drain` shows if the proxy is draining and the number of sessions.
+//! - `POST /admin/drain` stops accepting new sessions.
+//! - `GET /admin/stats` shows the number of connections and server processes.
+//!
+//! Connections already started keep their servers. The changes are kept when the config is
+//! reloaded.
+use std::{collections::HashMap, path::PathBuf};
+
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use super::{json_body, json_error_response, json_response, proxy, with_context};
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    pub proxy: proxy::SharedContext,
+    /// Token required in the `Authorization` header.
+    pub token: String,
+}
+
+/// Rejection for a request without the admin token.
+#[derive(Debug)]
+pub(super) struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Server definition.
+///
+/// ```json
+/// {"command": "pyright-langserver", "args": ["--stdio"]}
+/// ```
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Definition {
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    cwd: Option<PathBuf>,
+    remap: Option<bool>,
+    sync: Option<bool>,
+    fallback: Option<String>,
+    #[serde(default)]
+    limits: crate::backend::Limits,
+    #[serde(rename = "initializationOptions")]
+    initialization_options: Option<serde_json::Value>,
+    capabilities: Option<serde_json::Value>,
+    diagnostics: Option<crate::api::diagnostics::DiagnosticRules>,
+    settings: Option<serde_json::Value>,
+    commands: Option<Vec<String>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ServerInfo<'a> {
+    name: &'a str,
+    command: &'a [String],
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Stats {
+    connections: usize,
+    processes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_processes: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct DrainStatus {
+    draining: bool,
+    sessions: usize,
+}
+
+/// Handler for `/admin/servers`, `/admin/drain`, and `/admin/stats`.
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let servers = warp::path!("admin" / "servers")
+        .and(warp::get())
+        .and(with_authorization(ctx.clone()))
+        .map(list_servers);
+    let put = warp::path!("admin" / "servers" / String)
+        .and(warp::put())
+        .and(with_authorization(ctx.clone()))
+        .and(json_body::<Definition>())
+        .map(put_server);
+    let delete = warp::path!("admin" / "servers" / String)
+        .and(warp::delete())
+        .and(with_authorization(ctx.clone()))
+        .map(delete_server);
+    let handover = warp::path!("admin" / "servers" / String / "handover")
+        .and(warp::post())
+        .and(with_authorization(ctx.clone()))
+        .map(handover);
+    let drain_status = warp::path!("admin" / "drain")
+        .and(warp::get())
+        .and(with_authorization(ctx.clone()))
+        .map(|ctx: Context| drain_status(&ctx, StatusCode::OK));
+    let drain = warp::path!("admin" / "drain")
+        .and(warp::post())
+        .and(with_authorization(ctx.clone()))
+        .map(|ctx: Context| {
+            ctx.proxy.get().shutdown.drain();
+            drain_status(&ctx, StatusCode::ACCEPTED)
+        });
+    let stats = warp::path!("admin" / "stats")
+        .and(warp::get())
+        .and(with_authorization(ctx))
+        .map(stats);
+    servers
+        .or(put)
+        .or(delete)
+        .or(handover)
+        .or(drain_status)
+        .or(drain)
+        .or(stats)
+}
+
+fn with_authorization(
+    ctx: Context,
+) -> impl Filter<Extract = (Context,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_context(ctx))
+        .and_then(|authorization: Option<String>, ctx: Context| async move {
+            let expected = format!("Bearer {}", ctx.token);
+            if authorization.as_deref() == Some(expected.as_str()) {
+                Ok(ctx)
+            } else {
+                Err(warp::reject::custom(Unauthorized))
+            }
+        })
+}
+
+fn list_servers(ctx: Context) -> impl Reply {
+    let proxy = ctx.proxy.get();
+    let servers: Vec<_> = proxy
+        .servers
+        .iter()
+        .map(|server| ServerInfo {
+            name: &server.name,
+            command: &server.command,
+        })
+        .collect();
+    json_response(&servers, StatusCode::OK)
+}
+
+fn drain_status(ctx: &Context, status: StatusCode) -> warp::reply::Response {
+    let shutdown = ctx.proxy.get().shutdown;
+    let body = DrainStatus {
+        draining: shutdown.is_draining(),
+        sessions: shutdown.sessions(),
+    };
+    json_response(&body, status)
+}
+
+fn handover(name: String, ctx: Context) -> warp::reply::Response {
+    let proxy = ctx.proxy.get();
+    if !proxy.servers.iter().any(|s| s.name == name) {
+        return json_error_response(format!("unknown server {}", name), StatusCode::NOT_FOUND);
+    }
+    match &proxy.standby {
+        Some(standby) => {
+            standby.handover(&proxy, &name);
+            StatusCode::ACCEPTED.into_response()
+        }
+        None => json_error_response("handover requires --standby", StatusCode::CONFLICT),
+    }
+}
+
+fn stats(ctx: Context) -> impl Reply {
+    let proxy = ctx.proxy.get();
+    let body = Stats {
+        connections: proxy.shutdown.sessions(),
+        processes: crate::backend::Process::running(),
+        max_processes: proxy.processes.as_ref().map(|limit| limit.max()),
+    };
+    json_response(&body, StatusCode::OK)
+}
+
+fn put_server(name: String, ctx: Context, definition: Definition) -> impl Reply {
+    let mut command = vec![definition.command];
+    command.extend(definition.args);
+    let server = proxy::Server {
+        name: name.clone(),
+        command,
+        env: definition.env,
+        cwd: definition.cwd,
+        cwd_from_root: false,
+        remap: definition.remap,
+        sync: definition.sync,
+        fallback: definition.fallback,
+        limits: definition.limits,
+        stop_signals: Default::default(),
+        initialization_options: definition.initialization_options,
+        capabilities: definition.capabilities,
+        plugins: Vec::new(),
+        script: None,
+        hook: None,
+        diagnostics: definition.diagnostics,
+        settings: definition.settings,
+        commands: definition.commands,
+    };
+
+    let status = if ctx.proxy.register(server) {
+        StatusCode::OK
+    } else {
+        StatusCode::CREATED
+    };
+    tracing::info!("registered server {}", name);
+    status
+}
+
+fn delete_server(name: String, ctx: Context) -> warp::reply::Response {
+    if !ctx.proxy.unregister(&name) {
+        return json_error_response(format!("unknown server {}", name), StatusCode::NOT_FOUND);
+    }
+    tracing::info!("unregistered server {}", name);
+    StatusCode::NO_CONTENT.into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use super::*;
+
+    fn context() -> Context {
+        let proxy = proxy::Context {
+            servers: vec![proxy::Server::from(vec!["rust-analyzer".to_owned()])],
+            connect: None,
+            docker: None,
+            languages: Vec::new(),
+            extensions: Vec::new(),
+            pool: None,
+            shared: None,
+            sessions: None,
+            processes: None,
+            resume: None,
+            session_token: None,
+            idle_timeout: None,
+            shutdown: crate::api::shutdown::Shutdown::new(std::time::Duration::from_secs(1)),
+            restart: false,
+            max_restarts: 5,
+            probe_interval: None,
+            standby: None,
+            max_session_duration: None,
+            request_timeout: None,
+            coalesce_changes: None,
+            strip_snippets: false,
+            apply_edits: false,
+            watch: false,
+            memory: false,
+            quota: Default::default(),
+            sync_roots: Default::default(),
+            file_operations: Default::default(),
+            repo: None,
+            allowed_repos: Vec::new(),
+            clone_dir: std::env::temp_dir(),
+            clone_depth: Some(1),
+            isolate: false,
+            workspace_dir: std::env::temp_dir(),
+            workspaces: Default::default(),
+            workspace_session: None,
+            telemetry: None,
+            answer_unsupported: false,
+            message_action: Default::default(),
+            normalize_line_endings: false,
+            position_encoding: None,
+            size_limits: Default::default(),
+            partial_results: None,
+            ping_interval: Some(std::time::Duration::from_secs(30)),
+            ping_timeout: std::time::Duration::from_secs(30),
+            sync: false,
+            remap: false,
+            remap_rules: crate::lsp::ext::RemapRules::new(
+                &Url::parse("file:///tmp/").unwrap(),
+                Vec::new(),
+            ),
+            deep_remap: false,
+            redact_paths: false,
+            initialization_options: None,
+            capabilities: None,
+            middlewares: Default::default(),
+            plugins: Default::default(),
+            scripts: Default::default(),
+            hooks: Default::default(),
+            filters: Default::default(),
+            webhooks: Default::default(),
+            rate_limits: Vec::new(),
+            cache: Vec::new(),
+            compression: true,
+            server_header: None,
+            params: Vec::new(),
+            param_values: HashMap::new(),
+            client_subject: None,
+            cwd: Url::parse("file:///tmp/").unwrap(),
+        };
+        Context {
+            proxy: proxy::SharedContext::new(proxy),
+            token: "secret".to_owned(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requires_token() {
+        let api = handler(context()).recover(super::super::recover);
+        let res = warp::test::request()
+            .path("/admin/servers")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = warp::test::request()
+            .path("/admin/servers")
+            .header("authorization", "Bearer wrong")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_register_server() {
+        let ctx = context();
+        let api = handler(ctx.clone());
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/admin/servers/pyright")
+            .header("authorization", "Bearer secret")
+            .json(&serde_json::json!({"command": "pyright-langserver", "args": ["--stdio"]}))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let servers = ctx.proxy.get().servers;
+        assert_eq!(servers[1].name, "pyright");
+        assert_eq!(servers[1].command, vec!["pyright-langserver", "--stdio"]);
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/admin/servers/pyright")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(ctx.proxy.get().servers.len(), 1);
+
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/admin/servers/pyright")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_register_concurrently() {
+        let ctx = context();
+        let api = handler(ctx.clone());
+        let requests = (0..20).map(|i| {
+            warp::test::request()
+                .method("PUT")
+                .path(&format!("/admin/servers/server-{}", i))
+                .header("authorization", "Bearer secret")
+                .json(&serde_json::json!({"command": "pyright-langserver"}))
+                .reply(&api)
+        });
+        for res in futures_util::future::join_all(requests).await {
+            assert_eq!(res.status(), StatusCode::CREATED);
+        }
+        assert_eq!(ctx.proxy.get().servers.len(), 21);
+    }
+
+    #[tokio::test]
+    async fn test_kept_on_reload() {
+        let ctx = context();
+        let api = handler(ctx.clone());
+        let res = warp::test::request()
+            .method("PUT")
+            .path("/admin/servers/pyright")
+            .header("authorization", "Bearer secret")
+            .json(&serde_json::json!({"command": "pyright-langserver"}))
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = warp::test::request()
+            .method("DELETE")
+            .path("/admin/servers/rust-analyzer")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+        ctx.proxy.reload(
+            vec![
+                proxy::Server::from(vec!["rust-analyzer".to_owned()]),
+                proxy::Server::from(vec!["clangd".to_owned()]),
+            ],
+            |next| next.sync = true,
+        );
+        let next = ctx.proxy.get();
+        assert!(next.sync);
+        let names = next
+            .servers
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["clangd", "pyright"]);
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let ctx = context();
+        let api = handler(ctx.clone());
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/drain")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(res.body()).unwrap(),
+            serde_json::json!({"draining": true, "sessions": 0})
+        );
+        assert!(ctx.proxy.get().shutdown.is_draining());
+    }
+
+    #[tokio::test]
+    async fn test_handover() {
+        let api = handler(context());
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/servers/rust-analyzer/handover")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+
+        let res = warp::test::request()
+            .method("POST")
+            .path("/admin/servers/pyright/handover")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let api = handler(context());
+        let res = warp::test::request()
+            .path("/admin/stats")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let stats: serde_json::Value = serde_json::from_slice(res.body()).unwrap();
+        assert_eq!(stats["connections"], serde_json::json!(0));
+        assert!(stats.get("max_processes").is_none());
+    }
+}
@@ -0,0 +1,392 @@
+//! Extracting archives uploaded to `POST /files/archive` into the workspace, and creating them
+//! for `GET /files/archive`.
+//!
+//! Playgrounds seed whole projects, and writing them file by file takes hundreds of requests.
+//! Zip and gzipped tar archives are extracted with their paths checked to stay in the workspace,
+//! and the size of the extracted files is limited because archives can expand a lot. Browser
+//! environments have no other way to download the work, so the workspace can be archived as a
+//! zip too, optionally without the files ignored by `.gitignore`.
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use thiserror::Error;
+
+use super::{files, quota};
+
+/// Maximum number of entries in an archive.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Error)]
+pub(super) enum Error {
+    #[error("unsupported archive, expected zip or tar.gz")]
+    Unsupported,
+
+    #[error("invalid archive: {0}")]
+    Invalid(String),
+
+    #[error("{0} is not a relative path in the workspace")]
+    UnsafePath(String),
+
+    #[error("the files in the archive are over {0} bytes")]
+    TooLarge(u64),
+
+    #[error("the archive has over {0} entries")]
+    TooManyEntries(usize),
+
+    #[error("failed to write {path}: {source}")]
+    Write { path: String, source: io::Error },
+
+    #[error("failed to read {path}: {source}")]
+    Read { path: String, source: io::Error },
+
+    #[error("failed to create archive: {0}")]
+    Create(String),
+
+    #[error(transparent)]
+    Quota(#[from] quota::Exceeded),
+}
+
+fn invalid(err: impl std::fmt::Display) -> Error {
+    Error::Invalid(err.to_string())
+}
+
+enum Format {
+    Zip,
+    TarGz,
+}
+
+fn format(bytes: &[u8]) -> Option<Format> {
+    if bytes.starts_with(b"PK\x03\x04") || bytes.starts_with(b"PK\x05\x06") {
+        Some(Format::Zip)
+    } else if bytes.starts_with(&[0x1f, 0x8b]) {
+        Some(Format::TarGz)
+    } else {
+        None
+    }
+}
+
+/// Relative path of the entry `name`, or `None` for the root.
+fn relative_path(name: &str) -> Result<Option<PathBuf>, Error> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => {}
+            _ => return Err(Error::UnsafePath(name.to_owned())),
+        }
+    }
+    Ok(Some(path).filter(|path| path.components().next().is_some()))
+}
+
+/// Call `f` with the relative path, whether it's a directory, the size, and the contents of each
+/// entry of the archive in `bytes`. Links and other special files are skipped.
+fn each_entry<F>(bytes: &[u8], mut f: F) -> Result<(), Error>
+where
+    F: FnMut(PathBuf, bool, u64, &mut dyn Read) -> Result<(), Error>,
+{
+    match format(bytes).ok_or(Error::Unsupported)? {
+        Format::Zip => {
+            let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes)).map_err(invalid)?;
+            if archive.len() > MAX_ENTRIES {
+                return Err(Error::TooManyEntries(MAX_ENTRIES));
+            }
+            for i in 0..archive.len() {
+                let mut file = archive.by_index(i).map_err(invalid)?;
+                let is_link = file
+                    .unix_mode()
+                    .map_or(false, |mode| mode & 0o170000 == 0o120000);
+                if is_link {
+                    continue;
+                }
+                if let Some(path) = relative_path(file.name())? {
+                    let (is_dir, size) = (file.is_dir(), file.size());
+                    f(path, is_dir, size, &mut file)?;
+                }
+            }
+        }
+        Format::TarGz => {
+            let mut archive = tar::Archive::new(GzDecoder::new(bytes));
+            for (i, entry) in archive.entries().map_err(invalid)?.enumerate() {
+                if i >= MAX_ENTRIES {
+                    return Err(Error::TooManyEntries(MAX_ENTRIES));
+                }
+                let mut entry = entry.map_err(invalid)?;
+                let kind = entry.header().entry_type();
+                if !kind.is_dir() && !kind.is_file() {
+                    continue;
+                }
+                let name = entry
+                    .path()
+                    .map_err(invalid)?
+                    .to_string_lossy()
+                    .into_owned();
+                if let Some(path) = relative_path(&name)? {
+                    let size = entry.size();
+                    f(path, kind.is_dir(), size, &mut entry)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the relative `path` in `dir`, or an existing ancestor of it, is a symlink, which could
+/// point out of it. Creating the file would follow it.
+fn through_symlink(dir: &Path, path: &Path) -> bool {
+    let mut apath = dir.to_owned();
+    path.components().any(|component| {
+        apath.push(component);
+        std::fs::symlink_metadata(&apath)
+            .map_or(false, |metadata| metadata.file_type().is_symlink())
+    })
+}
+
+/// Relative paths and sizes of the files in the archive in `bytes`.
+pub(super) fn sizes(bytes: &[u8]) -> Result<Vec<(PathBuf, u64)>, Error> {
+    let mut files = Vec::new();
+    each_entry(bytes, |path, is_dir, size, _| {
+        if !is_dir {
+            files.push((path, size));
+        }
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+/// Extract the zip or gzipped tar archive in `bytes` into `dir`, with files of at most `max_size`
+/// bytes in total, returning the relative paths of the files and whether they were created.
+///
+/// The entries are all checked before writing any.
+pub(super) fn extract(
+    bytes: &[u8],
+    dir: &Path,
+    max_size: u64,
+) -> Result<Vec<(PathBuf, bool)>, Error> {
+    let mut total = 0u64;
+    each_entry(bytes, |path, _, size, _| {
+        total = total.saturating_add(size);
+        if total > max_size {
+            return Err(Error::TooLarge(max_size));
+        }
+        if through_symlink(dir, &path) {
+            return Err(Error::UnsafePath(path.display().to_string()));
+        }
+        Ok(())
+    })?;
+
+    let mut files = Vec::new();
+    let mut written = 0u64;
+    each_entry(bytes, |path, is_dir, _, contents| {
+        let apath = dir.join(&path);
+        let write_error = |source| Error::Write {
+            path: path.display().to_string(),
+            source,
+        };
+        if is_dir {
+            return std::fs::create_dir_all(&apath).map_err(write_error);
+        }
+        if let Some(parent) = apath.parent() {
+            std::fs::create_dir_all(parent).map_err(write_error)?;
+        }
+        let created = !apath.exists();
+        let mut file = std::fs::File::create(&apath).map_err(write_error)?;
+        // The sizes in the headers can be wrong.
+        let mut contents = contents.take(max_size - written + 1);
+        written += io::copy(&mut contents, &mut file).map_err(write_error)?;
+        if written > max_size {
+            return Err(Error::TooLarge(max_size));
+        }
+        files.push((path, created));
+        Ok(())
+    })?;
+    Ok(files)
+}
+
+/// Write a zip of the files in `dir` to `out` without the ones matching `ignore`, or ignored by
+/// `.gitignore` if `gitignore`, returning `out` at the start.
+///
+/// Symlinks are left out, they could point out of the workspace.
+pub(super) fn create(
+    dir: &Path,
+    ignore: Vec<String>,
+    gitignore: bool,
+    out: File,
+) -> Result<File, Error> {
+    let root = dir.to_owned();
+    let walk = ignore::WalkBuilder::new(dir)
+        .standard_filters(false)
+        .git_ignore(gitignore)
+        .require_git(false)
+        .filter_entry(move |entry| {
+            let relative = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+            !files::is_ignored(&ignore, relative)
+        })
+        .build();
+    let mut writer = zip::ZipWriter::new(out);
+    for entry in walk {
+        let entry = entry.map_err(|err| Error::Create(err.to_string()))?;
+        let relative = match entry.path().strip_prefix(dir) {
+            Ok(relative) if relative.components().next().is_some() => relative,
+            _ => continue,
+        };
+        let name = files::slash_path(relative);
+        let read_error = |source| Error::Read {
+            path: name.clone(),
+            source,
+        };
+        let metadata = std::fs::symlink_metadata(entry.path()).map_err(read_error)?;
+        let options = zip::write::FileOptions::default();
+        #[cfg(unix)]
+        let options = {
+            use std::os::unix::fs::PermissionsExt;
+            options.unix_permissions(metadata.permissions().mode())
+        };
+        if metadata.is_dir() {
+            writer
+                .add_directory(name.as_str(), options)
+                .map_err(|err| Error::Create(err.to_string()))?;
+        } else if metadata.is_file() {
+            writer
+                .start_file(name.as_str(), options)
+                .map_err(|err| Error::Create(err.to_string()))?;
+            let mut file = File::open(entry.path()).map_err(read_error)?;
+            io::copy(&mut file, &mut writer).map_err(read_error)?;
+        }
+    }
+    let mut out = writer
+        .finish()
+        .map_err(|err| Error::Create(err.to_string()))?;
+    out.seek(SeekFrom::Start(0))
+        .map_err(|err| Error::Create(err.to_string()))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn zip_archive(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            writer
+                .start_file(*name, zip::write::FileOptions::default())
+                .unwrap();
+            writer.write_all(contents.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_extract() {
+        let dir = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("README.md"), "old").unwrap();
+
+        let archive = zip_archive(&[("src/main.rs", "fn main() {}\n"), ("README.md", "# Demo\n")]);
+        assert_eq!(
+            sizes(&archive).unwrap(),
+            vec![
+                (PathBuf::from("src/main.rs"), 13),
+                (PathBuf::from("README.md"), 7),
+            ]
+        );
+        let files = extract(&archive, &dir, 1024).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (PathBuf::from("src/main.rs"), true),
+                (PathBuf::from("README.md"), false),
+            ]
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("src/main.rs")).unwrap(),
+            "fn main() {}\n"
+        );
+
+        // Nothing is written if an entry is rejected
+        let archive = zip_archive(&[("a.rs", ""), ("../escape.rs", "")]);
+        assert!(matches!(
+            extract(&archive, &dir, 1024),
+            Err(Error::UnsafePath(_))
+        ));
+        assert!(!dir.join("a.rs").exists());
+        #[cfg(unix)]
+        {
+            // Not written through a symlink already in the workspace
+            let secret = dir.with_extension("secret");
+            std::fs::write(&secret, "secret").unwrap();
+            std::os::unix::fs::symlink(&secret, dir.join("link.txt")).unwrap();
+            let archive = zip_archive(&[("link.txt", "overwritten")]);
+            assert!(matches!(
+                extract(&archive, &dir, 1024),
+                Err(Error::UnsafePath(_))
+            ));
+            assert_eq!(std::fs::read_to_string(&secret).unwrap(), "secret");
+            std::fs::remove_file(secret).unwrap();
+        }
+        let archive = zip_archive(&[("big.txt", &"x".repeat(2048))]);
+        assert!(matches!(
+            extract(&archive, &dir, 1024),
+            Err(Error::TooLarge(1024))
+        ));
+        assert!(matches!(
+            extract(b"not an archive", &dir, 1024),
+            Err(Error::Unsupported)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_create() {
+        let dir = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        for (path, contents) in &[
+            ("src/main.rs", "fn main() {}\n"),
+            ("target/debug/main", ""),
+            (".git/HEAD", ""),
+            (".gitignore", "/target\n"),
+        ] {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        let names = |gitignore: bool| {
+            let out = tempfile(&dir);
+            let out = create(&dir, vec![".git".to_owned()], gitignore, out).unwrap();
+            let archive = zip::ZipArchive::new(out).unwrap();
+            let mut names = archive.file_names().map(String::from).collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+        assert_eq!(names(true), vec![".gitignore", "src/", "src/main.rs"]);
+        assert_eq!(
+            names(false),
+            vec![
+                ".gitignore",
+                "src/",
+                "src/main.rs",
+                "target/",
+                "target/debug/",
+                "target/debug/main",
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn tempfile(dir: &Path) -> File {
+        let path = dir.with_extension(format!("{}.zip", uuid::Uuid::new_v4()));
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(path).ok();
+        file
+    }
+}
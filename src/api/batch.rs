@@ -0,0 +1,123 @@
+//! Answering JSON-RPC batches from WebSocket clients with one array.
+//!
+//! A frame with a batch, an array of messages, is split into its messages for the server. The
+//! responses to the requests of the batch are held until all of them arrived, and are sent to the
+//! client in one array like JSON-RPC 2.0 requires. The other messages, like the notifications and
+//! requests from the server, are sent as they arrive. The responses held for a batch are sent when
+//! the connection closes before it was answered.
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+
+/// Batches from the client waiting for the responses, shared by both directions of a connection.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Batches(Arc<Mutex<Vec<Batch>>>);
+
+#[derive(Debug)]
+struct Batch {
+    /// IDs of the requests without a response yet.
+    waiting: Vec<Value>,
+    responses: Vec<Value>,
+}
+
+impl Batches {
+    /// Hold the responses to the requests in `batch` from the client.
+    pub(super) fn received(&self, batch: &[Value]) {
+        let waiting = batch
+            .iter()
+            .filter(|msg| msg.get("method").is_some())
+            .filter_map(|msg| msg.get("id").cloned())
+            .collect::<Vec<_>>();
+        // No response to a batch of notifications.
+        if !waiting.is_empty() {
+            self.0.lock().expect("lock batches").push(Batch {
+                waiting,
+                responses: Vec::new(),
+            });
+        }
+    }
+
+    /// The texts to send to the client for `text`: nothing if it's a response held for a batch,
+    /// the array of the responses if it was the last one, or `text` otherwise.
+    pub(super) fn on_send(&self, text: String) -> Vec<String> {
+        let mut batches = self.0.lock().expect("lock batches");
+        // Not parsed unless the client sent a batch.
+        if batches.is_empty() {
+            return vec![text];
+        }
+        let msg = match serde_json::from_str::<Value>(&text) {
+            Ok(msg) if msg.get("method").is_none() => msg,
+            _ => return vec![text],
+        };
+        let index = match msg
+            .get("id")
+            .and_then(|id| batches.iter().position(|batch| batch.waiting.contains(id)))
+        {
+            Some(index) => index,
+            None => return vec![text],
+        };
+        let batch = &mut batches[index];
+        batch.waiting.retain(|id| Some(id) != msg.get("id"));
+        batch.responses.push(msg);
+        if !batch.waiting.is_empty() {
+            return Vec::new();
+        }
+        vec![Value::Array(batches.remove(index).responses).to_string()]
+    }
+
+    /// The responses held for the batches not answered yet, when the connection closes.
+    pub(super) fn flush(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .expect("lock batches")
+            .drain(..)
+            .filter(|batch| !batch.responses.is_empty())
+            .map(|batch| Value::Array(batch.responses).to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_batches() {
+        let batches = Batches::default();
+        let progress = json!({"jsonrpc": "2.0", "method": "$/progress", "params": {}}).to_string();
+        assert_eq!(batches.on_send(progress.clone()), vec![progress.clone()]);
+
+        batches.received(&[
+            json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/hover"}),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            json!({"jsonrpc": "2.0", "id": "a", "method": "textDocument/definition"}),
+        ]);
+        let hover = json!({"jsonrpc": "2.0", "id": 1, "result": null});
+        let definition = json!({"jsonrpc": "2.0", "id": "a", "result": []});
+        assert!(batches.on_send(definition.to_string()).is_empty());
+        // Sent as they arrive
+        assert_eq!(batches.on_send(progress.clone()), vec![progress]);
+        let other = json!({"jsonrpc": "2.0", "id": 2, "result": null}).to_string();
+        assert_eq!(batches.on_send(other.clone()), vec![other]);
+        assert_eq!(
+            batches.on_send(hover.to_string()),
+            vec![json!([definition, hover]).to_string()]
+        );
+        assert!(batches.0.lock().unwrap().is_empty());
+
+        // Notifications only
+        batches.received(&[json!({"jsonrpc": "2.0", "method": "initialized", "params": {}})]);
+        assert!(batches.0.lock().unwrap().is_empty());
+
+        batches.received(&[
+            json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/hover"}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "textDocument/hover"}),
+        ]);
+        let hover = json!({"jsonrpc": "2.0", "id": 3, "result": null});
+        assert!(batches.on_send(hover.to_string()).is_empty());
+        assert_eq!(batches.flush(), vec![json!([hover]).to_string()]);
+        assert!(batches.flush().is_empty());
+    }
+}
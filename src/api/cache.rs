@@ -0,0 +1,178 @@
+//! Cache of the responses to idempotent requests, for each session.
+//!
+//! Requests with the methods given with `--cache`, like `textDocument/documentSymbol`, are
+//! answered from the cache when the client repeats them for the same version of the document,
+//! without sending them to the server. The responses for a document are evicted when it changes or
+//! closes.
+use std::{collections::HashMap, sync::Mutex};
+
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Add a middleware caching the responses to the requests with `methods` to `middlewares`.
+pub(super) fn apply(methods: &[String], middlewares: &mut Middlewares) {
+    if !methods.is_empty() {
+        middlewares.push(Cache {
+            methods: methods.to_vec(),
+            state: Default::default(),
+        });
+    }
+}
+
+/// Method, URI, version of the document, and params of a request.
+type Key = (String, String, i64, String);
+
+#[derive(Debug, Default)]
+struct State {
+    // Versions of the open documents.
+    versions: HashMap<String, i64>,
+    // Requests sent to the server, by id.
+    pending: HashMap<String, Key>,
+    responses: HashMap<Key, Value>,
+}
+
+impl State {
+    fn evict(&mut self, uri: &str) {
+        self.responses.retain(|(_, cached, _, _), _| cached != uri);
+    }
+}
+
+struct Cache {
+    methods: Vec<String>,
+    state: Mutex<State>,
+}
+
+impl Cache {
+    /// The cached response to the request `msg`, or `None` if it should be forwarded.
+    fn on_client(&self, msg: &Value) -> Option<Value> {
+        let method = msg.get("method").and_then(Value::as_str)?;
+        let params = msg.get("params");
+        let uri = params
+            .and_then(|params| params.pointer("/textDocument/uri"))
+            .and_then(Value::as_str);
+        let mut state = self.state.lock().expect("lock cache");
+        match (method, msg.get("id"), uri) {
+            ("textDocument/didOpen", None, Some(uri))
+            | ("textDocument/didChange", None, Some(uri)) => {
+                let version = params
+                    .and_then(|params| params.pointer("/textDocument/version"))
+                    .and_then(Value::as_i64);
+                state.evict(uri);
+                match version {
+                    Some(version) => state.versions.insert(uri.to_owned(), version),
+                    None => state.versions.remove(uri),
+                };
+                None
+            }
+            ("textDocument/didClose", None, Some(uri)) => {
+                state.evict(uri);
+                state.versions.remove(uri);
+                None
+            }
+            (method, Some(id), Some(uri)) if self.methods.iter().any(|m| m == method) => {
+                let version = *state.versions.get(uri)?;
+                let key = (
+                    method.to_owned(),
+                    uri.to_owned(),
+                    version,
+                    params.map(Value::to_string).unwrap_or_default(),
+                );
+                match state.responses.get(&key) {
+                    Some(result) => Some(json!({"jsonrpc": "2.0", "id": id, "result": result})),
+                    None => {
+                        state.pending.insert(id.to_string(), key);
+                        None
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Cache the result of the response `msg` if it's for a cached request.
+    fn on_server(&self, msg: &Value) {
+        if msg.get("method").is_some() {
+            return;
+        }
+        let id = match msg.get("id") {
+            Some(id) => id.to_string(),
+            None => return,
+        };
+        let mut state = self.state.lock().expect("lock cache");
+        if let Some(key) = state.pending.remove(&id) {
+            // Not cached if the document changed while the server was working on it.
+            let current = state.versions.get(&key.1) == Some(&key.2);
+            if let (Some(result), true) = (msg.get("result"), current) {
+                state.responses.insert(key, result.clone());
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Cache {
+    async fn on_client_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        let value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        match self
+            .on_client(&value)
+            .and_then(|response| serde_json::from_value(response).ok())
+        {
+            Some(response) => {
+                tracing::debug!("answered from the cache");
+                outbox.send_to_client(response);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if let Ok(value) = serde_json::to_value(&msg) {
+            self.on_server(&value);
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let cache = Cache {
+            methods: vec!["textDocument/foldingRange".to_owned()],
+            state: Default::default(),
+        };
+        let open = json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": {"uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": ""},
+        }});
+        let request = |id: i64| {
+            json!({"jsonrpc": "2.0", "id": id, "method": "textDocument/foldingRange", "params": {
+                "textDocument": {"uri": "file:///a.rs"},
+            }})
+        };
+        let change = json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": {"uri": "file:///a.rs", "version": 2},
+            "contentChanges": [{"text": "fn main() {}"}],
+        }});
+
+        assert_eq!(cache.on_client(&open), None);
+        assert_eq!(cache.on_client(&request(1)), None);
+        cache.on_server(&json!({"jsonrpc": "2.0", "id": 1, "result": []}));
+        assert_eq!(
+            cache.on_client(&request(2)),
+            Some(json!({"jsonrpc": "2.0", "id": 2, "result": []}))
+        );
+
+        // Evicted when the document changes
+        assert_eq!(cache.on_client(&change), None);
+        assert_eq!(cache.on_client(&request(3)), None);
+    }
+}
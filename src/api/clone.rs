@@ -0,0 +1,177 @@
+//! Cloning a git repository into the workspace of the session before starting the server.
+//!
+//! With `--repo <url>[#<branch>]`, or `?repo=<url>&branch=<branch>` with a URL starting with a
+//! prefix allowed with `--allow-repo`, the repository is cloned into the workspace of the session
+//! under `--clone-dir`, shallow unless `--clone-depth 0`. See [`super::workspace`].
+use std::{
+    collections::HashMap, convert::TryFrom, path::Path, process::Stdio, str::FromStr,
+    time::Duration,
+};
+
+use tokio::process::Command;
+use warp::{Filter, Rejection};
+
+use super::proxy::SharedContext;
+
+/// Time the clone has to finish before the session fails.
+const CLONE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Repository to clone, with the branch if not the default.
+///
+/// ```text
+/// https://github.com/qualified/lsp-ws-proxy
+/// https://github.com/qualified/lsp-ws-proxy#main
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Repo {
+    pub url: String,
+    pub branch: Option<String>,
+}
+
+impl Repo {
+    fn new(url: &str, branch: Option<&str>) -> Result<Self, String> {
+        // Would be taken as options by git.
+        if url.is_empty() || url.starts_with('-') {
+            return Err(format!("invalid repository {}", url));
+        }
+        if let Some(branch) = branch.filter(|branch| !is_valid_branch(branch)) {
+            return Err(format!("invalid branch {}", branch));
+        }
+        Ok(Self {
+            url: url.to_owned(),
+            branch: branch.map(String::from),
+        })
+    }
+}
+
+impl FromStr for Repo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.rsplit_once('#') {
+            Some((url, branch)) => Self::new(url, Some(branch)),
+            None => Self::new(s, None),
+        }
+    }
+}
+
+impl TryFrom<String> for Repo {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn is_valid_branch(branch: &str) -> bool {
+    !branch.is_empty()
+        && !branch.starts_with('-')
+        && !branch.contains("..")
+        && branch
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+/// Rejection for a repository in the query that isn't allowed.
+#[derive(Debug)]
+pub(super) struct InvalidRepo(pub(super) String);
+
+impl warp::reject::Reject for InvalidRepo {}
+
+/// Repository in the query parameters `repo` and `branch`, rejected with `InvalidRepo` unless it
+/// starts with a prefix allowed with `--allow-repo`.
+pub(super) fn with_repo(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Option<Repo>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .or_else(|_| async { Ok::<_, Rejection>((HashMap::new(),)) })
+        .and_then(move |query: HashMap<String, String>| {
+            let allowed = ctx.get().allowed_repos;
+            async move {
+                let url = match query.get("repo") {
+                    Some(url) => url,
+                    None => return Ok(None),
+                };
+                if !allowed
+                    .iter()
+                    .any(|prefix| url.starts_with(prefix.as_str()))
+                {
+                    return Err(warp::reject::custom(InvalidRepo(url.clone())));
+                }
+                Repo::new(url, query.get("branch").map(String::as_str))
+                    .map(Some)
+                    .map_err(|_| warp::reject::custom(InvalidRepo(url.clone())))
+            }
+        })
+}
+
+/// Clone `repo` into `dir`, with only `depth` commits if set.
+pub(super) async fn clone(
+    repo: &Repo,
+    dir: &Path,
+    depth: Option<u32>,
+) -> Result<(), std::io::Error> {
+    let mut command = Command::new("git");
+    command.args(&["-c", "protocol.ext.allow=never", "clone", "--quiet"]);
+    if let Some(depth) = depth {
+        command.arg("--depth").arg(depth.to_string());
+    }
+    if let Some(branch) = &repo.branch {
+        command.arg("--branch").arg(branch);
+    }
+    command
+        .arg("--")
+        .arg(&repo.url)
+        .arg(dir)
+        // Fail instead of waiting for credentials.
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    tracing::info!("cloning {} into {}", repo.url, dir.display());
+    let output = tokio::time::timeout(CLONE_TIMEOUT, command.output())
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("timed out cloning {}", repo.url),
+            )
+        })??;
+    if !output.status.success() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!(
+                "failed to clone {}: {}",
+                repo.url,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repo() {
+        assert_eq!(
+            "https://github.com/qualified/lsp-ws-proxy#release/0.9".parse::<Repo>(),
+            Ok(Repo {
+                url: "https://github.com/qualified/lsp-ws-proxy".to_owned(),
+                branch: Some("release/0.9".to_owned()),
+            })
+        );
+        assert_eq!(
+            "git@github.com:qualified/lsp-ws-proxy.git".parse::<Repo>(),
+            Ok(Repo {
+                url: "git@github.com:qualified/lsp-ws-proxy.git".to_owned(),
+                branch: None,
+            })
+        );
+        assert!("--upload-pack=touch /tmp/x".parse::<Repo>().is_err());
+        assert!("https://github.com/a/b#--orphan".parse::<Repo>().is_err());
+        assert!("https://github.com/a/b#a..b".parse::<Repo>().is_err());
+    }
+}
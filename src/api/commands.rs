@@ -0,0 +1,244 @@
+//! Allowlist of the commands of a server the client can execute.
+//!
+//! Some servers expose commands touching the host, like opening terminals or running builds.
+//! With `commands` of a server, `workspace/executeCommand` of other commands is rejected, and
+//! the commands are stripped from the code actions, code lenses, and completions of the server,
+//! and from `executeCommandProvider` in its capabilities.
+use std::{collections::HashSet, sync::Mutex};
+
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Add a middleware allowing only the `commands` to `middlewares`.
+pub(super) fn apply(commands: Option<&Vec<String>>, middlewares: &mut Middlewares) {
+    if let Some(commands) = commands {
+        middlewares.push(Allowlist {
+            commands: commands.clone(),
+            pending: Default::default(),
+        });
+    }
+}
+
+/// Methods with commands in their results.
+const WITH_COMMANDS: &[&str] = &[
+    "initialize",
+    "textDocument/codeAction",
+    "codeAction/resolve",
+    "textDocument/codeLens",
+    "codeLens/resolve",
+    "textDocument/completion",
+    "completionItem/resolve",
+];
+
+struct Allowlist {
+    /// Commands, ending with `*` to match the prefix.
+    commands: Vec<String>,
+    // Requests from the client with commands in their results, by id.
+    pending: Mutex<HashSet<String>>,
+}
+
+impl Allowlist {
+    fn allows(&self, command: &str) -> bool {
+        self.commands
+            .iter()
+            .any(|allowed| match allowed.strip_suffix('*') {
+                Some(prefix) => command.starts_with(prefix),
+                None => command == allowed,
+            })
+    }
+
+    /// Whether `value` is a `Command` that isn't allowed.
+    fn denies(&self, value: &Value) -> bool {
+        value
+            .get("command")
+            .and_then(Value::as_str)
+            .map_or(false, |command| !self.allows(command))
+    }
+
+    /// The response rejecting `msg` from the client if it executes a command that isn't allowed,
+    /// or track it if its result has commands.
+    fn on_client(&self, msg: &Value) -> Option<Value> {
+        let id = msg.get("id")?;
+        match msg.get("method").and_then(Value::as_str)? {
+            "workspace/executeCommand" => {
+                let command = msg.pointer("/params/command").and_then(Value::as_str)?;
+                if self.allows(command) {
+                    return None;
+                }
+                tracing::warn!("rejected command {}", command);
+                Some(json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {
+                        "code": -32600,
+                        "message": format!("command {} is not allowed by the proxy", command),
+                    },
+                }))
+            }
+            method if WITH_COMMANDS.contains(&method) => {
+                self.pending
+                    .lock()
+                    .expect("lock commands")
+                    .insert(id.to_string());
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Strip the commands that aren't allowed from the response `msg`, returning whether it
+    /// changed.
+    fn on_server(&self, msg: &mut Value) -> bool {
+        if msg.get("method").is_some() {
+            return false;
+        }
+        let id = match msg.get("id") {
+            Some(id) => id.to_string(),
+            None => return false,
+        };
+        if !self.pending.lock().expect("lock commands").remove(&id) {
+            return false;
+        }
+        let result = match msg.get_mut("result") {
+            Some(result) => result,
+            None => return false,
+        };
+        if let Some(Value::Array(commands)) =
+            result.pointer_mut("/capabilities/executeCommandProvider/commands")
+        {
+            let len = commands.len();
+            commands.retain(|command| command.as_str().map_or(false, |c| self.allows(c)));
+            return commands.len() != len;
+        }
+        self.strip(result)
+    }
+
+    /// Remove the commands that aren't allowed from the items or the item in `result`.
+    fn strip(&self, result: &mut Value) -> bool {
+        match result {
+            // `(Command | CodeAction)[]`, `CodeLens[]`, or `CompletionItem[]`
+            Value::Array(items) => {
+                let len = items.len();
+                // Commands of code actions
+                items.retain(|item| !self.denies(item));
+                let changed = items.len() != len;
+                items
+                    .iter_mut()
+                    .fold(changed, |changed, item| self.strip(item) || changed)
+            }
+            // `CompletionList`
+            Value::Object(list) if list.contains_key("items") => match list.get_mut("items") {
+                Some(items) => self.strip(items),
+                None => false,
+            },
+            // `CodeAction`, `CodeLens`, or `CompletionItem` with `command`
+            Value::Object(item) => {
+                let denied = item
+                    .get("command")
+                    .map_or(false, |command| command.is_object() && self.denies(command));
+                if denied {
+                    item.remove("command");
+                }
+                denied
+            }
+            _ => false,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Allowlist {
+    async fn on_client_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Request(_) | Message::Unknown(_)) {
+            return Some(msg);
+        }
+        let rejection = serde_json::to_value(&msg)
+            .ok()
+            .and_then(|value| self.on_client(&value))
+            .and_then(|response| serde_json::from_value(response).ok());
+        match rejection {
+            Some(rejection) => {
+                outbox.send_to_client(rejection);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Response(_) | Message::Unknown(_)) {
+            return Some(msg);
+        }
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        if self.on_server(&mut value) {
+            tracing::debug!("stripped commands that aren't allowed");
+            return Some(serde_json::from_value(value).unwrap_or(msg));
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let allowlist = Allowlist {
+            commands: vec![
+                "rust-analyzer.applySourceChange".to_owned(),
+                "editor.*".to_owned(),
+            ],
+            pending: Default::default(),
+        };
+        let execute = |command: &str| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "workspace/executeCommand", "params": {
+                "command": command,
+                "arguments": [],
+            }})
+        };
+        assert_eq!(
+            allowlist.on_client(&execute("editor.action.triggerSuggest")),
+            None
+        );
+        assert_eq!(
+            allowlist.on_client(&execute("rust-analyzer.runSingle")),
+            Some(json!({"jsonrpc": "2.0", "id": 1, "error": {
+                "code": -32600,
+                "message": "command rust-analyzer.runSingle is not allowed by the proxy",
+            }}))
+        );
+
+        allowlist
+            .on_client(&json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/codeAction"}));
+        let mut response = json!({"jsonrpc": "2.0", "id": 2, "result": [
+            {"title": "Run", "command": "rust-analyzer.runSingle"},
+            {"title": "Fix", "edit": {}, "command": {"title": "Run", "command": "terminal.open"}},
+            {"title": "Apply", "command": {"title": "Apply", "command": "rust-analyzer.applySourceChange"}},
+        ]});
+        assert!(allowlist.on_server(&mut response));
+        assert_eq!(
+            response["result"],
+            json!([
+                {"title": "Fix", "edit": {}},
+                {"title": "Apply", "command": {"title": "Apply", "command": "rust-analyzer.applySourceChange"}},
+            ])
+        );
+
+        allowlist.on_client(&json!({"jsonrpc": "2.0", "id": 0, "method": "initialize"}));
+        let mut initialize = json!({"jsonrpc": "2.0", "id": 0, "result": {"capabilities": {
+            "executeCommandProvider": {"commands": ["rust-analyzer.runSingle", "editor.fold"]},
+        }}});
+        assert!(allowlist.on_server(&mut initialize));
+        assert_eq!(
+            initialize["result"]["capabilities"]["executeCommandProvider"]["commands"],
+            json!(["editor.fold"])
+        );
+    }
+}
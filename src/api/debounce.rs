@@ -0,0 +1,147 @@
+//! Coalescing bursts of `textDocument/didChange` from the client.
+//!
+//! With `--coalesce-changes`, changes to a document are buffered for the delay, and consecutive
+//! ones are merged into a single notification with their content changes in order and the latest
+//! version, reducing the work of servers reanalyzing the document after each keystroke. Buffered
+//! changes are sent before any other message, so the server sees the messages in order.
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+/// Changes waiting to be sent to the server.
+#[derive(Debug)]
+pub(super) struct Debouncer {
+    delay: Duration,
+    buffered: Option<Buffered>,
+}
+
+#[derive(Debug)]
+struct Buffered {
+    uri: String,
+    msg: Value,
+    since: Instant,
+}
+
+impl Debouncer {
+    pub(super) fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            buffered: None,
+        }
+    }
+
+    /// Buffer `text` if it changes a document, returning the messages to send now, in order.
+    pub(super) fn push(&mut self, text: String, now: Instant) -> Vec<String> {
+        let msg = match serde_json::from_str::<Value>(&text) {
+            Ok(msg)
+                if msg.get("method").and_then(Value::as_str) == Some("textDocument/didChange") =>
+            {
+                msg
+            }
+            _ => return self.flush().into_iter().chain(Some(text)).collect(),
+        };
+        let uri = match msg
+            .pointer("/params/textDocument/uri")
+            .and_then(Value::as_str)
+        {
+            Some(uri) => uri.to_owned(),
+            None => return self.flush().into_iter().chain(Some(text)).collect(),
+        };
+        if let Some(buffered) = self.buffered.as_mut().filter(|b| b.uri == uri) {
+            merge(&mut buffered.msg, msg);
+            return Vec::new();
+        }
+        let flushed = self.flush();
+        self.buffered = Some(Buffered {
+            uri,
+            msg,
+            since: now,
+        });
+        flushed.into_iter().collect()
+    }
+
+    /// The buffered changes if they waited for the delay.
+    pub(super) fn flush_due(&mut self, now: Instant) -> Option<String> {
+        match &self.buffered {
+            Some(buffered) if now.saturating_duration_since(buffered.since) >= self.delay => {
+                self.flush()
+            }
+            _ => None,
+        }
+    }
+
+    /// The buffered changes.
+    pub(super) fn flush(&mut self) -> Option<String> {
+        self.buffered
+            .take()
+            .map(|buffered| buffered.msg.to_string())
+    }
+}
+
+/// Append the content changes of `next` to `msg`, and set the version of `next`.
+fn merge(msg: &mut Value, next: Value) {
+    if let Some(version) = next.pointer("/params/textDocument/version") {
+        msg["params"]["textDocument"]["version"] = version.clone();
+    }
+    let changes = match next
+        .pointer("/params/contentChanges")
+        .and_then(Value::as_array)
+    {
+        Some(changes) => changes.clone(),
+        None => return,
+    };
+    if let Some(merged) = msg
+        .pointer_mut("/params/contentChanges")
+        .and_then(Value::as_array_mut)
+    {
+        merged.extend(changes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn change(uri: &str, version: i64, text: &str) -> String {
+        json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": {"uri": uri, "version": version},
+            "contentChanges": [{"text": text}],
+        }})
+        .to_string()
+    }
+
+    #[test]
+    fn test_debouncer() {
+        let mut debouncer = Debouncer::new(Duration::from_millis(100));
+        let now = Instant::now();
+        assert!(debouncer
+            .push(change("file:///a.rs", 1, "a"), now)
+            .is_empty());
+        assert!(debouncer
+            .push(change("file:///a.rs", 2, "ab"), now)
+            .is_empty());
+        assert_eq!(debouncer.flush_due(now + Duration::from_millis(50)), None);
+        let merged = debouncer
+            .flush_due(now + Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&merged).unwrap()["params"],
+            json!({
+                "textDocument": {"uri": "file:///a.rs", "version": 2},
+                "contentChanges": [{"text": "a"}, {"text": "ab"}],
+            })
+        );
+
+        // Other messages are sent after the buffered changes.
+        assert!(debouncer
+            .push(change("file:///a.rs", 3, "abc"), now)
+            .is_empty());
+        let hover = r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#;
+        let sent = debouncer.push(hover.to_owned(), now);
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[1], hover);
+        assert_eq!(debouncer.flush(), None);
+    }
+}
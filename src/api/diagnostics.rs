@@ -0,0 +1,181 @@
+//! Rules transforming the diagnostics published by a server.
+//!
+//! With `diagnostics` of a server in the config file, `textDocument/publishDiagnostics` from the
+//! server is rewritten before it's sent to the client: diagnostics from some sources are dropped,
+//! severities are remapped, the ones less severe than a minimum are dropped, and the rest are
+//! capped for each file, keeping the most severe.
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Severity of a diagnostic, from the most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+    #[serde(alias = "info")]
+    Information = 3,
+    Hint = 4,
+}
+
+impl Severity {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value.as_u64()? {
+            1 => Some(Self::Error),
+            2 => Some(Self::Warning),
+            3 => Some(Self::Information),
+            4 => Some(Self::Hint),
+            _ => None,
+        }
+    }
+}
+
+/// Transformation of the diagnostics of a server.
+///
+/// ```toml
+/// [servers.diagnostics]
+/// drop-sources = ["clippy"]
+/// severities = { warning = "information" }
+/// min-severity = "information"
+/// max-per-file = 20
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DiagnosticRules {
+    /// Sources of the diagnostics to drop, like `clippy`.
+    pub drop_sources: HashSet<String>,
+    /// Severities to replace, applied before `min_severity`.
+    pub severities: HashMap<Severity, Severity>,
+    /// Drop the diagnostics less severe than this, e.g. `information` to drop hints.
+    pub min_severity: Option<Severity>,
+    /// Maximum number of diagnostics for each file, keeping the most severe.
+    pub max_per_file: Option<usize>,
+}
+
+impl DiagnosticRules {
+    /// Transform the diagnostics of `textDocument/publishDiagnostics` in `msg`.
+    fn apply(&self, msg: &mut Value) {
+        if msg.get("method").and_then(Value::as_str) != Some("textDocument/publishDiagnostics") {
+            return;
+        }
+        let diagnostics = match msg
+            .pointer_mut("/params/diagnostics")
+            .and_then(Value::as_array_mut)
+        {
+            Some(diagnostics) => diagnostics,
+            None => return,
+        };
+
+        diagnostics.retain(|diagnostic| {
+            diagnostic
+                .get("source")
+                .and_then(Value::as_str)
+                .map_or(true, |source| !self.drop_sources.contains(source))
+        });
+        for diagnostic in diagnostics.iter_mut() {
+            let severity = diagnostic.get("severity").and_then(Severity::from_value);
+            if let Some(remapped) = severity.and_then(|s| self.severities.get(&s)) {
+                diagnostic["severity"] = Value::from(*remapped as u8);
+            }
+        }
+        if let Some(min) = self.min_severity {
+            // Diagnostics without a severity are left to the client to interpret.
+            diagnostics.retain(|diagnostic| {
+                diagnostic
+                    .get("severity")
+                    .and_then(Severity::from_value)
+                    .map_or(true, |severity| severity <= min)
+            });
+        }
+        if let Some(max) = self.max_per_file {
+            if diagnostics.len() > max {
+                diagnostics.sort_by_key(|diagnostic| {
+                    diagnostic
+                        .get("severity")
+                        .and_then(Severity::from_value)
+                        .unwrap_or(Severity::Error)
+                });
+                diagnostics.truncate(max);
+            }
+        }
+    }
+}
+
+/// Add a middleware transforming the diagnostics with `rules` to `middlewares`.
+pub(super) fn apply(rules: Option<&DiagnosticRules>, middlewares: &mut Middlewares) {
+    if let Some(rules) = rules {
+        middlewares.push(Transformer(rules.clone()));
+    }
+}
+
+struct Transformer(DiagnosticRules);
+
+#[async_trait::async_trait]
+impl Middleware for Transformer {
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Notification(_)) {
+            return Some(msg);
+        }
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        self.0.apply(&mut value);
+        Some(serde_json::from_value(value).unwrap_or(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_diagnostic_rules() {
+        let rules: DiagnosticRules = toml::from_str(
+            r#"
+            drop-sources = ["clippy"]
+            severities = { warning = "information" }
+            min-severity = "information"
+            max-per-file = 2
+            "#,
+        )
+        .unwrap();
+        let diagnostic = |source: &str, severity: u8, message: &str| {
+            json!({
+                "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 1}},
+                "source": source,
+                "severity": severity,
+                "message": message,
+            })
+        };
+        let mut msg = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {
+                "uri": "file:///a.rs",
+                "diagnostics": [
+                    diagnostic("rustc", 4, "hint"),
+                    diagnostic("clippy", 1, "lint"),
+                    diagnostic("rustc", 2, "unused"),
+                    diagnostic("rustc", 3, "info"),
+                    diagnostic("rustc", 1, "mismatched types"),
+                ],
+            },
+        });
+        rules.apply(&mut msg);
+        assert_eq!(
+            msg["params"]["diagnostics"],
+            json!([
+                diagnostic("rustc", 1, "mismatched types"),
+                diagnostic("rustc", 3, "unused"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,347 @@
+//! Applying `workspace/applyEdit` from the server to the files of the workspace.
+//!
+//! Refactorings like renames ask the client to apply their edits with `workspace/applyEdit`, and
+//! browser clients can usually only apply them to the documents they have open. With `--sync` and
+//! `--apply-edits`, edits of only documents the client hasn't opened are applied to the files by
+//! the proxy, and the server gets the response without the client seeing the request. Edits of
+//! open documents are forwarded to the client, which saves them with `didSave`. Edits that would
+//! put the workspace over `--quota` aren't applied.
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use serde_json::{json, Value};
+use thiserror::Error;
+use tokio::fs;
+use url::Url;
+
+use crate::lsp;
+
+use super::{quota, sandbox, text};
+
+#[derive(Debug, Error)]
+enum Error {
+    #[error("invalid edit")]
+    InvalidEdit,
+
+    #[error("{0} is not in the workspace")]
+    NotWorkspacePath(String),
+
+    #[error("overlapping edits in {0}")]
+    Overlapping(String),
+
+    #[error("{0} already exists")]
+    Exists(String),
+
+    #[error("{path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Quota(#[from] quota::Exceeded),
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |source| Error::Io {
+        path: path.display().to_string(),
+        source,
+    }
+}
+
+/// Documents open by the client, with the edits left to it.
+#[derive(Debug, Default)]
+pub(super) struct OpenDocuments(HashSet<String>);
+
+impl OpenDocuments {
+    /// Track the documents opened and closed by `msg` from the client.
+    pub(super) fn on_client(&mut self, msg: &lsp::Message) {
+        match msg {
+            lsp::Message::Notification(lsp::Notification::DidOpen { params }) => {
+                self.0.insert(params.text_document.uri.to_string());
+            }
+            lsp::Message::Notification(lsp::Notification::DidClose { params }) => {
+                self.0.remove(params.text_document.uri.as_str());
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether `text` from the server is `workspace/applyEdit` the proxy applies, without edits
+    /// of open documents.
+    pub(super) fn applies(&self, text: &str) -> bool {
+        // Most messages aren't, so they're not parsed.
+        if !text.contains("workspace/applyEdit") {
+            return false;
+        }
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return false,
+        };
+        if msg.get("method").and_then(Value::as_str) != Some("workspace/applyEdit") {
+            return false;
+        }
+        match msg.pointer("/params/edit") {
+            Some(edit) => uris(edit).iter().all(|uri| !self.0.contains(uri)),
+            None => false,
+        }
+    }
+}
+
+/// URIs of the documents changed by `WorkspaceEdit`, normalized like the ones from the client.
+fn uris(edit: &Value) -> Vec<String> {
+    let mut uris = Vec::new();
+    if let Some(Value::Object(changes)) = edit.get("changes") {
+        uris.extend(changes.keys().map(String::as_str));
+    }
+    if let Some(Value::Array(changes)) = edit.get("documentChanges") {
+        for change in changes {
+            let uri = change
+                .pointer("/textDocument/uri")
+                .or_else(|| change.get("uri"));
+            uris.extend(
+                [uri, change.get("oldUri"), change.get("newUri")]
+                    .iter()
+                    .filter_map(|uri| uri.and_then(Value::as_str)),
+            );
+        }
+    }
+    uris.into_iter()
+        .map(|uri| Url::parse(uri).map_or_else(|_| uri.to_owned(), String::from))
+        .collect()
+}
+
+/// Apply the `workspace/applyEdit` request in `text` to the files in `root` within `quota`,
+/// returning the response for the server.
+pub(super) async fn apply(text: &str, root: &Path, quota: &quota::Quota) -> String {
+    let msg = serde_json::from_str::<Value>(text).unwrap_or_default();
+    let applied = match msg.pointer("/params/edit") {
+        Some(edit) => apply_edit(edit, root, quota).await,
+        None => Err(Error::InvalidEdit),
+    };
+    let result = match applied {
+        Ok(()) => {
+            tracing::info!("applied workspace edit to the files");
+            json!({"applied": true})
+        }
+        Err(err) => {
+            tracing::warn!("failed to apply workspace edit: {}", err);
+            json!({"applied": false, "failureReason": err.to_string()})
+        }
+    };
+    json!({"jsonrpc": "2.0", "id": msg.get("id"), "result": result}).to_string()
+}
+
+/// Apply the changes of `WorkspaceEdit` in order, stopping at the first failure.
+async fn apply_edit(edit: &Value, root: &Path, quota: &quota::Quota) -> Result<(), Error> {
+    // Preferred over `changes` when both are present.
+    if let Some(Value::Array(changes)) = edit.get("documentChanges") {
+        for change in changes {
+            match change.get("kind").and_then(Value::as_str) {
+                Some("create") => create_file(change, root, quota).await?,
+                // Renaming doesn't add to the size or the number of the files.
+                Some("rename") => rename_file(change, root).await?,
+                Some("delete") => delete_file(change, root).await?,
+                _ => {
+                    let path = get_path(change.pointer("/textDocument/uri"), root)?;
+                    edit_file(&path, change.get("edits"), root, quota).await?;
+                }
+            }
+        }
+    } else if let Some(Value::Object(changes)) = edit.get("changes") {
+        for (uri, edits) in changes {
+            let path = get_path(Some(&Value::from(uri.as_str())), root)?;
+            edit_file(&path, Some(edits), root, quota).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of the file `uri` in `root`.
+fn get_path(uri: Option<&Value>, root: &Path) -> Result<PathBuf, Error> {
+    let uri = uri.and_then(Value::as_str).ok_or(Error::InvalidEdit)?;
+    let path = Url::parse(uri)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+        .ok_or_else(|| Error::NotWorkspacePath(uri.to_owned()))?;
+    // Symlinks in the workspace can point out of it.
+    if sandbox::resolve(root, &path).is_none() {
+        return Err(Error::NotWorkspacePath(uri.to_owned()));
+    }
+    Ok(path)
+}
+
+/// Whether `flag` of the `options` of the change is set.
+fn option(change: &Value, flag: &str) -> bool {
+    change
+        .get("options")
+        .and_then(|options| options.get(flag))
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+async fn edit_file(
+    path: &Path,
+    edits: Option<&Value>,
+    root: &Path,
+    quota: &quota::Quota,
+) -> Result<(), Error> {
+    let edits = edits.and_then(Value::as_array).ok_or(Error::InvalidEdit)?;
+    let mut text = fs::read_to_string(path).await.map_err(io_error(path))?;
+    let mut ranges = edits
+        .iter()
+        .map(|edit| {
+            let start = text::offset_at(&text, edit.pointer("/range/start")?)?;
+            let end = text::offset_at(&text, edit.pointer("/range/end")?)?;
+            let new_text = edit.get("newText")?.as_str()?;
+            Some((start, end.max(start), new_text))
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::InvalidEdit)?;
+    // Stable, so inserts at the same position keep their order.
+    ranges.sort_by_key(|(start, end, _)| (*start, *end));
+    if ranges.windows(2).any(|pair| pair[0].1 > pair[1].0) {
+        return Err(Error::Overlapping(path.display().to_string()));
+    }
+    for (start, end, new_text) in ranges.into_iter().rev() {
+        text.replace_range(start..end, new_text);
+    }
+    let files = vec![(path.to_owned(), text.len() as u64)];
+    quota.check_blocking(root.to_owned(), files).await?;
+    tracing::debug!("writing edits to {:?}", path);
+    fs::write(path, text).await.map_err(io_error(path))
+}
+
+async fn create_parent_dirs(path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await.map_err(io_error(parent))?;
+    }
+    Ok(())
+}
+
+async fn create_file(change: &Value, root: &Path, quota: &quota::Quota) -> Result<(), Error> {
+    let path = get_path(change.get("uri"), root)?;
+    if path.exists() && !option(change, "overwrite") {
+        if option(change, "ignoreIfExists") {
+            return Ok(());
+        }
+        return Err(Error::Exists(path.display().to_string()));
+    }
+    quota
+        .check_blocking(root.to_owned(), vec![(path.clone(), 0)])
+        .await?;
+    create_parent_dirs(&path).await?;
+    tracing::debug!("creating {:?}", path);
+    fs::write(&path, "").await.map_err(io_error(&path))
+}
+
+async fn rename_file(change: &Value, root: &Path) -> Result<(), Error> {
+    let from = get_path(change.get("oldUri"), root)?;
+    let to = get_path(change.get("newUri"), root)?;
+    if to.exists() && !option(change, "overwrite") {
+        if option(change, "ignoreIfExists") {
+            return Ok(());
+        }
+        return Err(Error::Exists(to.display().to_string()));
+    }
+    create_parent_dirs(&to).await?;
+    tracing::debug!("renaming {:?} to {:?}", from, to);
+    fs::rename(&from, &to).await.map_err(io_error(&from))
+}
+
+async fn delete_file(change: &Value, root: &Path) -> Result<(), Error> {
+    let path = get_path(change.get("uri"), root)?;
+    let metadata = match fs::metadata(&path).await {
+        Ok(metadata) => metadata,
+        Err(_) if option(change, "ignoreIfNotExists") => return Ok(()),
+        Err(source) => return Err(io_error(&path)(source)),
+    };
+    tracing::debug!("deleting {:?}", path);
+    let deleted = if !metadata.is_dir() {
+        fs::remove_file(&path).await
+    } else if option(change, "recursive") {
+        fs::remove_dir_all(&path).await
+    } else {
+        fs::remove_dir(&path).await
+    };
+    deleted.map_err(io_error(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply_edits() {
+        let root = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("a.rs"), "fn foo() {}\nfn main() { foo(); }\n").unwrap();
+        let uri = |path: &str| Url::from_file_path(root.join(path)).unwrap().to_string();
+
+        let mut documents = OpenDocuments::default();
+        let rename = json!({"jsonrpc": "2.0", "id": 3, "method": "workspace/applyEdit", "params": {
+            "edit": {"documentChanges": [
+                {"textDocument": {"uri": uri("a.rs"), "version": null}, "edits": [
+                    {"range": {"start": {"line": 1, "character": 12}, "end": {"line": 1, "character": 15}}, "newText": "bar"},
+                    {"range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 6}}, "newText": "bar"},
+                ]},
+                {"kind": "rename", "oldUri": uri("a.rs"), "newUri": uri("src/b.rs")},
+            ]},
+        }})
+        .to_string();
+        assert!(documents.applies(&rename));
+        assert_eq!(
+            serde_json::from_str::<Value>(&apply(&rename, &root, &quota::Quota::default()).await)
+                .unwrap(),
+            json!({"jsonrpc": "2.0", "id": 3, "result": {"applied": true}})
+        );
+        assert_eq!(
+            std::fs::read_to_string(root.join("src/b.rs")).unwrap(),
+            "fn bar() {}\nfn main() { bar(); }\n"
+        );
+
+        // Left to the client if it has the document open
+        let open = json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": {"uri": uri("a.rs"), "languageId": "rust", "version": 1, "text": ""},
+        }});
+        documents.on_client(&serde_json::from_value(open).unwrap());
+        assert!(!documents.applies(&rename));
+
+        let outside =
+            json!({"jsonrpc": "2.0", "id": 4, "method": "workspace/applyEdit", "params": {
+                "edit": {"changes": {"file:///etc/passwd": []}},
+            }})
+            .to_string();
+        let response =
+            serde_json::from_str::<Value>(&apply(&outside, &root, &quota::Quota::default()).await)
+                .unwrap();
+        assert_eq!(response["result"]["applied"], json!(false));
+
+        // Over the quota of the workspace
+        let quota = quota::Quota::new(&["files=1".parse().unwrap()]);
+        let create = json!({"jsonrpc": "2.0", "id": 5, "method": "workspace/applyEdit", "params": {
+            "edit": {"documentChanges": [{"kind": "create", "uri": uri("c.rs")}]},
+        }})
+        .to_string();
+        let response = serde_json::from_str::<Value>(&apply(&create, &root, &quota).await).unwrap();
+        assert_eq!(response["result"]["applied"], json!(false));
+        assert!(!root.join("c.rs").exists());
+        let quota = quota::Quota::new(&["file-size=1".parse().unwrap()]);
+        let edit = json!({"jsonrpc": "2.0", "id": 6, "method": "workspace/applyEdit", "params": {
+            "edit": {"changes": {uri("src/b.rs"): [
+                {"range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}}, "newText": "x".repeat(1024)},
+            ]}},
+        }})
+        .to_string();
+        let response = serde_json::from_str::<Value>(&apply(&edit, &root, &quota).await).unwrap();
+        assert_eq!(response["result"]["applied"], json!(false));
+        assert_eq!(
+            std::fs::read_to_string(root.join("src/b.rs")).unwrap(),
+            "fn bar() {}\nfn main() { bar(); }\n"
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+//! WebSocket close codes telling the client why the session ended, so it can decide whether to
+//! reconnect.
+//!
+//! | Code | Reason                                          |
+//! | ---- | ----------------------------------------------- |
+//! | 1000 | The server exited cleanly                       |
+//! | 1001 | The proxy is shutting down                      |
+//! | 4000 | The server kept crashing with `--restart`       |
+//! | 4001 | The server crashed                              |
+//! | 4002 | The server was killed, e.g. when out of memory  |
+//! | 4003 | The proxy ended the session                     |
+use std::process::ExitStatus;
+
+use serde_json::{json, Value};
+
+/// Close code when the proxy is shutting down.
+pub(super) const SHUTDOWN: u16 = 1001;
+/// Close code when `--restart` gave up.
+pub(super) const SERVER_FAILED: u16 = 4000;
+/// Close code when the proxy ended the session, e.g. after `--idle-timeout`.
+pub(super) const ENDED: u16 = 4003;
+
+/// How the server process exited.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Exit {
+    /// Exited with 0.
+    Clean,
+    /// Exited with a non-zero code, or a signal other than `SIGKILL`.
+    Crashed(String),
+    /// Killed with `SIGKILL`, usually by the OOM killer.
+    Killed,
+}
+
+impl From<ExitStatus> for Exit {
+    fn from(status: ExitStatus) -> Self {
+        if status.success() {
+            return Self::Clean;
+        }
+        if let Some(code) = status.code() {
+            return Self::Crashed(format!("exit code {}", code));
+        }
+        from_signal(status)
+    }
+}
+
+#[cfg(unix)]
+fn from_signal(status: ExitStatus) -> Exit {
+    use std::os::unix::process::ExitStatusExt;
+
+    match status.signal() {
+        Some(libc::SIGKILL) => Exit::Killed,
+        Some(signal) => Exit::Crashed(format!("signal {}", signal)),
+        None => Exit::Crashed(status.to_string()),
+    }
+}
+
+#[cfg(not(unix))]
+fn from_signal(status: ExitStatus) -> Exit {
+    Exit::Crashed(status.to_string())
+}
+
+impl Exit {
+    pub(super) fn close_code(&self) -> u16 {
+        match self {
+            Self::Clean => 1000,
+            Self::Crashed(_) => 4001,
+            Self::Killed => 4002,
+        }
+    }
+
+    /// Reason of the close frame, within its 123 bytes.
+    pub(super) fn reason(&self) -> String {
+        match self {
+            Self::Clean => "server exited".to_owned(),
+            Self::Crashed(how) => format!("server crashed with {}", how),
+            Self::Killed => "server was killed".to_owned(),
+        }
+    }
+}
+
+/// `window/logMessage` with the end of stderr of the server, or `None` if it didn't write any.
+pub(super) fn stderr_message(reason: &str, tail: &str) -> Option<Value> {
+    if tail.is_empty() {
+        return None;
+    }
+    Some(json!({
+        "jsonrpc": "2.0",
+        "method": "window/logMessage",
+        "params": {
+            "type": 1,
+            "message": format!("Language server exited ({}). The end of its stderr:\n{}", reason, tail),
+        },
+    }))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn test_exit_status() {
+        // Wait statuses as returned by `waitpid`.
+        assert_eq!(Exit::from(ExitStatus::from_raw(0)), Exit::Clean);
+        assert_eq!(
+            Exit::from(ExitStatus::from_raw(1 << 8)),
+            Exit::Crashed("exit code 1".to_owned())
+        );
+        assert_eq!(
+            Exit::from(ExitStatus::from_raw(libc::SIGKILL)),
+            Exit::Killed
+        );
+        assert_eq!(
+            Exit::from(ExitStatus::from_raw(libc::SIGSEGV)).close_code(),
+            4001
+        );
+    }
+}
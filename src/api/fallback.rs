@@ -0,0 +1,227 @@
+//! Fall back to another server when the server fails to start or to initialize.
+//!
+//! The `initialize` request from the client is sent to the server, and if the server exits or
+//! responds with an error, the request is sent to the fallback server instead.
+use std::str::FromStr;
+
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde_json::Value;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::Instrument;
+
+use crate::{
+    backend,
+    lsp::{self, framed::LspFrameCodec},
+};
+
+use super::proxy::{self, Context, Query};
+
+/// Server to use when the named server fails.
+///
+/// ```text
+/// rust-analyzer=rls
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fallback {
+    pub name: String,
+    pub fallback: String,
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, fallback)) if !name.is_empty() && !fallback.is_empty() => Ok(Self {
+                name: name.to_owned(),
+                fallback: fallback.to_owned(),
+            }),
+            _ => Err(format!("expected <name>=<fallback>, got {}", s)),
+        }
+    }
+}
+
+/// Start the server selected by `query`, or `fallback` if it fails.
+pub(super) fn connect(
+    ctx: &Context,
+    query: Option<&Query>,
+    fallback: &str,
+) -> Result<backend::Connection, std::io::Error> {
+    let fallback = Query {
+        name: fallback.to_owned(),
+    };
+    let server = match proxy::spawn_server(ctx, query) {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::warn!("failed to start server, using {}: {}", fallback.name, err);
+            return proxy::spawn_server(ctx, Some(&fallback));
+        }
+    };
+
+    let (front, back) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(back);
+    let ctx = ctx.clone();
+    tokio::spawn(
+        async move {
+            if let Err(err) = forward(&ctx, &fallback, server, reader, writer).await {
+                tracing::error!("fallback error: {}", err);
+            }
+        }
+        .in_current_span(),
+    );
+    let (reader, writer) = tokio::io::split(front);
+    Ok(backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    })
+}
+
+struct Server {
+    send: FramedWrite<backend::Writer, LspFrameCodec>,
+    recv: FramedRead<backend::Reader, LspFrameCodec>,
+    _child: Option<backend::Process>,
+}
+
+impl From<backend::Connection> for Server {
+    fn from(conn: backend::Connection) -> Self {
+        Self {
+            send: lsp::framed::writer(conn.writer),
+            recv: lsp::framed::reader(conn.reader),
+            _child: conn.child,
+        }
+    }
+}
+
+enum Event {
+    Client(Option<String>),
+    Server(Option<String>),
+}
+
+async fn forward<R, W>(
+    ctx: &Context,
+    fallback: &Query,
+    server: backend::Connection,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut client_send = lsp::framed::writer(writer);
+    let mut client_recv = lsp::framed::reader(reader).filter_map(|msg| future::ready(msg.ok()));
+    let first = match client_recv.next().await {
+        Some(text) => text,
+        None => return Ok(()),
+    };
+    let mut server = Server::from(server);
+    server.send.send(first.clone()).await?;
+
+    if let Some(id) = initialize_id(&first) {
+        let mut initialized = false;
+        // Forward anything else from the server until the response.
+        while let Some(text) = server.recv.next().await {
+            let text = match text {
+                Ok(text) => text,
+                Err(_) => continue,
+            };
+            match response_to(&text, &id) {
+                Some(true) => {
+                    client_send.send(text).await?;
+                    initialized = true;
+                    break;
+                }
+                Some(false) => break,
+                None => client_send.send(text).await?,
+            }
+        }
+        if !initialized {
+            tracing::warn!("server failed to initialize, using {}", fallback.name);
+            server = Server::from(proxy::spawn_server(ctx, Some(fallback))?);
+            server.send.send(first).await?;
+        }
+    }
+
+    let Server {
+        mut send,
+        recv,
+        _child,
+    } = server;
+    let client_recv = client_recv
+        .map(|text| Event::Client(Some(text)))
+        .chain(stream::once(future::ready(Event::Client(None))));
+    let server_recv = recv
+        .filter_map(|msg| future::ready(msg.ok()))
+        .map(|text| Event::Server(Some(text)))
+        .chain(stream::once(future::ready(Event::Server(None))));
+    let mut events = stream::select(client_recv, server_recv);
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Client(Some(text)) => send.send(text).await?,
+            Event::Server(Some(text)) => client_send.send(text).await?,
+            Event::Client(None) | Event::Server(None) => break,
+        }
+    }
+    Ok(())
+}
+
+// ID of the message if it's `initialize`.
+fn initialize_id(text: &str) -> Option<Value> {
+    let msg: Value = serde_json::from_str(text).ok()?;
+    if msg.get("method").and_then(Value::as_str) == Some("initialize") {
+        msg.get("id").cloned()
+    } else {
+        None
+    }
+}
+
+// Whether the message is a successful response to the request `id`, or `None` if not a response.
+fn response_to(text: &str, id: &Value) -> Option<bool> {
+    let msg: Value = serde_json::from_str(text).ok()?;
+    if msg.get("method").is_some() || msg.get("id") != Some(id) {
+        return None;
+    }
+    Some(msg.get("error").map_or(true, Value::is_null))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fallback() {
+        assert_eq!(
+            "rust-analyzer=rls".parse::<Fallback>(),
+            Ok(Fallback {
+                name: "rust-analyzer".to_owned(),
+                fallback: "rls".to_owned(),
+            })
+        );
+        assert!("rust-analyzer".parse::<Fallback>().is_err());
+    }
+
+    #[test]
+    fn test_response_to_initialize() {
+        let id = initialize_id(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#);
+        let id = id.unwrap();
+        assert_eq!(
+            response_to(r#"{"jsonrpc":"2.0","id":1,"result":{}}"#, &id),
+            Some(true)
+        );
+        assert_eq!(
+            response_to(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32603,"message":"failed"}}"#,
+                &id
+            ),
+            Some(false)
+        );
+        assert_eq!(
+            response_to(
+                r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#,
+                &id
+            ),
+            None
+        );
+    }
+}
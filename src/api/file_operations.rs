@@ -0,0 +1,332 @@
+//! Notifying the servers of the files removed and renamed with `POST /files`.
+//!
+//! Servers keep an index of the workspace, and miss the files removed and renamed by the proxy
+//! unless they watch the files. The operations are broadcast to the sessions, which send
+//! `workspace/didDeleteFiles` and `workspace/didRenameFiles` to the servers declaring them in
+//! `workspace.fileOperations` of their capabilities. Before renaming, the servers declaring
+//! `willRename` are asked for the edits to make with `workspace/willRenameFiles`, which are
+//! returned to the client to apply. The filters of the capabilities are not checked.
+//!
+//! Only the sessions in the workspace of the operations receive them, so the isolated sessions
+//! don't learn of each other's files.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{stream, Stream};
+use serde_json::{json, Value};
+use tokio::sync::{broadcast, mpsc};
+use url::Url;
+
+/// Time the servers have to respond to `workspace/willRenameFiles`.
+const WILL_RENAME_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Prefix of the ids of `workspace/willRenameFiles` from the proxy.
+const WILL_RENAME_ID: &str = "lsp-ws-proxy/willRenameFiles/";
+
+/// Operation on the files of the workspace, with the URIs on the servers.
+#[derive(Debug, Clone)]
+pub(super) enum Event {
+    /// The files are about to be renamed from and to. Each session replies with the edit of its
+    /// server, if any.
+    WillRename {
+        files: Vec<(Url, Url)>,
+        edits: mpsc::UnboundedSender<Option<Value>>,
+    },
+    /// The files were renamed from and to.
+    Renamed(Vec<(Url, Url)>),
+    /// The files were removed.
+    Deleted(Vec<Url>),
+}
+
+/// Operations on the files, broadcast to the sessions in the workspace.
+#[derive(Debug, Clone, Default)]
+pub struct FileOperations {
+    // Channels of the sessions by the directory of their workspace.
+    channels: Arc<Mutex<HashMap<PathBuf, broadcast::Sender<Event>>>>,
+}
+
+impl FileOperations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Send `event` to the sessions in `root`, returning how many there are.
+    fn send(&self, root: &Path, event: Event) -> usize {
+        let channels = self.channels.lock().expect("lock file operations");
+        channels
+            .get(root)
+            .and_then(|events| events.send(event).ok())
+            .unwrap_or(0)
+    }
+
+    /// Ask the servers of the sessions in `root` for the edits to make before renaming `files`,
+    /// until all of them responded or the timeout.
+    pub(super) async fn will_rename(&self, root: &Path, files: Vec<(Url, Url)>) -> Vec<Value> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let sessions = self.send(root, Event::WillRename { files, edits: tx });
+        let mut edits = Vec::new();
+        let replies = async {
+            for _ in 0..sessions {
+                match rx.recv().await {
+                    Some(Some(edit)) => edits.push(edit),
+                    Some(None) => {}
+                    None => break,
+                }
+            }
+        };
+        if tokio::time::timeout(WILL_RENAME_TIMEOUT, replies)
+            .await
+            .is_err()
+        {
+            tracing::warn!("servers didn't respond to willRenameFiles in time");
+        }
+        edits
+    }
+
+    pub(super) fn renamed(&self, root: &Path, files: Vec<(Url, Url)>) {
+        self.send(root, Event::Renamed(files));
+    }
+
+    pub(super) fn deleted(&self, root: &Path, files: Vec<Url>) {
+        self.send(root, Event::Deleted(files));
+    }
+
+    /// Stream of the operations in the workspace `root`, from when it's called.
+    pub(super) fn events(&self, root: &Path) -> impl Stream<Item = Event> + Send + Unpin {
+        let rx = {
+            let mut channels = self.channels.lock().expect("lock file operations");
+            // Of the workspaces without sessions anymore.
+            channels.retain(|_, events| events.receiver_count() > 0);
+            channels
+                .entry(root.to_owned())
+                .or_insert_with(|| broadcast::channel(16).0)
+                .subscribe()
+        };
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+}
+
+/// File operations the server declared in its capabilities.
+#[derive(Debug, Default, Clone, Copy)]
+struct Support {
+    will_rename: bool,
+    did_rename: bool,
+    did_delete: bool,
+}
+
+/// File operations of the server of a session.
+#[derive(Debug, Default)]
+pub(super) struct Session {
+    support: Support,
+    // Replies to `workspace/willRenameFiles` by the id of the request.
+    pending: HashMap<String, mpsc::UnboundedSender<Option<Value>>>,
+    next_id: u64,
+}
+
+impl Session {
+    /// Set the file operations the server supports from its `initialize` response in `text`.
+    pub(super) fn on_server_text(&mut self, text: &str) {
+        // Most messages don't have them, so they're not parsed.
+        if !text.contains("fileOperations") {
+            return;
+        }
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        if let Some(operations) = msg.pointer("/result/capabilities/workspace/fileOperations") {
+            let declared = |name: &str| {
+                operations
+                    .get(name)
+                    .map_or(false, |options| !options.is_null())
+            };
+            self.support = Support {
+                will_rename: declared("willRename"),
+                did_rename: declared("didRename"),
+                did_delete: declared("didDelete"),
+            };
+        }
+    }
+
+    /// The message to send to the server for `event`, if it supports it.
+    pub(super) fn on_event(&mut self, event: Event) -> Option<String> {
+        match event {
+            Event::WillRename { files, edits } => {
+                if !self.support.will_rename {
+                    edits.send(None).ok();
+                    return None;
+                }
+                self.next_id += 1;
+                let id = format!("{}{}", WILL_RENAME_ID, self.next_id);
+                self.pending.insert(id.clone(), edits);
+                Some(request(id, &files).to_string())
+            }
+            Event::Renamed(files) if self.support.did_rename => Some(
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "workspace/didRenameFiles",
+                    "params": {"files": renames(&files)},
+                })
+                .to_string(),
+            ),
+            Event::Deleted(files) if self.support.did_delete => Some(
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "workspace/didDeleteFiles",
+                    "params": {
+                        "files": files.iter().map(|uri| json!({"uri": uri})).collect::<Vec<_>>(),
+                    },
+                })
+                .to_string(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Whether `text` from the server is the response to `workspace/willRenameFiles` from the
+    /// proxy, replying with its edit.
+    pub(super) fn responds(&mut self, text: &str) -> bool {
+        if self.pending.is_empty() || !text.contains(WILL_RENAME_ID) {
+            return false;
+        }
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return false,
+        };
+        if msg.get("method").is_some() {
+            return false;
+        }
+        let edits = match msg.get("id").and_then(Value::as_str) {
+            Some(id) => self.pending.remove(id),
+            None => None,
+        };
+        match edits {
+            Some(edits) => {
+                let edit = msg.get("result").filter(|edit| !edit.is_null()).cloned();
+                edits.send(edit).ok();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+fn request(id: String, files: &[(Url, Url)]) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "method": "workspace/willRenameFiles",
+        "params": {"files": renames(files)},
+    })
+}
+
+fn renames(files: &[(Url, Url)]) -> Vec<Value> {
+    files
+        .iter()
+        .map(|(old, new)| json!({"oldUri": old, "newUri": new}))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{FutureExt, StreamExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_operations() {
+        let operations = FileOperations::new();
+        let root = Path::new("/workspace");
+        let mut events = operations.events(root);
+        let mut session = Session::default();
+        session.on_server_text(
+            &json!({"jsonrpc": "2.0", "id": 0, "result": {"capabilities": {"workspace": {
+                "fileOperations": {"willRename": {"filters": []}, "didDelete": {"filters": []}},
+            }}}})
+            .to_string(),
+        );
+        let from = Url::parse("file:///workspace/a.rs").unwrap();
+        let to = Url::parse("file:///workspace/b.rs").unwrap();
+
+        let asking = tokio::spawn({
+            let operations = operations.clone();
+            let files = vec![(from.clone(), to.clone())];
+            async move { operations.will_rename(root, files).await }
+        });
+        let request = session.on_event(events.next().await.unwrap()).unwrap();
+        let request = serde_json::from_str::<Value>(&request).unwrap();
+        assert_eq!(request["method"], json!("workspace/willRenameFiles"));
+        assert_eq!(
+            request["params"],
+            json!({"files": [{"oldUri": "file:///workspace/a.rs", "newUri": "file:///workspace/b.rs"}]})
+        );
+        let edit = json!({"changes": {"file:///workspace/main.rs": []}});
+        assert!(session
+            .responds(&json!({"jsonrpc": "2.0", "id": request["id"], "result": edit}).to_string()));
+        assert_eq!(asking.await.unwrap(), vec![edit]);
+
+        // Without `didRename`
+        operations.renamed(root, vec![(from.clone(), to)]);
+        assert_eq!(session.on_event(events.next().await.unwrap()), None);
+        operations.deleted(root, vec![from]);
+        assert_eq!(
+            serde_json::from_str::<Value>(&session.on_event(events.next().await.unwrap()).unwrap())
+                .unwrap(),
+            json!({"jsonrpc": "2.0", "method": "workspace/didDeleteFiles", "params": {
+                "files": [{"uri": "file:///workspace/a.rs"}],
+            }})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_isolated_workspaces() {
+        let operations = FileOperations::new();
+        let (a, b) = (Path::new("/workspaces/a"), Path::new("/workspaces/b"));
+        let (mut events_a, mut events_b) = (operations.events(a), operations.events(b));
+        let capabilities = json!({"jsonrpc": "2.0", "id": 0, "result": {"capabilities": {
+            "workspace": {"fileOperations": {"willRename": {}, "didDelete": {}}},
+        }}})
+        .to_string();
+        let mut session_a = Session::default();
+        session_a.on_server_text(&capabilities);
+        let from = Url::parse("file:///workspaces/a/a.rs").unwrap();
+        let to = Url::parse("file:///workspaces/a/b.rs").unwrap();
+
+        // Only the session in the workspace is asked, and waited for
+        let asking = tokio::spawn({
+            let operations = operations.clone();
+            let files = vec![(from.clone(), to)];
+            async move { operations.will_rename(a, files).await }
+        });
+        let request = session_a.on_event(events_a.next().await.unwrap()).unwrap();
+        let request = serde_json::from_str::<Value>(&request).unwrap();
+        let edit = json!({"changes": {"file:///workspaces/a/main.rs": []}});
+        assert!(session_a
+            .responds(&json!({"jsonrpc": "2.0", "id": request["id"], "result": edit}).to_string()));
+        assert_eq!(asking.await.unwrap(), vec![edit]);
+
+        operations.deleted(a, vec![from]);
+        assert!(matches!(
+            events_a.next().await,
+            Some(Event::Deleted(files)) if files.len() == 1
+        ));
+        assert!(events_b.next().now_or_never().is_none());
+        // Without sessions in the workspace
+        assert!(operations
+            .will_rename(Path::new("/workspaces/c"), Vec::new())
+            .await
+            .is_empty());
+    }
+}
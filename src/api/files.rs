@@ -1,15 +1,27 @@
 use std::{
+    collections::hash_map::DefaultHasher,
     convert::Infallible,
-    path::{Path, PathBuf},
+    hash::{Hash, Hasher},
+    path::{Component, Path, PathBuf},
 };
 
+use futures_util::stream;
 use lsp_types::{FileChangeType, FileEvent};
 use thiserror::Error;
-use tokio::fs;
+use tokio::{fs, io::AsyncReadExt};
 use url::Url;
-use warp::{http::StatusCode, Filter, Rejection, Reply};
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
 
-use super::{json_body, json_response, with_context};
+use super::{
+    admin::Unauthorized,
+    archive,
+    file_operations::FileOperations,
+    json_body, json_error_response, json_response, quota,
+    resume::UnknownSession,
+    sandbox::{self, OutsideRoots, Roots},
+    with_context,
+    workspace::Workspaces,
+};
 
 #[derive(Debug, Error)]
 enum Error {
@@ -22,6 +34,24 @@ enum Error {
         source: std::io::Error,
     },
 
+    #[error("failed to read {path}: {source}")]
+    ReadFile {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("{0} is a directory")]
+    IsDirectory(String),
+
+    #[error("failed to read directory {path}: {source}")]
+    ReadDir {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("{0} is not a directory")]
+    NotDirectory(String),
+
     #[error("failed to write {path}: {source}")]
     WriteFile {
         path: String,
@@ -160,7 +190,11 @@ where
     P: AsRef<Path>,
 {
     let apath = cwd.as_ref().join(path);
-    if !apath.starts_with(&cwd) {
+    let escapes = Path::new(path)
+        .components()
+        .any(|c| matches!(c, Component::ParentDir));
+    // Symlinks in the workspace can point out of it.
+    if escapes || sandbox::resolve(cwd.as_ref(), &apath).is_none() {
         return Err(Error::NotProjectPath(path.to_owned()));
     }
     Ok(apath)
@@ -250,6 +284,101 @@ fn test_path_uri() {
     );
 }
 
+#[tokio::test]
+async fn test_read_file() {
+    let cwd = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&cwd).unwrap();
+    std::fs::write(cwd.join("main.rs"), "fn main() {}\n").unwrap();
+    let workspaces = Workspaces::default();
+    let session = cwd.join("session");
+    std::fs::create_dir_all(&session).unwrap();
+    std::fs::write(session.join("main.rs"), "fn session() {}\n").unwrap();
+    let _workspace = workspaces.register("a", session);
+    let api = handler(Context {
+        cwd: cwd.clone(),
+        remap: false,
+        token: Some("secret".to_owned()),
+        ignore: Vec::new(),
+        file_operations: FileOperations::new(),
+        max_upload: 1024,
+        quota: Default::default(),
+        workspaces,
+        isolate: false,
+        temp_dir: std::env::temp_dir(),
+        sync_roots: Roots::default(),
+    })
+    .recover(super::recover);
+
+    let res = warp::test::request()
+        .path("/files?path=main.rs")
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let res = warp::test::request()
+        .path("/files?path=main.rs")
+        .header("authorization", "Bearer secret")
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.body(), "fn main() {}\n");
+    let etag = res.headers()["etag"].to_str().unwrap().to_owned();
+
+    let res = warp::test::request()
+        .path("/files?path=main.rs")
+        .header("authorization", "Bearer secret")
+        .header("if-none-match", &etag)
+        .reply(&api)
+        .await;
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+    for (path, status) in &[
+        ("missing.rs", StatusCode::NOT_FOUND),
+        ("../secret", StatusCode::FORBIDDEN),
+    ] {
+        let res = warp::test::request()
+            .path(&format!("/files?path={}", path))
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), *status);
+    }
+    #[cfg(unix)]
+    {
+        // Symlinks out of the workspace aren't followed
+        let secret = cwd.with_extension("secret");
+        std::fs::write(&secret, "secret").unwrap();
+        std::os::unix::fs::symlink(&secret, cwd.join("escape")).unwrap();
+        let res = warp::test::request()
+            .path("/files?path=escape")
+            .header("authorization", "Bearer secret")
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        std::fs::remove_file(secret).unwrap();
+    }
+
+    // In the workspace of the session
+    for (id, status, body) in &[
+        ("a", StatusCode::OK, "fn session() {}\n"),
+        (
+            "b",
+            StatusCode::NOT_FOUND,
+            r#"{"reason":"Unknown Session"}"#,
+        ),
+    ] {
+        let res = warp::test::request()
+            .path("/files?path=main.rs")
+            .header("authorization", "Bearer secret")
+            .header("x-session-id", *id)
+            .reply(&api)
+            .await;
+        assert_eq!(res.status(), *status);
+        assert_eq!(res.body(), body);
+    }
+    std::fs::remove_dir_all(cwd).unwrap();
+}
+
 #[derive(Debug, serde::Serialize)]
 struct Response {
     /// `FileEvent`s for `workspace/didChangeWatchedFiles` notification.
@@ -257,44 +386,386 @@ struct Response {
     /// Any errors that occured trying to perform operations.
     #[serde(skip_serializing_if = "Option::is_none")]
     errors: Option<Vec<OperationError>>,
+    /// `WorkspaceEdit` from `workspace/willRenameFiles` of the servers for the client to apply.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    edits: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct OperationError {
     operation: Operation,
     reason: String,
+    /// The quota and its limit if the operation was over it.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    quota: Option<quota::Exceeded>,
 }
 
 #[derive(Debug, Clone)]
 pub struct Context {
     pub cwd: PathBuf,
     pub remap: bool,
+    /// Require `Authorization: Bearer <token>` if set.
+    pub token: Option<String>,
+    /// Names of files and directories left out of the listings, or `*` and their ends.
+    pub ignore: Vec<String>,
+    /// Notify the servers of the sessions of the removed and renamed files.
+    pub file_operations: FileOperations,
+    /// Maximum bytes of the uploaded archives, and of the files extracted from them.
+    pub max_upload: u64,
+    /// Quotas of the files in the workspace, or in the workspace of each session.
+    pub quota: quota::Quota,
+    /// Workspaces of the sessions, selected with the `x-session-id` header.
+    pub workspaces: Workspaces,
+    /// Require the `x-session-id` header instead of defaulting to `cwd`.
+    pub isolate: bool,
+    /// Directory of the archives being downloaded.
+    pub temp_dir: PathBuf,
+    /// Directories the workspaces must be in.
+    pub sync_roots: Roots,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ReadQuery {
+    path: String,
 }
 
-/// Handler for `POST /files`
+/// Handler for `POST /files`, `GET /files?path=<path>`, `GET /files/list`, and
+/// `POST` and `GET /files/archive`
 pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::post()
+    let download = warp::get()
+        .and(warp::path!("files" / "archive"))
+        .and(with_workspace(ctx.clone()))
+        .and(warp::query::<DownloadQuery>())
+        .and_then(download_archive);
+    let upload = warp::post()
+        .and(warp::path!("files" / "archive"))
+        .and(with_workspace(ctx.clone()))
+        .and(warp::query::<ArchiveQuery>())
+        .and(warp::body::content_length_limit(ctx.max_upload))
+        .and(warp::body::bytes())
+        .and_then(upload_archive);
+    let list = warp::get()
+        .and(warp::path!("files" / "list"))
+        .and(with_workspace(ctx.clone()))
+        .and(warp::query::<ListQuery>())
+        .and_then(list_files);
+    let read = warp::get()
         .and(warp::path("files"))
         .and(warp::path::end())
-        .and(with_context(ctx))
+        .and(with_workspace(ctx.clone()))
+        .and(warp::query::<ReadQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and_then(read_file);
+    let write = warp::post()
+        .and(warp::path("files"))
+        .and(warp::path::end())
+        .and(with_workspace(ctx))
         .and(json_body::<Payload>())
-        .and_then(handle_operations)
+        .and_then(handle_operations);
+    list.or(read).or(download).or(upload).or(write)
+}
+
+fn with_authorization(
+    ctx: Context,
+) -> impl Filter<Extract = (Context,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_context(ctx))
+        .and_then(|authorization: Option<String>, ctx: Context| async move {
+            match &ctx.token {
+                Some(token) if authorization != Some(format!("Bearer {}", token)) => {
+                    Err(warp::reject::custom(Unauthorized))
+                }
+                _ => Ok(ctx),
+            }
+        })
+}
+
+/// Context with `cwd` in the workspace of the session in the `x-session-id` header, rejected with
+/// `UnknownSession` if it's unknown, or if it's missing and `isolate`, and with `OutsideRoots` if
+/// the workspace isn't in `sync_roots`.
+fn with_workspace(ctx: Context) -> impl Filter<Extract = (Context,), Error = Rejection> + Clone {
+    with_authorization(ctx)
+        .and(warp::header::optional::<String>("x-session-id"))
+        .and_then(|mut ctx: Context, id: Option<String>| async move {
+            match id {
+                Some(id) => {
+                    ctx.cwd = ctx
+                        .workspaces
+                        .get(&id)
+                        .ok_or_else(|| warp::reject::custom(UnknownSession))?;
+                }
+                None if ctx.isolate => return Err(warp::reject::custom(UnknownSession)),
+                None => {}
+            }
+            if !ctx.sync_roots.allows(&ctx.cwd) {
+                tracing::warn!("rejected /files in {:?} outside of --sync-root", ctx.cwd);
+                return Err(warp::reject::custom(OutsideRoots));
+            }
+            Ok(ctx)
+        })
+}
+
+/// Entity tag of the file with `contents`, changing with them.
+fn etag(contents: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    contents.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Whether `If-None-Match` of the request matches `etag`.
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+#[tracing::instrument(level = "debug", skip(ctx, if_none_match))]
+async fn read_file(
+    ctx: Context,
+    query: ReadQuery,
+    if_none_match: Option<String>,
+) -> Result<reply::Response, Infallible> {
+    let contents = match read_contents(&ctx.cwd, &query.path).await {
+        Ok(contents) => contents,
+        Err(err) => return Ok(json_error_response(err.to_string(), read_status(&err))),
+    };
+    let etag = etag(&contents);
+    if if_none_match.map_or(false, |tags| matches_etag(&tags, &etag)) {
+        return Ok(reply::with_header(
+            reply::with_status(reply::reply(), StatusCode::NOT_MODIFIED),
+            "etag",
+            etag,
+        )
+        .into_response());
+    }
+    let content_type = if std::str::from_utf8(&contents).is_ok() {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    };
+    let reply = reply::with_header(contents, "content-type", content_type);
+    Ok(reply::with_header(reply, "etag", etag).into_response())
+}
+
+/// Status of the response to a read failing with `err`.
+fn read_status(err: &Error) -> StatusCode {
+    match err {
+        Error::NotProjectPath(_) => StatusCode::FORBIDDEN,
+        Error::IsDirectory(_) | Error::NotDirectory(_) => StatusCode::BAD_REQUEST,
+        Error::ReadFile { source, .. } | Error::ReadDir { source, .. }
+            if source.kind() == std::io::ErrorKind::NotFound =>
+        {
+            StatusCode::NOT_FOUND
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn read_contents<P>(cwd: P, path: &str) -> Result<Vec<u8>, Error>
+where
+    P: AsRef<Path>,
+{
+    let apath = get_path(&cwd, path)?;
+    if apath.is_dir() {
+        return Err(Error::IsDirectory(path.to_owned()));
+    }
+    tracing::debug!("reading file {:?}", path);
+    fs::read(&apath).await.map_err(|source| Error::ReadFile {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Default and maximum numbers of the entries in a page of `GET /files/list`.
+const PAGE_SIZE: usize = 1000;
+const MAX_PAGE_SIZE: usize = 10_000;
+
+#[derive(Debug, serde::Deserialize)]
+struct ListQuery {
+    /// Directory to list, the workspace by default.
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum EntryType {
+    File,
+    Directory,
+}
+
+/// File or directory in a listing.
+#[derive(Debug, serde::Serialize)]
+struct Entry {
+    /// Path relative to the workspace.
+    path: String,
+    uri: Url,
+    #[serde(rename = "type")]
+    kind: EntryType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+}
+
+/// Page of the entries under a directory.
+#[derive(Debug, serde::Serialize)]
+struct Listing {
+    entries: Vec<Entry>,
+    /// Number of the entries in all pages.
+    total: usize,
+    /// Offset of the next page, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next: Option<usize>,
+}
+
+#[tracing::instrument(level = "debug", skip(ctx))]
+async fn list_files(ctx: Context, query: ListQuery) -> Result<reply::Response, Infallible> {
+    let entries = match list_entries(&ctx, &query.path).await {
+        Ok(entries) => entries,
+        Err(err) => return Ok(json_error_response(err.to_string(), read_status(&err))),
+    };
+    let limit = query.limit.unwrap_or(PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let total = entries.len();
+    let end = query.offset.saturating_add(limit);
+    let listing = Listing {
+        entries: entries.into_iter().skip(query.offset).take(limit).collect(),
+        total,
+        next: Some(end).filter(|end| *end < total),
+    };
+    Ok(json_response(&listing, StatusCode::OK))
+}
+
+/// Whether a component of the relative `path` matches a pattern of `ignore`.
+pub(super) fn is_ignored(ignore: &[String], path: &Path) -> bool {
+    path.components().any(|component| {
+        let name = component.as_os_str().to_string_lossy();
+        ignore
+            .iter()
+            .any(|pattern| match pattern.strip_prefix('*') {
+                Some(end) => name.ends_with(end),
+                None => name == pattern.as_str(),
+            })
+    })
+}
+
+/// The entries under the relative directory `dir` recursively, sorted by path.
+///
+/// Symlinks are left out, they could point out of the workspace.
+async fn list_entries(ctx: &Context, dir: &str) -> Result<Vec<Entry>, Error> {
+    let root = get_path(&ctx.cwd, dir)?;
+    let metadata = fs::metadata(&root).await.map_err(|source| Error::ReadDir {
+        path: dir.to_owned(),
+        source,
+    })?;
+    if !metadata.is_dir() {
+        return Err(Error::NotDirectory(dir.to_owned()));
+    }
+    let mut entries = Vec::new();
+    let mut dirs = vec![root];
+    while let Some(dir) = dirs.pop() {
+        let read_error = |source| Error::ReadDir {
+            path: dir.display().to_string(),
+            source,
+        };
+        let mut read = fs::read_dir(&dir).await.map_err(read_error)?;
+        while let Some(entry) = read.next_entry().await.map_err(read_error)? {
+            let apath = entry.path();
+            let relative = match apath.strip_prefix(&ctx.cwd) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            };
+            if is_ignored(&ctx.ignore, relative) {
+                continue;
+            }
+            let path = slash_path(relative);
+            let file_type = entry.file_type().await.map_err(read_error)?;
+            if file_type.is_dir() {
+                entries.push(Entry {
+                    uri: path_uri(&ctx.cwd, &path, true, ctx.remap),
+                    path,
+                    kind: EntryType::Directory,
+                    size: None,
+                });
+                dirs.push(apath);
+            } else if file_type.is_file() {
+                let size = entry.metadata().await.ok().map(|metadata| metadata.len());
+                entries.push(Entry {
+                    uri: path_uri(&ctx.cwd, &path, false, ctx.remap),
+                    path,
+                    kind: EntryType::File,
+                    size,
+                });
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
 }
 
 #[tracing::instrument(level = "debug", skip(ctx, payload))]
 async fn handle_operations(ctx: Context, payload: Payload) -> Result<impl Reply, Infallible> {
     let mut errors = Vec::new();
     let mut changes = Vec::new();
+    let mut edits = Vec::new();
+    // URIs on the servers, without remapping.
+    let uri = |path: &str, is_dir: bool| path_uri(&ctx.cwd, path, is_dir, false);
     // Do them one by one in order
     for op in payload.operations {
+        let rename = match &op {
+            Operation::Rename { from, to }
+                if get_path(&ctx.cwd, from).is_ok() && get_path(&ctx.cwd, to).is_ok() =>
+            {
+                let is_dir = ctx.cwd.join(from).is_dir();
+                Some((uri(from, is_dir), uri(to, is_dir)))
+            }
+            _ => None,
+        };
+        let mut rename_edits = match &rename {
+            Some(rename) => {
+                ctx.file_operations
+                    .will_rename(&ctx.cwd, vec![rename.clone()])
+                    .await
+            }
+            None => Vec::new(),
+        };
+        if let Operation::Write { path, contents } = &op {
+            if let Ok(apath) = get_path(&ctx.cwd, path) {
+                let files = vec![(apath, contents.len() as u64)];
+                if let Err(err) = ctx.quota.check_blocking(ctx.cwd.clone(), files).await {
+                    errors.push(OperationError {
+                        operation: op,
+                        reason: err.to_string(),
+                        quota: Some(err),
+                    });
+                    continue;
+                }
+            }
+        }
         match op.perform(&ctx.cwd, ctx.remap).await {
             Ok(mut events) => {
+                if ctx.remap {
+                    for edit in &mut rename_edits {
+                        remap_uris(edit, uri("", true).as_str());
+                    }
+                }
+                edits.append(&mut rename_edits);
+                match (&op, rename) {
+                    (_, Some(rename)) => ctx.file_operations.renamed(&ctx.cwd, vec![rename]),
+                    (Operation::Remove { path }, _) => ctx
+                        .file_operations
+                        .deleted(&ctx.cwd, vec![uri(path, false)]),
+                    _ => {}
+                }
                 changes.append(&mut events);
             }
             Err(err) => {
                 errors.push(OperationError {
                     operation: op,
                     reason: err.to_string(),
+                    quota: None,
                 });
             }
         }
@@ -305,5 +776,309 @@ async fn handle_operations(ctx: Context, payload: Payload) -> Result<impl Reply,
     } else {
         (Some(errors), StatusCode::UNPROCESSABLE_ENTITY)
     };
-    Ok(json_response(&Response { changes, errors }, status))
+    Ok(json_response(
+        &Response {
+            changes,
+            errors,
+            edits,
+        },
+        status,
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ArchiveQuery {
+    /// Directory to extract into, the workspace by default.
+    #[serde(default)]
+    path: String,
+}
+
+#[tracing::instrument(level = "debug", skip(ctx, body))]
+async fn upload_archive(
+    ctx: Context,
+    query: ArchiveQuery,
+    body: bytes::Bytes,
+) -> Result<reply::Response, Infallible> {
+    let dir = match get_path(&ctx.cwd, &query.path) {
+        Ok(dir) => dir,
+        Err(err) => return Ok(json_error_response(err.to_string(), StatusCode::FORBIDDEN)),
+    };
+    let (max_size, quota, root) = (ctx.max_upload, ctx.quota, ctx.cwd.clone());
+    // Reading and writing the files blocks.
+    let extracted = tokio::task::spawn_blocking(move || -> Result<_, archive::Error> {
+        if quota.is_set() {
+            let files = archive::sizes(&body)?
+                .into_iter()
+                .map(|(path, size)| (dir.join(path), size))
+                .collect::<Vec<_>>();
+            quota.check(&root, &files)?;
+        }
+        archive::extract(&body, &dir, max_size)
+    })
+    .await;
+    let files = match extracted {
+        Ok(Ok(files)) => files,
+        Ok(Err(err)) => {
+            let status = match &err {
+                archive::Error::Unsupported | archive::Error::Invalid(_) => StatusCode::BAD_REQUEST,
+                archive::Error::UnsafePath(_) => StatusCode::FORBIDDEN,
+                archive::Error::Quota(err) => return Ok(quota::response(err)),
+                archive::Error::TooLarge(_) | archive::Error::TooManyEntries(_) => {
+                    StatusCode::PAYLOAD_TOO_LARGE
+                }
+                archive::Error::Write { .. }
+                | archive::Error::Read { .. }
+                | archive::Error::Create(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            return Ok(json_error_response(err.to_string(), status));
+        }
+        Err(err) => {
+            return Ok(json_error_response(
+                err.to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    };
+    tracing::info!("extracted {} files from the archive", files.len());
+    let changes = files
+        .into_iter()
+        .map(|(path, created)| {
+            let path = slash_path(&Path::new(&query.path).join(path));
+            FileEvent::new(
+                path_uri(&ctx.cwd, &path, false, ctx.remap),
+                if created {
+                    FileChangeType::Created
+                } else {
+                    FileChangeType::Changed
+                },
+            )
+        })
+        .collect();
+    let response = Response {
+        changes,
+        errors: None,
+        edits: Vec::new(),
+    };
+    Ok(json_response(&response, StatusCode::OK))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DownloadQuery {
+    /// Directory to archive, the workspace by default.
+    #[serde(default)]
+    path: String,
+    /// Leave out the files ignored by `.gitignore`.
+    #[serde(default)]
+    gitignore: bool,
+}
+
+/// Temporary file removed when dropped.
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            tracing::warn!("failed to remove {:?}: {}", self.0, err);
+        }
+    }
+}
+
+/// Size of the chunks of the archive in the response.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[tracing::instrument(level = "debug", skip(ctx))]
+async fn download_archive(
+    ctx: Context,
+    query: DownloadQuery,
+) -> Result<reply::Response, Infallible> {
+    let dir = match get_path(&ctx.cwd, &query.path) {
+        Ok(dir) => dir,
+        Err(err) => return Ok(json_error_response(err.to_string(), StatusCode::FORBIDDEN)),
+    };
+    match fs::metadata(&dir).await {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            let err = Error::NotDirectory(query.path);
+            return Ok(json_error_response(
+                err.to_string(),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+        Err(source) => {
+            let err = Error::ReadDir {
+                path: query.path,
+                source,
+            };
+            return Ok(json_error_response(err.to_string(), read_status(&err)));
+        }
+    }
+    let name = dir.file_name().map_or_else(
+        || "workspace".to_owned(),
+        |name| name.to_string_lossy().replace('"', "_"),
+    );
+
+    // Zip needs to seek, so it's written to a file before streaming it.
+    let temp = TempFile(
+        ctx.temp_dir
+            .join(format!("lsp-ws-proxy-{}.zip", uuid::Uuid::new_v4())),
+    );
+    let path = temp.0.clone();
+    let ignore = ctx.ignore.clone();
+    let created = tokio::task::spawn_blocking(move || {
+        let out = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| archive::Error::Create(err.to_string()))?;
+        archive::create(&dir, ignore, query.gitignore, out)
+    })
+    .await;
+    let file = match created {
+        Ok(Ok(file)) => fs::File::from_std(file),
+        Ok(Err(err)) => {
+            return Ok(json_error_response(
+                err.to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+        Err(err) => {
+            return Ok(json_error_response(
+                err.to_string(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ))
+        }
+    };
+    // The file is removed with the stream, even if the client disconnects.
+    let chunks = stream::unfold((file, temp), |(mut file, temp)| async move {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(n) => {
+                chunk.truncate(n);
+                Some((Ok::<_, std::io::Error>(chunk), (file, temp)))
+            }
+            Err(err) => {
+                tracing::warn!("failed to read archive: {}", err);
+                None
+            }
+        }
+    });
+    let reply = reply::with_header(
+        reply::Response::new(hyper::Body::wrap_stream(chunks)),
+        "content-type",
+        "application/zip",
+    );
+    let reply = reply::with_header(
+        reply,
+        "content-disposition",
+        format!("attachment; filename=\"{}.zip\"", name),
+    );
+    Ok(reply.into_response())
+}
+
+/// The relative `path` with `/` between the components.
+pub(super) fn slash_path(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Replace the URIs starting with `workspace` in `value` with relative `source://`.
+fn remap_uris(value: &mut serde_json::Value, workspace: &str) {
+    let remap = |uri: &str| {
+        uri.strip_prefix(workspace)
+            .map(|path| format!("source://{}", path))
+    };
+    match value {
+        serde_json::Value::String(s) => {
+            if let Some(uri) = remap(s) {
+                *s = uri;
+            }
+        }
+        serde_json::Value::Array(values) => {
+            for value in values {
+                remap_uris(value, workspace);
+            }
+        }
+        serde_json::Value::Object(map) => {
+            // Keys of `changes`
+            *map = std::mem::take(map)
+                .into_iter()
+                .map(|(key, mut value)| {
+                    remap_uris(&mut value, workspace);
+                    (remap(&key).unwrap_or(key), value)
+                })
+                .collect();
+        }
+        _ => {}
+    }
+}
+
+#[tokio::test]
+async fn test_list_files() {
+    let cwd = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+    for path in &[
+        "src/main.rs",
+        "src/api/mod.rs",
+        "README.md",
+        ".git/HEAD",
+        "debug.log",
+    ] {
+        let path = cwd.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+    let api = handler(Context {
+        cwd: cwd.clone(),
+        remap: true,
+        token: None,
+        ignore: vec![".git".to_owned(), "*.log".to_owned()],
+        file_operations: FileOperations::new(),
+        max_upload: 1024,
+        quota: Default::default(),
+        workspaces: Workspaces::default(),
+        isolate: false,
+        temp_dir: std::env::temp_dir(),
+        sync_roots: Roots::default(),
+    });
+    let list = |query: &str| {
+        let request = warp::test::request().path(&format!("/files/list{}", query));
+        let api = api.clone();
+        async move {
+            let res = request.reply(&api).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()
+        }
+    };
+
+    let listing = list("").await;
+    let paths = listing["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["path"].as_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paths,
+        vec![
+            "README.md",
+            "src",
+            "src/api",
+            "src/api/mod.rs",
+            "src/main.rs"
+        ]
+    );
+    assert_eq!(listing["total"], 5);
+    assert_eq!(
+        listing["entries"][1],
+        serde_json::json!({"path": "src", "uri": "source://src/", "type": "directory"})
+    );
+
+    let page = list("?path=src&offset=1&limit=1").await;
+    assert_eq!(page["entries"][0]["path"], "src/api/mod.rs");
+    assert_eq!(page["entries"][0]["size"], 0);
+    assert_eq!(page["next"], 2);
+    std::fs::remove_dir_all(cwd).unwrap();
 }
@@ -0,0 +1,158 @@
+//! Filters dropping messages by method before they're forwarded.
+//!
+//! Requests dropped are answered with `MethodNotFound`, so the sender isn't left waiting.
+//! Responses are always forwarded.
+use std::{convert::TryFrom, str::FromStr};
+
+use serde_json::{json, Value};
+
+/// Where the message is from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Client,
+    Server,
+}
+
+/// Methods of the messages from the client or the server, ending with `*` to match the prefix.
+///
+/// ```text
+/// server:telemetry/event
+/// client:workspace/executeCommand
+/// client:$/*
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Rule {
+    from: Direction,
+    method: String,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (from, method) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected client:<method> or server:<method>: {}", s))?;
+        let from = match from {
+            "client" => Direction::Client,
+            "server" => Direction::Server,
+            _ => return Err(format!("expected client or server: {}", from)),
+        };
+        if method.is_empty() {
+            return Err(format!("empty method: {}", s));
+        }
+        Ok(Self {
+            from,
+            method: method.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl Rule {
+    pub(super) fn matches(&self, from: Direction, method: &str) -> bool {
+        self.from == from
+            && match self.method.strip_suffix('*') {
+                Some(prefix) => method.starts_with(prefix),
+                None => method == self.method,
+            }
+    }
+}
+
+/// Messages are dropped if they match a `drop` rule, or if there are `allow` rules for their
+/// direction and they match none of them.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    pub drop: Vec<Rule>,
+    pub allow: Vec<Rule>,
+}
+
+impl Filters {
+    fn has_rules(&self, from: Direction) -> bool {
+        self.drop
+            .iter()
+            .chain(&self.allow)
+            .any(|rule| rule.from == from)
+    }
+
+    /// Whether the message `text` from `from` is dropped.
+    pub(super) fn drops(&self, from: Direction, text: &str) -> bool {
+        if !self.has_rules(from) {
+            return false;
+        }
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return false,
+        };
+        let method = match msg.get("method").and_then(Value::as_str) {
+            Some(method) => method,
+            None => return false,
+        };
+        let allowed = !self.allow.iter().any(|rule| rule.from == from)
+            || self.allow.iter().any(|rule| rule.matches(from, method));
+        !allowed || self.drop.iter().any(|rule| rule.matches(from, method))
+    }
+}
+
+/// Error response to the dropped message `text`, or `None` if it's a notification.
+pub(super) fn rejection(text: &str) -> Option<String> {
+    let msg = serde_json::from_str::<Value>(text).ok()?;
+    let id = msg.get("id")?;
+    let method = msg
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let response = json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": -32601,
+            "message": format!("{} is blocked by the proxy", method),
+        },
+    });
+    Some(response.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        let filters = Filters {
+            drop: vec![
+                "server:telemetry/event".parse().unwrap(),
+                "client:workspace/executeCommand".parse().unwrap(),
+            ],
+            allow: vec!["server:textDocument/*".parse().unwrap()],
+        };
+        let telemetry = r#"{"jsonrpc":"2.0","method":"telemetry/event","params":{}}"#;
+        let command = r#"{"jsonrpc":"2.0","id":1,"method":"workspace/executeCommand","params":{}}"#;
+        let diagnostics = r#"{"jsonrpc":"2.0","method":"textDocument/publishDiagnostics"}"#;
+        let response = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+
+        assert!(filters.drops(Direction::Server, telemetry));
+        assert!(!filters.drops(Direction::Client, telemetry));
+        assert!(filters.drops(Direction::Client, command));
+        // Not allowed from the server
+        assert!(filters.drops(Direction::Server, command));
+        assert!(!filters.drops(Direction::Server, diagnostics));
+        assert!(!filters.drops(Direction::Server, response));
+
+        assert_eq!(rejection(telemetry), None);
+        let rejected: Value = serde_json::from_str(&rejection(command).unwrap()).unwrap();
+        assert_eq!(rejected["id"], 1);
+        assert_eq!(rejected["error"]["code"], -32601);
+
+        assert!("proxy:exit".parse::<Rule>().is_err());
+        assert!("client:".parse::<Rule>().is_err());
+    }
+}
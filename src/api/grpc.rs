@@ -0,0 +1,129 @@
+//! gRPC transport for clients where gRPC is the standard RPC layer.
+//!
+//! `LanguageServer.Connect` is a bidirectional stream of `Message` carrying one serialized
+//! LSP message each. See `proto/lsp.proto`.
+use std::net::SocketAddr;
+
+use futures_util::{sink, stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+use tracing::Instrument;
+
+use super::{
+    limit,
+    proxy::{self, Message, Outgoing, Query},
+    workspace,
+};
+
+mod pb {
+    tonic::include_proto!("lsp_ws_proxy");
+}
+
+use pb::language_server_server::{LanguageServer, LanguageServerServer};
+
+/// Serve the gRPC service on `addr`.
+pub async fn serve(ctx: proxy::SharedContext, addr: SocketAddr) -> std::io::Result<()> {
+    tracing::info!("gRPC listening on {}", addr);
+    Server::builder()
+        .add_service(LanguageServerServer::new(Service { ctx }))
+        .serve(addr)
+        .await
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))
+}
+
+struct Service {
+    ctx: proxy::SharedContext,
+}
+
+#[tonic::async_trait]
+impl LanguageServer for Service {
+    type ConnectStream = ReceiverStream<Result<pb::Message, Status>>;
+
+    async fn connect(
+        &self,
+        request: Request<Streaming<pb::Message>>,
+    ) -> Result<Response<Self::ConnectStream>, Status> {
+        let ctx = self.ctx.get();
+        let query = request
+            .metadata()
+            .get("name")
+            .and_then(|name| name.to_str().ok())
+            .map(|name| Query {
+                name: name.to_owned(),
+            });
+        if let Some(query) = query.as_ref().filter(|query| !ctx.accepts(query)) {
+            return Err(Status::invalid_argument(format!(
+                "unknown server {}",
+                query.name
+            )));
+        }
+        if ctx.shutdown.is_draining() {
+            return Err(Status::unavailable("draining"));
+        }
+        let session = limit::start(&self.ctx)
+            .await
+            .map_err(|_| Status::resource_exhausted("too many sessions"))?;
+        let id = proxy::new_session_id();
+        let ctx = ctx.for_query(query.as_ref(), &id);
+        let span = proxy::session_span(&id);
+        let (ctx, workspace) = workspace::prepare(ctx, query.as_ref(), &id)
+            .instrument(span.clone())
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))?;
+        let server = proxy::connect_server(&ctx, query.as_ref())
+            .instrument(span.clone())
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))?;
+
+        let (server_tx, server_rx) = mpsc::channel(16);
+        let client_recv = request
+            .into_inner()
+            .flat_map(|msg| {
+                let msgs: Vec<_> = match msg {
+                    Ok(msg) => proxy::parse_client_messages(&msg.json)
+                        .into_iter()
+                        .map(Ok)
+                        .collect(),
+                    Err(err) => vec![Err(err)],
+                };
+                stream::iter(msgs)
+            })
+            .chain(stream::once(async { Ok(Message::Done) }))
+            .boxed();
+        let client_send = sink::unfold(server_tx, |tx, msg: Outgoing| async move {
+            match msg {
+                Outgoing::Text(json) => tx.send(Ok(pb::Message { json })).await.map(|_| tx),
+                // HTTP/2 keeps the connection alive, and the stream ends when dropped.
+                Outgoing::Ping
+                | Outgoing::Close
+                | Outgoing::Shutdown
+                | Outgoing::Exited(_)
+                | Outgoing::Ended => Ok(tx),
+                Outgoing::ServerFailed => tx
+                    .send(Err(Status::unavailable("server failed")))
+                    .await
+                    .map(|_| tx),
+            }
+        });
+
+        tokio::spawn(
+            async move {
+                tracing::info!("connected with gRPC");
+                if let Err(err) = proxy::run(&ctx, server, client_recv, Box::pin(client_send)).await
+                {
+                    tracing::error!("connection error: {}", err);
+                }
+                drop(session);
+                drop(workspace);
+                tracing::info!("disconnected");
+            }
+            .instrument(span),
+        );
+        let mut response = Response::new(ReceiverStream::new(server_rx));
+        if let Ok(id) = id.parse() {
+            response.metadata_mut().insert("x-session-id", id);
+        }
+        Ok(response)
+    }
+}
@@ -0,0 +1,232 @@
+//! External processes transforming the messages of the sessions of a server.
+//!
+//! The hook reads a line of JSON on stdin for each message, `{"from":"client","message":{...}}`,
+//! and writes a line back on stdout with the message to forward, modified or not, or `null` to
+//! drop it. Messages are sent one at a time in order, so the hook can be a simple loop in any
+//! language.
+//!
+//! Each hook is started with the first message, shared by the sessions, and started again if it
+//! exits. A message is dropped if the hook fails or doesn't reply in time.
+use std::{collections::HashMap, io, process::Stdio, str::FromStr, sync::Arc, time::Duration};
+
+use serde_json::json;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Mutex,
+};
+
+use crate::lsp::Message;
+
+use super::{
+    middleware::{Middleware, Middlewares, Outbox},
+    proxy::Server,
+};
+
+/// Time the hook has to reply to a message before it's stopped.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hook of the server `name`, given with `--hook`.
+///
+/// ```text
+/// rust-analyzer=python3 /etc/lsp-ws-proxy/hook.py
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookOption {
+    pub name: String,
+    pub command: Vec<String>,
+}
+
+impl FromStr for HookOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, command)) if !name.is_empty() && !command.trim().is_empty() => Ok(Self {
+                name: name.to_owned(),
+                command: command.split_whitespace().map(str::to_owned).collect(),
+            }),
+            _ => Err(format!("expected <name>=<command>, got {}", s)),
+        }
+    }
+}
+
+/// Hooks of the servers, by command.
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    commands: HashMap<Vec<String>, Hook>,
+}
+
+impl Hooks {
+    /// Hooks of `servers`, started when they get their first message.
+    pub fn new(servers: &[Server]) -> Self {
+        let commands = servers
+            .iter()
+            .filter_map(|server| server.hook.as_ref())
+            .filter(|command| !command.is_empty())
+            .map(|command| {
+                let hook = Hook {
+                    command: command.clone(),
+                    process: Default::default(),
+                };
+                (command.clone(), hook)
+            })
+            .collect();
+        Self { commands }
+    }
+
+    /// Add the hook of `server` to `middlewares`.
+    pub(super) fn apply(&self, server: &Server, middlewares: &mut Middlewares) {
+        if let Some(hook) = server
+            .hook
+            .as_ref()
+            .and_then(|command| self.commands.get(command))
+        {
+            middlewares.push(hook.clone());
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Hook {
+    command: Vec<String>,
+    process: Arc<Mutex<Option<Process>>>,
+}
+
+impl std::fmt::Debug for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hook")
+            .field("command", &self.command)
+            .finish()
+    }
+}
+
+impl Hook {
+    async fn run(&self, from: &str, msg: Message) -> Option<Message> {
+        let line = match serde_json::to_vec(&json!({ "from": from, "message": msg })) {
+            Ok(line) => line,
+            Err(_) => return Some(msg),
+        };
+        let mut process = self.process.lock().await;
+        let reply = match tokio::time::timeout(TIMEOUT, self.call(&mut process, &line)).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(err)) => {
+                tracing::error!("hook {} failed: {}", self.command.join(" "), err);
+                *process = None;
+                return None;
+            }
+            Err(_) => {
+                tracing::error!("hook {} timed out", self.command.join(" "));
+                *process = None;
+                return None;
+            }
+        };
+        match serde_json::from_str::<Option<Message>>(&reply) {
+            Ok(Some(msg)) => Some(msg),
+            Ok(None) => {
+                tracing::debug!("hook {} dropped the message", self.command.join(" "));
+                None
+            }
+            Err(err) => {
+                tracing::error!(
+                    "hook {} returned invalid message: {}",
+                    self.command.join(" "),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Send `line` to the process, starting it if it's not running.
+    async fn call(&self, process: &mut Option<Process>, line: &[u8]) -> io::Result<String> {
+        if process.is_none() {
+            *process = Some(Process::spawn(&self.command)?);
+            tracing::info!("started hook {}", self.command.join(" "));
+        }
+        process.as_mut().expect("started").call(line).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Hook {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        self.run("client", msg).await
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        self.run("server", msg).await
+    }
+}
+
+struct Process {
+    // Killed when the process is dropped.
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+}
+
+impl Process {
+    fn spawn(command: &[String]) -> io::Result<Self> {
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("piped stdout")).lines();
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+
+    async fn call(&mut self, line: &[u8]) -> io::Result<String> {
+        self.stdin.write_all(line).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await?;
+        self.stdout
+            .next_line()
+            .await?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "hook exited"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hook() {
+        assert_eq!(
+            "rust-analyzer=python3 hook.py".parse::<HookOption>(),
+            Ok(HookOption {
+                name: "rust-analyzer".to_owned(),
+                command: vec!["python3".to_owned(), "hook.py".to_owned()],
+            })
+        );
+        assert!("rust-analyzer=".parse::<HookOption>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_hook() {
+        let hook = Hook {
+            command: ["sh", "-c", "while read -r line; do echo null; done"]
+                .iter()
+                .map(|s| s.to_string())
+                .collect(),
+            process: Default::default(),
+        };
+        let log = Message::from_str(
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#,
+        )
+        .unwrap();
+        assert_eq!(hook.run("server", log.clone()).await, None);
+        // The process is kept for the next message.
+        assert!(hook.process.lock().await.is_some());
+        assert_eq!(hook.run("client", log).await, None);
+    }
+}
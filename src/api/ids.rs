@@ -0,0 +1,85 @@
+//! IDs of the requests sent to a server, unique for the server.
+//!
+//! Requests are sent with IDs allocated by the proxy, and the responses get the original IDs
+//! back, so requests from the client and requests originating from the proxy, like the copies of
+//! `shutdown` sent to every multiplexed server, never collide.
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Who sent a request.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum Origin {
+    /// The client, with the original ID.
+    Client(Value),
+    /// The proxy.
+    Proxy,
+}
+
+/// Requests sent to a server waiting for a response, by the ID they were sent with.
+#[derive(Debug, Default)]
+pub(super) struct IdMap {
+    next: u64,
+    requests: HashMap<u64, Origin>,
+}
+
+impl IdMap {
+    /// The ID to send the request from the client with `id` with.
+    pub(super) fn client(&mut self, id: Value) -> Value {
+        self.allocate(Origin::Client(id))
+    }
+
+    /// The ID to send a request originating from the proxy with.
+    pub(super) fn proxy(&mut self) -> Value {
+        self.allocate(Origin::Proxy)
+    }
+
+    fn allocate(&mut self, origin: Origin) -> Value {
+        let id = self.next;
+        self.next += 1;
+        self.requests.insert(id, origin);
+        Value::from(id)
+    }
+
+    /// Who sent the request the response with `id` is for, forgetting it. `None` if the server
+    /// didn't receive a request with the ID.
+    pub(super) fn response(&mut self, id: &Value) -> Option<Origin> {
+        self.requests.remove(&id.as_u64()?)
+    }
+
+    /// The ID the request from the client with `id` was sent with, e.g. to cancel it.
+    pub(super) fn find(&self, id: &Value) -> Option<Value> {
+        self.requests
+            .iter()
+            .find_map(|(sent, origin)| match origin {
+                Origin::Client(original) if original == id => Some(Value::from(*sent)),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        let mut ids = IdMap::default();
+        let a = ids.client(json!("a"));
+        let b = ids.client(json!(1));
+        let proxy = ids.proxy();
+        assert_ne!(a, b);
+        assert_ne!(b, proxy);
+        assert_eq!(ids.find(&json!(1)), Some(b.clone()));
+        assert_eq!(ids.find(&json!(2)), None);
+
+        assert_eq!(ids.response(&b), Some(Origin::Client(json!(1))));
+        assert_eq!(ids.response(&b), None);
+        assert_eq!(ids.find(&json!(1)), None);
+        assert_eq!(ids.response(&proxy), Some(Origin::Proxy));
+        assert_eq!(ids.response(&json!("a")), None);
+        assert_eq!(ids.response(&a), Some(Origin::Client(json!("a"))));
+    }
+}
@@ -0,0 +1,206 @@
+//! Answering interactive requests of the server the client doesn't support.
+//!
+//! Servers send `window/showDocument` and `window/showMessageRequest` to clients that don't support
+//! them, and some wait for the response forever when minimal clients ignore them. With
+//! `--answer-unsupported`, the proxy answers them itself when the client didn't declare support in
+//! `initialize`: `showDocument` fails, and the message of `showMessageRequest` is shown with
+//! `window/showMessage` instead, answered with no action or the first with `--message-action first`.
+use std::{str::FromStr, sync::Mutex};
+
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Action to answer `window/showMessageRequest` with.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageAction {
+    /// No action, like the user dismissed the message.
+    None,
+    /// The first of the actions.
+    First,
+}
+
+impl Default for MessageAction {
+    fn default() -> Self {
+        Self::None
+    }
+}
+
+impl FromStr for MessageAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "first" => Ok(Self::First),
+            _ => Err(format!("expected none or first, got {}", s)),
+        }
+    }
+}
+
+/// Add a middleware answering unsupported requests with `action` to `middlewares` if `enabled`.
+pub(super) fn apply(enabled: bool, action: MessageAction, middlewares: &mut Middlewares) {
+    if enabled {
+        middlewares.push(Answerer {
+            action,
+            support: Default::default(),
+        });
+    }
+}
+
+/// Requests the client supports.
+#[derive(Debug, Clone, Copy)]
+struct Support {
+    show_document: bool,
+    show_message_request: bool,
+}
+
+struct Answerer {
+    action: MessageAction,
+    // Set from `initialize`, until then the requests are forwarded.
+    support: Mutex<Option<Support>>,
+}
+
+impl Answerer {
+    fn on_client(&self, msg: &Value) {
+        if msg.get("method").and_then(Value::as_str) != Some("initialize") {
+            return;
+        }
+        let window = msg.pointer("/params/capabilities/window");
+        let support = Support {
+            show_document: window
+                .and_then(|window| window.pointer("/showDocument/support"))
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
+            show_message_request: window
+                .and_then(|window| window.get("showMessage"))
+                .map_or(false, |capability| !capability.is_null()),
+        };
+        *self.support.lock().expect("lock support") = Some(support);
+    }
+
+    /// The response to the request `msg` from the server, and the notification to send to the
+    /// client instead, if the client doesn't support it.
+    fn on_server(&self, msg: &Value) -> Option<(Value, Option<Value>)> {
+        let support = (*self.support.lock().expect("lock support"))?;
+        let id = msg.get("id")?;
+        match msg.get("method").and_then(Value::as_str)? {
+            "window/showDocument" if !support.show_document => Some((
+                json!({"jsonrpc": "2.0", "id": id, "result": {"success": false}}),
+                None,
+            )),
+            "window/showMessageRequest" if !support.show_message_request => {
+                let params = msg.get("params")?;
+                let action = match self.action {
+                    MessageAction::None => Value::Null,
+                    MessageAction::First => {
+                        params.pointer("/actions/0").cloned().unwrap_or(Value::Null)
+                    }
+                };
+                let notification = json!({
+                    "jsonrpc": "2.0",
+                    "method": "window/showMessage",
+                    "params": {"type": params.get("type"), "message": params.get("message")},
+                });
+                Some((
+                    json!({"jsonrpc": "2.0", "id": id, "result": action}),
+                    Some(notification),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Answerer {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if matches!(&msg, Message::Request(_) | Message::Unknown(_)) {
+            if let Ok(value) = serde_json::to_value(&msg) {
+                self.on_client(&value);
+            }
+        }
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Request(_) | Message::Unknown(_)) {
+            return Some(msg);
+        }
+        let answer = serde_json::to_value(&msg)
+            .ok()
+            .and_then(|value| self.on_server(&value));
+        let (response, notification) = match answer {
+            Some(answer) => answer,
+            None => return Some(msg),
+        };
+        match serde_json::from_value(response) {
+            Ok(response) => outbox.send_to_server(response),
+            Err(_) => return Some(msg),
+        }
+        tracing::debug!("answered a request the client doesn't support");
+        if let Some(Ok(notification)) = notification.map(serde_json::from_value) {
+            outbox.send_to_client(notification);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answer_unsupported() {
+        let answerer = Answerer {
+            action: MessageAction::First,
+            support: Default::default(),
+        };
+        let show_document = json!({"jsonrpc": "2.0", "id": 1, "method": "window/showDocument", "params": {
+            "uri": "https://example.com", "external": true,
+        }});
+        let request = json!({"jsonrpc": "2.0", "id": 2, "method": "window/showMessageRequest", "params": {
+            "type": 3,
+            "message": "Reload the workspace?",
+            "actions": [{"title": "Reload"}, {"title": "Later"}],
+        }});
+        // Forwarded before `initialize`
+        assert_eq!(answerer.on_server(&show_document), None);
+
+        answerer.on_client(
+            &json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {
+                "capabilities": {"window": {"showDocument": {"support": false}}},
+            }}),
+        );
+        assert_eq!(
+            answerer.on_server(&show_document),
+            Some((
+                json!({"jsonrpc": "2.0", "id": 1, "result": {"success": false}}),
+                None
+            ))
+        );
+        assert_eq!(
+            answerer.on_server(&request),
+            Some((
+                json!({"jsonrpc": "2.0", "id": 2, "result": {"title": "Reload"}}),
+                Some(
+                    json!({"jsonrpc": "2.0", "method": "window/showMessage", "params": {
+                        "type": 3,
+                        "message": "Reload the workspace?",
+                    }})
+                )
+            ))
+        );
+
+        answerer.on_client(
+            &json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {
+                "capabilities": {"window": {"showMessage": {}, "showDocument": {"support": true}}},
+            }}),
+        );
+        assert_eq!(answerer.on_server(&show_document), None);
+        assert_eq!(answerer.on_server(&request), None);
+    }
+}
@@ -0,0 +1,154 @@
+//! Limit the number of concurrent sessions, and of server processes.
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use warp::{Filter, Rejection};
+
+use crate::backend;
+
+use super::proxy::SharedContext;
+
+/// Maximum number of concurrent sessions.
+#[derive(Debug, Clone)]
+pub struct SessionLimit {
+    permits: Arc<Semaphore>,
+    /// Time to wait for a session to end before rejecting new ones.
+    wait: Option<Duration>,
+}
+
+impl SessionLimit {
+    pub fn new(max: usize, wait: Option<Duration>) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            wait,
+        }
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        match self.wait {
+            Some(wait) => tokio::time::timeout(wait, self.permits.clone().acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+            None => self.permits.clone().try_acquire_owned().ok(),
+        }
+    }
+}
+
+/// Maximum number of server processes running.
+#[derive(Debug, Clone)]
+pub struct ProcessLimit {
+    max: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl ProcessLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            permits: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    fn reserve(&self) -> io::Result<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().map_err(|_| {
+            tracing::warn!("rejecting server over {} processes", self.max);
+            io::Error::new(io::ErrorKind::Other, "too many server processes")
+        })
+    }
+}
+
+/// Start a server with `spawn`, unless it would exceed the limit.
+/// The process is counted until it exits.
+pub(super) fn spawn_process<F>(
+    limit: Option<&ProcessLimit>,
+    spawn: F,
+) -> io::Result<backend::Connection>
+where
+    F: FnOnce() -> io::Result<backend::Connection>,
+{
+    let permit = limit.map(ProcessLimit::reserve).transpose()?;
+    let mut conn = spawn()?;
+    if let (Some(child), Some(permit)) = (conn.child.as_mut(), permit) {
+        child.hold(permit);
+    }
+    Ok(conn)
+}
+
+/// Held for the duration of the session.
+#[derive(Debug)]
+pub(super) struct Session {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Rejection for a session over the limit.
+#[derive(Debug)]
+pub(super) struct TooManySessions;
+
+impl warp::reject::Reject for TooManySessions {}
+
+/// Start a session, or reject with `TooManySessions`.
+pub(super) async fn start(ctx: &SharedContext) -> Result<Session, TooManySessions> {
+    let limit = ctx.get().sessions;
+    match limit {
+        Some(limit) => match limit.acquire().await {
+            Some(permit) => Ok(Session {
+                _permit: Some(permit),
+            }),
+            None => {
+                tracing::warn!("rejecting session over the limit");
+                Err(TooManySessions)
+            }
+        },
+        None => Ok(Session { _permit: None }),
+    }
+}
+
+pub(super) fn with_session(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Session,), Error = Rejection> + Clone {
+    warp::any().and_then(move || {
+        let ctx = ctx.clone();
+        async move { start(&ctx).await.map_err(warp::reject::custom) }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reject_over_limit() {
+        let limit = SessionLimit::new(1, None);
+        let first = limit.acquire().await;
+        assert!(first.is_some());
+        assert!(limit.acquire().await.is_none());
+        drop(first);
+        assert!(limit.acquire().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_session() {
+        let limit = SessionLimit::new(1, Some(Duration::from_millis(50)));
+        let first = limit.acquire().await;
+        assert!(limit.acquire().await.is_none());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(first);
+        });
+        assert!(limit.acquire().await.is_some());
+    }
+
+    #[test]
+    fn test_reject_over_process_limit() {
+        let limit = ProcessLimit::new(1);
+        let first = limit.reserve().unwrap();
+        assert!(limit.reserve().is_err());
+        drop(first);
+        assert!(limit.reserve().is_ok());
+    }
+}
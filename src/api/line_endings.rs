@@ -0,0 +1,283 @@
+//! Normalizing the line endings of the documents to LF for the servers.
+//!
+//! Windows clients send documents with CRLF, and servers assuming LF count the `\r` as a
+//! character of the line, reporting diagnostics past its end and computing edits that split line
+//! endings. With `--normalize-line-endings`, the texts in the document synchronization are sent to
+//! the server with LF only, and the ranges of the changes past the end of a line are clamped to it.
+//! The edits from the server in documents the client opened with CRLF get CRLF back.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use serde_json::Value;
+
+use crate::lsp::Message;
+
+use super::{
+    middleware::{Middleware, Middlewares, Outbox},
+    positions::Document,
+    text,
+};
+
+/// Add a middleware normalizing the line endings to `middlewares` if `enabled`.
+pub(super) fn apply(enabled: bool, middlewares: &mut Middlewares) {
+    if enabled {
+        middlewares.push(Normalizer::default());
+    }
+}
+
+/// The text with CRLF and CR line endings replaced with LF.
+fn normalize(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// The text with CRLF line endings.
+fn restore(text: &str) -> String {
+    normalize(text).replace('\n', "\r\n")
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Normalized texts of the open documents.
+    documents: HashMap<String, Document>,
+    // Documents opened with CRLF.
+    crlf: HashSet<String>,
+    // Documents with CRLF of the requests from the client, by id.
+    pending: HashMap<String, String>,
+}
+
+#[derive(Default)]
+struct Normalizer {
+    state: Mutex<State>,
+}
+
+impl Normalizer {
+    /// Normalize the texts of the document synchronization in `msg`, returning whether it changed.
+    fn on_client(&self, msg: &mut Value) -> bool {
+        let mut state = self.state.lock().expect("lock line endings");
+        let method = msg.get("method").and_then(Value::as_str).map(String::from);
+        let uri = match msg
+            .pointer("/params/textDocument/uri")
+            .and_then(Value::as_str)
+        {
+            Some(uri) => uri.to_owned(),
+            None => return false,
+        };
+        match method.as_deref() {
+            Some("textDocument/didOpen") => {
+                let text = match msg.pointer_mut("/params/textDocument/text") {
+                    Some(Value::String(text)) => text,
+                    _ => return false,
+                };
+                if text.contains('\r') {
+                    state.crlf.insert(uri.clone());
+                    *text = normalize(text);
+                }
+                state.documents.insert(uri, Document::new(text.clone()));
+                true
+            }
+            Some("textDocument/didChange") => {
+                let changes = match msg.pointer_mut("/params/contentChanges") {
+                    Some(Value::Array(changes)) => changes,
+                    _ => return false,
+                };
+                for change in changes {
+                    if let Some(Value::String(text)) = change.get_mut("text") {
+                        *text = normalize(text);
+                    }
+                    if let Some(doc) = state.documents.remove(&uri) {
+                        if let Some(range) = change.get_mut("range") {
+                            clamp(range, &doc);
+                        }
+                        let mut text = doc.into_text();
+                        if !text::apply_change(&mut text, change) {
+                            tracing::warn!("failed to apply the change to {}", uri);
+                        }
+                        state.documents.insert(uri.clone(), Document::new(text));
+                    }
+                }
+                true
+            }
+            Some("textDocument/didSave") => match msg.pointer_mut("/params/text") {
+                Some(Value::String(text)) => {
+                    *text = normalize(text);
+                    true
+                }
+                _ => false,
+            },
+            Some("textDocument/didClose") => {
+                state.documents.remove(&uri);
+                state.crlf.remove(&uri);
+                false
+            }
+            Some(_) => {
+                if let Some(id) = msg.get("id") {
+                    if state.crlf.contains(&uri) {
+                        state.pending.insert(id.to_string(), uri);
+                    }
+                }
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Restore CRLF in the edits in `msg` of the documents opened with it, returning whether it
+    /// changed.
+    fn on_server(&self, msg: &mut Value) -> bool {
+        let mut state = self.state.lock().expect("lock line endings");
+        if state.crlf.is_empty() {
+            return false;
+        }
+        let uri = match (msg.get("method"), msg.get("id")) {
+            (None, Some(id)) => state.pending.remove(&id.to_string()),
+            _ => None,
+        };
+        restore_edits(msg, uri.as_deref(), &state.crlf)
+    }
+}
+
+/// Clamp the positions of `range` to the ends of their lines in `doc`.
+fn clamp(range: &mut Value, doc: &Document) {
+    for key in &["start", "end"] {
+        let position = match range.get_mut(*key) {
+            Some(position) => position,
+            None => continue,
+        };
+        let line = position.get("line").and_then(Value::as_u64);
+        let character = position.get("character").and_then(Value::as_u64);
+        if let (Some(line), Some(character)) = (line, character) {
+            if let Some(text) = doc.line(line as usize) {
+                let len = text.encode_utf16().count() as u64;
+                if character > len {
+                    position["character"] = Value::from(len);
+                }
+            }
+        }
+    }
+}
+
+/// Restore CRLF in the `newText` of the edits in `value`, in the document `uri` unless they're in
+/// an object with another.
+fn restore_edits(value: &mut Value, uri: Option<&str>, crlf: &HashSet<String>) -> bool {
+    match value {
+        Value::Object(map) => {
+            let inner = map
+                .get("uri")
+                .or_else(|| map.get("textDocument").and_then(|doc| doc.get("uri")))
+                .and_then(Value::as_str)
+                .map(String::from);
+            let uri = inner.as_deref().or(uri);
+            let mut changed = false;
+            for (key, child) in map.iter_mut() {
+                changed = match (key.as_str(), child) {
+                    ("newText", Value::String(text)) if uri.map_or(false, |u| crlf.contains(u)) => {
+                        *text = restore(text);
+                        true
+                    }
+                    // `WorkspaceEdit.changes` by URI
+                    ("changes", Value::Object(changes)) => {
+                        changes.iter_mut().fold(false, |changed, (uri, edits)| {
+                            restore_edits(edits, Some(uri), crlf) || changed
+                        })
+                    }
+                    (_, child) => restore_edits(child, uri, crlf),
+                } || changed;
+            }
+            changed
+        }
+        Value::Array(values) => values.iter_mut().fold(false, |changed, value| {
+            restore_edits(value, uri, crlf) || changed
+        }),
+        _ => false,
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Normalizer {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        if self.on_client(&mut value) {
+            return Some(serde_json::from_value(value).unwrap_or(msg));
+        }
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        if self.on_server(&mut value) {
+            return Some(serde_json::from_value(value).unwrap_or(msg));
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize_line_endings() {
+        let normalizer = Normalizer::default();
+        let mut open = json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": {"uri": "file:///a.c", "languageId": "c", "version": 1, "text": "int a;\r\nint b;\r\n"},
+        }});
+        assert!(normalizer.on_client(&mut open));
+        assert_eq!(
+            open["params"]["textDocument"]["text"],
+            json!("int a;\nint b;\n")
+        );
+
+        // The client counts the `\r` in the line.
+        let mut change = json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": {"uri": "file:///a.c", "version": 2},
+            "contentChanges": [{
+                "range": {"start": {"line": 0, "character": 6}, "end": {"line": 0, "character": 7}},
+                "text": "\r\nint c;",
+            }],
+        }});
+        assert!(normalizer.on_client(&mut change));
+        assert_eq!(
+            change["params"]["contentChanges"][0],
+            json!({
+                "range": {"start": {"line": 0, "character": 6}, "end": {"line": 0, "character": 6}},
+                "text": "\nint c;",
+            })
+        );
+
+        let mut formatting = json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/formatting", "params": {
+            "textDocument": {"uri": "file:///a.c"},
+            "options": {"tabSize": 4, "insertSpaces": true},
+        }});
+        assert!(!normalizer.on_client(&mut formatting));
+        let mut response = json!({"jsonrpc": "2.0", "id": 1, "result": [{
+            "range": {"start": {"line": 0, "character": 0}, "end": {"line": 2, "character": 6}},
+            "newText": "int a;\nint c;\nint b;",
+        }]});
+        assert!(normalizer.on_server(&mut response));
+        assert_eq!(
+            response["result"][0]["newText"],
+            json!("int a;\r\nint c;\r\nint b;")
+        );
+
+        let mut edit = json!({"jsonrpc": "2.0", "id": 0, "method": "workspace/applyEdit", "params": {
+            "edit": {"changes": {
+                "file:///a.c": [{"range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}}, "newText": "\n"}],
+                "file:///b.c": [{"range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 0}}, "newText": "\n"}],
+            }},
+        }});
+        assert!(normalizer.on_server(&mut edit));
+        let changes = &edit["params"]["edit"]["changes"];
+        assert_eq!(changes["file:///a.c"][0]["newText"], json!("\r\n"));
+        assert_eq!(changes["file:///b.c"][0]["newText"], json!("\n"));
+    }
+}
@@ -0,0 +1,117 @@
+//! Keeping the workspace in memory with `--memory`, for hosts that must not persist the code of
+//! the users.
+//!
+//! The servers read the files of the workspace from the disk, so instead of answering their reads,
+//! the workspace is a new directory in a memory filesystem (tmpfs, `/dev/shm` by default). The
+//! servers are started in it, documents are only written on save inside the workspace of the
+//! session, the repositories, the workspaces of the sessions, and the archives downloaded from
+//! `/files/archive` are created next to it, and it's removed when the proxy stops.
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Default memory filesystem to create the workspace in.
+pub const DEFAULT_DIR: &str = "/dev/shm";
+
+/// Directory in memory with the workspace, removed when dropped.
+#[derive(Debug)]
+pub struct Memory {
+    dir: PathBuf,
+}
+
+impl Memory {
+    /// Create a directory in `parent`, which must be in a memory filesystem.
+    pub fn create(parent: &Path) -> io::Result<Self> {
+        if !is_memory(parent)? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a memory filesystem (tmpfs)", parent.display()),
+            ));
+        }
+        let dir = parent.join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        let mut builder = std::fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            // Other users of the host can read `/dev/shm`.
+            builder.mode(0o700);
+        }
+        builder.create(&dir)?;
+        let memory = Self { dir };
+        std::fs::create_dir(memory.workspace())?;
+        std::fs::create_dir(memory.temp())?;
+        tracing::info!("keeping the workspace in {}", memory.workspace().display());
+        Ok(memory)
+    }
+
+    /// Directory of the workspace.
+    pub fn workspace(&self) -> PathBuf {
+        self.dir.join("workspace")
+    }
+
+    /// Directory to clone the repositories of the sessions into.
+    pub fn clones(&self) -> PathBuf {
+        self.dir.join("clones")
+    }
+
+    /// Directory to create the workspaces of the sessions in.
+    pub fn sessions(&self) -> PathBuf {
+        self.dir.join("sessions")
+    }
+
+    /// Directory of the temporary files, like the archives of the workspace.
+    pub fn temp(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.dir) {
+            tracing::warn!("failed to remove {}: {}", self.dir.display(), err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_memory(path: &Path) -> io::Result<bool> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    const TMPFS_MAGIC: u32 = 0x0102_1994;
+    const RAMFS_MAGIC: u32 = 0x8584_58f6;
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The type of `f_type` depends on the architecture.
+    #[allow(clippy::unnecessary_cast)]
+    let kind = stat.f_type as u32;
+    Ok(kind == TMPFS_MAGIC || kind == RAMFS_MAGIC)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_memory(_path: &Path) -> io::Result<bool> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "--memory is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_create() {
+        if let Ok(memory) = Memory::create(Path::new(DEFAULT_DIR)) {
+            let workspace = memory.workspace();
+            assert!(workspace.is_dir());
+            drop(memory);
+            assert!(!workspace.exists());
+        }
+    }
+}
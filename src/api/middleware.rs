@@ -0,0 +1,149 @@
+//! Middlewares intercepting the messages between the client and the server.
+//!
+//! Each middleware can modify, drop, or synthesize messages. Messages from the client go through
+//! the stack in order before the proxy remaps them, and messages from the server go through it in
+//! reverse after the proxy remapped them, so the first middleware is the closest to the client.
+//! Messages synthesized by a middleware are sent as is, without going through the others.
+//!
+//! ```ignore
+//! struct DropTelemetry;
+//!
+//! #[async_trait::async_trait]
+//! impl Middleware for DropTelemetry {
+//!     async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+//!         match msg {
+//!             Message::Notification(Notification::TelemetryEvent { .. }) => None,
+//!             msg => Some(msg),
+//!         }
+//!     }
+//! }
+//!
+//! ctx.middlewares.push(DropTelemetry);
+//! ```
+use std::{fmt, sync::Arc};
+
+use crate::lsp::Message;
+
+/// Messages to send in addition to the intercepted one.
+#[derive(Debug, Default)]
+pub struct Outbox {
+    pub(super) to_client: Vec<Message>,
+    pub(super) to_server: Vec<Message>,
+}
+
+impl Outbox {
+    /// Send `msg` to the client, e.g. a response to a request dropped from the client.
+    pub fn send_to_client(&mut self, msg: Message) {
+        self.to_client.push(msg);
+    }
+
+    /// Send `msg` to the server.
+    pub fn send_to_server(&mut self, msg: Message) {
+        self.to_server.push(msg);
+    }
+}
+
+/// Hooks called with each message, returning the message to forward, or `None` to drop it.
+#[async_trait::async_trait]
+pub trait Middleware: Send + Sync {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        Some(msg)
+    }
+}
+
+/// Stack of middlewares run by each session.
+#[derive(Clone, Default)]
+pub struct Middlewares {
+    stack: Vec<Arc<dyn Middleware>>,
+}
+
+impl fmt::Debug for Middlewares {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Middlewares")
+            .field("len", &self.stack.len())
+            .finish()
+    }
+}
+
+impl Middlewares {
+    /// Add `middleware` to the end of the stack, the closest to the server.
+    pub fn push<M: Middleware + 'static>(&mut self, middleware: M) {
+        self.stack.push(Arc::new(middleware));
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    pub(super) async fn on_client_message(&self, msg: Message) -> (Option<Message>, Outbox) {
+        let mut outbox = Outbox::default();
+        let mut msg = Some(msg);
+        for middleware in &self.stack {
+            msg = match msg {
+                Some(msg) => middleware.on_client_message(msg, &mut outbox).await,
+                None => break,
+            };
+        }
+        (msg, outbox)
+    }
+
+    pub(super) async fn on_server_message(&self, msg: Message) -> (Option<Message>, Outbox) {
+        let mut outbox = Outbox::default();
+        let mut msg = Some(msg);
+        for middleware in self.stack.iter().rev() {
+            msg = match msg {
+                Some(msg) => middleware.on_server_message(msg, &mut outbox).await,
+                None => break,
+            };
+        }
+        (msg, outbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::lsp::Notification;
+
+    const SHOW: &str =
+        r#"{"jsonrpc":"2.0","method":"window/showMessage","params":{"type":3,"message":"hi"}}"#;
+
+    struct DropLogs;
+
+    #[async_trait::async_trait]
+    impl Middleware for DropLogs {
+        async fn on_server_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+            match msg {
+                Message::Notification(Notification::LogMessage { .. }) => {
+                    outbox.send_to_client(Message::from_str(SHOW).unwrap());
+                    None
+                }
+                msg => Some(msg),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middlewares() {
+        let mut middlewares = Middlewares::default();
+        middlewares.push(DropLogs);
+        let log = Message::from_str(
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#,
+        )
+        .unwrap();
+        let (forwarded, outbox) = middlewares.on_server_message(log.clone()).await;
+        assert_eq!(forwarded, None);
+        assert_eq!(outbox.to_client, vec![Message::from_str(SHOW).unwrap()]);
+
+        // Only server messages are intercepted.
+        let (forwarded, outbox) = middlewares.on_client_message(log.clone()).await;
+        assert_eq!(forwarded, Some(log));
+        assert!(outbox.to_client.is_empty());
+    }
+}
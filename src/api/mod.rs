@@ -1,9 +1,58 @@
 use std::{convert::Infallible, error::Error};
 
-use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+use warp::{filters::BoxedFilter, http::StatusCode, reply, Filter, Rejection, Reply};
 
+pub mod admin;
+pub mod archive;
+pub mod batch;
+pub mod cache;
+pub mod clone;
+pub mod commands;
+pub mod debounce;
+pub mod diagnostics;
+pub mod edits;
+pub mod exit;
+pub mod fallback;
+pub mod file_operations;
 pub mod files;
+pub mod filter;
+pub mod grpc;
+pub mod hook;
+pub mod ids;
+pub mod interactive;
+pub mod limit;
+pub mod line_endings;
+pub mod memory;
+pub mod middleware;
+pub mod multiplex;
+pub mod partial;
+pub mod pending;
+pub mod persist;
+pub mod plugin;
+pub mod pool;
+pub mod positions;
+pub mod progress;
 pub mod proxy;
+pub mod quota;
+pub mod rate_limit;
+pub mod restart;
+pub mod resume;
+pub mod root;
+pub mod sandbox;
+pub mod script;
+pub mod settings;
+pub mod shared;
+pub mod shutdown;
+pub mod size;
+pub mod snippets;
+pub mod sse;
+pub mod standby;
+pub mod telemetry;
+pub mod template;
+pub mod text;
+pub mod watch;
+pub mod webhook;
+pub mod workspace;
 
 fn with_context<T>(ctx: T) -> impl Filter<Extract = (T,), Error = Infallible> + Clone
 where
@@ -12,6 +61,52 @@ where
     warp::any().map(move || ctx.clone())
 }
 
+/// Reject with `404 Not Found` unless `enabled`.
+pub fn enabled(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || async move {
+            if enabled {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// Match the segments of `path` (e.g. `/lsp/`) and continue with the rest of the path.
+pub fn path_prefix(path: &str) -> BoxedFilter<()> {
+    path.split('/').filter(|segment| !segment.is_empty()).fold(
+        warp::any().boxed(),
+        |filter, segment| {
+            let segment = segment.to_owned();
+            filter
+                .and(warp::path::param::<String>())
+                .and_then(move |param: String| {
+                    let matched = param == segment;
+                    async move {
+                        if matched {
+                            Ok(())
+                        } else {
+                            Err(warp::reject::not_found())
+                        }
+                    }
+                })
+                .untuple_one()
+                .boxed()
+        },
+    )
+}
+
+/// Match any of `paths` exactly. Matches the root if `paths` is empty.
+pub fn any_path(paths: &[String]) -> BoxedFilter<()> {
+    paths
+        .iter()
+        .map(|path| path_prefix(path).and(warp::path::end()).boxed())
+        .reduce(|a, b| a.or(b).unify().boxed())
+        .unwrap_or_else(|| warp::path::end().boxed())
+}
+
 fn json_body<T>() -> impl Filter<Extract = (T,), Error = Rejection> + Clone
 where
     T: serde::de::DeserializeOwned + Send,
@@ -26,29 +121,55 @@ fn json_response<T: serde::Serialize>(res: &T, status: StatusCode) -> reply::Res
 /// Convert rejections into a JSON response.
 #[allow(clippy::unused_async)]
 pub async fn recover(err: Rejection) -> Result<impl Reply, Rejection> {
-    let (reason, status) = if err.is_not_found() {
-        ("Not Found", StatusCode::NOT_FOUND)
-    } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
-        if let Some(cause) = e.source() {
-            tracing::debug!("deserialize error: {:?}", cause);
-            if let Some(err) = cause.downcast_ref::<serde_json::Error>() {
-                return Ok(json_error_response(
-                    err.to_string(),
-                    StatusCode::BAD_REQUEST,
-                ));
+    let (reason, status) =
+        if let Some(proxy::UnknownServer(name)) = err.find::<proxy::UnknownServer>() {
+            return Ok(json_error_response(
+                format!("unknown server {}", name),
+                StatusCode::BAD_REQUEST,
+            ));
+        } else if let Some(template::InvalidParam(name)) = err.find::<template::InvalidParam>() {
+            return Ok(json_error_response(
+                format!("invalid query parameter {}", name),
+                StatusCode::BAD_REQUEST,
+            ));
+        } else if let Some(clone::InvalidRepo(url)) = err.find::<clone::InvalidRepo>() {
+            return Ok(json_error_response(
+                format!("repository {} is not allowed", url),
+                StatusCode::BAD_REQUEST,
+            ));
+        } else if err.find::<limit::TooManySessions>().is_some() {
+            ("Too Many Sessions", StatusCode::SERVICE_UNAVAILABLE)
+        } else if err.find::<shutdown::Draining>().is_some() {
+            ("Draining", StatusCode::SERVICE_UNAVAILABLE)
+        } else if err.find::<resume::UnknownSession>().is_some() {
+            ("Unknown Session", StatusCode::NOT_FOUND)
+        } else if err.find::<admin::Unauthorized>().is_some() {
+            ("Unauthorized", StatusCode::UNAUTHORIZED)
+        } else if err.find::<sandbox::OutsideRoots>().is_some() {
+            ("Forbidden", StatusCode::FORBIDDEN)
+        } else if err.is_not_found() {
+            ("Not Found", StatusCode::NOT_FOUND)
+        } else if let Some(e) = err.find::<warp::filters::body::BodyDeserializeError>() {
+            if let Some(cause) = e.source() {
+                tracing::debug!("deserialize error: {:?}", cause);
+                if let Some(err) = cause.downcast_ref::<serde_json::Error>() {
+                    return Ok(json_error_response(
+                        err.to_string(),
+                        StatusCode::BAD_REQUEST,
+                    ));
+                }
             }
-        }
-        ("Bad Request", StatusCode::BAD_REQUEST)
-    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
-        ("Unsupported Media Type", StatusCode::UNSUPPORTED_MEDIA_TYPE)
-    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
-        ("Payload Too Large", StatusCode::PAYLOAD_TOO_LARGE)
-    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
-        ("Method Not Allowed", StatusCode::METHOD_NOT_ALLOWED)
-    } else {
-        tracing::warn!("unhandled rejection: {:?}", err);
-        ("Internal Server Error", StatusCode::INTERNAL_SERVER_ERROR)
-    };
+            ("Bad Request", StatusCode::BAD_REQUEST)
+        } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+            ("Unsupported Media Type", StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+            ("Payload Too Large", StatusCode::PAYLOAD_TOO_LARGE)
+        } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+            ("Method Not Allowed", StatusCode::METHOD_NOT_ALLOWED)
+        } else {
+            tracing::warn!("unhandled rejection: {:?}", err);
+            ("Internal Server Error", StatusCode::INTERNAL_SERVER_ERROR)
+        };
 
     Ok(json_error_response(reason, status))
 }
@@ -70,3 +191,55 @@ fn json_error_response<T: Into<String>>(
     )
     .into_response()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_path_prefix() {
+        let files = path_prefix("/lsp/").and(warp::path("files"));
+        assert!(
+            warp::test::request()
+                .path("/lsp/files")
+                .matches(&files)
+                .await
+        );
+        assert!(!warp::test::request().path("/files").matches(&files).await);
+        assert!(
+            warp::test::request()
+                .path("/files")
+                .matches(&path_prefix("/").and(warp::path("files")))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_any_path() {
+        let paths = vec!["/".to_owned(), "/ws".to_owned()];
+        assert!(
+            warp::test::request()
+                .path("/")
+                .matches(&any_path(&paths))
+                .await
+        );
+        assert!(
+            warp::test::request()
+                .path("/ws")
+                .matches(&any_path(&paths))
+                .await
+        );
+        assert!(
+            !warp::test::request()
+                .path("/ws/x")
+                .matches(&any_path(&paths))
+                .await
+        );
+        assert!(
+            warp::test::request()
+                .path("/")
+                .matches(&any_path(&[]))
+                .await
+        );
+    }
+}
@@ -0,0 +1,807 @@
+//! Multiplex several Language Servers on one connection by `languageId`.
+//!
+//! Documents are routed to the server registered for the `languageId` in `textDocument/didOpen`,
+//! or for the extension of the document URI, and anything else goes to the first server.
+//! Lifecycle messages are sent to every server. Requests from the client are sent with IDs unique
+//! for each server, and requests from the servers are namespaced (`"<index>:<id>"`) so the
+//! responses from the client can be routed back.
+//!
+//! The client receives the capabilities of every server merged, and requests not about an open
+//! document are sent to the first server advertising the capability.
+use std::{collections::HashMap, str::FromStr};
+
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde_json::Value;
+use tracing::Instrument;
+
+use crate::{backend, lsp};
+
+use super::{
+    ids::{IdMap, Origin},
+    proxy::{self, Context, Query},
+};
+
+/// Capability required by each request.
+const CAPABILITIES: &[(&str, &str)] = &[
+    ("textDocument/hover", "hoverProvider"),
+    ("textDocument/completion", "completionProvider"),
+    ("textDocument/signatureHelp", "signatureHelpProvider"),
+    ("textDocument/declaration", "declarationProvider"),
+    ("textDocument/definition", "definitionProvider"),
+    ("textDocument/typeDefinition", "typeDefinitionProvider"),
+    ("textDocument/implementation", "implementationProvider"),
+    ("textDocument/references", "referencesProvider"),
+    (
+        "textDocument/documentHighlight",
+        "documentHighlightProvider",
+    ),
+    ("textDocument/documentSymbol", "documentSymbolProvider"),
+    ("textDocument/codeAction", "codeActionProvider"),
+    ("textDocument/codeLens", "codeLensProvider"),
+    ("textDocument/documentLink", "documentLinkProvider"),
+    ("textDocument/documentColor", "colorProvider"),
+    ("textDocument/formatting", "documentFormattingProvider"),
+    (
+        "textDocument/rangeFormatting",
+        "documentRangeFormattingProvider",
+    ),
+    (
+        "textDocument/onTypeFormatting",
+        "documentOnTypeFormattingProvider",
+    ),
+    ("textDocument/rename", "renameProvider"),
+    ("textDocument/foldingRange", "foldingRangeProvider"),
+    ("textDocument/selectionRange", "selectionRangeProvider"),
+    ("textDocument/prepareCallHierarchy", "callHierarchyProvider"),
+    ("textDocument/semanticTokens/full", "semanticTokensProvider"),
+    (
+        "textDocument/semanticTokens/range",
+        "semanticTokensProvider",
+    ),
+    (
+        "textDocument/linkedEditingRange",
+        "linkedEditingRangeProvider",
+    ),
+    ("textDocument/moniker", "monikerProvider"),
+    ("workspace/symbol", "workspaceSymbolProvider"),
+];
+
+/// Language to start the named server for.
+///
+/// ```text
+/// rust=rust-analyzer
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Language {
+    pub id: String,
+    pub name: String,
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((id, name)) if !id.is_empty() && !name.is_empty() => Ok(Self {
+                id: id.to_owned(),
+                name: name.to_owned(),
+            }),
+            _ => Err(format!("expected <languageId>=<name>, got {}", s)),
+        }
+    }
+}
+
+/// File extension to start the named server for.
+///
+/// ```text
+/// rs=rust-analyzer
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Extension {
+    /// Extension without the leading dot.
+    pub extension: String,
+    pub name: String,
+}
+
+impl FromStr for Extension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((extension, name)) if !extension.is_empty() && !name.is_empty() => Ok(Self {
+                extension: extension.trim_start_matches('.').to_owned(),
+                name: name.to_owned(),
+            }),
+            _ => Err(format!("expected <extension>=<name>, got {}", s)),
+        }
+    }
+}
+
+/// Start a server for each language and extension, and multiplex them on one connection.
+pub(super) fn connect(ctx: &Context) -> Result<backend::Connection, std::io::Error> {
+    let mut names: Vec<&str> = Vec::new();
+    let registered = ctx
+        .languages
+        .iter()
+        .map(|language| &language.name)
+        .chain(ctx.extensions.iter().map(|extension| &extension.name));
+    for name in registered {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    let index_of = |name: &str| {
+        names
+            .iter()
+            .position(|n| *n == name)
+            .expect("registered name")
+    };
+    let servers = names
+        .iter()
+        .map(|name| {
+            let query = Query {
+                name: (*name).to_owned(),
+            };
+            proxy::spawn_server(ctx, Some(&query))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let languages = ctx
+        .languages
+        .iter()
+        .map(|language| (language.id.clone(), index_of(&language.name)))
+        .collect();
+    let extensions = ctx
+        .extensions
+        .iter()
+        .map(|extension| (extension.extension.clone(), index_of(&extension.name)))
+        .collect();
+    let router = Router::new(languages, extensions);
+
+    let (front, back) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(back);
+    tokio::spawn(
+        async move {
+            if let Err(err) = forward(router, servers, reader, writer).await {
+                tracing::error!("multiplexer error: {}", err);
+            }
+        }
+        .in_current_span(),
+    );
+    let (reader, writer) = tokio::io::split(front);
+    Ok(backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    })
+}
+
+enum Event {
+    Client(Option<String>),
+    Server(usize, Option<String>),
+}
+
+async fn forward<R, W>(
+    mut router: Router,
+    servers: Vec<backend::Connection>,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut client_send = lsp::framed::writer(writer);
+    let client_recv = lsp::framed::reader(reader)
+        .filter_map(|msg| future::ready(msg.ok()))
+        .map(|text| Event::Client(Some(text)))
+        .chain(stream::once(future::ready(Event::Client(None))));
+
+    let mut server_sends = Vec::with_capacity(servers.len());
+    let mut server_recvs = Vec::with_capacity(servers.len());
+    // Keep the processes until the multiplexer stops.
+    let mut children = Vec::with_capacity(servers.len());
+    for (index, server) in servers.into_iter().enumerate() {
+        server_sends.push(lsp::framed::writer(server.writer));
+        server_recvs.push(
+            lsp::framed::reader(server.reader)
+                .filter_map(|msg| future::ready(msg.ok()))
+                .map(move |text| Event::Server(index, Some(text)))
+                .chain(stream::once(future::ready(Event::Server(index, None))))
+                .boxed(),
+        );
+        children.push(server.child);
+    }
+    let mut events = stream::select(client_recv, stream::select_all(server_recvs));
+
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Client(Some(text)) => match serde_json::from_str::<Value>(&text) {
+                Ok(msg) => {
+                    for (index, msg) in router.route_client(msg) {
+                        server_sends[index].send(msg.to_string()).await?;
+                    }
+                }
+                Err(_) => {
+                    tracing::warn!("sending invalid message to the first server");
+                    server_sends[0].send(text).await?;
+                }
+            },
+
+            Event::Server(index, Some(text)) => match serde_json::from_str::<Value>(&text) {
+                Ok(msg) => {
+                    if let Some(msg) = router.route_server(index, msg) {
+                        client_send.send(msg.to_string()).await?;
+                    }
+                }
+                Err(_) => client_send.send(text).await?,
+            },
+
+            Event::Client(None) => break,
+
+            Event::Server(index, None) => {
+                tracing::error!("multiplexed server {} exited", index);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decides which servers receive each message.
+struct Router {
+    /// Server index for each `languageId`.
+    languages: HashMap<String, usize>,
+    /// Server index for each file extension.
+    extensions: HashMap<String, usize>,
+    /// Server index for each open document.
+    documents: HashMap<String, usize>,
+    /// Number of servers.
+    servers: usize,
+    /// Requests sent to each server. The responses to the ones from the proxy are not forwarded.
+    ids: Vec<IdMap>,
+    /// `initialize` waiting for the results from every server.
+    initializing: Option<Initializing>,
+    /// Capabilities of each server, once initialized.
+    capabilities: Vec<Value>,
+}
+
+struct Initializing {
+    /// ID of the request from the client.
+    id: Value,
+    /// Proxy ID of the request sent to each server.
+    ids: Vec<Value>,
+    /// Response from each server.
+    responses: Vec<Option<Value>>,
+}
+
+impl Router {
+    fn new(languages: HashMap<String, usize>, extensions: HashMap<String, usize>) -> Self {
+        let servers = languages
+            .values()
+            .chain(extensions.values())
+            .max()
+            .map_or(1, |max| max + 1);
+        Self {
+            languages,
+            extensions,
+            documents: HashMap::new(),
+            servers,
+            ids: (0..servers).map(|_| IdMap::default()).collect(),
+            initializing: None,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Messages to send to the servers for the message from the client.
+    fn route_client(&mut self, mut msg: Value) -> Vec<(usize, Value)> {
+        let method = msg.get("method").and_then(Value::as_str).map(String::from);
+        let method = match method {
+            Some(method) => method,
+            // Response to a namespaced request from a server.
+            None => {
+                let target = msg.get("id").and_then(Value::as_str).and_then(split_id);
+                return match target {
+                    Some((index, id)) if index < self.servers => {
+                        msg["id"] = id;
+                        vec![(index, msg)]
+                    }
+                    _ => vec![(0, msg)],
+                };
+            }
+        };
+
+        match method.as_str() {
+            "initialize" if self.servers > 1 => self.initialize(msg),
+
+            "initialize" | "shutdown" => self.broadcast_request(msg),
+
+            // Only the server with the request can cancel it.
+            "$/cancelRequest" => {
+                let id = msg.pointer("/params/id").cloned().unwrap_or(Value::Null);
+                (0..self.servers)
+                    .filter_map(|index| {
+                        let sent = self.ids[index].find(&id)?;
+                        let mut msg = msg.clone();
+                        msg["params"]["id"] = sent;
+                        Some((index, msg))
+                    })
+                    .collect()
+            }
+
+            "initialized"
+            | "exit"
+            | "$/setTrace"
+            | "workspace/didChangeConfiguration"
+            | "workspace/didChangeWorkspaceFolders"
+            | "workspace/didChangeWatchedFiles" => (0..self.servers)
+                .map(|index| (index, msg.clone()))
+                .collect(),
+
+            "textDocument/didOpen" => {
+                let index = msg
+                    .pointer("/params/textDocument/languageId")
+                    .and_then(Value::as_str)
+                    .and_then(|id| self.languages.get(id).copied());
+                let uri = document_uri(&msg);
+                let index = index
+                    .or_else(|| uri.as_deref().and_then(|uri| self.for_extension(uri)))
+                    .unwrap_or(0);
+                if let Some(uri) = uri {
+                    self.documents.insert(uri, index);
+                }
+                vec![(index, msg)]
+            }
+
+            "textDocument/didClose" => {
+                let index = document_uri(&msg)
+                    .and_then(|uri| {
+                        self.documents
+                            .remove(&uri)
+                            .or_else(|| self.for_extension(&uri))
+                    })
+                    .unwrap_or(0);
+                vec![(index, msg)]
+            }
+
+            "workspace/executeCommand" => {
+                let index = msg
+                    .pointer("/params/command")
+                    .and_then(Value::as_str)
+                    .and_then(|command| {
+                        self.capabilities.iter().position(|capabilities| {
+                            capabilities
+                                .pointer("/executeCommandProvider/commands")
+                                .and_then(Value::as_array)
+                                .map_or(false, |commands| commands.iter().any(|c| c == command))
+                        })
+                    })
+                    .unwrap_or(0);
+                self.send(index, msg)
+            }
+
+            _ => {
+                let document = document_uri(&msg).and_then(|uri| {
+                    self.documents
+                        .get(&uri)
+                        .copied()
+                        .or_else(|| self.for_extension(&uri))
+                });
+                let advertised = CAPABILITIES
+                    .iter()
+                    .find(|(m, _)| *m == method)
+                    .map(|(_, capability)| *capability);
+                let index = match (document, advertised) {
+                    (Some(index), Some(capability)) if !self.advertises(index, capability) => {
+                        self.first_advertising(capability).unwrap_or(index)
+                    }
+                    (Some(index), _) => index,
+                    (None, Some(capability)) => self.first_advertising(capability).unwrap_or(0),
+                    (None, None) => 0,
+                };
+                self.send(index, msg)
+            }
+        }
+    }
+
+    // Send the message from the client to the server at `index`, with an ID unique for the server
+    // if it's a request.
+    fn send(&mut self, index: usize, mut msg: Value) -> Vec<(usize, Value)> {
+        if let Some(id) = msg.get("id").cloned() {
+            msg["id"] = self.ids[index].client(id);
+        }
+        vec![(index, msg)]
+    }
+
+    // Server registered for the extension of the document.
+    fn for_extension(&self, uri: &str) -> Option<usize> {
+        let path = uri.split(|c| c == '?' || c == '#').next()?;
+        let (_, extension) = path.rsplit('/').next()?.rsplit_once('.')?;
+        self.extensions.get(extension).copied()
+    }
+
+    // Whether the server at `index` advertises `capability`.
+    // Servers are assumed to support everything until initialized.
+    fn advertises(&self, index: usize, capability: &str) -> bool {
+        match self.capabilities.get(index) {
+            Some(capabilities) => !matches!(
+                capabilities.get(capability),
+                None | Some(Value::Null) | Some(Value::Bool(false))
+            ),
+            None => true,
+        }
+    }
+
+    fn first_advertising(&self, capability: &str) -> Option<usize> {
+        (0..self.servers).find(|index| self.advertises(*index, capability))
+    }
+
+    // Send `initialize` to every server, and respond to the client once every server responded.
+    fn initialize(&mut self, msg: Value) -> Vec<(usize, Value)> {
+        let mut ids = Vec::with_capacity(self.servers);
+        let mut msgs = Vec::with_capacity(self.servers);
+        for index in 0..self.servers {
+            let id = self.ids[index].proxy();
+            let mut msg = msg.clone();
+            msg["id"] = id.clone();
+            ids.push(id);
+            msgs.push((index, msg));
+        }
+        self.initializing = Some(Initializing {
+            id: msg["id"].clone(),
+            ids,
+            responses: vec![None; self.servers],
+        });
+        msgs
+    }
+
+    // Record the response to `initialize`, and return the response to the client when complete.
+    fn initialized(&mut self, index: usize, msg: Value) -> Option<Value> {
+        let initializing = self.initializing.as_mut()?;
+        initializing.responses[index] = Some(msg);
+        if initializing.responses.iter().any(Option::is_none) {
+            return None;
+        }
+        let initializing = self.initializing.take()?;
+        let responses: Vec<Value> = initializing.responses.into_iter().flatten().collect();
+        let mut response = responses[0].clone();
+        response["id"] = initializing.id;
+        // The result from the first server is required.
+        if response.get("error").is_some() {
+            return Some(response);
+        }
+        for (index, response) in responses.iter().enumerate().skip(1) {
+            if let Some(error) = response.get("error") {
+                tracing::warn!(
+                    "multiplexed server {} failed to initialize: {}",
+                    index,
+                    error
+                );
+            }
+        }
+        self.capabilities = responses
+            .iter()
+            .map(|response| {
+                response
+                    .pointer("/result/capabilities")
+                    .cloned()
+                    .unwrap_or_else(|| Value::Object(serde_json::Map::new()))
+            })
+            .collect();
+        response["result"]["capabilities"] = merge_capabilities(&self.capabilities);
+        Some(response)
+    }
+
+    /// Message to send to the client for the message from the server at `index`.
+    fn route_server(&mut self, index: usize, mut msg: Value) -> Option<Value> {
+        let id = match msg.get("id") {
+            Some(id) => id.clone(),
+            None => return Some(msg),
+        };
+        if msg.get("method").is_some() {
+            msg["id"] = Value::String(format!("{}:{}", index, id));
+            return Some(msg);
+        }
+        match self.ids[index].response(&id) {
+            Some(Origin::Client(original)) => {
+                msg["id"] = original;
+                Some(msg)
+            }
+            Some(Origin::Proxy)
+                if self.initializing.as_ref().map_or(false, |initializing| {
+                    initializing.ids.get(index) == Some(&id)
+                }) =>
+            {
+                self.initialized(index, msg)
+            }
+            Some(Origin::Proxy) => None,
+            None => Some(msg),
+        }
+    }
+
+    // Send the request to every server, but only forward the response from the first one.
+    fn broadcast_request(&mut self, msg: Value) -> Vec<(usize, Value)> {
+        let mut msgs = Vec::with_capacity(self.servers);
+        for index in 1..self.servers {
+            let mut msg = msg.clone();
+            msg["id"] = self.ids[index].proxy();
+            msgs.push((index, msg));
+        }
+        msgs.splice(0..0, self.send(0, msg));
+        msgs
+    }
+}
+
+// Capabilities of the first server, with the capabilities missing filled in from the others.
+fn merge_capabilities(capabilities: &[Value]) -> Value {
+    let mut merged = serde_json::Map::new();
+    let mut commands: Vec<Value> = Vec::new();
+    for capabilities in capabilities {
+        if let Some(capabilities) = capabilities.as_object() {
+            for (key, value) in capabilities {
+                let missing = matches!(
+                    merged.get(key),
+                    None | Some(Value::Null) | Some(Value::Bool(false))
+                );
+                if missing {
+                    merged.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        let server_commands = capabilities
+            .pointer("/executeCommandProvider/commands")
+            .and_then(Value::as_array);
+        for command in server_commands.into_iter().flatten() {
+            if !commands.contains(command) {
+                commands.push(command.clone());
+            }
+        }
+    }
+    if !commands.is_empty() {
+        merged.insert(
+            "executeCommandProvider".to_owned(),
+            serde_json::json!({ "commands": commands }),
+        );
+    }
+    Value::Object(merged)
+}
+
+fn document_uri(msg: &Value) -> Option<String> {
+    msg.pointer("/params/textDocument/uri")
+        .and_then(Value::as_str)
+        .map(String::from)
+}
+
+// Split namespaced ID `"<index>:<id>"` into the server index and the original ID.
+fn split_id(id: &str) -> Option<(usize, Value)> {
+    let (index, id) = id.split_once(':')?;
+    Some((index.parse().ok()?, serde_json::from_str(id).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    // `msg` as sent to a server, with the ID allocated by the proxy.
+    fn sent(msg: &Value, id: u64) -> Value {
+        let mut msg = msg.clone();
+        msg["id"] = json!(id);
+        msg
+    }
+
+    fn router() -> Router {
+        let mut languages = HashMap::new();
+        languages.insert("rust".to_owned(), 0);
+        languages.insert("python".to_owned(), 1);
+        let mut extensions = HashMap::new();
+        extensions.insert("py".to_owned(), 1);
+        Router::new(languages, extensions)
+    }
+
+    #[test]
+    fn test_parse_language() {
+        assert_eq!(
+            "rust=rust-analyzer".parse::<Language>(),
+            Ok(Language {
+                id: "rust".to_owned(),
+                name: "rust-analyzer".to_owned(),
+            })
+        );
+        assert!("rust".parse::<Language>().is_err());
+    }
+
+    #[test]
+    fn test_route_documents_by_language() {
+        let mut router = router();
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///a.py", "languageId": "python", "version": 1, "text": ""}}
+        });
+        assert_eq!(router.route_client(open.clone()), vec![(1, open)]);
+
+        let hover = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/hover",
+            "params": {"textDocument": {"uri": "file:///a.py"}, "position": {"line": 0, "character": 0}}
+        });
+        assert_eq!(
+            router.route_client(hover.clone()),
+            vec![(1, sent(&hover, 0))]
+        );
+
+        let symbol = json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {"query": ""}});
+        assert_eq!(
+            router.route_client(symbol.clone()),
+            vec![(0, sent(&symbol, 0))]
+        );
+    }
+
+    // Initialize both servers, and return the response to the client.
+    fn initialize(router: &mut Router) -> Option<Value> {
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}});
+        let msgs = router.route_client(initialize);
+        assert_eq!(msgs.len(), 2);
+        let results = [
+            json!({
+                "capabilities": {
+                    "hoverProvider": true,
+                    "workspaceSymbolProvider": false,
+                    "executeCommandProvider": {"commands": ["rust.run"]},
+                },
+                "serverInfo": {"name": "rust-analyzer"},
+            }),
+            json!({
+                "capabilities": {
+                    "hoverProvider": true,
+                    "workspaceSymbolProvider": true,
+                    "executeCommandProvider": {"commands": ["pyright.organizeimports"]},
+                },
+            }),
+        ];
+        let mut responses = msgs
+            .into_iter()
+            .zip(results.iter())
+            .map(|((index, msg), result)| {
+                let response = json!({"jsonrpc": "2.0", "id": msg["id"], "result": result});
+                router.route_server(index, response)
+            });
+        assert_eq!(responses.next(), Some(None));
+        responses.next().flatten()
+    }
+
+    #[test]
+    fn test_merge_capabilities() {
+        let mut router = router();
+        assert_eq!(
+            initialize(&mut router),
+            Some(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": {
+                    "capabilities": {
+                        "hoverProvider": true,
+                        "workspaceSymbolProvider": true,
+                        "executeCommandProvider": {
+                            "commands": ["rust.run", "pyright.organizeimports"]
+                        },
+                    },
+                    "serverInfo": {"name": "rust-analyzer"},
+                },
+            }))
+        );
+    }
+
+    #[test]
+    fn test_route_by_capability() {
+        let mut router = router();
+        initialize(&mut router);
+
+        let symbol = json!({"jsonrpc": "2.0", "id": 2, "method": "workspace/symbol", "params": {"query": ""}});
+        assert_eq!(
+            router.route_client(symbol.clone()),
+            vec![(1, sent(&symbol, 1))]
+        );
+
+        let command = json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "method": "workspace/executeCommand",
+            "params": {"command": "pyright.organizeimports"}
+        });
+        assert_eq!(
+            router.route_client(command.clone()),
+            vec![(1, sent(&command, 2))]
+        );
+    }
+
+    #[test]
+    fn test_parse_extension() {
+        assert_eq!(
+            ".rs=rust-analyzer".parse::<Extension>(),
+            Ok(Extension {
+                extension: "rs".to_owned(),
+                name: "rust-analyzer".to_owned(),
+            })
+        );
+        assert!("rs=".parse::<Extension>().is_err());
+    }
+
+    #[test]
+    fn test_route_documents_by_extension() {
+        let mut router = router();
+        let definition = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "textDocument/definition",
+            "params": {"textDocument": {"uri": "file:///b.py"}, "position": {"line": 0, "character": 0}}
+        });
+        assert_eq!(
+            router.route_client(definition.clone()),
+            vec![(1, sent(&definition, 0))]
+        );
+
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///c.py", "languageId": "plaintext", "version": 1, "text": ""}}
+        });
+        assert_eq!(router.route_client(open.clone()), vec![(1, open)]);
+    }
+
+    #[test]
+    fn test_broadcast_shutdown() {
+        let mut router = router();
+        let shutdown = json!({"jsonrpc": "2.0", "id": 1, "method": "shutdown"});
+        let msgs = router.route_client(shutdown.clone());
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0], (0, sent(&shutdown, 0)));
+        assert_eq!(msgs[1].0, 1);
+
+        let id = msgs[1].1["id"].clone();
+        let response = json!({"jsonrpc": "2.0", "id": id, "result": null});
+        assert_eq!(router.route_server(1, response), None);
+        let response = json!({"jsonrpc": "2.0", "id": 0, "result": null});
+        assert_eq!(
+            router.route_server(0, response),
+            Some(json!({"jsonrpc": "2.0", "id": 1, "result": null}))
+        );
+    }
+
+    #[test]
+    fn test_cancel_request() {
+        let mut router = router();
+        let hover = json!({
+            "jsonrpc": "2.0",
+            "id": "a",
+            "method": "textDocument/hover",
+            "params": {"textDocument": {"uri": "file:///b.py"}, "position": {"line": 0, "character": 0}}
+        });
+        let msgs = router.route_client(hover);
+        assert_eq!(msgs[0].0, 1);
+        let cancel = json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": {"id": "a"}});
+        assert_eq!(
+            router.route_client(cancel),
+            vec![(
+                1,
+                json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": {"id": msgs[0].1["id"]}})
+            )]
+        );
+    }
+
+    #[test]
+    fn test_namespace_server_requests() {
+        let mut router = router();
+        let request = json!({"jsonrpc": "2.0", "id": 3, "method": "workspace/configuration", "params": {"items": []}});
+        let forwarded = router.route_server(1, request).unwrap();
+        assert_eq!(forwarded["id"], json!("1:3"));
+
+        let response = json!({"jsonrpc": "2.0", "id": "1:3", "result": []});
+        assert_eq!(
+            router.route_client(response),
+            vec![(1, json!({"jsonrpc": "2.0", "id": 3, "result": []}))]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+//! Streaming large results as partial results.
+//!
+//! With `--partial-results`, an array result over the size of a request with a
+//! `partialResultToken`, like the references of a common symbol, is sent to the client as
+//! `$/progress` notifications with chunks of the array, followed by the response with an empty
+//! array, so the client can render the results as they arrive instead of parsing one huge
+//! message.
+use std::{collections::HashMap, sync::Mutex};
+
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Add a middleware splitting the results over `threshold` bytes to `middlewares`.
+pub(super) fn apply(threshold: Option<usize>, middlewares: &mut Middlewares) {
+    if let Some(threshold) = threshold {
+        middlewares.push(Splitter {
+            threshold,
+            tokens: Default::default(),
+        });
+    }
+}
+
+struct Splitter {
+    threshold: usize,
+    // Partial result tokens of the requests from the client, by id.
+    tokens: Mutex<HashMap<String, Value>>,
+}
+
+impl Splitter {
+    /// Remember the partial result token of the request `msg`.
+    fn on_client(&self, msg: &Value) {
+        let id = match msg.get("id") {
+            Some(id) if msg.get("method").is_some() => id,
+            _ => return,
+        };
+        if let Some(token) = msg.pointer("/params/partialResultToken") {
+            self.tokens
+                .lock()
+                .expect("lock tokens")
+                .insert(id.to_string(), token.clone());
+        }
+    }
+
+    /// The partial results and the final response to send instead of the response `msg`, or
+    /// `None` to send it as is.
+    fn on_server(&self, msg: &Value) -> Option<(Vec<Value>, Value)> {
+        if msg.get("method").is_some() {
+            return None;
+        }
+        let id = msg.get("id")?;
+        let token = self
+            .tokens
+            .lock()
+            .expect("lock tokens")
+            .remove(&id.to_string())?;
+        let items = msg.get("result")?.as_array()?;
+        if items.len() < 2 || msg.get("result")?.to_string().len() <= self.threshold {
+            return None;
+        }
+
+        let mut partials = Vec::new();
+        let mut chunk = Vec::new();
+        let mut size = 0;
+        for item in items {
+            let len = item.to_string().len() + 1;
+            if !chunk.is_empty() && size + len > self.threshold {
+                partials.push(progress(&token, std::mem::take(&mut chunk)));
+                size = 0;
+            }
+            chunk.push(item.clone());
+            size += len;
+        }
+        partials.push(progress(&token, chunk));
+        let response = json!({"jsonrpc": "2.0", "id": id, "result": []});
+        Some((partials, response))
+    }
+}
+
+fn progress(token: &Value, items: Vec<Value>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "$/progress",
+        "params": { "token": token, "value": items },
+    })
+}
+
+#[async_trait::async_trait]
+impl Middleware for Splitter {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if matches!(&msg, Message::Request(_) | Message::Unknown(_)) {
+            if let Ok(value) = serde_json::to_value(&msg) {
+                self.on_client(&value);
+            }
+        }
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Response(_) | Message::Unknown(_)) {
+            return Some(msg);
+        }
+        let value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        let (partials, response) = match self.on_server(&value) {
+            Some(split) => split,
+            None => return Some(msg),
+        };
+        let response = match serde_json::from_value(response) {
+            Ok(response) => response,
+            Err(_) => return Some(msg),
+        };
+        tracing::debug!("sending the result as {} partial results", partials.len());
+        for partial in partials {
+            if let Ok(partial) = serde_json::from_value(partial) {
+                outbox.send_to_client(partial);
+            }
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_result() {
+        let splitter = Splitter {
+            threshold: 200,
+            tokens: Default::default(),
+        };
+        let location = |line: u32| {
+            json!({"uri": "file:///a.rs", "range": {
+                "start": {"line": line, "character": 0},
+                "end": {"line": line, "character": 4},
+            }})
+        };
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/references", "params": {
+            "textDocument": {"uri": "file:///a.rs"},
+            "position": {"line": 0, "character": 0},
+            "context": {"includeDeclaration": true},
+            "partialResultToken": "refs",
+        }});
+        splitter.on_client(&request);
+        let locations = (0..5).map(location).collect::<Vec<_>>();
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": locations});
+
+        let (partials, last) = splitter.on_server(&response).unwrap();
+        assert_eq!(last, json!({"jsonrpc": "2.0", "id": 1, "result": []}));
+        assert!(partials.len() > 1);
+        let mut streamed = Vec::new();
+        for partial in &partials {
+            assert_eq!(partial["method"], json!("$/progress"));
+            assert_eq!(partial["params"]["token"], json!("refs"));
+            streamed.extend(
+                partial["params"]["value"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .cloned(),
+            );
+        }
+        assert_eq!(streamed, locations);
+
+        // Only once for the request, and not without the token
+        assert_eq!(splitter.on_server(&response), None);
+    }
+}
@@ -0,0 +1,173 @@
+//! Requests from the client waiting for a response from the server.
+//!
+//! With `--request-timeout`, requests the server doesn't respond to in time are answered with
+//! `RequestCancelled`, and cancelled with `$/cancelRequest` so the server can stop working on
+//! them. The response of the server if it comes later is dropped, because the client already got
+//! one. The requests still pending when the client disconnects are cancelled too, so the server
+//! doesn't keep computing results nobody will receive.
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+/// Error code of `RequestCancelled`.
+const REQUEST_CANCELLED: i64 = -32800;
+
+/// Requests sent to the server waiting for a response.
+#[derive(Debug, Default)]
+pub(super) struct Pending {
+    timeout: Option<Duration>,
+    requests: HashMap<String, (Value, Instant)>,
+    // Requests answered by the proxy, with the response of the server to drop.
+    expired: HashSet<String>,
+}
+
+impl Pending {
+    pub(super) fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            ..Self::default()
+        }
+    }
+
+    /// Track `text` sent to the server if it's a request.
+    pub(super) fn sent(&mut self, text: &str, now: Instant) {
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        // Cancelling `initialize` would leave the session unusable.
+        match msg.get("method").and_then(Value::as_str) {
+            None | Some("initialize") => return,
+            Some(_) => {}
+        }
+        if let Some(id) = msg.get("id") {
+            self.requests.insert(id.to_string(), (id.clone(), now));
+        }
+    }
+
+    /// Whether `text` from the server should be forwarded, `false` if it responds to an expired
+    /// request.
+    pub(super) fn received(&mut self, text: &str) -> bool {
+        if self.requests.is_empty() && self.expired.is_empty() {
+            return true;
+        }
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return true,
+        };
+        if msg.get("method").is_some() {
+            return true;
+        }
+        match msg.get("id").map(Value::to_string) {
+            Some(id) => self.requests.remove(&id).is_some() || !self.expired.remove(&id),
+            None => true,
+        }
+    }
+
+    /// Remove the requests pending for longer than the timeout, returning the responses to the
+    /// client and the notifications to the server.
+    pub(super) fn expire(&mut self, now: Instant) -> Vec<(String, String)> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Vec::new(),
+        };
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, (_, sent))| now.saturating_duration_since(*sent) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        expired
+            .into_iter()
+            .filter_map(|key| {
+                let (id, _) = self.requests.remove(&key)?;
+                self.expired.insert(key);
+                let response = json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {
+                        "code": REQUEST_CANCELLED,
+                        "message": format!("request timed out after {} seconds", timeout.as_secs()),
+                    },
+                });
+                Some((response.to_string(), cancel_request(&id)))
+            })
+            .collect()
+    }
+
+    /// Remove all the pending requests, returning the notifications cancelling them.
+    pub(super) fn cancel_all(&mut self) -> Vec<String> {
+        self.requests
+            .drain()
+            .map(|(_, (id, _))| cancel_request(&id))
+            .collect()
+    }
+}
+
+fn cancel_request(id: &Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "$/cancelRequest",
+        "params": { "id": id },
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending() {
+        let mut pending = Pending::new(Some(Duration::from_secs(10)));
+        let now = Instant::now();
+        pending.sent(
+            r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{}}"#,
+            now,
+        );
+        pending.sent(
+            r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/completion","params":{}}"#,
+            now + Duration::from_secs(5),
+        );
+        assert!(pending.expire(now + Duration::from_secs(5)).is_empty());
+
+        let expired = pending.expire(now + Duration::from_secs(10));
+        assert_eq!(expired.len(), 1);
+        let (response, cancel) = &expired[0];
+        let response = serde_json::from_str::<Value>(response).unwrap();
+        assert_eq!(response["id"], json!(1));
+        assert_eq!(response["error"]["code"], json!(REQUEST_CANCELLED));
+        let cancel = serde_json::from_str::<Value>(cancel).unwrap();
+        assert_eq!(cancel["params"]["id"], json!(1));
+
+        // The late response is dropped, and the other is forwarded.
+        assert!(!pending.received(r#"{"jsonrpc":"2.0","id":1,"result":null}"#));
+        assert!(pending.received(r#"{"jsonrpc":"2.0","id":2,"result":null}"#));
+        assert!(pending.expire(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        let mut pending = Pending::new(None);
+        let now = Instant::now();
+        pending.sent(
+            r#"{"jsonrpc":"2.0","id":"a","method":"textDocument/references","params":{}}"#,
+            now,
+        );
+        pending.sent(
+            r#"{"jsonrpc":"2.0","method":"textDocument/didSave","params":{}}"#,
+            now,
+        );
+        assert!(pending.expire(now + Duration::from_secs(3600)).is_empty());
+        let cancels = pending.cancel_all();
+        assert_eq!(cancels.len(), 1);
+        assert_eq!(
+            serde_json::from_str::<Value>(&cancels[0]).unwrap(),
+            json!({"jsonrpc": "2.0", "method": "$/cancelRequest", "params": {"id": "a"}})
+        );
+        assert!(pending.cancel_all().is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+//! Save the state of resumable sessions to `--state-dir`, so clients can resume them after the
+//! proxy restarts.
+//!
+//! Each session is saved to `<token>.json` with the `initialize` request, the `initialized`
+//! notification, and the documents opened by the client. A client resuming a saved session gets a
+//! new server initialized with them.
+use std::{
+    io,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use super::restart::State;
+
+/// Directory of the saved sessions.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    // Path of the session, or `None` if the token can't be one.
+    fn path(&self, token: &str) -> Option<PathBuf> {
+        uuid::Uuid::parse_str(token).ok()?;
+        Some(self.dir.join(format!("{}.json", token)))
+    }
+
+    /// The session was saved within `timeout`.
+    pub(super) fn contains(&self, token: &str, timeout: Duration) -> bool {
+        self.path(token)
+            .and_then(|path| std::fs::metadata(path).ok())
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+            .map_or(false, |age| age <= timeout)
+    }
+
+    pub(super) async fn load(&self, token: &str) -> io::Result<State> {
+        let path = self.path(token).ok_or_else(unknown_session)?;
+        let bytes = tokio::fs::read(path).await?;
+        serde_json::from_slice(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    pub(super) async fn save(&self, token: &str, state: &State) -> io::Result<()> {
+        let path = self.path(token).ok_or_else(unknown_session)?;
+        let bytes = serde_json::to_vec(state)?;
+        // Write to a temporary file first so a crash doesn't leave a partial session.
+        let tmp = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp, bytes).await?;
+        tokio::fs::rename(tmp, path).await
+    }
+
+    pub(super) async fn remove(&self, token: &str) {
+        if let Some(path) = self.path(token) {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+}
+
+fn unknown_session() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "unknown session")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_save_session() {
+        let dir = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        let store = Store::new(dir.clone()).unwrap();
+        let token = uuid::Uuid::new_v4().to_string();
+        let mut state = State::default();
+        state.on_client(&json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}));
+        state.on_client(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {
+                "uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "a"
+            }}
+        }));
+
+        assert!(!store.contains(&token, Duration::from_secs(60)));
+        store.save(&token, &state).await.unwrap();
+        assert!(store.contains(&token, Duration::from_secs(60)));
+        let loaded = store.load(&token).await.unwrap();
+        assert_eq!(loaded.replay(), state.replay());
+
+        store.remove(&token).await;
+        assert!(!store.contains(&token, Duration::from_secs(60)));
+        // Not a token
+        assert!(!store.contains("../secret", Duration::from_secs(60)));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+//! WebAssembly plugins rewriting or vetoing the messages of the sessions of a server.
+//!
+//! A plugin is a module exporting `memory`, `alloc(len: i32) -> i32`, and one or both of
+//! `on_client_message(ptr: i32, len: i32) -> i64` and `on_server_message(ptr: i32, len: i32) -> i64`.
+//! The message is written as JSON to the memory allocated with `alloc`, and the hook returns:
+//!
+//! - `0` to forward the message unchanged,
+//! - `-1` to drop it,
+//! - or `(ptr << 32) | len` of the rewritten message as JSON in its memory.
+//!
+//! Plugins are run as middlewares after the middlewares of the context, in the order they're
+//! configured. Each plugin is instantiated once and shared by the sessions, so it can keep state.
+//! A message is dropped if the plugin fails, so a broken policy doesn't let messages through.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use crate::lsp::Message;
+
+use super::{
+    middleware::{Middleware, Middlewares, Outbox},
+    proxy::Server,
+};
+
+/// Plugin of the server `name`, given with `--plugin`.
+///
+/// ```text
+/// rust-analyzer=/etc/lsp-ws-proxy/policy.wasm
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct PluginOption {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for PluginOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(Self {
+                name: name.to_owned(),
+                path: PathBuf::from(path),
+            }),
+            _ => Err(format!("expected <name>=<path>, got {}", s)),
+        }
+    }
+}
+
+/// Plugins of the servers, loaded by path.
+#[derive(Debug, Clone, Default)]
+pub struct Plugins {
+    loaded: HashMap<PathBuf, Plugin>,
+}
+
+impl Plugins {
+    /// Load the plugins of `servers`.
+    pub fn load(servers: &[Server]) -> Result<Self, String> {
+        let mut loaded = HashMap::new();
+        for path in servers.iter().flat_map(|server| &server.plugins) {
+            if loaded.contains_key(path) {
+                continue;
+            }
+            let instance = wasm::Instance::load(path)
+                .map_err(|err| format!("failed to load plugin {}: {}", path.display(), err))?;
+            tracing::info!("loaded plugin {}", path.display());
+            let plugin = Plugin {
+                path: path.clone(),
+                instance: Arc::new(Mutex::new(instance)),
+            };
+            loaded.insert(path.clone(), plugin);
+        }
+        Ok(Self { loaded })
+    }
+
+    /// Add the plugins of `server` to `middlewares`.
+    pub(super) fn apply(&self, server: &Server, middlewares: &mut Middlewares) {
+        for path in &server.plugins {
+            if let Some(plugin) = self.loaded.get(path) {
+                middlewares.push(plugin.clone());
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Plugin {
+    path: PathBuf,
+    instance: Arc<Mutex<wasm::Instance>>,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+/// What the hook of the plugin returned.
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Forward,
+    Drop,
+    Rewrite(Vec<u8>),
+}
+
+impl Plugin {
+    fn run(&self, hook: &str, msg: Message) -> Option<Message> {
+        let json = match serde_json::to_vec(&msg) {
+            Ok(json) => json,
+            Err(_) => return Some(msg),
+        };
+        let verdict = self.instance.lock().expect("lock plugin").call(hook, &json);
+        match verdict {
+            Ok(Verdict::Forward) => Some(msg),
+            Ok(Verdict::Drop) => {
+                tracing::debug!("plugin {} dropped the message", self.path.display());
+                None
+            }
+            Ok(Verdict::Rewrite(json)) => match serde_json::from_slice(&json) {
+                Ok(msg) => Some(msg),
+                Err(err) => {
+                    tracing::error!(
+                        "plugin {} returned invalid message: {}",
+                        self.path.display(),
+                        err
+                    );
+                    None
+                }
+            },
+            Err(err) => {
+                tracing::error!("plugin {} failed: {}", self.path.display(), err);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Plugin {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        self.run("on_client_message", msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        self.run("on_server_message", msg)
+    }
+}
+
+#[cfg_attr(not(feature = "wasm"), allow(dead_code))]
+fn verdict(
+    result: i64,
+    read: impl FnOnce(usize, usize) -> Result<Vec<u8>, String>,
+) -> Result<Verdict, String> {
+    match result {
+        0 => Ok(Verdict::Forward),
+        -1 => Ok(Verdict::Drop),
+        _ => {
+            let ptr = (result as u64 >> 32) as usize;
+            let len = (result as u64 & 0xffff_ffff) as usize;
+            read(ptr, len).map(Verdict::Rewrite)
+        }
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use std::path::Path;
+
+    use wasmtime::{Engine, Memory, Module, Store, TypedFunc};
+
+    use super::Verdict;
+
+    pub(super) struct Instance {
+        store: Store<()>,
+        memory: Memory,
+        alloc: TypedFunc<i32, i32>,
+        instance: wasmtime::Instance,
+    }
+
+    impl Instance {
+        pub(super) fn load(path: &Path) -> Result<Self, String> {
+            let engine = Engine::default();
+            let module = Module::from_file(&engine, path).map_err(|err| err.to_string())?;
+            let mut store = Store::new(&engine, ());
+            let instance =
+                wasmtime::Instance::new(&mut store, &module, &[]).map_err(|err| err.to_string())?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or("missing export memory")?;
+            let alloc = instance
+                .get_typed_func::<i32, i32, _>(&mut store, "alloc")
+                .map_err(|err| err.to_string())?;
+            Ok(Self {
+                store,
+                memory,
+                alloc,
+                instance,
+            })
+        }
+
+        /// Call `hook` with `msg`, forwarding the message if the plugin doesn't export it.
+        pub(super) fn call(&mut self, hook: &str, msg: &[u8]) -> Result<Verdict, String> {
+            let hook = match self
+                .instance
+                .get_typed_func::<(i32, i32), i64, _>(&mut self.store, hook)
+            {
+                Ok(hook) => hook,
+                Err(_) => return Ok(Verdict::Forward),
+            };
+            let len = msg.len() as i32;
+            let ptr = self
+                .alloc
+                .call(&mut self.store, len)
+                .map_err(|err| err.to_string())?;
+            self.memory
+                .write(&mut self.store, ptr as usize, msg)
+                .map_err(|err| err.to_string())?;
+            let result = hook
+                .call(&mut self.store, (ptr, len))
+                .map_err(|err| err.to_string())?;
+            let (memory, store) = (self.memory, &self.store);
+            super::verdict(result, |ptr, len| {
+                let mut buf = vec![0; len];
+                memory
+                    .read(store, ptr, &mut buf)
+                    .map_err(|err| err.to_string())?;
+                Ok(buf)
+            })
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod wasm {
+    use std::path::Path;
+
+    use super::Verdict;
+
+    pub(super) struct Instance;
+
+    impl Instance {
+        pub(super) fn load(_path: &Path) -> Result<Self, String> {
+            Err("built without the wasm feature".to_owned())
+        }
+
+        pub(super) fn call(&mut self, _hook: &str, _msg: &[u8]) -> Result<Verdict, String> {
+            Ok(Verdict::Forward)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdict() {
+        let read = |ptr: usize, len: usize| Ok(vec![ptr as u8, len as u8]);
+        assert_eq!(verdict(0, read), Ok(Verdict::Forward));
+        assert_eq!(verdict(-1, read), Ok(Verdict::Drop));
+        assert_eq!(
+            verdict((16 << 32) | 2, read),
+            Ok(Verdict::Rewrite(vec![16, 2]))
+        );
+        assert!("rust-analyzer".parse::<PluginOption>().is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Pool of servers started and initialized ahead of connections.
+//!
+//! Each server in the pool is initialized by the proxy with the project root.
+//! The `initialize` request from the client is answered with the result from the server,
+//! and the `initialized` notification is dropped because the server already received it.
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::Notify;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use crate::{
+    backend,
+    lsp::{self, framed::LspFrameCodec},
+};
+
+use super::proxy::{self, Context};
+
+/// ID of the `initialize` request sent by the proxy.
+const INITIALIZE_ID: &str = "lsp-ws-proxy/initialize";
+
+/// Shared pool of initialized servers.
+#[derive(Clone)]
+pub struct Pool {
+    ready: Arc<Mutex<VecDeque<Warm>>>,
+    refill: Arc<Notify>,
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ready = self.ready.lock().expect("lock pool").len();
+        f.debug_struct("Pool").field("ready", &ready).finish()
+    }
+}
+
+/// Server initialized by the proxy.
+struct Warm {
+    send: FramedWrite<backend::Writer, LspFrameCodec>,
+    recv: FramedRead<backend::Reader, LspFrameCodec>,
+    child: Option<backend::Process>,
+    /// The result of `initialize`.
+    result: Value,
+}
+
+impl Pool {
+    /// Keep `size` servers started with `ctx` ready.
+    pub fn new(ctx: Context, size: usize) -> Self {
+        let pool = Self {
+            ready: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+            refill: Arc::new(Notify::new()),
+        };
+        let ready = pool.ready.clone();
+        let refill = pool.refill.clone();
+        tokio::spawn(async move {
+            loop {
+                while ready.lock().expect("lock pool").len() < size {
+                    match warm_up(&ctx).await {
+                        Ok(warm) => ready.lock().expect("lock pool").push_back(warm),
+                        Err(err) => {
+                            tracing::error!("failed to start pooled server: {}", err);
+                            break;
+                        }
+                    }
+                }
+                refill.notified().await;
+            }
+        });
+        pool
+    }
+
+    /// Take a server from the pool, or `None` if the pool is empty.
+    pub(super) fn take(&self) -> Option<backend::Connection> {
+        let warm = self.ready.lock().expect("lock pool").pop_front();
+        self.refill.notify_one();
+        warm.map(connect)
+    }
+}
+
+async fn warm_up(ctx: &Context) -> Result<Warm, Box<dyn std::error::Error + Send + Sync>> {
+    let backend::Connection {
+        reader,
+        writer,
+        child,
+    } = proxy::spawn_server(ctx, None)?;
+    let mut send = lsp::framed::writer(writer);
+    let mut recv = lsp::framed::reader(reader);
+    let server = proxy::select_server(&ctx.servers, None).ok();
+    let mut initialize = json!({
+        "jsonrpc": "2.0",
+        "id": INITIALIZE_ID,
+        "method": "initialize",
+        "params": {
+            "processId": null,
+            "rootUri": ctx.cwd.as_str(),
+            "capabilities": {},
+            "initializationOptions": server.and_then(|server| server.initialization_options.clone()),
+        },
+    });
+    if let Some(patch) = server.and_then(|server| server.capabilities.as_ref()) {
+        lsp::ext::patch_client_capabilities(&mut initialize, patch);
+    }
+    send.send(initialize.to_string()).await?;
+    let result = loop {
+        let text = recv
+            .next()
+            .await
+            .ok_or("server exited before initialized")??;
+        let msg: Value = serde_json::from_str(&text)?;
+        // Ignore anything else before the response.
+        if msg.get("method").is_none() && msg["id"] == INITIALIZE_ID {
+            break msg.get("result").cloned().ok_or("failed to initialize")?;
+        }
+    };
+    let initialized = json!({"jsonrpc": "2.0", "method": "initialized", "params": {}});
+    send.send(initialized.to_string()).await?;
+    tracing::debug!("pooled server ready");
+    Ok(Warm {
+        send,
+        recv,
+        child,
+        result,
+    })
+}
+
+enum Event {
+    Client(Option<String>),
+    Server(Option<String>),
+}
+
+// Connect to the pooled server, answering `initialize` from the client.
+fn connect(warm: Warm) -> backend::Connection {
+    let (front, back) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(back);
+    tokio::spawn(async move {
+        if let Err(err) = forward(warm, reader, writer).await {
+            tracing::error!("pooled server error: {}", err);
+        }
+    });
+    let (reader, writer) = tokio::io::split(front);
+    backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    }
+}
+
+async fn forward<R, W>(
+    warm: Warm,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let Warm {
+        mut send,
+        recv,
+        child: _child,
+        result,
+    } = warm;
+    let mut client_send = lsp::framed::writer(writer);
+    let client_recv = lsp::framed::reader(reader)
+        .filter_map(|msg| future::ready(msg.ok()))
+        .map(|text| Event::Client(Some(text)))
+        .chain(stream::once(future::ready(Event::Client(None))));
+    let server_recv = recv
+        .filter_map(|msg| future::ready(msg.ok()))
+        .map(|text| Event::Server(Some(text)))
+        .chain(stream::once(future::ready(Event::Server(None))));
+    let mut events = stream::select(client_recv, server_recv);
+    let mut initialized = false;
+
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Client(Some(text)) => {
+                let msg = serde_json::from_str::<Value>(&text).ok();
+                let method = msg
+                    .as_ref()
+                    .and_then(|msg| msg.get("method"))
+                    .and_then(Value::as_str);
+                match (method, msg.as_ref()) {
+                    (Some("initialize"), Some(msg)) if !initialized => {
+                        let response = json!({
+                            "jsonrpc": "2.0",
+                            "id": msg["id"],
+                            "result": result,
+                        });
+                        client_send.send(response.to_string()).await?;
+                    }
+                    (Some("initialized"), _) if !initialized => {
+                        initialized = true;
+                    }
+                    _ => send.send(text).await?,
+                }
+            }
+
+            Event::Server(Some(text)) => client_send.send(text).await?,
+
+            Event::Client(None) | Event::Server(None) => break,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,403 @@
+//! Translating positions between the encoding of the client and UTF-16 of the servers.
+//!
+//! LSP positions count characters in UTF-16 code units by default, which clients in other
+//! languages than JavaScript often get wrong. With `--position-encoding utf-8` or `utf-32`, the
+//! proxy keeps a copy of the documents open by the client, and translates every position in the
+//! messages between the encoding of the client and UTF-16. Positions in documents that aren't open
+//! are kept as they are.
+use std::{collections::HashMap, str::FromStr, sync::Mutex};
+
+use serde_json::{Map, Value};
+
+use crate::lsp::Message;
+
+use super::{
+    middleware::{Middleware, Middlewares, Outbox},
+    text,
+};
+
+/// How the characters of a position are counted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PositionEncoding {
+    /// In UTF-8 code units, bytes.
+    Utf8,
+    /// In UTF-16 code units, the default of LSP.
+    Utf16,
+    /// In Unicode code points.
+    Utf32,
+}
+
+impl FromStr for PositionEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf-8" => Ok(Self::Utf8),
+            "utf-16" => Ok(Self::Utf16),
+            "utf-32" => Ok(Self::Utf32),
+            _ => Err(format!("expected utf-8, utf-16, or utf-32, got {}", s)),
+        }
+    }
+}
+
+impl PositionEncoding {
+    /// The name in `positionEncoding` of the server capabilities.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf16 => "utf-16",
+            Self::Utf32 => "utf-32",
+        }
+    }
+
+    fn len(self, c: char) -> u64 {
+        match self {
+            Self::Utf8 => c.len_utf8() as u64,
+            Self::Utf16 => c.len_utf16() as u64,
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+/// Add a middleware translating the positions from and to `encoding` to `middlewares`.
+pub(super) fn apply(encoding: Option<PositionEncoding>, middlewares: &mut Middlewares) {
+    match encoding {
+        Some(PositionEncoding::Utf16) | None => {}
+        Some(encoding) => middlewares.push(Translator {
+            encoding,
+            state: Default::default(),
+        }),
+    }
+}
+
+/// Document open by the client.
+#[derive(Debug)]
+pub(super) struct Document {
+    text: String,
+    /// Byte offsets of the starts of the lines.
+    lines: Vec<usize>,
+}
+
+impl Document {
+    pub(super) fn new(text: String) -> Self {
+        let lines = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { text, lines }
+    }
+
+    pub(super) fn into_text(self) -> String {
+        self.text
+    }
+
+    /// The text of the line without the line ending.
+    pub(super) fn line(&self, line: usize) -> Option<&str> {
+        let start = *self.lines.get(line)?;
+        let end = self
+            .lines
+            .get(line + 1)
+            .map_or(self.text.len(), |next| next - 1);
+        Some(self.text[start..end].trim_end_matches('\r'))
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    documents: HashMap<String, Document>,
+    // Document of each request from the client, by id.
+    pending: HashMap<String, Option<String>>,
+    initialize: Option<String>,
+}
+
+/// Translates positions of `from` to `to` in a message.
+struct Translation<'a> {
+    documents: &'a HashMap<String, Document>,
+    from: PositionEncoding,
+    to: PositionEncoding,
+}
+
+impl Translation<'_> {
+    /// Translate the positions in `value`, in the document `uri` unless they're in an object with
+    /// another.
+    fn translate(&self, value: &mut Value, uri: Option<&str>) {
+        match value {
+            Value::Object(map) => {
+                if let Some((line, character)) = position(map) {
+                    if let Some(character) = self.character(uri, line, character) {
+                        map.insert("character".to_owned(), Value::from(character));
+                    }
+                    return;
+                }
+                let inner = map
+                    .get("uri")
+                    .or_else(|| map.get("targetUri"))
+                    .or_else(|| map.get("textDocument").and_then(|doc| doc.get("uri")))
+                    .and_then(Value::as_str)
+                    .map(String::from);
+                for (key, child) in map.iter_mut() {
+                    match (key.as_str(), child) {
+                        // `WorkspaceEdit.changes` by URI
+                        ("changes", Value::Object(changes)) => {
+                            for (uri, edits) in changes.iter_mut() {
+                                self.translate(edits, Some(uri));
+                            }
+                        }
+                        // In the document of the request, unlike the rest of `LocationLink`
+                        ("originSelectionRange", child) => self.translate(child, uri),
+                        (_, child) => self.translate(child, inner.as_deref().or(uri)),
+                    }
+                }
+            }
+            Value::Array(values) => {
+                for value in values {
+                    self.translate(value, uri);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn character(&self, uri: Option<&str>, line: u64, character: u64) -> Option<u64> {
+        let line = self.documents.get(uri?)?.line(line as usize)?;
+        Some(convert(line, character, self.from, self.to))
+    }
+}
+
+/// Line and character of the object if it's a `Position`.
+fn position(map: &Map<String, Value>) -> Option<(u64, u64)> {
+    if map.len() != 2 {
+        return None;
+    }
+    Some((map.get("line")?.as_u64()?, map.get("character")?.as_u64()?))
+}
+
+/// The character in `line` counted in `from` counted in `to`.
+fn convert(line: &str, character: u64, from: PositionEncoding, to: PositionEncoding) -> u64 {
+    let (mut from_units, mut to_units) = (0, 0);
+    for c in line.chars() {
+        if from_units >= character {
+            return to_units;
+        }
+        from_units += from.len(c);
+        to_units += to.len(c);
+    }
+    // Past the end of the line
+    to_units + character.saturating_sub(from_units)
+}
+
+struct Translator {
+    encoding: PositionEncoding,
+    state: Mutex<State>,
+}
+
+impl Translator {
+    fn on_client(&self, msg: &mut Value) {
+        let mut state = self.state.lock().expect("lock documents");
+        let method = msg.get("method").and_then(Value::as_str).map(String::from);
+        let uri = msg
+            .pointer("/params/textDocument/uri")
+            .and_then(Value::as_str)
+            .map(String::from);
+        match (method.as_deref(), uri) {
+            // Responses to the server are rarely about positions, and can't be related to a
+            // document.
+            (None, _) => {}
+            (Some("initialize"), _) => {
+                // The server should use UTF-16.
+                if let Some(Value::Object(general)) =
+                    msg.pointer_mut("/params/capabilities/general")
+                {
+                    general.remove("positionEncodings");
+                }
+                state.initialize = msg.get("id").map(Value::to_string);
+            }
+            (Some("textDocument/didOpen"), Some(uri)) => {
+                let text = msg
+                    .pointer("/params/textDocument/text")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                state.documents.insert(uri, Document::new(text.to_owned()));
+            }
+            (Some("textDocument/didChange"), Some(uri)) => {
+                let changes = match msg.pointer_mut("/params/contentChanges") {
+                    Some(Value::Array(changes)) => changes,
+                    _ => return,
+                };
+                // Each range is in the text after the changes before it.
+                for change in changes {
+                    if let Some(range) = change.get_mut("range") {
+                        Translation {
+                            documents: &state.documents,
+                            from: self.encoding,
+                            to: PositionEncoding::Utf16,
+                        }
+                        .translate(range, Some(&uri));
+                    }
+                    // In the code units of the client, wrong after translating the range
+                    if let Value::Object(change) = change {
+                        change.remove("rangeLength");
+                    }
+                    if let Some(doc) = state.documents.remove(&uri) {
+                        let mut text = doc.into_text();
+                        if !text::apply_change(&mut text, change) {
+                            tracing::warn!("failed to apply the change to {}", uri);
+                        }
+                        state.documents.insert(uri.clone(), Document::new(text));
+                    }
+                }
+            }
+            (Some("textDocument/didClose"), Some(uri)) => {
+                state.documents.remove(&uri);
+            }
+            (Some(_), uri) => {
+                Translation {
+                    documents: &state.documents,
+                    from: self.encoding,
+                    to: PositionEncoding::Utf16,
+                }
+                .translate(msg, uri.as_deref());
+                if let Some(id) = msg.get("id") {
+                    let id = id.to_string();
+                    state.pending.insert(id, uri);
+                }
+            }
+        }
+    }
+
+    fn on_server(&self, msg: &mut Value) {
+        let mut state = self.state.lock().expect("lock documents");
+        let uri = match (msg.get("method"), msg.get("id")) {
+            (None, Some(id)) => {
+                let id = id.to_string();
+                if state.initialize.as_ref() == Some(&id) {
+                    state.initialize = None;
+                    if let Some(Value::Object(capabilities)) =
+                        msg.pointer_mut("/result/capabilities")
+                    {
+                        capabilities.insert(
+                            "positionEncoding".to_owned(),
+                            Value::from(self.encoding.name()),
+                        );
+                    }
+                    return;
+                }
+                state.pending.remove(&id).flatten()
+            }
+            _ => None,
+        };
+        Translation {
+            documents: &state.documents,
+            from: PositionEncoding::Utf16,
+            to: self.encoding,
+        }
+        .translate(msg, uri.as_deref());
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Translator {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        self.on_client(&mut value);
+        Some(serde_json::from_value(value).unwrap_or(msg))
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        self.on_server(&mut value);
+        Some(serde_json::from_value(value).unwrap_or(msg))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let line = "a😀b";
+        assert_eq!(
+            convert(line, 5, PositionEncoding::Utf8, PositionEncoding::Utf16),
+            3
+        );
+        assert_eq!(
+            convert(line, 3, PositionEncoding::Utf16, PositionEncoding::Utf8),
+            5
+        );
+        assert_eq!(
+            convert(line, 2, PositionEncoding::Utf32, PositionEncoding::Utf16),
+            3
+        );
+        assert_eq!(
+            convert(line, 8, PositionEncoding::Utf8, PositionEncoding::Utf16),
+            6
+        );
+    }
+
+    #[test]
+    fn test_translate_positions() {
+        let translator = Translator {
+            encoding: PositionEncoding::Utf8,
+            state: Default::default(),
+        };
+        let mut open = json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {
+            "textDocument": {"uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "let s = \"😀\";\n"},
+        }});
+        translator.on_client(&mut open);
+
+        // Replace the emoji with "é"
+        let mut change = json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "textDocument": {"uri": "file:///a.rs", "version": 2},
+            "contentChanges": [{
+                "range": {"start": {"line": 0, "character": 9}, "end": {"line": 0, "character": 13}},
+                "rangeLength": 4,
+                "text": "é",
+            }],
+        }});
+        translator.on_client(&mut change);
+        assert_eq!(
+            change["params"]["contentChanges"][0],
+            json!({
+                "range": {"start": {"line": 0, "character": 9}, "end": {"line": 0, "character": 11}},
+                "text": "é",
+            })
+        );
+
+        let mut hover = json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/hover", "params": {
+            "textDocument": {"uri": "file:///a.rs"},
+            "position": {"line": 0, "character": 11},
+        }});
+        translator.on_client(&mut hover);
+        assert_eq!(
+            hover["params"]["position"],
+            json!({"line": 0, "character": 10})
+        );
+
+        let mut response = json!({"jsonrpc": "2.0", "id": 1, "result": {
+            "contents": "&str",
+            "range": {"start": {"line": 0, "character": 8}, "end": {"line": 0, "character": 11}},
+        }});
+        translator.on_server(&mut response);
+        assert_eq!(
+            response["result"]["range"],
+            json!({"start": {"line": 0, "character": 8}, "end": {"line": 0, "character": 12}})
+        );
+
+        // Documents that aren't open are kept as they are.
+        let mut diagnostics = json!({"jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": {
+            "uri": "file:///b.rs",
+            "diagnostics": [{"range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 4}}, "message": ""}],
+        }});
+        let expected = diagnostics.clone();
+        translator.on_server(&mut diagnostics);
+        assert_eq!(diagnostics, expected);
+    }
+}
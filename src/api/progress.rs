@@ -0,0 +1,103 @@
+//! Work done progress reported by the server.
+//!
+//! Progress started by a server that crashes, or by the server of a session the proxy ends, never
+//! ends, and browser UIs keep showing its spinner forever. The tokens from
+//! `window/workDoneProgress/create` and `$/progress` are tracked until their `end`, so the proxy
+//! can end the ones still running itself.
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+/// Progress tokens of the server not ended yet.
+#[derive(Debug, Default)]
+pub(super) struct Progress {
+    // By the serialized token, because tokens can be numbers or strings.
+    tokens: HashMap<String, Value>,
+}
+
+impl Progress {
+    /// Track the progress in `text` from the server.
+    pub(super) fn on_server_text(&mut self, text: &str) {
+        // Most messages aren't about progress, so they're not parsed.
+        if !text.contains("workDoneProgress/create") && !text.contains("$/progress") {
+            return;
+        }
+        if let Ok(msg) = serde_json::from_str::<Value>(text) {
+            self.on_server(&msg);
+        }
+    }
+
+    /// Track the progress in `msg` from the server.
+    pub(super) fn on_server(&mut self, msg: &Value) {
+        let token = match msg.pointer("/params/token") {
+            Some(token) => token,
+            None => return,
+        };
+        match msg.get("method").and_then(Value::as_str) {
+            Some("window/workDoneProgress/create") => {
+                self.tokens.insert(token.to_string(), token.clone());
+            }
+            Some("$/progress") => match msg.pointer("/params/value/kind").and_then(Value::as_str) {
+                Some("begin") | Some("report") => {
+                    self.tokens.insert(token.to_string(), token.clone());
+                }
+                Some("end") => {
+                    self.tokens.remove(&token.to_string());
+                }
+                // Partial results
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Notifications to the client ending the progress still running, forgetting it.
+    pub(super) fn end_all(&mut self) -> Vec<Value> {
+        self.tokens
+            .drain()
+            .map(|(_, token)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "$/progress",
+                    "params": {"token": token, "value": {"kind": "end"}},
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress() {
+        let mut progress = Progress::default();
+        let create = |token: Value| {
+            json!({"jsonrpc": "2.0", "id": 1, "method": "window/workDoneProgress/create", "params": {
+                "token": token,
+            }})
+        };
+        let report = |token: Value, kind: &str| {
+            json!({"jsonrpc": "2.0", "method": "$/progress", "params": {
+                "token": token,
+                "value": {"kind": kind, "title": "Indexing"},
+            }})
+        };
+        progress.on_server_text(&create(json!("indexing")).to_string());
+        progress.on_server(&report(json!("indexing"), "begin"));
+        progress.on_server(&create(json!(7)));
+        progress.on_server(&report(json!(7), "begin"));
+        progress.on_server(&report(json!(7), "end"));
+        progress.on_server_text(r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#);
+
+        assert_eq!(
+            progress.end_all(),
+            vec![json!({"jsonrpc": "2.0", "method": "$/progress", "params": {
+                "token": "indexing",
+                "value": {"kind": "end"},
+            }})]
+        );
+        assert!(progress.end_all().is_empty());
+    }
+}
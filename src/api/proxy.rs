@@ -1,61 +1,642 @@
-use std::{convert::Infallible, process::Stdio, str::FromStr};
+use std::{
+    collections::HashMap,
+    convert::{Infallible, TryFrom},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
 
 use futures_util::{
     future::{select, Either},
-    stream, SinkExt, StreamExt,
+    stream, Sink, SinkExt, Stream, StreamExt,
 };
-use tokio::{fs, process::Command};
+use tokio::fs;
+use tracing::Instrument;
 use url::Url;
-use warp::{Filter, Rejection, Reply};
+use warp::{reply, Filter, Rejection, Reply};
 
-use crate::lsp;
+use crate::{backend, listener, lsp};
 
-use super::with_context;
+use super::{
+    batch, cache, clone, commands, debounce, diagnostics, edits, exit, fallback, file_operations,
+    filter, hook, interactive, limit, line_endings, middleware, multiplex, partial, pending,
+    plugin, pool, positions, progress, quota, rate_limit, restart, resume, root, sandbox, script,
+    settings, shared, shutdown, size, snippets, standby, telemetry, template, watch, webhook,
+    workspace,
+};
+
+/// Language Server to start.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Server {
+    /// Name to select the server with.
+    pub name: String,
+    /// Program and arguments.
+    pub command: Vec<String>,
+    /// Additional environment variables for the process.
+    pub env: HashMap<String, String>,
+    /// Working directory of the process.
+    pub cwd: Option<PathBuf>,
+    /// Start the process in the workspace of the client instead of `cwd` if it's a local directory.
+    pub cwd_from_root: bool,
+    /// Overrides `remap` of the context.
+    pub remap: Option<bool>,
+    /// Overrides `sync` of the context.
+    pub sync: Option<bool>,
+    /// Name of the server to start if this one fails to start or initialize.
+    pub fallback: Option<String>,
+    /// Resource limits of the process.
+    pub limits: backend::Limits,
+    /// Signals to stop the process with instead of killing it.
+    pub stop_signals: backend::StopSignals,
+    /// Merged into `initializationOptions` of `initialize`.
+    pub initialization_options: Option<serde_json::Value>,
+    /// Merge patch applied to `capabilities` of `initialize`.
+    pub capabilities: Option<serde_json::Value>,
+    /// WebAssembly plugins run on the messages of the sessions.
+    pub plugins: Vec<PathBuf>,
+    /// Script run on the messages of the sessions.
+    pub script: Option<PathBuf>,
+    /// Command the messages of the sessions are piped through.
+    pub hook: Option<Vec<String>>,
+    /// Rules transforming the diagnostics published by the server.
+    pub diagnostics: Option<diagnostics::DiagnosticRules>,
+    /// Settings to answer `workspace/configuration` of the server with.
+    pub settings: Option<serde_json::Value>,
+    /// Commands the client can execute, ending with `*` to match the prefix. All if `None`.
+    pub commands: Option<Vec<String>>,
+}
+
+impl From<Vec<String>> for Server {
+    /// Server named after the program.
+    fn from(command: Vec<String>) -> Self {
+        Self {
+            name: command[0].clone(),
+            command,
+            ..Self::default()
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Context {
-    /// One or more commands to start a Language Server.
-    pub commands: Vec<Vec<String>>,
+    /// One or more Language Servers to start.
+    pub servers: Vec<Server>,
+    /// Connect to a running Language Server instead of starting one.
+    pub connect: Option<backend::Remote>,
+    /// Start the Language Server in a Docker container.
+    pub docker: Option<backend::Docker>,
+    /// Multiplex the servers for these languages when no server is selected.
+    pub languages: Vec<multiplex::Language>,
+    /// Multiplex the servers for these file extensions when no server is selected.
+    pub extensions: Vec<multiplex::Extension>,
+    /// Servers started ahead of connections to the default server.
+    pub pool: Option<pool::Pool>,
+    /// Share servers between connections.
+    pub shared: Option<shared::Hubs>,
+    /// Limit the number of concurrent sessions.
+    pub sessions: Option<limit::SessionLimit>,
+    /// Limit the number of server processes.
+    pub processes: Option<limit::ProcessLimit>,
+    /// Keep sessions after the client disconnects so it can resume them.
+    pub resume: Option<resume::Sessions>,
+    /// Token of the session the connection resumes.
+    pub session_token: Option<String>,
+    /// Close sessions without messages from the client for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Stop the servers gracefully when the proxy is stopped.
+    pub shutdown: shutdown::Shutdown,
+    /// Restart servers that crash during the session.
+    pub restart: bool,
+    /// Crashes in a row before giving up restarting.
+    pub max_restarts: u32,
+    /// Check the restarted servers respond this often.
+    pub probe_interval: Option<Duration>,
+    /// Keep a started server ready to replace the crashed ones.
+    pub standby: Option<standby::Standby>,
+    /// Close sessions after this long, warning the user before.
+    pub max_session_duration: Option<Duration>,
+    /// Answer requests the server doesn't respond to within this long with an error.
+    pub request_timeout: Option<Duration>,
+    /// Buffer changes to documents for this long, merging them.
+    pub coalesce_changes: Option<Duration>,
+    /// Convert snippet completions to plain text for clients without snippet support.
+    pub strip_snippets: bool,
+    /// Answer the interactive requests of the servers the clients don't support.
+    pub answer_unsupported: bool,
+    /// Action to answer `window/showMessageRequest` with when the client doesn't support it.
+    pub message_action: interactive::MessageAction,
+    /// Normalize the line endings of the documents to LF for the servers.
+    pub normalize_line_endings: bool,
+    /// Translate positions between this encoding of the clients and UTF-16 of the servers.
+    pub position_encoding: Option<positions::PositionEncoding>,
+    /// Maximum sizes of the messages in each direction.
+    pub size_limits: size::SizeLimits,
+    /// Stream array results over this many bytes as partial results when the client asks for them.
+    pub partial_results: Option<usize>,
+    /// Ping WebSocket clients this often, or never if `None`.
+    pub ping_interval: Option<Duration>,
+    /// Close WebSocket connections without a pong for this long after a ping.
+    pub ping_timeout: Duration,
     /// Write file on save.
     pub sync: bool,
+    /// With `sync`, apply `workspace/applyEdit` to the files of the documents the client hasn't opened.
+    pub apply_edits: bool,
+    /// Files removed and renamed with `POST /files` to notify the servers of.
+    pub file_operations: file_operations::FileOperations,
+    /// Watch the workspace for the files the servers registered watchers for.
+    pub watch: bool,
+    /// The workspace is in memory. The servers without a `cwd` are started in it, and documents
+    /// are only written on save inside it.
+    pub memory: bool,
+    /// Quotas of the files written on save in the workspace.
+    pub quota: quota::Quota,
+    /// Directories the workspaces must be in to write documents on save and apply edits.
+    pub sync_roots: sandbox::Roots,
+    /// Repository to clone into the workspace of the session before starting the server.
+    pub repo: Option<clone::Repo>,
+    /// Prefixes of the repositories the clients can clone with `?repo=`.
+    pub allowed_repos: Vec<String>,
+    /// Directory to clone the repositories of the sessions into.
+    pub clone_dir: PathBuf,
+    /// Clone only this many commits, or the whole history.
+    pub clone_depth: Option<u32>,
+    /// Start each session in a new workspace.
+    pub isolate: bool,
+    /// Directory to create the workspaces of the sessions in.
+    pub workspace_dir: PathBuf,
+    /// Workspaces of the sessions, for `/files`.
+    pub workspaces: workspace::Workspaces,
+    /// ID of the session with its own workspace, sent to the client.
+    pub workspace_session: Option<String>,
     /// Remap relative `source://` to absolute `file://`.
     pub remap: bool,
+    /// Rules to remap URIs with, starting with `source://` relative to `cwd`.
+    pub remap_rules: lsp::ext::RemapRules,
+    /// With `remap`, also remap URIs in any field of the messages.
+    pub deep_remap: bool,
+    /// Rewrite the absolute paths in the workspace to relative ones in the messages from the server.
+    pub redact_paths: bool,
+    /// Merged into `initializationOptions` of `initialize`, from the server.
+    pub initialization_options: Option<serde_json::Value>,
+    /// Merge patch applied to `capabilities` of `initialize`, from the server.
+    pub capabilities: Option<serde_json::Value>,
+    /// Middlewares intercepting the messages of each session.
+    pub middlewares: middleware::Middlewares,
+    /// Loaded plugins of the servers, added to the middlewares of their sessions.
+    pub plugins: plugin::Plugins,
+    /// Compiled scripts of the servers, added to the middlewares after the plugins.
+    pub scripts: script::Scripts,
+    /// Hook processes of the servers, added to the middlewares after the scripts.
+    pub hooks: hook::Hooks,
+    /// Methods to drop or allow in each direction.
+    pub filters: filter::Filters,
+    /// Webhooks posted the messages with selected methods, added to the middlewares last.
+    pub webhooks: webhook::Webhooks,
+    /// Send `telemetry/event` of the servers here instead of to the clients.
+    pub telemetry: Option<telemetry::Telemetry>,
+    /// Rate limits of the requests from the client of each session.
+    pub rate_limits: Vec<rate_limit::RateLimit>,
+    /// Methods of the idempotent requests to answer from the cache of each session.
+    pub cache: Vec<String>,
+    /// Negotiate permessage-deflate compression.
+    pub compression: bool,
+    /// Header of the upgrade request selecting the server, e.g. `X-LSP-Server`.
+    pub server_header: Option<String>,
+    /// Query parameters allowed in the commands.
+    pub params: Vec<template::Param>,
+    /// Values of the allowed query parameters of the connection.
+    pub param_values: HashMap<String, String>,
+    /// Subject of the client certificate of the connection with `--tls-client-ca`.
+    pub client_subject: Option<String>,
     /// Project root.
     pub cwd: Url,
 }
 
+impl Context {
+    /// Whether the server selected by `query` is registered.
+    /// Any name is accepted when connecting to a running server.
+    pub(super) fn accepts(&self, query: &Query) -> bool {
+        self.servers.is_empty() || self.servers.iter().any(|s| s.name == query.name)
+    }
+
+    /// Context for the session `id` with the server selected by `query`, with the options of the
+    /// server.
+    pub(super) fn for_query(&self, query: Option<&Query>, id: &str) -> Self {
+        let mut ctx = self.clone();
+        partial::apply(self.partial_results, &mut ctx.middlewares);
+        cache::apply(&self.cache, &mut ctx.middlewares);
+        snippets::apply(self.strip_snippets, &mut ctx.middlewares);
+        interactive::apply(
+            self.answer_unsupported,
+            self.message_action,
+            &mut ctx.middlewares,
+        );
+        if let Ok(server) = select_server(&self.servers, query) {
+            ctx.remap = server.remap.unwrap_or(self.remap);
+            ctx.sync = server.sync.unwrap_or(self.sync);
+            ctx.initialization_options = server.initialization_options.clone();
+            ctx.capabilities = server.capabilities.clone();
+            self.plugins.apply(server, &mut ctx.middlewares);
+            self.scripts.apply(server, &mut ctx.middlewares);
+            self.hooks.apply(server, &mut ctx.middlewares);
+            diagnostics::apply(server.diagnostics.as_ref(), &mut ctx.middlewares);
+            settings::apply(server.settings.as_ref(), &mut ctx.middlewares);
+            commands::apply(server.commands.as_ref(), &mut ctx.middlewares);
+        }
+        self.webhooks.apply(&mut ctx.middlewares);
+        if let Some(telemetry) = &self.telemetry {
+            let server = match select_server(&self.servers, query) {
+                Ok(server) => server.name.as_str(),
+                // Connected to a running server
+                Err(_) => query.map_or("", |query| query.name.as_str()),
+            };
+            telemetry.apply(id, server, &mut ctx.middlewares);
+        }
+        rate_limit::apply(&self.rate_limits, &mut ctx.middlewares);
+        // Last, so the messages the others send to the client, like cached responses, are
+        // already translated.
+        positions::apply(self.position_encoding, &mut ctx.middlewares);
+        line_endings::apply(self.normalize_line_endings, &mut ctx.middlewares);
+        ctx
+    }
+}
+
+/// Context that can be replaced while running, e.g. when the config is reloaded.
+/// Connections keep the context from when they started.
+#[derive(Debug, Clone)]
+pub struct SharedContext(Arc<RwLock<Shared>>);
+
+#[derive(Debug)]
+struct Shared {
+    ctx: Context,
+    /// Servers registered (`Some`) and unregistered (`None`) at runtime by name, in order, kept
+    /// when the servers are reloaded.
+    registered: Vec<(String, Option<Server>)>,
+}
+
+impl SharedContext {
+    pub fn new(ctx: Context) -> Self {
+        Self(Arc::new(RwLock::new(Shared {
+            ctx,
+            registered: Vec::new(),
+        })))
+    }
+
+    /// The current context.
+    pub fn get(&self) -> Context {
+        self.0.read().expect("lock context").ctx.clone()
+    }
+
+    /// Replace the context for new connections.
+    pub fn set(&self, ctx: Context) {
+        self.0.write().expect("lock context").ctx = ctx;
+    }
+
+    /// Change the context for new connections with `f` under the lock, so concurrent changes
+    /// aren't lost.
+    pub fn update<T>(&self, f: impl FnOnce(&mut Context) -> T) -> T {
+        f(&mut self.0.write().expect("lock context").ctx)
+    }
+
+    /// Register `server` at runtime, replacing the one with the same name. Returns whether it
+    /// replaced one.
+    pub(super) fn register(&self, server: Server) -> bool {
+        let mut shared = self.0.write().expect("lock context");
+        shared.registered.retain(|(name, _)| *name != server.name);
+        shared
+            .registered
+            .push((server.name.clone(), Some(server.clone())));
+        upsert_server(&mut shared.ctx.servers, server)
+    }
+
+    /// Unregister the server `name` at runtime. Returns whether it was registered.
+    pub(super) fn unregister(&self, name: &str) -> bool {
+        let mut shared = self.0.write().expect("lock context");
+        let before = shared.ctx.servers.len();
+        shared.ctx.servers.retain(|s| s.name != name);
+        if shared.ctx.servers.len() == before {
+            return false;
+        }
+        shared
+            .registered
+            .retain(|(registered, _)| registered != name);
+        shared.registered.push((name.to_owned(), None));
+        true
+    }
+
+    /// Replace the servers with the configured `servers`, keeping the changes made at runtime,
+    /// and change the rest of the context with `f`.
+    pub fn reload(&self, mut servers: Vec<Server>, f: impl FnOnce(&mut Context)) {
+        let mut shared = self.0.write().expect("lock context");
+        let shared = &mut *shared;
+        for (name, server) in &shared.registered {
+            match server {
+                Some(server) => {
+                    upsert_server(&mut servers, server.clone());
+                }
+                None => servers.retain(|s| s.name != *name),
+            }
+        }
+        f(&mut shared.ctx);
+        shared.ctx.servers = servers;
+    }
+}
+
+/// Replace the server with the name of `server` in `servers`, or add it. Returns whether it
+/// replaced one.
+fn upsert_server(servers: &mut Vec<Server>, server: Server) -> bool {
+    match servers.iter_mut().find(|s| s.name == server.name) {
+        Some(existing) => {
+            *existing = server;
+            true
+        }
+        None => {
+            servers.push(server);
+            false
+        }
+    }
+}
+
+pub(super) fn with_shared_context(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Context,), Error = Infallible> + Clone {
+    warp::any().map(move || ctx.get())
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
-struct Query {
+pub(super) struct Query {
     /// The command name of the Language Server to start.
     /// If not specified, the first one is started.
-    name: String,
+    #[serde(alias = "server")]
+    pub(super) name: String,
 }
 
+/// Rejection for a query selecting a server that is not registered.
+#[derive(Debug)]
+pub(super) struct UnknownServer(pub(super) String);
+
+impl warp::reject::Reject for UnknownServer {}
+
 fn with_optional_query() -> impl Filter<Extract = (Option<Query>,), Error = Infallible> + Clone {
     warp::query::<Query>()
         .map(Some)
         .or_else(|_| async { Ok::<(Option<Query>,), Infallible>((None,)) })
 }
 
-/// Handler for WebSocket connection.
-pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path::end()
-        .and(warp::ws())
-        .and(with_context(ctx))
-        .and(with_optional_query())
-        .map(|ws: warp::ws::Ws, ctx, query| {
-            ws.with_compression()
-                .on_upgrade(move |socket| on_upgrade(socket, ctx, query))
+/// Optional query, rejected with `UnknownServer` unless the server is registered
+/// so that clients can't start arbitrary commands.
+pub(super) fn with_valid_query(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Option<Query>,), Error = Rejection> + Clone {
+    with_optional_query()
+        .and(with_shared_context(ctx))
+        .and_then(|query: Option<Query>, ctx: Context| async move {
+            match query {
+                Some(query) if !ctx.accepts(&query) => {
+                    Err(warp::reject::custom(UnknownServer(query.name)))
+                }
+                query => Ok(query),
+            }
+        })
+}
+
+/// Prefix of the subprotocols selecting the server, e.g. `lsp.rust-analyzer`.
+const SERVER_PROTOCOL_PREFIX: &str = "lsp.";
+
+// Server selected with the subprotocol `lsp.<name>` offered by the client.
+fn protocol_query(protocols: Option<&str>) -> Option<Query> {
+    protocols?
+        .split(',')
+        .filter_map(|protocol| protocol.trim().strip_prefix(SERVER_PROTOCOL_PREFIX))
+        .find(|name| !name.is_empty())
+        .map(|name| Query {
+            name: name.to_owned(),
+        })
+}
+
+/// Server selected with the subprotocol, rejected with `UnknownServer` unless registered.
+fn with_protocol_query(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Option<Query>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("sec-websocket-protocol")
+        .and(with_shared_context(ctx))
+        .and_then(|protocols: Option<String>, ctx: Context| async move {
+            match protocol_query(protocols.as_deref()) {
+                Some(query) if !ctx.accepts(&query) => {
+                    Err(warp::reject::custom(UnknownServer(query.name)))
+                }
+                query => Ok(query),
+            }
+        })
+}
+
+/// Server selected with the header `ctx.server_header`, rejected with `UnknownServer`
+/// unless registered.
+fn with_header_query(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Option<Query>,), Error = Rejection> + Clone {
+    warp::header::headers_cloned()
+        .and(with_shared_context(ctx))
+        .and_then(|headers: warp::http::HeaderMap, ctx: Context| async move {
+            let name = ctx
+                .server_header
+                .as_deref()
+                .and_then(|header| headers.get(header))
+                .and_then(|value| value.to_str().ok())
+                .filter(|name| !name.is_empty());
+            match name {
+                Some(name) => {
+                    let query = Query {
+                        name: name.to_owned(),
+                    };
+                    if ctx.accepts(&query) {
+                        Ok(Some(query))
+                    } else {
+                        Err(warp::reject::custom(UnknownServer(query.name)))
+                    }
+                }
+                None => Ok(None),
+            }
         })
 }
 
-#[tracing::instrument(level = "debug", err, skip(msg))]
-async fn maybe_write_text_document(msg: &lsp::Message) -> Result<(), std::io::Error> {
+/// Encoding of messages exchanged with the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    /// JSON in text frames.
+    Json,
+    /// MessagePack in binary frames. Negotiated with the `msgpack` subprotocol.
+    MessagePack,
+}
+
+impl Encoding {
+    const MSGPACK_PROTOCOL: &'static str = "msgpack";
+
+    /// Choose the encoding from the subprotocols offered by the client.
+    fn negotiate(protocols: Option<&str>) -> Self {
+        let offered = protocols
+            .into_iter()
+            .flat_map(|protocols| protocols.split(','))
+            .any(|protocol| protocol.trim() == Self::MSGPACK_PROTOCOL);
+        if offered {
+            Self::MessagePack
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Path to start the named server on.
+///
+/// ```text
+/// /rust=rust-analyzer
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub path: String,
+    pub name: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((path, name)) if !path.is_empty() && !name.is_empty() => Ok(Self {
+                path: path.to_owned(),
+                name: name.to_owned(),
+            }),
+            _ => Err(format!("expected <path>=<name>, got {}", s)),
+        }
+    }
+}
+
+/// Handler for WebSocket connection on any of `paths`, and on the path of each route.
+pub fn handler(
+    ctx: SharedContext,
+    paths: &[String],
+    routes: &[Route],
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    // Routes take precedence over the query parameter,
+    // then the header and the subprotocol.
+    let query = routes
+        .iter()
+        .map(|route| {
+            let query = Query {
+                name: route.name.clone(),
+            };
+            super::path_prefix(&route.path)
+                .and(warp::path::end())
+                .map(move || Some(query.clone()))
+                .boxed()
+        })
+        .fold(
+            super::any_path(paths)
+                .and(with_valid_query(ctx.clone()))
+                .boxed(),
+            |filter, route| route.or(filter).unify().boxed(),
+        );
+    let query = query
+        .and(with_header_query(ctx.clone()))
+        .map(|query: Option<Query>, header: Option<Query>| query.or(header));
+    query
+        .and(with_protocol_query(ctx.clone()))
+        .and(warp::ws())
+        .and(
+            with_shared_context(ctx.clone())
+                .and(template::with_params(ctx.clone()))
+                .and(resume::with_token(ctx.clone()))
+                .and(clone::with_repo(ctx.clone()))
+                .and(warp::ext::optional::<listener::ClientSubject>())
+                .map(
+                    |mut ctx: Context,
+                     values,
+                     token,
+                     repo: Option<clone::Repo>,
+                     subject: Option<listener::ClientSubject>| {
+                        ctx.param_values = values;
+                        ctx.session_token = token;
+                        if repo.is_some() {
+                            ctx.repo = repo;
+                        }
+                        ctx.client_subject = subject.map(|subject| subject.0);
+                        ctx
+                    },
+                ),
+        )
+        .and(shutdown::with_accepting(ctx.clone()))
+        .and(limit::with_session(ctx))
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .map(
+            |query: Option<Query>,
+             protocol_query: Option<Query>,
+             ws: warp::ws::Ws,
+             ctx: Context,
+             session,
+             protocols: Option<String>| {
+                // permessage-deflate is only used if the client offers it in the handshake.
+                let ws = if ctx.compression {
+                    ws.with_compression()
+                } else {
+                    ws
+                };
+                let encoding = Encoding::negotiate(protocols.as_deref());
+                // Only one subprotocol can be accepted, and the client needs to know the encoding.
+                let accepted = match (encoding, &query, &protocol_query) {
+                    (Encoding::MessagePack, _, _) => Some(Encoding::MSGPACK_PROTOCOL.to_owned()),
+                    (Encoding::Json, None, Some(query)) => {
+                        Some(format!("{}{}", SERVER_PROTOCOL_PREFIX, query.name))
+                    }
+                    (Encoding::Json, _, _) => None,
+                };
+                let query = query.or(protocol_query);
+                let id = new_session_id();
+                let span = session_span(&id);
+                let session_id = id.clone();
+                let reply = ws.on_upgrade(move |socket| {
+                    on_upgrade(socket, ctx, query, encoding, session, session_id).instrument(span)
+                });
+                let reply = reply::with_header(reply, SESSION_ID_HEADER, id);
+                match accepted {
+                    Some(protocol) => reply::with_header(reply, "sec-websocket-protocol", protocol)
+                        .into_response(),
+                    None => reply.into_response(),
+                }
+            },
+        )
+}
+
+/// Write the text of the document saved with `msg`, returning the message to the client if it's
+/// over the quota of the workspace.
+#[tracing::instrument(level = "debug", err, skip(msg, ctx))]
+async fn maybe_write_text_document(
+    msg: &lsp::Message,
+    ctx: &Context,
+) -> Result<Option<String>, std::io::Error> {
     if let lsp::Message::Notification(lsp::Notification::DidSave { params }) = msg {
         if let Some(text) = &params.text {
             let uri = &params.text_document.uri;
             if uri.scheme() == "file" {
                 if let Ok(path) = uri.to_file_path() {
+                    let root = ctx.cwd.to_file_path().ok();
+                    // Only in the workspace of the session if it's in memory or in `sync_roots`.
+                    if ctx.memory || ctx.sync_roots.is_set() {
+                        let allowed = root.as_deref().map_or(false, |root| {
+                            ctx.sync_roots.allows(root) && sandbox::resolve(root, &path).is_some()
+                        });
+                        if !allowed {
+                            tracing::warn!("not writing {:?} outside of the workspace", path);
+                            return Ok(None);
+                        }
+                    }
+                    if let Some(root) = root {
+                        let files = vec![(path.clone(), text.len() as u64)];
+                        if let Err(err) = ctx.quota.check_blocking(root, files).await {
+                            tracing::warn!("not writing {:?}: {}", path, err);
+                            return Ok(Some(quota::show_message(&path, &err)));
+                        }
+                    }
                     if let Some(parent) = path.parent() {
                         tracing::debug!("writing to {:?}", path);
                         fs::create_dir_all(parent).await?;
@@ -65,14 +646,35 @@ async fn maybe_write_text_document(msg: &lsp::Message) -> Result<(), std::io::Er
             }
         }
     }
-    Ok(())
+    Ok(None)
 }
 
-async fn on_upgrade(socket: warp::ws::WebSocket, ctx: Context, query: Option<Query>) {
+/// Header of the upgrade response with the ID of the session in the logs.
+const SESSION_ID_HEADER: &str = "x-session-id";
+
+/// ID of a session, generated when the client connects.
+pub(super) fn new_session_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Span of everything logged for the session.
+pub(super) fn session_span(id: &str) -> tracing::Span {
+    tracing::info_span!("session", id = %id)
+}
+
+async fn on_upgrade(
+    socket: warp::ws::WebSocket,
+    ctx: Context,
+    query: Option<Query>,
+    encoding: Encoding,
+    session: limit::Session,
+    id: String,
+) {
     tracing::info!("connected");
-    if let Err(err) = connected(socket, ctx, query).await {
+    if let Err(err) = connected(socket, ctx, query, encoding, &id).await {
         tracing::error!("connection error: {}", err);
     }
+    drop(session);
     tracing::info!("disconnected");
 }
 
@@ -81,76 +683,338 @@ async fn connected(
     ws: warp::ws::WebSocket,
     ctx: Context,
     query: Option<Query>,
+    encoding: Encoding,
+    id: &str,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let command = if let Some(query) = query {
-        if let Some(command) = ctx.commands.iter().find(|v| v[0] == query.name) {
-            command
-        } else {
-            // TODO Validate this earlier and reject, or close immediately.
-            tracing::warn!(
-                "Unknown Language Server '{}', falling back to the default",
-                query.name
-            );
-            &ctx.commands[0]
-        }
-    } else {
-        &ctx.commands[0]
-    };
-    tracing::info!("starting {} in {}", command[0], ctx.cwd);
-    let mut server = Command::new(&command[0])
-        .args(&command[1..])
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .kill_on_drop(true)
-        .spawn()?;
-    tracing::debug!("running {}", command[0]);
-
-    let mut server_send = lsp::framed::writer(server.stdin.take().unwrap());
-    let mut server_recv = lsp::framed::reader(server.stdout.take().unwrap());
-    let (mut client_send, client_recv) = ws.split();
+    let ctx = ctx.for_query(query.as_ref(), id);
+    let (ctx, _workspace) = workspace::prepare(ctx, query.as_ref(), id).await?;
+    let server = connect_server(&ctx, query.as_ref()).await?;
+    let (client_send, client_recv) = ws.split();
+    let batches = batch::Batches::default();
+    let sent_batches = batches.clone();
+    let client_send = client_send.with_flat_map(move |msg: Outgoing| {
+        let msgs: Vec<Outgoing> = match msg {
+            Outgoing::Text(text) => sent_batches
+                .on_send(text)
+                .into_iter()
+                .map(Outgoing::Text)
+                .collect(),
+            Outgoing::Ping => vec![msg],
+            // Closing, after the responses held for the batches not answered yet.
+            _ => {
+                let mut msgs = sent_batches
+                    .flush()
+                    .into_iter()
+                    .map(Outgoing::Text)
+                    .collect::<Vec<_>>();
+                msgs.push(msg);
+                msgs
+            }
+        };
+        stream::iter(
+            msgs.into_iter()
+                .map(move |msg| Ok::<_, warp::Error>(to_warp_ws_message(msg, encoding))),
+        )
+    });
     let client_recv = client_recv
-        .filter_map(filter_map_warp_ws_message)
+        .flat_map(move |wsm| stream::iter(from_warp_ws_message(wsm, encoding, &batches)))
         // Chain this with `Done` so we know when the client disconnects
         .chain(stream::once(async { Ok(Message::Done) }));
-    // Tick every 30s so we can ping the client to keep the connection alive
-    let ticks = stream::unfold(
-        tokio::time::interval(std::time::Duration::from_secs(30)),
-        |mut interval| async move {
-            interval.tick().await;
-            Some((Ok(Message::Tick), interval))
-        },
-    );
-    let mut client_recv = stream::select(client_recv, ticks).boxed();
+    // Tick so we can ping the client to keep the connection alive,
+    // and notice when the pong doesn't arrive in time.
+    let period = ctx
+        .ping_interval
+        .map(|interval| interval.min(ctx.ping_timeout));
+    let ticks = stream::iter(period).flat_map(|period| {
+        stream::unfold(tokio::time::interval(period), |mut interval| async move {
+            let tick = interval.tick().await;
+            Some((Ok(Message::Tick(tick)), interval))
+        })
+    });
+    let client_recv = stream::select(client_recv, ticks).boxed();
+    run(&ctx, server, client_recv, client_send).await
+}
+
+/// Start or connect to the Language Server for the session, or resume the session.
+pub(super) async fn connect_server(
+    ctx: &Context,
+    query: Option<&Query>,
+) -> Result<backend::Connection, std::io::Error> {
+    match (&ctx.resume, &ctx.session_token) {
+        (Some(sessions), Some(token)) if sessions.is_saved(token) => {
+            tracing::info!("restoring session {}", token);
+            sessions
+                .restore(token, connect_backend(ctx, query).await?)
+                .await
+        }
+        (Some(sessions), Some(token)) => {
+            tracing::info!("resuming session {}", token);
+            sessions.resume(token)
+        }
+        (Some(sessions), None) => Ok(sessions.start(connect_backend(ctx, query).await?)),
+        (None, _) => connect_backend(ctx, query).await,
+    }
+}
+
+async fn connect_backend(
+    ctx: &Context,
+    query: Option<&Query>,
+) -> Result<backend::Connection, std::io::Error> {
+    match &ctx.connect {
+        Some(backend::Remote::Ssh(ssh)) => {
+            let server = select_server(&ctx.servers, query)?;
+            let command = template::expand(&server.command, &ctx.params, &ctx.param_values)?;
+            tracing::info!("starting {} on {}", server.name, ssh);
+            limit::spawn_process(ctx.processes.as_ref(), || {
+                backend::Connection::spawn(&ssh.command(&command))
+            })
+        }
+
+        Some(remote) => {
+            tracing::info!("connecting to {}", remote);
+            backend::Connection::connect(remote).await
+        }
+
+        None if query.is_none() && !(ctx.languages.is_empty() && ctx.extensions.is_empty()) => {
+            tracing::info!("starting multiplexed servers");
+            multiplex::connect(ctx)
+        }
+
+        None if ctx.shared.is_some() => {
+            let hubs = ctx.shared.as_ref().expect("shared");
+            shared::connect(hubs, ctx, query)
+        }
+
+        None => {
+            if let Some(conn) = ctx
+                .pool
+                .as_ref()
+                .filter(|_| query.is_none())
+                .and_then(|pool| pool.take())
+            {
+                tracing::info!("using pooled server");
+                return Ok(conn);
+            }
+            let server = select_server(&ctx.servers, query).ok();
+            if server.map_or(false, |server| server.cwd_from_root) {
+                return root::connect(ctx, query);
+            }
+            match server.and_then(|server| server.fallback.as_deref()) {
+                Some(fallback) => fallback::connect(ctx, query, fallback),
+                None if ctx.restart => restart::connect(ctx, query),
+                None => spawn_server(ctx, query),
+            }
+        }
+    }
+}
+
+/// Time before the end of a session to warn the user.
+const SESSION_END_WARNING: Duration = Duration::from_secs(60);
+
+/// How often to check for requests over `--request-timeout`.
+const REQUEST_TIMEOUT_CHECK: Duration = Duration::from_secs(1);
+
+/// Proxy messages between the client and the server until either of them disconnects.
+///
+/// `client_recv` must yield [`Message::Done`] when the client disconnects.
+pub(super) async fn run<S, E, K>(
+    ctx: &Context,
+    server: backend::Connection,
+    client_recv: S,
+    mut client_send: K,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: Stream<Item = Result<Message, E>> + Unpin,
+    E: std::fmt::Display,
+    K: Sink<Outgoing> + Unpin,
+    K::Error: std::error::Error + Send + Sync + 'static,
+{
+    let _guard = ctx.shutdown.track();
+    let backend::Connection {
+        reader,
+        writer,
+        mut child,
+    } = server;
+    let mut server_send = lsp::framed::writer(writer);
+    let mut server_recv = lsp::framed::reader(reader);
+
+    // Check for idle sessions twice within the timeout.
+    let idle_checks = stream::iter(ctx.idle_timeout).flat_map(|timeout| {
+        stream::unfold(
+            tokio::time::interval(timeout / 2),
+            |mut interval| async move {
+                interval.tick().await;
+                Some((Ok(Message::IdleCheck), interval))
+            },
+        )
+    });
+    let client_recv = stream::select(client_recv, Box::pin(idle_checks));
+    // Warn the user before the session reaches `--max-session-duration`.
+    let session_end = stream::iter(ctx.max_session_duration).flat_map(|duration| {
+        let warning = SESSION_END_WARNING.min(duration / 2);
+        let started = tokio::time::Instant::now();
+        stream::once(tokio::time::sleep_until(started + duration - warning))
+            .map(move |_| Ok(Message::SessionEnding(warning)))
+            .chain(
+                stream::once(tokio::time::sleep_until(started + duration))
+                    .map(|_| Ok(Message::SessionEnded)),
+            )
+    });
+    let client_recv = stream::select(client_recv, Box::pin(session_end));
+    let timeout_checks = stream::iter(ctx.request_timeout).flat_map(|timeout| {
+        stream::unfold(
+            tokio::time::interval(REQUEST_TIMEOUT_CHECK.min(timeout)),
+            |mut interval| async move {
+                interval.tick().await;
+                Some((Ok(Message::TimeoutCheck), interval))
+            },
+        )
+    });
+    let client_recv = stream::select(client_recv, Box::pin(timeout_checks));
+    // Check for buffered changes twice within the delay.
+    let change_flushes = stream::iter(ctx.coalesce_changes).flat_map(|delay| {
+        stream::unfold(
+            tokio::time::interval(delay / 2),
+            |mut interval| async move {
+                interval.tick().await;
+                Some((Ok(Message::FlushChanges), interval))
+            },
+        )
+    });
+    let client_recv = stream::select(client_recv, Box::pin(change_flushes));
+    let shutdown = ctx.shutdown.signaled().map(|_| Ok(Message::Shutdown));
+    let client_recv = stream::select(client_recv, shutdown);
+    let file_events = ctx
+        .file_operations
+        .events(&ctx.cwd.to_file_path().unwrap_or_default())
+        .map(|event| Ok(Message::FileOperation(event)));
+    let client_recv = stream::select(client_recv, file_events);
+    // Files changed on the disk, watched while `_watcher` is alive.
+    let watch_root = ctx.cwd.to_file_path().ok().filter(|_| ctx.watch);
+    let (_watcher, changes) = match watch_root.as_deref().map(watch::watch) {
+        Some(Ok((watcher, changes))) => (Some(watcher), Some(changes)),
+        Some(Err(err)) => {
+            tracing::warn!("failed to watch the workspace: {}", err);
+            (None, None)
+        }
+        None => (None, None),
+    };
+    let changes = stream::iter(changes)
+        .flatten()
+        .map(|changes| Ok(Message::FilesChanged(changes)));
+    let mut client_recv = stream::select(client_recv, changes);
+    let mut last_seen = Instant::now();
 
     let mut client_msg = client_recv.next();
     let mut server_msg = server_recv.next();
-    // Keeps track if `pong` was received since sending the last `ping`.
-    let mut is_alive = true;
+    // When the last `ping` was sent, and if it's waiting for `pong`.
+    let mut last_ping: Option<tokio::time::Instant> = None;
+    let mut awaiting_pong = false;
+    let mut pending = pending::Pending::new(ctx.request_timeout);
+    let mut debouncer = ctx.coalesce_changes.map(debounce::Debouncer::new);
+    // Progress to end if the session ends before the server ends it.
+    let mut progress = progress::Progress::default();
+    // Documents open by the client, and the workspace to apply the other edits to.
+    let mut documents = edits::OpenDocuments::default();
+    let edits_root = ctx
+        .cwd
+        .to_file_path()
+        .ok()
+        .filter(|root| ctx.sync && ctx.apply_edits && ctx.sync_roots.allows(root));
+    // File operations the server supports, and its pending `workspace/willRenameFiles`.
+    let mut file_operations = file_operations::Session::default();
+    // Watchers registered by the server.
+    let mut watchers = watch::Watchers::default();
+    if let Some(id) = &ctx.workspace_session {
+        client_send
+            .send(Outgoing::Text(workspace::notification(id)))
+            .await?;
+    }
 
     loop {
         match select(client_msg, server_msg).await {
             // From Client
             Either::Left((from_client, p_server_msg)) => {
                 match from_client {
+                    // Over `--max-client-message`
+                    Some(Ok(Message::Message(msg))) if ctx.size_limits.client_exceeds(&msg) => {
+                        last_seen = Instant::now();
+                        let text = serde_json::to_string(&msg)?;
+                        tracing::warn!("dropped message of {} bytes over the limit ->", text.len());
+                        if let Some(response) = size::rejection(&text, ctx.size_limits.client) {
+                            client_send.send(Outgoing::Text(response)).await?;
+                        }
+                    }
+
                     // Valid LSP message
-                    Some(Ok(Message::Message(mut msg))) => {
-                        if ctx.remap {
-                            lsp::ext::remap_relative_uri(&mut msg, &ctx.cwd)?;
-                            tracing::debug!("remapped relative URI from client");
+                    Some(Ok(Message::Message(msg))) => {
+                        last_seen = Instant::now();
+                        let (msg, outbox) = ctx.middlewares.on_client_message(msg).await;
+                        for msg in outbox.to_client {
+                            let text = serde_json::to_string(&msg)?;
+                            client_send.send(Outgoing::Text(text)).await?;
                         }
-                        if ctx.sync {
-                            maybe_write_text_document(&msg).await?;
+                        for msg in outbox.to_server {
+                            server_send.send(serde_json::to_string(&msg)?).await?;
                         }
-                        let text = serde_json::to_string(&msg)?;
-                        tracing::debug!("-> {}", text);
-                        server_send.send(text).await?;
+                        if let Some(mut msg) = msg {
+                            if ctx.remap {
+                                lsp::ext::remap_relative_uri(&mut msg, &ctx.remap_rules)?;
+                                tracing::debug!("remapped relative URI from client");
+                            }
+                            if let Some(options) = &ctx.initialization_options {
+                                lsp::ext::merge_initialization_options(&mut msg, options);
+                            }
+                            let text = client_text(&msg, &ctx)?;
+                            if ctx.filters.drops(filter::Direction::Client, &text) {
+                                tracing::debug!("dropped -> {}", text);
+                                if let Some(response) = filter::rejection(&text) {
+                                    client_send.send(Outgoing::Text(response)).await?;
+                                }
+                            } else {
+                                if ctx.sync {
+                                    if let Some(text) =
+                                        maybe_write_text_document(&msg, &ctx).await?
+                                    {
+                                        client_send.send(Outgoing::Text(text)).await?;
+                                    }
+                                    documents.on_client(&msg);
+                                }
+                                tracing::debug!("-> {}", text);
+                                pending.sent(&text, Instant::now());
+                                match &mut debouncer {
+                                    Some(debouncer) => {
+                                        for text in debouncer.push(text, Instant::now()) {
+                                            server_send.send(text).await?;
+                                        }
+                                    }
+                                    None => server_send.send(text).await?,
+                                }
+                            }
+                        }
+                    }
+
+                    // Invalid JSON body over the limit
+                    Some(Ok(Message::Invalid(text)))
+                        if ctx.size_limits.client_text_exceeds(&text) =>
+                    {
+                        last_seen = Instant::now();
+                        tracing::warn!(
+                            "dropped invalid message of {} bytes over the limit ->",
+                            text.len()
+                        );
                     }
 
                     // Invalid JSON body
                     Some(Ok(Message::Invalid(text))) => {
-                        tracing::warn!("-> {}", text);
-                        // Just forward it to the server as is.
-                        server_send.send(text).await?;
+                        last_seen = Instant::now();
+                        if ctx.filters.drops(filter::Direction::Client, &text) {
+                            tracing::debug!("dropped -> {}", text);
+                        } else {
+                            tracing::warn!("-> {}", text);
+                            // Just forward it to the server as is.
+                            server_send.send(text).await?;
+                        }
                     }
 
                     // Close message
@@ -160,37 +1024,161 @@ async fn connected(
                     }
 
                     // Ping the client to keep the connection alive
-                    Some(Ok(Message::Tick)) => {
-                        // Terminate if we haven't heard back from the previous ping.
-                        if !is_alive {
+                    Some(Ok(Message::Tick(tick))) => {
+                        let since_ping = last_ping.map(|ping| tick.duration_since(ping));
+                        // Terminate if we haven't heard back from the previous ping in time.
+                        if awaiting_pong && since_ping.map_or(false, |d| d >= ctx.ping_timeout) {
                             tracing::warn!("terminating unhealthy connection");
                             break;
                         }
 
-                        is_alive = false;
-                        tracing::debug!("pinging the client");
-                        client_send.send(warp::ws::Message::ping(vec![])).await?;
+                        let due = ctx
+                            .ping_interval
+                            .map_or(false, |interval| since_ping.map_or(true, |d| d >= interval));
+                        if !awaiting_pong && due {
+                            last_ping = Some(tick);
+                            awaiting_pong = true;
+                            tracing::debug!("pinging the client");
+                            client_send.send(Outgoing::Ping).await?;
+                        }
+                    }
+
+                    // Close the session if the client stopped sending messages.
+                    Some(Ok(Message::IdleCheck)) => {
+                        if ctx
+                            .idle_timeout
+                            .map_or(false, |timeout| last_seen.elapsed() >= timeout)
+                        {
+                            tracing::info!("closing idle session");
+                            for msg in progress.end_all() {
+                                client_send.send(Outgoing::Text(msg.to_string())).await?;
+                            }
+                            client_send.send(Outgoing::Ended).await?;
+                            break;
+                        }
+                    }
+
+                    // Send the changes buffered for the delay
+                    Some(Ok(Message::FlushChanges)) => {
+                        if let Some(text) = debouncer
+                            .as_mut()
+                            .and_then(|debouncer| debouncer.flush_due(Instant::now()))
+                        {
+                            tracing::debug!("-> {}", text);
+                            server_send.send(text).await?;
+                        }
+                    }
+
+                    // Answer the requests over the timeout, and cancel them
+                    Some(Ok(Message::TimeoutCheck)) => {
+                        for (response, cancel) in pending.expire(Instant::now()) {
+                            tracing::warn!("request timed out: {}", response);
+                            client_send.send(Outgoing::Text(response)).await?;
+                            server_send.send(cancel).await?;
+                        }
+                    }
+
+                    // The session is about to reach the maximum duration
+                    Some(Ok(Message::SessionEnding(left))) => {
+                        tracing::info!("session ends in {:?}", left);
+                        let warning = serde_json::json!({
+                            "jsonrpc": "2.0",
+                            "method": "window/showMessage",
+                            "params": {
+                                "type": 2,
+                                "message": format!(
+                                    "The session will end in {} seconds.",
+                                    left.as_secs()
+                                ),
+                            }
+                        });
+                        client_send
+                            .send(Outgoing::Text(warning.to_string()))
+                            .await?;
+                    }
+
+                    Some(Ok(Message::SessionEnded)) => {
+                        tracing::info!("closing session after the maximum duration");
+                        for msg in progress.end_all() {
+                            client_send.send(Outgoing::Text(msg.to_string())).await?;
+                        }
+                        client_send.send(Outgoing::Ended).await?;
+                        break;
+                    }
+
+                    // The proxy is stopping
+                    Some(Ok(Message::Shutdown)) => {
+                        drop(p_server_msg);
+                        if let Some(text) = debouncer.as_mut().and_then(|d| d.flush()) {
+                            server_send.send(text).await?;
+                        }
+                        for cancel in pending.cancel_all() {
+                            server_send.send(cancel).await?;
+                        }
+                        tracing::info!("stopping server");
+                        shutdown::stop_server(
+                            &mut server_send,
+                            &mut server_recv,
+                            child.as_mut(),
+                            ctx.shutdown.grace(),
+                        )
+                        .await?;
+                        for msg in progress.end_all() {
+                            client_send.send(Outgoing::Text(msg.to_string())).await?;
+                        }
+                        client_send.send(Outgoing::Shutdown).await?;
+                        break;
+                    }
+
+                    // Files removed or renamed with `POST /files`
+                    Some(Ok(Message::FileOperation(event))) => {
+                        if let Some(text) = file_operations.on_event(event) {
+                            tracing::debug!("-> {}", text);
+                            server_send.send(text).await?;
+                        }
+                    }
+
+                    // Files changed on the disk
+                    Some(Ok(Message::FilesChanged(changes))) => {
+                        let root = watch_root.as_deref().expect("watched");
+                        if let Some(text) = watchers.notification(root, changes) {
+                            tracing::debug!("-> {}", text);
+                            server_send.send(text).await?;
+                        }
                     }
 
                     // Mark the connection as alive on any pong.
                     Some(Ok(Message::Pong)) => {
                         tracing::debug!("received pong");
-                        is_alive = true;
+                        awaiting_pong = false;
                     }
 
                     // Connection closed
                     Some(Ok(Message::Done)) => {
                         tracing::info!("connection closed");
+                        if let Some(text) = debouncer.as_mut().and_then(|d| d.flush()) {
+                            server_send.send(text).await?;
+                        }
+                        // Resumable sessions keep the requests for the client to resume.
+                        if ctx.resume.is_none() {
+                            let cancels = pending.cancel_all();
+                            if !cancels.is_empty() {
+                                tracing::debug!("cancelling {} pending requests", cancels.len());
+                            }
+                            for cancel in cancels {
+                                server_send.send(cancel).await?;
+                            }
+                        }
                         break;
                     }
 
-                    // WebSocket Error
+                    // Transport Error
                     Some(Err(err)) => {
-                        tracing::error!("websocket error: {}", err);
+                        tracing::error!("client error: {}", err);
                     }
 
                     None => {
-                        // Unreachable because of the interval stream
+                        // Unreachable because we stop after `Done`
                         unreachable!("should never yield None");
                     }
                 }
@@ -202,22 +1190,100 @@ async fn connected(
             // From Server
             Either::Right((from_server, p_client_msg)) => {
                 match from_server {
+                    // Restarting the server failed
+                    Some(Ok(text)) if is_server_failed(&text) => {
+                        tracing::error!("server failed, closing the connection");
+                        client_send.send(Outgoing::ServerFailed).await?;
+                        break;
+                    }
+
+                    // Response to `workspace/willRenameFiles` from the proxy
+                    Some(Ok(text)) if file_operations.responds(&text) => {
+                        tracing::debug!("received file operation edits <- {}", text);
+                    }
+
+                    // Dropped by the filters
+                    Some(Ok(text)) if ctx.filters.drops(filter::Direction::Server, &text) => {
+                        tracing::debug!("dropped <- {}", text);
+                        if let Some(response) = filter::rejection(&text) {
+                            server_send.send(response).await?;
+                        }
+                    }
+
+                    // Response to a request that timed out
+                    Some(Ok(text)) if !pending.received(&text) => {
+                        tracing::debug!("dropped late response <- {}", text);
+                    }
+
+                    // Over `--max-server-message`
+                    Some(Ok(text)) if ctx.size_limits.server_exceeds(&text) => {
+                        tracing::warn!("dropped message of {} bytes over the limit <-", text.len());
+                        if let Some(response) = size::rejection(&text, ctx.size_limits.server) {
+                            server_send.send(response).await?;
+                        } else if let Some(error) = ctx.size_limits.replacement(&text) {
+                            client_send.send(Outgoing::Text(error)).await?;
+                        }
+                    }
+
+                    // Applied to the files by the proxy
+                    Some(Ok(text)) if edits_root.is_some() && documents.applies(&text) => {
+                        tracing::debug!("applying <- {}", text);
+                        let root = edits_root.as_deref().expect("checked in the guard");
+                        let response = edits::apply(&text, root, &ctx.quota).await;
+                        tracing::debug!("-> {}", response);
+                        server_send.send(response).await?;
+                    }
+
                     // Serialized LSP Message
                     Some(Ok(text)) => {
-                        if ctx.remap {
+                        progress.on_server_text(&text);
+                        file_operations.on_server_text(&text);
+                        watchers.on_server_text(&text);
+                        let text = if ctx.remap && ctx.deep_remap {
+                            remap_deep_from_server(text, &ctx.remap_rules)?
+                        } else if ctx.remap {
                             if let Ok(mut msg) = lsp::Message::from_str(&text) {
-                                lsp::ext::remap_relative_uri(&mut msg, &ctx.cwd)?;
+                                lsp::ext::remap_relative_uri(&mut msg, &ctx.remap_rules)?;
                                 tracing::debug!("remapped relative URI from server");
-                                let text = serde_json::to_string(&msg)?;
-                                tracing::debug!("<- {}", text);
-                                client_send.send(warp::ws::Message::text(text)).await?;
+                                serde_json::to_string(&msg)?
                             } else {
-                                tracing::warn!("<- {}", text);
-                                client_send.send(warp::ws::Message::text(text)).await?;
+                                tracing::warn!("invalid message from server");
+                                text
                             }
                         } else {
-                            tracing::debug!("<- {}", text);
-                            client_send.send(warp::ws::Message::text(text)).await?;
+                            text
+                        };
+                        let text = if ctx.redact_paths {
+                            redact_from_server(text, &ctx.cwd)?
+                        } else {
+                            text
+                        };
+                        // Parsed only for the middlewares
+                        let msg = if ctx.middlewares.is_empty() {
+                            None
+                        } else {
+                            lsp::Message::from_str(&text).ok()
+                        };
+                        match msg {
+                            Some(msg) => {
+                                let (msg, outbox) = ctx.middlewares.on_server_message(msg).await;
+                                for msg in outbox.to_server {
+                                    server_send.send(serde_json::to_string(&msg)?).await?;
+                                }
+                                for msg in outbox.to_client {
+                                    let text = serde_json::to_string(&msg)?;
+                                    client_send.send(Outgoing::Text(text)).await?;
+                                }
+                                if let Some(msg) = msg {
+                                    let text = serde_json::to_string(&msg)?;
+                                    tracing::debug!("<- {}", text);
+                                    client_send.send(Outgoing::Text(text)).await?;
+                                }
+                            }
+                            None => {
+                                tracing::debug!("<- {}", text);
+                                client_send.send(Outgoing::Text(text)).await?;
+                            }
                         }
                     }
 
@@ -228,8 +1294,36 @@ async fn connected(
 
                     // Server exited
                     None => {
-                        tracing::error!("server process exited unexpectedly");
-                        client_send.send(warp::ws::Message::close()).await?;
+                        for msg in progress.end_all() {
+                            client_send.send(Outgoing::Text(msg.to_string())).await?;
+                        }
+                        match server_exit(child.as_mut()).await {
+                            Some(exit::Exit::Clean) => {
+                                tracing::info!("server process exited");
+                                client_send
+                                    .send(Outgoing::Exited(exit::Exit::Clean))
+                                    .await?;
+                            }
+                            Some(exit) => {
+                                let tail = match child.as_mut() {
+                                    Some(child) => child.stderr_tail().await,
+                                    None => String::new(),
+                                };
+                                tracing::error!(
+                                    "server process exited unexpectedly: {}\n{}",
+                                    exit.reason(),
+                                    tail
+                                );
+                                if let Some(msg) = exit::stderr_message(&exit.reason(), &tail) {
+                                    client_send.send(Outgoing::Text(msg.to_string())).await?;
+                                }
+                                client_send.send(Outgoing::Exited(exit)).await?;
+                            }
+                            None => {
+                                tracing::error!("server process exited unexpectedly");
+                                client_send.send(Outgoing::Close).await?;
+                            }
+                        }
                         break;
                     }
                 }
@@ -243,47 +1337,382 @@ async fn connected(
     Ok(())
 }
 
+/// Environment variable with the subject of the client certificate for the servers of the
+/// session.
+const CLIENT_SUBJECT_ENV: &str = "LSP_WS_PROXY_CLIENT_SUBJECT";
+
+// Start the selected server, in a container if configured.
+pub(super) fn spawn_server(
+    ctx: &Context,
+    query: Option<&Query>,
+) -> Result<backend::Connection, std::io::Error> {
+    let server = select_server(&ctx.servers, query)?;
+    let command = template::expand(&server.command, &ctx.params, &ctx.param_values)?;
+    tracing::info!("starting {} in {}", server.name, ctx.cwd);
+    let conn = limit::spawn_process(ctx.processes.as_ref(), || {
+        if let Some(docker) = &ctx.docker {
+            let cwd = ctx.cwd.to_file_path().expect("cwd is a file url");
+            backend::Connection::spawn(&docker.command(&command, cwd))
+        } else {
+            let cwd = server
+                .cwd
+                .clone()
+                .or_else(|| ctx.cwd.to_file_path().ok().filter(|_| ctx.memory));
+            let mut env = server.env.clone();
+            // Shared servers aren't the session's.
+            if let Some(subject) = ctx.client_subject.as_ref().filter(|_| ctx.shared.is_none()) {
+                env.insert(CLIENT_SUBJECT_ENV.to_owned(), subject.clone());
+            }
+            backend::Connection::spawn_with(
+                &command,
+                &env,
+                cwd.as_deref(),
+                &server.limits,
+                &server.stop_signals,
+            )
+        }
+    })?;
+    tracing::debug!("running {}", server.name);
+    Ok(conn)
+}
+
+pub(super) fn select_server<'a>(
+    servers: &'a [Server],
+    query: Option<&Query>,
+) -> Result<&'a Server, std::io::Error> {
+    if let Some(query) = query {
+        servers
+            .iter()
+            .find(|s| s.name == query.name)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("unknown Language Server '{}'", query.name),
+                )
+            })
+    } else {
+        servers.first().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "no Language Server")
+        })
+    }
+}
+
 // Type to describe a message from the client conveniently.
 #[allow(clippy::large_enum_variant)]
 #[allow(clippy::enum_variant_names)]
-enum Message {
+pub(super) enum Message {
     // Valid LSP message
     Message(lsp::Message),
     // Invalid JSON
     Invalid(String),
     // Close message
     Close,
-    // Ping the client to keep the connection alive.
+    // Ping the client to keep the connection alive, with the time of the tick.
     // Note that this is from the interval stream and not actually from client.
-    Tick,
+    Tick(tokio::time::Instant),
+    // Check if the client has been idle for `--idle-timeout`.
+    IdleCheck,
+    // The session ends after this long because of `--max-session-duration`.
+    SessionEnding(Duration),
+    // The session reached `--max-session-duration`.
+    SessionEnded,
+    // Check for requests over `--request-timeout`.
+    TimeoutCheck,
+    // Check for changes buffered for `--coalesce-changes`.
+    FlushChanges,
+    // The proxy is stopping.
+    Shutdown,
+    // Files were removed or renamed with `POST /files`.
+    FileOperation(file_operations::Event),
+    // Files changed on the disk with `--watch`.
+    FilesChanged(watch::Changes),
     // Client disconnected. Necessary because the combined stream is infinite.
     Done,
     // A reply for ping or heartbeat from client.
     Pong,
 }
 
-// Parse the message and ignore anything we don't care.
-async fn filter_map_warp_ws_message(
+// Type to describe a message to the client.
+#[derive(Debug)]
+pub(super) enum Outgoing {
+    // Serialized LSP message
+    Text(String),
+    // Ping the client to keep the connection alive
+    Ping,
+    // Close the connection
+    Close,
+    // Close the connection because the proxy is stopping
+    Shutdown,
+    // Close the connection because the server can't be restarted
+    ServerFailed,
+    // Close the connection because the server exited
+    Exited(exit::Exit),
+    // Close the connection because the proxy ended the session
+    Ended,
+}
+
+// How the server exited after closing its stdout, if it's a process that exited.
+async fn server_exit(child: Option<&mut backend::Process>) -> Option<exit::Exit> {
+    let child = child?;
+    match tokio::time::timeout(Duration::from_secs(1), child.wait()).await {
+        Ok(Ok(status)) => Some(exit::Exit::from(status)),
+        _ => None,
+    }
+}
+
+// Serialize the message from the client, remapping the URIs in any field with `--deep-remap`,
+// and patching the capabilities of `initialize`.
+fn client_text(msg: &lsp::Message, ctx: &Context) -> Result<String, serde_json::Error> {
+    let deep_remap = ctx.remap && ctx.deep_remap;
+    let capabilities = ctx
+        .capabilities
+        .as_ref()
+        .filter(|_| matches!(msg, lsp::Message::Request(lsp::Request::Initialize { .. })));
+    if !deep_remap && capabilities.is_none() {
+        return serde_json::to_string(msg);
+    }
+    let mut value = serde_json::to_value(msg)?;
+    if deep_remap {
+        lsp::ext::remap_relative_uri_deep_to_file(&mut value, &ctx.remap_rules);
+    }
+    if let Some(patch) = capabilities {
+        lsp::ext::patch_client_capabilities(&mut value, patch);
+    }
+    serde_json::to_string(&value)
+}
+
+// Remap the URIs in any field of the message from the server, keeping it as is if it's not JSON.
+fn remap_deep_from_server(
+    text: String,
+    rules: &lsp::ext::RemapRules,
+) -> Result<String, serde_json::Error> {
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(mut value) => {
+            lsp::ext::remap_relative_uri_deep_to_source(&mut value, rules);
+            serde_json::to_string(&value)
+        }
+        Err(_) => Ok(text),
+    }
+}
+
+// Rewrite the paths in the workspace `cwd` in any string of the message from the server.
+fn redact_from_server(text: String, cwd: &Url) -> Result<String, serde_json::Error> {
+    let root = match cwd.to_file_path() {
+        Ok(root) => root.to_string_lossy().into_owned(),
+        Err(_) => return Ok(text),
+    };
+    if !text.contains(root.trim_end_matches('/')) {
+        return Ok(text);
+    }
+    match serde_json::from_str::<serde_json::Value>(&text) {
+        Ok(mut value) => {
+            lsp::ext::redact_paths(&mut value, &root);
+            serde_json::to_string(&value)
+        }
+        Err(_) => Ok(text),
+    }
+}
+
+// The notification from `restart` that it gave up.
+fn is_server_failed(text: &str) -> bool {
+    text.contains(restart::FAILED_METHOD)
+        && serde_json::from_str::<serde_json::Value>(text).map_or(false, |msg| {
+            msg.get("id").is_none() && msg["method"] == restart::FAILED_METHOD
+        })
+}
+
+// Parse the text from the client, keeping it as is if it's not a valid LSP message.
+fn parse_client_message(text: &str) -> Message {
+    match lsp::Message::from_str(text) {
+        Ok(msg) => Message::Message(msg),
+        Err(_) => Message::Invalid(text.to_owned()),
+    }
+}
+
+// Parse the text from the client, splitting a JSON-RPC batch into its messages.
+pub(super) fn parse_client_messages(text: &str) -> Vec<Message> {
+    match parse_batch(text) {
+        Some(batch) => batch.into_iter().map(parse_client_value).collect(),
+        None => vec![parse_client_message(text)],
+    }
+}
+
+// The messages of the text from the client if it's a JSON-RPC batch.
+fn parse_batch(text: &str) -> Option<Vec<serde_json::Value>> {
+    if !text.trim_start().starts_with('[') {
+        return None;
+    }
+    let batch = serde_json::from_str::<Vec<serde_json::Value>>(text)
+        .ok()
+        .filter(|batch| !batch.is_empty())?;
+    tracing::debug!("received batch of {} messages", batch.len());
+    Some(batch)
+}
+
+fn parse_client_value(value: serde_json::Value) -> Message {
+    match lsp::Message::try_from(value.clone()) {
+        Ok(msg) => Message::Message(msg),
+        Err(_) => Message::Invalid(value.to_string()),
+    }
+}
+
+// Encode serialized message from the server for the client.
+fn encode_text(text: String, encoding: Encoding) -> warp::ws::Message {
+    match encoding {
+        Encoding::Json => warp::ws::Message::text(text),
+        Encoding::MessagePack => {
+            let encoded = serde_json::from_str::<serde_json::Value>(&text)
+                .ok()
+                .and_then(|value| rmp_serde::to_vec_named(&value).ok());
+            if let Some(bytes) = encoded {
+                warp::ws::Message::binary(bytes)
+            } else {
+                // Not JSON, send it as is.
+                warp::ws::Message::text(text)
+            }
+        }
+    }
+}
+
+// Decode MessagePack from the client, splitting a batch into its messages.
+fn decode_binary(bytes: &[u8], batches: &batch::Batches) -> Vec<Message> {
+    match rmp_serde::from_slice::<serde_json::Value>(bytes) {
+        Ok(serde_json::Value::Array(batch)) if !batch.is_empty() => {
+            batches.received(&batch);
+            batch.into_iter().map(parse_client_value).collect()
+        }
+        Ok(value) => vec![parse_client_value(value)],
+        Err(err) => {
+            tracing::warn!("ignoring invalid MessagePack: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+// Frame of the message to the client.
+fn to_warp_ws_message(msg: Outgoing, encoding: Encoding) -> warp::ws::Message {
+    match msg {
+        Outgoing::Text(text) => encode_text(text, encoding),
+        Outgoing::Ping => warp::ws::Message::ping(vec![]),
+        Outgoing::Close => warp::ws::Message::close(),
+        Outgoing::Shutdown => warp::ws::Message::close_with(exit::SHUTDOWN, "proxy shutting down"),
+        Outgoing::Ended => warp::ws::Message::close_with(exit::ENDED, "session ended"),
+        Outgoing::Exited(exit) => warp::ws::Message::close_with(exit.close_code(), exit.reason()),
+        Outgoing::ServerFailed => {
+            warp::ws::Message::close_with(exit::SERVER_FAILED, "server failed")
+        }
+    }
+}
+
+// Parse the message and ignore anything we don't care, holding the responses to the batches.
+fn from_warp_ws_message(
     wsm: Result<warp::ws::Message, warp::Error>,
-) -> Option<Result<Message, warp::Error>> {
+    encoding: Encoding,
+    batches: &batch::Batches,
+) -> Vec<Result<Message, warp::Error>> {
     match wsm {
         Ok(msg) => {
             if msg.is_close() {
-                Some(Ok(Message::Close))
+                vec![Ok(Message::Close)]
             } else if msg.is_text() {
                 let text = msg.to_str().expect("text");
-                match lsp::Message::from_str(text) {
-                    Ok(msg) => Some(Ok(Message::Message(msg))),
-                    Err(_) => Some(Ok(Message::Invalid(text.to_owned()))),
+                match parse_batch(text) {
+                    Some(batch) => {
+                        batches.received(&batch);
+                        batch.into_iter().map(parse_client_value).map(Ok).collect()
+                    }
+                    None => vec![Ok(parse_client_message(text))],
                 }
+            } else if msg.is_binary() && encoding == Encoding::MessagePack {
+                decode_binary(msg.as_bytes(), batches)
+                    .into_iter()
+                    .map(Ok)
+                    .collect()
             } else if msg.is_pong() {
-                Some(Ok(Message::Pong))
+                vec![Ok(Message::Pong)]
             } else {
                 // Ignore any other message types
-                None
+                Vec::new()
             }
         }
 
-        Err(err) => Some(Err(err)),
+        Err(err) => vec![Err(err)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route() {
+        assert_eq!(
+            "/rust=rust-analyzer".parse::<Route>(),
+            Ok(Route {
+                path: "/rust".to_owned(),
+                name: "rust-analyzer".to_owned(),
+            })
+        );
+        assert!("/rust".parse::<Route>().is_err());
+        assert!("=rust-analyzer".parse::<Route>().is_err());
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(Encoding::negotiate(None), Encoding::Json);
+        assert_eq!(Encoding::negotiate(Some("lsp")), Encoding::Json);
+        assert_eq!(
+            Encoding::negotiate(Some("lsp, msgpack")),
+            Encoding::MessagePack
+        );
+    }
+
+    #[test]
+    fn test_protocol_query() {
+        assert_eq!(
+            protocol_query(Some("msgpack, lsp.rust-analyzer")).map(|q| q.name),
+            Some("rust-analyzer".to_owned())
+        );
+        assert!(protocol_query(Some("lsp.")).is_none());
+        assert!(protocol_query(Some("msgpack")).is_none());
+        assert!(protocol_query(None).is_none());
+    }
+
+    #[test]
+    fn test_msgpack_roundtrip() {
+        let text = r#"{"jsonrpc":"2.0","method":"initialized","params":{}}"#;
+        let msg = encode_text(text.to_owned(), Encoding::MessagePack);
+        assert!(msg.is_binary());
+        assert!(matches!(
+            decode_binary(msg.as_bytes(), &batch::Batches::default()).as_slice(),
+            [Message::Message(_)]
+        ));
+    }
+
+    #[test]
+    fn test_parse_batch() {
+        let batch = r#"[
+            {"jsonrpc":"2.0","method":"initialized","params":{}},
+            {"jsonrpc":"2.0","id":1,"method":"shutdown"}
+        ]"#;
+        assert!(matches!(
+            parse_client_messages(batch).as_slice(),
+            [Message::Message(_), Message::Message(_)]
+        ));
+        assert!(matches!(
+            parse_client_messages("[]").as_slice(),
+            [Message::Invalid(_)]
+        ));
+    }
+
+    #[test]
+    fn test_server_failed() {
+        assert!(is_server_failed(
+            r#"{"jsonrpc":"2.0","method":"$/lsp-ws-proxy/serverFailed","params":{}}"#
+        ));
+        // Mentioned in a message from the server
+        assert!(!is_server_failed(
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"message":"$/lsp-ws-proxy/serverFailed"}}"#
+        ));
     }
 }
@@ -0,0 +1,253 @@
+//! Quotas on the files synced to the workspaces, so a single user can't fill the disk of the host.
+//!
+//! With `--quota size=<MiB>`, `files=<count>`, or `file-size=<KiB>`, the writes with `POST /files`,
+//! the archives extracted with `POST /files/archive`, the documents written on save, and the
+//! workspace edits applied with `--apply-edits` are rejected if a file or the workspace would be
+//! over them. The usage is measured on the disk before each write, so the files created by the
+//! servers count too. The quotas apply to the workspace of each session with `--isolate` or `--repo`, and to the shared workspace otherwise.
+use std::{
+    convert::TryFrom,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde_json::json;
+use thiserror::Error;
+
+/// One of the quotas with its value.
+///
+/// ```text
+/// size=100
+/// files=1000
+/// file-size=1024
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Limit {
+    /// Mebibytes of the files of the workspace.
+    Size(u64),
+    /// Number of files of the workspace.
+    Files(u64),
+    /// Kibibytes of each file.
+    FileSize(u64),
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (quota, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <quota>=<value>, got {}", s))?;
+        let value: u64 = value
+            .parse()
+            .map_err(|_| format!("invalid value of {}: {}", quota, value))?;
+        // The sizes must fit in bytes.
+        let bytes = |unit: u64| {
+            value
+                .checked_mul(unit)
+                .map(|_| value)
+                .ok_or_else(|| format!("{} is too large: {}", quota, value))
+        };
+        match quota {
+            "size" => Ok(Self::Size(bytes(1024 * 1024)?)),
+            "files" => Ok(Self::Files(value)),
+            "file-size" => Ok(Self::FileSize(bytes(1024)?)),
+            _ => Err(format!(
+                "unknown quota {}, expected size, files, or file-size",
+                quota
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Limit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Quotas of a workspace, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Quota {
+    size: Option<u64>,
+    files: Option<u64>,
+    file_size: Option<u64>,
+}
+
+/// A write over a quota.
+#[derive(Debug, Error, serde::Serialize)]
+#[serde(tag = "quota", rename_all = "kebab-case")]
+pub(super) enum Exceeded {
+    #[error("{path} would be {size} bytes, over the quota of {limit} bytes per file")]
+    FileSize { path: String, size: u64, limit: u64 },
+
+    #[error("the workspace would have {files} files, over the quota of {limit}")]
+    Files { files: u64, limit: u64 },
+
+    #[error("the workspace would be {size} bytes, over the quota of {limit} bytes")]
+    Size { size: u64, limit: u64 },
+}
+
+impl Quota {
+    pub fn new(limits: &[Limit]) -> Self {
+        let mut quota = Self::default();
+        for limit in limits {
+            match *limit {
+                Limit::Size(mib) => quota.size = Some(mib.saturating_mul(1024 * 1024)),
+                Limit::Files(files) => quota.files = Some(files),
+                Limit::FileSize(kib) => quota.file_size = Some(kib.saturating_mul(1024)),
+            }
+        }
+        quota
+    }
+
+    /// Whether any of the quotas is set.
+    pub(super) fn is_set(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// Check writing the `files` at the absolute paths with the sizes in the workspace `root`.
+    ///
+    /// Walks the workspace, call it off the runtime.
+    pub(super) fn check(&self, root: &Path, files: &[(PathBuf, u64)]) -> Result<(), Exceeded> {
+        if let Some(limit) = self.file_size {
+            if let Some((path, size)) = files.iter().find(|(_, size)| *size > limit) {
+                let path = path.strip_prefix(root).unwrap_or(path);
+                return Err(Exceeded::FileSize {
+                    path: super::files::slash_path(path),
+                    size: *size,
+                    limit,
+                });
+            }
+        }
+        if self.size.is_none() && self.files.is_none() {
+            return Ok(());
+        }
+
+        let (mut size, mut count) = usage(root);
+        for (path, new_size) in files {
+            match std::fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_file() => {
+                    size = size.saturating_sub(metadata.len()) + new_size;
+                }
+                _ => {
+                    size += new_size;
+                    count += 1;
+                }
+            }
+        }
+        match (self.files, self.size) {
+            (Some(limit), _) if count > limit => Err(Exceeded::Files {
+                files: count,
+                limit,
+            }),
+            (_, Some(limit)) if size > limit => Err(Exceeded::Size { size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// `check` on a blocking thread.
+    pub(super) async fn check_blocking(
+        &self,
+        root: PathBuf,
+        files: Vec<(PathBuf, u64)>,
+    ) -> Result<(), Exceeded> {
+        if !self.is_set() {
+            return Ok(());
+        }
+        let quota = *self;
+        tokio::task::spawn_blocking(move || quota.check(&root, &files))
+            .await
+            .expect("checking the quota doesn't panic")
+    }
+}
+
+/// Bytes and number of the files in `dir`. Symlinks and unreadable entries are left out.
+fn usage(dir: &Path) -> (u64, u64) {
+    let (mut size, mut count) = (0, 0);
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.filter_map(Result::ok) {
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => dirs.push(entry.path()),
+                Ok(metadata) if metadata.is_file() => {
+                    size += metadata.len();
+                    count += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    (size, count)
+}
+
+/// Response to a write over the quota, with the quota and its limit.
+pub(super) fn response(err: &Exceeded) -> warp::reply::Response {
+    let mut body = json!(err);
+    body["reason"] = json!(err.to_string());
+    super::json_response(&body, warp::http::StatusCode::PAYLOAD_TOO_LARGE)
+}
+
+/// Notification to the client that the document at `path` wasn't saved because of `err`.
+pub(super) fn show_message(path: &Path, err: &Exceeded) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "window/showMessage",
+        "params": {
+            // Error
+            "type": 1,
+            "message": format!("{} was not saved: {}", path.display(), err),
+        },
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let root = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), vec![b'x'; 1024]).unwrap();
+
+        let quota = Quota::new(&[
+            "size=1".parse().unwrap(),
+            "files=2".parse().unwrap(),
+            "file-size=512".parse().unwrap(),
+        ]);
+        // Replacing a file doesn't add to the count
+        assert!(quota
+            .check(&root, &[(root.join("src/main.rs"), 2048)])
+            .is_ok());
+        assert!(matches!(
+            quota.check(&root, &[(root.join("src/big.rs"), 1024 * 1024)]),
+            Err(Exceeded::FileSize { path, limit: 524_288, .. }) if path == "src/big.rs"
+        ));
+        assert!(matches!(
+            quota.check(&root, &[(root.join("a.rs"), 1), (root.join("b.rs"), 1)]),
+            Err(Exceeded::Files { files: 3, limit: 2 })
+        ));
+        let quota = Quota::new(&["size=1".parse().unwrap()]);
+        assert!(matches!(
+            quota.check(&root, &[(root.join("a.rs"), 1024 * 1024)]),
+            Err(Exceeded::Size {
+                size: 1_049_600,
+                limit: 1_048_576
+            })
+        ));
+        assert!("memory=1".parse::<Limit>().is_err());
+        assert!("size=18446744073709551615".parse::<Limit>().is_err());
+        assert!("file-size=18446744073709551615".parse::<Limit>().is_err());
+        assert!("files=18446744073709551615".parse::<Limit>().is_ok());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Rate limits of the requests from the client by method, for each session.
+//!
+//! Requests over the limit are answered with an error instead of forwarded, so a chatty client
+//! can't overload a heavy server. Notifications are always forwarded, because dropping one like
+//! `textDocument/didChange` would leave the server out of sync.
+use std::{
+    convert::TryFrom,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Error code of `RequestFailed`.
+const REQUEST_FAILED: i64 = -32803;
+
+/// At most `count` requests with `method` from the client in `per`, ending with `*` to match the
+/// prefix.
+///
+/// ```text
+/// textDocument/completion=10/s
+/// workspace/*=100/m
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    method: String,
+    count: u32,
+    per: Duration,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected <method>=<count>/<s|m>, got {}", s);
+        let (method, rate) = s.split_once('=').ok_or_else(invalid)?;
+        let (count, per) = rate.split_once('/').ok_or_else(invalid)?;
+        let count = count.parse::<u32>().map_err(|_| invalid())?;
+        let per = match per {
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            _ => return Err(invalid()),
+        };
+        if method.is_empty() || count == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            method: method.to_owned(),
+            count,
+            per,
+        })
+    }
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl RateLimit {
+    fn matches(&self, method: &str) -> bool {
+        match self.method.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => method == self.method,
+        }
+    }
+}
+
+/// Add a middleware enforcing `limits` for a session to `middlewares`.
+pub(super) fn apply(limits: &[RateLimit], middlewares: &mut Middlewares) {
+    if !limits.is_empty() {
+        let buckets = limits
+            .iter()
+            .map(|limit| Bucket {
+                tokens: f64::from(limit.count),
+                last: Instant::now(),
+            })
+            .collect();
+        middlewares.push(Limiter {
+            limits: Arc::new(limits.to_vec()),
+            buckets: Mutex::new(buckets),
+        });
+    }
+}
+
+/// Tokens left of a limit, refilled continuously.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> bool {
+        let rate = f64::from(limit.count) / limit.per.as_secs_f64();
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(f64::from(limit.count));
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct Limiter {
+    limits: Arc<Vec<RateLimit>>,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl Limiter {
+    /// The limit exceeded by the request `msg`, if any.
+    fn exceeded(&self, msg: &Value, now: Instant) -> Option<&RateLimit> {
+        msg.get("id")?;
+        let method = msg.get("method").and_then(Value::as_str)?;
+        let mut buckets = self.buckets.lock().expect("lock buckets");
+        for (limit, bucket) in self.limits.iter().zip(buckets.iter_mut()) {
+            if limit.matches(method) && !bucket.take(limit, now) {
+                return Some(limit);
+            }
+        }
+        None
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Limiter {
+    async fn on_client_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        let value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        let limit = match self.exceeded(&value, Instant::now()) {
+            Some(limit) => limit,
+            None => return Some(msg),
+        };
+        tracing::debug!("rate limit {} exceeded", limit.method);
+        let method = value["method"].as_str().unwrap_or_default();
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": value["id"],
+            "error": {
+                "code": REQUEST_FAILED,
+                "message": format!("rate limit of {} exceeded", method),
+            },
+        });
+        if let Ok(response) = serde_json::from_value(response) {
+            outbox.send_to_client(response);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = "textDocument/completion=2/s".parse::<RateLimit>().unwrap();
+        let limiter = Limiter {
+            limits: Arc::new(vec![limit.clone()]),
+            buckets: Mutex::new(vec![Bucket {
+                tokens: 2.0,
+                last: Instant::now(),
+            }]),
+        };
+        let now = Instant::now();
+        let completion = json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/completion"});
+        let hover = json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover"});
+        assert_eq!(limiter.exceeded(&completion, now), None);
+        assert_eq!(limiter.exceeded(&completion, now), None);
+        assert_eq!(limiter.exceeded(&completion, now), Some(&limit));
+        assert_eq!(limiter.exceeded(&hover, now), None);
+        // Refilled after half a second
+        let later = now + Duration::from_millis(500);
+        assert_eq!(limiter.exceeded(&completion, later), None);
+        assert_eq!(limiter.exceeded(&completion, later), Some(&limit));
+
+        assert!("textDocument/completion=10/h".parse::<RateLimit>().is_err());
+        assert!("textDocument/completion=0/s".parse::<RateLimit>().is_err());
+    }
+}
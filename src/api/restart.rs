@@ -0,0 +1,544 @@
+//! Restart the server when it crashes, without ending the session.
+//!
+//! The `initialize` request, the `initialized` notification, and the documents opened by the
+//! client are kept so they can be sent to the new server. Requests the crashed server didn't
+//! respond to are answered with an error, and the progress it didn't end is ended.
+//!
+//! After too many crashes in a row, the client is sent `window/showMessage` with an error and
+//! the connection is closed with 4000.
+//!
+//! With `--probe-interval`, the server is also sent a request it should respond to quickly, and
+//! restarted like a crashed one if it didn't respond to [`MAX_UNANSWERED_PROBES`] in a row.
+use std::{collections::HashMap, time::Duration};
+
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::time::Instant;
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::Instrument;
+
+use crate::{
+    backend,
+    lsp::{self, framed::LspFrameCodec},
+};
+
+use super::{
+    exit, progress,
+    proxy::{self, Context, Query},
+    text,
+};
+
+/// ID of the `initialize` request sent to the restarted server.
+const INITIALIZE_ID: &str = "lsp-ws-proxy/restart";
+/// Method of the notification ending the connection after giving up.
+pub(super) const FAILED_METHOD: &str = "$/lsp-ws-proxy/serverFailed";
+/// Method of the request checking the server responds. Servers respond with an error to unknown
+/// requests starting with `$/`.
+const PROBE_METHOD: &str = "$/lsp-ws-proxy/ping";
+/// Prefix of the IDs of the probes.
+const PROBE_ID_PREFIX: &str = "lsp-ws-proxy/probe-";
+/// Probes without a response in a row before the server is considered hung.
+const MAX_UNANSWERED_PROBES: u32 = 3;
+/// Delay before the first restart, doubled after each.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest delay between restarts. The count is reset when a server runs this long.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Start the server selected by `query`, and restart it when it crashes.
+pub(super) fn connect(
+    ctx: &Context,
+    query: Option<&Query>,
+) -> Result<backend::Connection, std::io::Error> {
+    let server = proxy::spawn_server(ctx, query)?;
+    if let Some(standby) = &ctx.standby {
+        standby.prepare(ctx, query);
+    }
+    let (front, back) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(back);
+    let ctx = ctx.clone();
+    let query = query.cloned();
+    tokio::spawn(
+        async move {
+            if let Err(err) = forward(&ctx, query.as_ref(), server, reader, writer).await {
+                tracing::error!("connection error: {}", err);
+            }
+        }
+        .in_current_span(),
+    );
+    let (reader, writer) = tokio::io::split(front);
+    Ok(backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    })
+}
+
+struct Server {
+    send: FramedWrite<backend::Writer, LspFrameCodec>,
+    recv: FramedRead<backend::Reader, LspFrameCodec>,
+    child: Option<backend::Process>,
+    started: Instant,
+}
+
+impl From<backend::Connection> for Server {
+    fn from(conn: backend::Connection) -> Self {
+        Self {
+            send: lsp::framed::writer(conn.writer),
+            recv: lsp::framed::reader(conn.reader),
+            child: conn.child,
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Document opened by the client.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    language_id: Value,
+    version: Value,
+    text: String,
+}
+
+/// What the client sent that a new server needs to know.
+///
+/// Only what's needed to start a new server is kept when saved.
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+pub(super) struct State {
+    initialize: Option<Value>,
+    initialized: Option<Value>,
+    documents: HashMap<String, Document>,
+    /// IDs of the requests from the client without responses.
+    #[serde(skip)]
+    pending: Vec<Value>,
+    /// The client asked the server to stop.
+    #[serde(skip)]
+    stopping: bool,
+    /// Progress of the server to end if it crashes.
+    #[serde(skip)]
+    progress: progress::Progress,
+}
+
+impl State {
+    pub(super) fn on_client(&mut self, msg: &Value) {
+        let method = msg.get("method").and_then(Value::as_str);
+        if let (Some(_), Some(id)) = (method, msg.get("id")) {
+            self.pending.push(id.clone());
+        }
+        let params = msg.get("params");
+        let uri = params
+            .and_then(|p| p.pointer("/textDocument/uri"))
+            .and_then(Value::as_str)
+            .map(String::from);
+        match (method, uri) {
+            (Some("initialize"), _) => self.initialize = Some(msg.clone()),
+            (Some("initialized"), _) => self.initialized = Some(msg.clone()),
+            (Some("shutdown"), _) | (Some("exit"), _) => self.stopping = true,
+            (Some("textDocument/didOpen"), Some(uri)) => {
+                let doc = params.and_then(|p| p.get("textDocument"));
+                if let Some(doc) = doc {
+                    self.documents.insert(
+                        uri,
+                        Document {
+                            language_id: doc.get("languageId").cloned().unwrap_or(Value::Null),
+                            version: doc.get("version").cloned().unwrap_or(Value::Null),
+                            text: doc.get("text").and_then(Value::as_str).unwrap_or("").into(),
+                        },
+                    );
+                }
+            }
+            (Some("textDocument/didChange"), Some(uri)) => {
+                let changes = params
+                    .and_then(|p| p.get("contentChanges"))
+                    .and_then(Value::as_array);
+                let version = params.and_then(|p| p.pointer("/textDocument/version"));
+                let applied = match (self.documents.get_mut(&uri), changes) {
+                    (Some(doc), Some(changes)) => {
+                        if let Some(version) = version {
+                            doc.version = version.clone();
+                        }
+                        changes
+                            .iter()
+                            .all(|change| text::apply_change(&mut doc.text, change))
+                    }
+                    _ => true,
+                };
+                // Stop tracking the document if the change can't be applied.
+                if !applied {
+                    tracing::warn!("failed to apply change to {}", uri);
+                    self.documents.remove(&uri);
+                }
+            }
+            (Some("textDocument/didClose"), Some(uri)) => {
+                self.documents.remove(&uri);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_server(&mut self, msg: &Value) {
+        self.progress.on_server(msg);
+        if msg.get("method").is_none() {
+            if let Some(id) = msg.get("id") {
+                self.pending.retain(|pending| pending != id);
+            }
+        }
+    }
+
+    /// Messages to bring a new server to the state of the crashed one.
+    pub(super) fn replay(&self) -> Vec<Value> {
+        let mut messages = Vec::new();
+        if let Some(initialize) = &self.initialize {
+            let mut initialize = initialize.clone();
+            initialize["id"] = json!(INITIALIZE_ID);
+            messages.push(initialize);
+        }
+        messages.extend(self.initialized.clone());
+        for (uri, doc) in &self.documents {
+            messages.push(json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {
+                    "textDocument": {
+                        "uri": uri,
+                        "languageId": doc.language_id,
+                        "version": doc.version,
+                        "text": doc.text,
+                    }
+                }
+            }));
+        }
+        messages
+    }
+
+    /// Error responses to the requests the crashed server didn't respond to.
+    fn fail_pending(&mut self) -> Vec<Value> {
+        self.pending
+            .drain(..)
+            .map(|id| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": -32603, "message": "server restarted"},
+                })
+            })
+            .collect()
+    }
+}
+
+/// Requests checking that the server still responds.
+#[derive(Debug, Default)]
+struct Probe {
+    id: u64,
+    /// Probes sent since the last response.
+    unanswered: u32,
+}
+
+impl Probe {
+    fn next(&mut self) -> Value {
+        self.id += 1;
+        self.unanswered += 1;
+        json!({
+            "jsonrpc": "2.0",
+            "id": format!("{}{}", PROBE_ID_PREFIX, self.id),
+            "method": PROBE_METHOD,
+        })
+    }
+
+    /// Whether the message is a response to a probe, which isn't forwarded to the client.
+    fn on_server(&mut self, msg: &Value) -> bool {
+        let is_probe = msg.get("method").is_none()
+            && msg
+                .get("id")
+                .and_then(Value::as_str)
+                .map_or(false, |id| id.starts_with(PROBE_ID_PREFIX));
+        if is_probe {
+            self.unanswered = 0;
+        }
+        is_probe
+    }
+}
+
+enum Event {
+    Client(Option<String>),
+    Server(Option<String>),
+    Probe,
+    Handover(String),
+}
+
+async fn forward<R, W>(
+    ctx: &Context,
+    query: Option<&Query>,
+    server: backend::Connection,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut client_send = lsp::framed::writer(writer);
+    let mut client_recv = lsp::framed::reader(reader)
+        .filter_map(|msg| future::ready(msg.ok()))
+        .map(|text| Event::Client(Some(text)))
+        .chain(stream::once(future::ready(Event::Client(None))));
+    let mut server = Server::from(server);
+    let mut state = State::default();
+    let mut restarts = 0;
+    let name = proxy::select_server(&ctx.servers, query)?.name.clone();
+    let mut handovers = stream::iter(ctx.standby.as_ref().map(|standby| standby.handovers()))
+        .flatten()
+        .map(Event::Handover);
+
+    loop {
+        let server_recv = (&mut server.recv)
+            .filter_map(|msg| future::ready(msg.ok()))
+            .map(|text| Event::Server(Some(text)))
+            .chain(stream::once(future::ready(Event::Server(None))));
+        let probes = stream::iter(ctx.probe_interval).flat_map(|period| {
+            stream::unfold(
+                tokio::time::interval_at(Instant::now() + period, period),
+                |mut interval| async move {
+                    interval.tick().await;
+                    Some((Event::Probe, interval))
+                },
+            )
+        });
+        let mut events = stream::select(
+            stream::select(&mut client_recv, server_recv),
+            stream::select(Box::pin(probes), &mut handovers),
+        );
+        let mut probe = Probe::default();
+        let mut hung = false;
+        let mut exited = false;
+        let mut handover = false;
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Client(Some(text)) => {
+                    if let Ok(msg) = serde_json::from_str::<Value>(&text) {
+                        state.on_client(&msg);
+                    }
+                    server.send.send(text).await?;
+                }
+                Event::Server(Some(text)) => {
+                    if let Ok(msg) = serde_json::from_str::<Value>(&text) {
+                        if probe.on_server(&msg) {
+                            continue;
+                        }
+                        state.on_server(&msg);
+                    }
+                    client_send.send(text).await?;
+                }
+                Event::Probe => {
+                    if probe.unanswered >= MAX_UNANSWERED_PROBES {
+                        tracing::warn!("server didn't respond to {} probes", probe.unanswered);
+                        hung = true;
+                        exited = true;
+                        break;
+                    }
+                    server.send.send(probe.next().to_string()).await?;
+                }
+                Event::Handover(server_name) => {
+                    if server_name == name {
+                        handover = true;
+                        break;
+                    }
+                }
+                Event::Client(None) => break,
+                Event::Server(None) => {
+                    exited = true;
+                    break;
+                }
+            }
+        }
+        drop(events);
+        if !(exited || handover) || state.stopping {
+            return Ok(());
+        }
+
+        for msg in state.fail_pending() {
+            client_send.send(msg.to_string()).await?;
+        }
+        for msg in state.progress.end_all() {
+            client_send.send(msg.to_string()).await?;
+        }
+        // Report why the server crashed.
+        match server.child.as_mut() {
+            Some(child) if !hung && !handover => {
+                let tail = child.stderr_tail().await;
+                tracing::error!("server crashed\n{}", tail);
+                if let Some(msg) = exit::stderr_message("server crashed", &tail) {
+                    client_send.send(msg.to_string()).await?;
+                }
+            }
+            _ => {}
+        }
+        if handover {
+            tracing::info!("handing the session over to a new server");
+        } else {
+            if server.started.elapsed() >= MAX_BACKOFF {
+                restarts = 0;
+            }
+            if restarts >= ctx.max_restarts {
+                tracing::error!("server crashed {} times in a row, giving up", restarts);
+                let message = format!("Language server crashed {} times, giving up", restarts + 1);
+                return give_up(&mut client_send, &message).await;
+            }
+            let backoff = backoff(restarts);
+            restarts += 1;
+            tracing::warn!("server crashed or hung, restarting in {:?}", backoff);
+            tokio::time::sleep(backoff).await;
+        }
+        let next = match &ctx.standby {
+            Some(standby) => standby.take(ctx, query),
+            None => proxy::spawn_server(ctx, query),
+        };
+        server = match next {
+            Ok(conn) => Server::from(conn),
+            Err(err) => {
+                tracing::error!("failed to restart server: {}", err);
+                let message = format!("Language server failed to start: {}", err);
+                return give_up(&mut client_send, &message).await;
+            }
+        };
+        replay(&mut server, &state, &mut client_send).await?;
+    }
+}
+
+// Show the error to the user, and end the connection with `FAILED_METHOD`.
+async fn give_up<W>(
+    client_send: &mut FramedWrite<W, LspFrameCodec>,
+    message: &str,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let show = json!({
+        "jsonrpc": "2.0",
+        "method": "window/showMessage",
+        "params": {"type": 1, "message": message},
+    });
+    client_send.send(show.to_string()).await?;
+    let failed = json!({"jsonrpc": "2.0", "method": FAILED_METHOD, "params": {}});
+    client_send.send(failed.to_string()).await?;
+    Ok(())
+}
+
+// Send the state to the new server, forwarding anything but the response to `initialize`.
+async fn replay<W>(
+    server: &mut Server,
+    state: &State,
+    client_send: &mut FramedWrite<W, LspFrameCodec>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    for text in reinitialize(&mut server.send, &mut server.recv, state).await? {
+        client_send.send(text).await?;
+    }
+    tracing::info!("restarted server with {} documents", state.documents.len());
+    Ok(())
+}
+
+/// Bring a new server to the state, and return the messages it sent before responding to
+/// `initialize`.
+pub(super) async fn reinitialize(
+    send: &mut FramedWrite<backend::Writer, LspFrameCodec>,
+    recv: &mut FramedRead<backend::Reader, LspFrameCodec>,
+    state: &State,
+) -> Result<Vec<String>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut received = Vec::new();
+    let mut messages = state.replay().into_iter();
+    if state.initialize.is_some() {
+        if let Some(initialize) = messages.next() {
+            send.send(initialize.to_string()).await?;
+        }
+        while let Some(text) = recv.next().await {
+            let text = match text {
+                Ok(text) => text,
+                Err(_) => continue,
+            };
+            let is_response = serde_json::from_str::<Value>(&text)
+                .map_or(false, |msg| msg.get("id") == Some(&json!(INITIALIZE_ID)));
+            if is_response {
+                break;
+            }
+            received.push(text);
+        }
+    }
+    for msg in messages {
+        send.send(msg.to_string()).await?;
+    }
+    Ok(received)
+}
+
+// Delay before restarting a server that crashed after `restarts` restarts in a row.
+fn backoff(restarts: u32) -> Duration {
+    // The exponent is capped so a large `--max-restarts` doesn't overflow.
+    (INITIAL_BACKOFF * 2u32.pow(restarts.min(16))).min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), INITIAL_BACKOFF);
+        assert_eq!(backoff(2), INITIAL_BACKOFF * 4);
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_probe() {
+        let mut probe = Probe::default();
+        let first = probe.next();
+        probe.next();
+        assert_eq!(probe.unanswered, 2);
+        assert!(!probe.on_server(&json!({"jsonrpc": "2.0", "id": 1, "result": null})));
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": first["id"],
+            "error": {"code": -32601, "message": "method not found"}
+        });
+        assert!(probe.on_server(&response));
+        assert_eq!(probe.unanswered, 0);
+    }
+
+    #[test]
+    fn test_replay_state() {
+        let mut state = State::default();
+        state.on_client(&json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}));
+        state.on_server(&json!({"jsonrpc": "2.0", "id": 1, "result": {}}));
+        state.on_client(&json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}));
+        state.on_client(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {
+                "uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": "a"
+            }}
+        }));
+        state.on_client(&json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": {"uri": "file:///a.rs", "version": 2},
+                "contentChanges": [{"text": "b"}]
+            }
+        }));
+        state.on_client(&json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover"}));
+
+        let replay = state.replay();
+        assert_eq!(replay.len(), 3);
+        assert_eq!(replay[0]["id"], json!(INITIALIZE_ID));
+        assert_eq!(replay[1]["method"], json!("initialized"));
+        assert_eq!(replay[2]["params"]["textDocument"]["text"], json!("b"));
+        assert_eq!(replay[2]["params"]["textDocument"]["version"], json!(2));
+
+        let failed = state.fail_pending();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0]["id"], json!(2));
+        assert!(state.pending.is_empty());
+    }
+}
@@ -0,0 +1,453 @@
+//! Keep the server of a session for a while after the client disconnects, so the client can
+//! reconnect to it.
+//!
+//! The client receives the notification `$/lsp-ws-proxy/session` with the token of the session
+//! when it connects, and resumes the session by reconnecting with the query parameter `session`.
+//! Messages from the server while the client is away are buffered and replayed when it resumes.
+//!
+//! With a `persist::Store`, sessions are also saved so they can be resumed with a new server after
+//! the proxy restarts.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{future, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::{DuplexStream, ReadHalf, WriteHalf},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::Instrument;
+use warp::{Filter, Rejection};
+
+use crate::{
+    backend,
+    lsp::{self, framed::LspFrameCodec},
+};
+
+use super::{
+    persist,
+    proxy::SharedContext,
+    restart::{self, State},
+    shutdown::Shutdown,
+};
+
+/// Method of the notification with the token of the session.
+const SESSION_METHOD: &str = "$/lsp-ws-proxy/session";
+/// Time between saves of a session that changed.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sessions that can be resumed.
+#[derive(Debug, Clone)]
+pub struct Sessions {
+    /// Time to keep the session after the client disconnects.
+    timeout: Duration,
+    /// Maximum size in bytes of the messages buffered while the client is away.
+    buffer: usize,
+    /// Sender of the streams of the clients attaching to each session.
+    sessions: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<DuplexStream>>>>,
+    /// Where the sessions are saved.
+    store: Option<persist::Store>,
+    /// Saved sessions are kept when the proxy shuts down.
+    shutdown: Option<Shutdown>,
+}
+
+/// Rejection for a token of a session that ended or never existed.
+#[derive(Debug)]
+pub(super) struct UnknownSession;
+
+impl warp::reject::Reject for UnknownSession {}
+
+impl Sessions {
+    pub fn new(timeout: Duration, buffer: usize) -> Self {
+        Self {
+            timeout,
+            buffer,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            store: None,
+            shutdown: None,
+        }
+    }
+
+    /// Save the sessions to `store`, and keep them when the proxy shuts down.
+    pub fn with_store(mut self, store: persist::Store, shutdown: Shutdown) -> Self {
+        self.store = Some(store);
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Start a session with the server.
+    pub(super) fn start(&self, server: backend::Connection) -> backend::Connection {
+        let token = uuid::Uuid::new_v4().to_string();
+        self.spawn(token, Server::from(server), State::default(), Vec::new())
+    }
+
+    /// Resume the saved session with `token` on a new server.
+    pub(super) async fn restore(
+        &self,
+        token: &str,
+        server: backend::Connection,
+    ) -> Result<backend::Connection, std::io::Error> {
+        let store = self.store.as_ref().ok_or_else(unknown_session)?;
+        let state = store.load(token).await?;
+        let mut server = Server::from(server);
+        let received = restart::reinitialize(&mut server.send, &mut server.recv, &state)
+            .await
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))?;
+        tracing::info!("restored saved session {}", token);
+        Ok(self.spawn(token.to_owned(), server, state, received))
+    }
+
+    fn spawn(
+        &self,
+        token: String,
+        server: Server,
+        state: State,
+        received: Vec<String>,
+    ) -> backend::Connection {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (front, back) = tokio::io::duplex(64 * 1024);
+        tx.send(back).expect("session receiver");
+        self.sessions
+            .lock()
+            .expect("lock sessions")
+            .insert(token.clone(), tx);
+
+        let this = self.clone();
+        tokio::spawn(
+            async move {
+                let mut state = state;
+                if let Err(err) = this.run(&token, server, &mut state, received, rx).await {
+                    tracing::error!("session error: {}", err);
+                }
+                this.end(&token, &state).await;
+                this.sessions.lock().expect("lock sessions").remove(&token);
+                tracing::info!("session {} ended", token);
+            }
+            .in_current_span(),
+        );
+        connection(front)
+    }
+
+    /// Attach to the session with `token`.
+    pub(super) fn resume(&self, token: &str) -> Result<backend::Connection, std::io::Error> {
+        let (front, back) = tokio::io::duplex(64 * 1024);
+        let sessions = self.sessions.lock().expect("lock sessions");
+        sessions
+            .get(token)
+            .and_then(|tx| tx.send(back).ok())
+            .ok_or_else(unknown_session)?;
+        Ok(connection(front))
+    }
+
+    /// The session with `token` isn't running, but was saved and can be restored.
+    pub(super) fn is_saved(&self, token: &str) -> bool {
+        !self.contains(token)
+            && self
+                .store
+                .as_ref()
+                .map_or(false, |store| store.contains(token, self.timeout))
+    }
+
+    fn contains(&self, token: &str) -> bool {
+        self.sessions
+            .lock()
+            .expect("lock sessions")
+            .contains_key(token)
+    }
+}
+
+/// Token from the query parameter `session`, rejected with `UnknownSession` unless resumable.
+pub(super) fn with_token(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .or_else(|_| async { Ok::<_, Rejection>((HashMap::new(),)) })
+        .and_then(move |mut query: HashMap<String, String>| {
+            let resume = ctx.get().resume;
+            async move {
+                match (query.remove("session"), resume) {
+                    (Some(token), Some(sessions))
+                        if sessions.contains(&token) || sessions.is_saved(&token) =>
+                    {
+                        Ok(Some(token))
+                    }
+                    (Some(_), Some(_)) => Err(warp::reject::custom(UnknownSession)),
+                    _ => Ok(None),
+                }
+            }
+        })
+}
+
+fn unknown_session() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "unknown session")
+}
+
+fn connection(stream: DuplexStream) -> backend::Connection {
+    let (reader, writer) = tokio::io::split(stream);
+    backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    }
+}
+
+struct Server {
+    send: FramedWrite<backend::Writer, LspFrameCodec>,
+    recv: FramedRead<backend::Reader, LspFrameCodec>,
+    _child: Option<backend::Process>,
+}
+
+impl From<backend::Connection> for Server {
+    fn from(conn: backend::Connection) -> Self {
+        Self {
+            send: lsp::framed::writer(conn.writer),
+            recv: lsp::framed::reader(conn.reader),
+            _child: conn.child,
+        }
+    }
+}
+
+struct Client {
+    send: FramedWrite<WriteHalf<DuplexStream>, LspFrameCodec>,
+    recv: FramedRead<ReadHalf<DuplexStream>, LspFrameCodec>,
+}
+
+/// Messages from the server while the client is away, dropping the oldest over the limit.
+#[derive(Debug, Default)]
+struct Buffer {
+    messages: VecDeque<String>,
+    size: usize,
+    limit: usize,
+}
+
+impl Buffer {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    fn push(&mut self, text: String) {
+        self.size += text.len();
+        self.messages.push_back(text);
+        while self.size > self.limit {
+            match self.messages.pop_front() {
+                Some(dropped) => self.size -= dropped.len(),
+                None => break,
+            }
+        }
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = String> + '_ {
+        self.size = 0;
+        self.messages.drain(..)
+    }
+}
+
+// Next message from the attached client, or `None` if it disconnected.
+async fn next_message(client: &mut Option<Client>) -> Option<String> {
+    let client = match client {
+        Some(client) => client,
+        None => return future::pending().await,
+    };
+    while let Some(msg) = client.recv.next().await {
+        if let Ok(text) = msg {
+            return Some(text);
+        }
+    }
+    None
+}
+
+impl Sessions {
+    async fn run(
+        &self,
+        token: &str,
+        server: Server,
+        state: &mut State,
+        received: Vec<String>,
+        mut attach: mpsc::UnboundedReceiver<DuplexStream>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let timeout = self.timeout;
+        let Server {
+            send: mut server_send,
+            recv: mut server_recv,
+            _child,
+        } = server;
+        let mut client: Option<Client> = None;
+        let mut buffer = Buffer::new(self.buffer);
+        for text in received {
+            buffer.push(text);
+        }
+        let mut deadline = Instant::now() + timeout;
+        // Save new sessions right away.
+        let mut dirty = self.store.is_some();
+        let mut save = tokio::time::interval(SAVE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                stream = attach.recv() => {
+                    let stream = match stream {
+                        Some(stream) => stream,
+                        None => break,
+                    };
+                    let (reader, writer) = tokio::io::split(stream);
+                    let mut attached = Client {
+                        send: lsp::framed::writer(writer),
+                        recv: lsp::framed::reader(reader),
+                    };
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": SESSION_METHOD,
+                        "params": {"token": token},
+                    });
+                    attached.send.send(notification.to_string()).await?;
+                    for text in buffer.drain() {
+                        attached.send.send(text).await?;
+                    }
+                    tracing::info!("client attached to session {}", token);
+                    client = Some(attached);
+                }
+
+                msg = server_recv.next() => match msg {
+                    Some(Ok(text)) => match client.as_mut() {
+                        Some(attached) => {
+                            // Keep it for the next client if this one disconnected.
+                            if attached.send.send(text.clone()).await.is_err() {
+                                buffer.push(text);
+                            }
+                        }
+                        None => buffer.push(text),
+                    },
+                    Some(Err(err)) => tracing::warn!("invalid message from server: {}", err),
+                    None => {
+                        tracing::error!("server of session {} exited", token);
+                        break;
+                    }
+                },
+
+                msg = next_message(&mut client) => match msg {
+                    Some(text) => {
+                        if self.store.is_some() {
+                            if let Ok(msg) = serde_json::from_str::<Value>(&text) {
+                                state.on_client(&msg);
+                                dirty = true;
+                            }
+                        }
+                        let exit = is_exit(&text);
+                        server_send.send(text).await?;
+                        // The client ended the session.
+                        if exit {
+                            break;
+                        }
+                    }
+                    None => {
+                        client = None;
+                        deadline = Instant::now() + timeout;
+                        tracing::info!("client detached, keeping session {} for {:?}", token, timeout);
+                    }
+                },
+
+                _ = tokio::time::sleep_until(deadline), if client.is_none() => {
+                    tracing::info!("session {} expired", token);
+                    break;
+                }
+
+                _ = save.tick(), if dirty => {
+                    self.save(token, state).await;
+                    dirty = false;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn save(&self, token: &str, state: &State) {
+        if let Some(store) = &self.store {
+            if let Err(err) = store.save(token, state).await {
+                tracing::warn!("failed to save session {}: {}", token, err);
+            }
+        }
+    }
+
+    // Keep the saved session if the proxy is shutting down, and remove it otherwise.
+    async fn end(&self, token: &str, state: &State) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        if self.shutdown.as_ref().map_or(false, Shutdown::is_stopping) {
+            self.save(token, state).await;
+            tracing::info!("saved session {} to resume after restart", token);
+        } else {
+            store.remove(token).await;
+        }
+    }
+}
+
+fn is_exit(text: &str) -> bool {
+    serde_json::from_str::<Value>(text)
+        .ok()
+        .and_then(|msg| {
+            msg.get("method")
+                .and_then(Value::as_str)
+                .map(|m| m == "exit")
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resume_session() {
+        let sessions = Sessions::new(Duration::from_secs(60), 1024);
+        // Echo server
+        let (server, echo) = tokio::io::duplex(1024);
+        let (echo_reader, echo_writer) = tokio::io::split(echo);
+        tokio::spawn(async move {
+            let mut recv = lsp::framed::reader(echo_reader);
+            let mut send = lsp::framed::writer(echo_writer);
+            while let Some(Ok(text)) = recv.next().await {
+                send.send(text).await.unwrap();
+            }
+        });
+
+        let conn = sessions.start(connection(server));
+        let mut recv = lsp::framed::reader(conn.reader);
+        let notification: Value =
+            serde_json::from_str(&recv.next().await.unwrap().unwrap()).unwrap();
+        assert_eq!(notification["method"], json!(SESSION_METHOD));
+        let token = notification["params"]["token"].as_str().unwrap().to_owned();
+        drop(conn.writer);
+        drop(recv);
+
+        let conn = sessions.resume(&token).unwrap();
+        let mut recv = lsp::framed::reader(conn.reader);
+        let mut send = lsp::framed::writer(conn.writer);
+        recv.next().await.unwrap().unwrap();
+        let ping = r#"{"jsonrpc":"2.0","method":"ping"}"#;
+        send.send(ping.to_owned()).await.unwrap();
+        assert_eq!(recv.next().await.unwrap().unwrap(), ping);
+
+        assert!(sessions.resume("unknown").is_err());
+    }
+
+    #[test]
+    fn test_buffer_drops_oldest() {
+        let mut buffer = Buffer::new(8);
+        buffer.push("first".to_owned());
+        buffer.push("second".to_owned());
+        assert_eq!(buffer.drain().collect::<Vec<_>>(), vec!["second"]);
+        assert_eq!(buffer.size, 0);
+
+        buffer.push("oversized".to_owned());
+        assert_eq!(buffer.drain().count(), 0);
+    }
+}
@@ -0,0 +1,154 @@
+//! Start the server in the workspace of the client.
+//!
+//! The server is started when the `initialize` request arrives, in the directory of `rootUri`
+//! (or the first of `workspaceFolders`) after remapping. The configured directory is used if the
+//! workspace is not an existing local directory.
+use std::path::PathBuf;
+
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde_json::Value;
+use tracing::Instrument;
+use url::Url;
+
+use crate::{backend, lsp};
+
+use super::{
+    fallback,
+    proxy::{self, Context, Query},
+};
+
+/// Start the server selected by `query` in the workspace from `initialize`.
+pub(super) fn connect(
+    ctx: &Context,
+    query: Option<&Query>,
+) -> Result<backend::Connection, std::io::Error> {
+    let (front, back) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(back);
+    let ctx = ctx.clone();
+    let query = query.cloned();
+    tokio::spawn(
+        async move {
+            if let Err(err) = forward(ctx, query, reader, writer).await {
+                tracing::error!("connection error: {}", err);
+            }
+        }
+        .in_current_span(),
+    );
+    let (reader, writer) = tokio::io::split(front);
+    Ok(backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    })
+}
+
+enum Event {
+    Client(Option<String>),
+    Server(Option<String>),
+}
+
+async fn forward<R, W>(
+    mut ctx: Context,
+    query: Option<Query>,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut client_send = lsp::framed::writer(writer);
+    let mut client_recv = lsp::framed::reader(reader).filter_map(|msg| future::ready(msg.ok()));
+    let first = match client_recv.next().await {
+        Some(text) => text,
+        None => return Ok(()),
+    };
+
+    let query = query.as_ref();
+    if let Some(dir) = workspace_dir(&first) {
+        let name = query.map_or_else(|| ctx.servers[0].name.clone(), |q| q.name.clone());
+        if let Some(server) = ctx.servers.iter_mut().find(|s| s.name == name) {
+            tracing::info!("starting {} in workspace {}", name, dir.display());
+            server.cwd = Some(dir);
+        }
+    }
+    let fallback = ctx
+        .servers
+        .iter()
+        .find(|s| query.map_or(true, |q| s.name == q.name))
+        .and_then(|s| s.fallback.clone());
+    let server = match fallback {
+        Some(fallback) => fallback::connect(&ctx, query, &fallback)?,
+        None => proxy::spawn_server(&ctx, query)?,
+    };
+    let backend::Connection {
+        reader,
+        writer,
+        child: _child,
+    } = server;
+    let mut server_send = lsp::framed::writer(writer);
+    server_send.send(first).await?;
+
+    let client_recv = client_recv
+        .map(|text| Event::Client(Some(text)))
+        .chain(stream::once(future::ready(Event::Client(None))));
+    let server_recv = lsp::framed::reader(reader)
+        .filter_map(|msg| future::ready(msg.ok()))
+        .map(|text| Event::Server(Some(text)))
+        .chain(stream::once(future::ready(Event::Server(None))));
+    let mut events = stream::select(client_recv, server_recv);
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Client(Some(text)) => server_send.send(text).await?,
+            Event::Server(Some(text)) => client_send.send(text).await?,
+            Event::Client(None) | Event::Server(None) => break,
+        }
+    }
+    Ok(())
+}
+
+// Local directory of the workspace in `initialize`.
+fn workspace_dir(text: &str) -> Option<PathBuf> {
+    let msg: Value = serde_json::from_str(text).ok()?;
+    if msg.get("method").and_then(Value::as_str) != Some("initialize") {
+        return None;
+    }
+    let uri = ["/params/rootUri", "/params/workspaceFolders/0/uri"]
+        .iter()
+        .find_map(|pointer| msg.pointer(pointer).and_then(Value::as_str))?;
+    let dir = Url::parse(uri).ok()?.to_file_path().ok()?;
+    if dir.is_dir() {
+        Some(dir)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace_dir() {
+        let dir = std::env::temp_dir();
+        let uri = Url::from_directory_path(&dir).unwrap();
+        let initialize = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {"rootUri": uri.as_str()}
+        });
+        assert_eq!(
+            workspace_dir(&initialize.to_string()).map(|d| d.canonicalize().unwrap()),
+            Some(dir.canonicalize().unwrap())
+        );
+
+        let missing = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {"rootUri": "file:///lsp-ws-proxy/missing"}
+        });
+        assert_eq!(workspace_dir(&missing.to_string()), None);
+    }
+}
@@ -0,0 +1,142 @@
+//! Keeping the paths of the sync API in the workspace and in the directories allowed with
+//! `--sync-root`.
+//!
+//! Paths from the clients are checked to be in the workspace after following the symlinks of the
+//! parts that exist, so a symlink in the workspace, created with an archive or by a server, can't
+//! be used to read or write outside of it. With `--sync-root <dir>`, the workspaces themselves must
+//! be in one of the directories, and documents are only written on save in the workspace of the
+//! session, so a misconfigured `--cwd` or a client can't expose the rest of the host.
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+/// Rejection for a workspace outside of the directories allowed with `--sync-root`.
+#[derive(Debug)]
+pub(super) struct OutsideRoots;
+
+impl warp::reject::Reject for OutsideRoots {}
+
+/// Directories the sync API can touch, canonicalized. Any directory if empty.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Roots {
+    dirs: Vec<PathBuf>,
+}
+
+impl Roots {
+    /// Allow the existing `dirs`.
+    pub fn new(dirs: &[PathBuf]) -> io::Result<Self> {
+        let dirs = dirs
+            .iter()
+            .map(|dir| {
+                dir.canonicalize().map_err(|err| {
+                    io::Error::new(err.kind(), format!("{}: {}", dir.display(), err))
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { dirs })
+    }
+
+    /// Whether any directory was allowed.
+    pub(super) fn is_set(&self) -> bool {
+        !self.dirs.is_empty()
+    }
+
+    /// Whether the sync API can touch the files in `workspace`.
+    pub(super) fn allows(&self, workspace: &Path) -> bool {
+        !self.is_set()
+            || workspace.canonicalize().map_or(false, |workspace| {
+                self.dirs.iter().any(|dir| workspace.starts_with(dir))
+            })
+    }
+}
+
+/// The absolute `path` with the symlinks of its existing ancestors resolved, if it's in
+/// `workspace` before and after resolving them.
+///
+/// Dangling symlinks are rejected, writing to them would create their target.
+pub(super) fn resolve(workspace: &Path, path: &Path) -> Option<PathBuf> {
+    if !path.starts_with(workspace) || path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    let workspace = workspace.canonicalize().ok()?;
+    let mut existing = path;
+    let mut missing = Vec::new();
+    let resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound
+                    && std::fs::symlink_metadata(existing).is_err() =>
+            {
+                missing.push(existing.file_name()?);
+                existing = existing.parent()?;
+            }
+            Err(_) => return None,
+        }
+    };
+    let resolved = missing
+        .into_iter()
+        .rev()
+        .fold(resolved, |path, name| path.join(name));
+    Some(resolved).filter(|resolved| resolved.starts_with(&workspace))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_resolve() {
+        let dir = temp_dir();
+        let workspace = dir.join("workspace");
+        std::fs::create_dir_all(workspace.join("src")).unwrap();
+        std::fs::write(workspace.join("src/main.rs"), "").unwrap();
+        let canonical = workspace.canonicalize().unwrap();
+
+        assert_eq!(
+            resolve(&workspace, &workspace.join("src/main.rs")),
+            Some(canonical.join("src/main.rs"))
+        );
+        assert_eq!(
+            resolve(&workspace, &workspace.join("new/lib.rs")),
+            Some(canonical.join("new/lib.rs"))
+        );
+        assert_eq!(resolve(&workspace, &workspace), Some(canonical));
+        assert_eq!(resolve(&workspace, &workspace.join("../secret")), None);
+        assert_eq!(resolve(&workspace, &dir.join("secret")), None);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&dir, workspace.join("escape")).unwrap();
+            std::os::unix::fs::symlink(dir.join("missing"), workspace.join("dangling")).unwrap();
+            std::os::unix::fs::symlink("src", workspace.join("inside")).unwrap();
+            assert_eq!(resolve(&workspace, &workspace.join("escape/secret")), None);
+            assert_eq!(resolve(&workspace, &workspace.join("dangling")), None);
+            assert!(resolve(&workspace, &workspace.join("inside/main.rs")).is_some());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_roots() {
+        let dir = temp_dir();
+        let (allowed, other) = (dir.join("allowed"), dir.join("other"));
+        std::fs::create_dir_all(allowed.join("session")).unwrap();
+        std::fs::create_dir_all(&other).unwrap();
+
+        let roots = Roots::new(&[allowed.clone()]).unwrap();
+        assert!(roots.allows(&allowed.join("session")));
+        assert!(!roots.allows(&other));
+        assert!(!roots.allows(&allowed.join("missing")));
+        assert!(Roots::default().allows(&other));
+        assert!(Roots::new(&[dir.join("missing")]).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+//! [Rhai] scripts modifying the messages of the sessions of a server.
+//!
+//! The script defines `on_client_message(msg)` and/or `on_server_message(msg)`, called with each
+//! message as an object map. The function returns the message to forward, modified or not, or `()`
+//! to drop it.
+//!
+//! ```text
+//! fn on_server_message(msg) {
+//!     if msg.method == "telemetry/event" {
+//!         return ();
+//!     }
+//!     msg
+//! }
+//! ```
+//!
+//! Scripts run as middlewares after the plugins of the server, and a message is dropped if the
+//! script fails.
+//!
+//! [Rhai]: https://rhai.rs/
+use std::{collections::HashMap, path::PathBuf, str::FromStr, sync::Arc};
+
+use crate::lsp::Message;
+
+use super::{
+    middleware::{Middleware, Middlewares, Outbox},
+    proxy::Server,
+};
+
+/// Script of the server `name`, given with `--script`.
+///
+/// ```text
+/// rust-analyzer=/etc/lsp-ws-proxy/rust.rhai
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptOption {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for ScriptOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && !path.is_empty() => Ok(Self {
+                name: name.to_owned(),
+                path: PathBuf::from(path),
+            }),
+            _ => Err(format!("expected <name>=<path>, got {}", s)),
+        }
+    }
+}
+
+/// Scripts of the servers, compiled by path.
+#[derive(Debug, Clone, Default)]
+pub struct Scripts {
+    loaded: HashMap<PathBuf, Script>,
+}
+
+impl Scripts {
+    /// Compile the scripts of `servers`.
+    pub fn load(servers: &[Server]) -> Result<Self, String> {
+        let mut loaded = HashMap::new();
+        for path in servers.iter().filter_map(|server| server.script.as_ref()) {
+            if loaded.contains_key(path) {
+                continue;
+            }
+            let compiled = engine::Compiled::load(path)
+                .map_err(|err| format!("failed to load script {}: {}", path.display(), err))?;
+            tracing::info!("loaded script {}", path.display());
+            let script = Script {
+                path: path.clone(),
+                compiled: Arc::new(compiled),
+            };
+            loaded.insert(path.clone(), script);
+        }
+        Ok(Self { loaded })
+    }
+
+    /// Add the script of `server` to `middlewares`.
+    pub(super) fn apply(&self, server: &Server, middlewares: &mut Middlewares) {
+        if let Some(script) = server
+            .script
+            .as_ref()
+            .and_then(|path| self.loaded.get(path))
+        {
+            middlewares.push(script.clone());
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Script {
+    path: PathBuf,
+    compiled: Arc<engine::Compiled>,
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+impl Script {
+    fn run(&self, hook: &str, msg: Message) -> Option<Message> {
+        match self.compiled.call(hook, &msg) {
+            Ok(Some(msg)) => Some(msg),
+            Ok(None) => {
+                tracing::debug!("script {} dropped the message", self.path.display());
+                None
+            }
+            Err(err) => {
+                tracing::error!("script {} failed: {}", self.path.display(), err);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Script {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        self.run("on_client_message", msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        self.run("on_server_message", msg)
+    }
+}
+
+#[cfg(feature = "script")]
+mod engine {
+    use std::{collections::HashSet, path::Path};
+
+    use rhai::{Dynamic, Engine, Scope, AST};
+
+    use crate::lsp::Message;
+
+    /// Operations a call can run before it's stopped, so a script can't hang the session.
+    const MAX_OPERATIONS: u64 = 1_000_000;
+
+    pub(super) struct Compiled {
+        engine: Engine,
+        ast: AST,
+        hooks: HashSet<String>,
+    }
+
+    impl Compiled {
+        pub(super) fn load(path: &Path) -> Result<Self, String> {
+            let mut engine = Engine::new();
+            engine.set_max_operations(MAX_OPERATIONS);
+            let ast = engine
+                .compile_file(path.to_path_buf())
+                .map_err(|err| err.to_string())?;
+            let hooks = ast.iter_functions().map(|f| f.name.to_owned()).collect();
+            Ok(Self { engine, ast, hooks })
+        }
+
+        /// Call `hook` with `msg`, returning `None` if it dropped the message. The message is
+        /// forwarded unchanged if the script doesn't define the hook.
+        pub(super) fn call(&self, hook: &str, msg: &Message) -> Result<Option<Message>, String> {
+            if !self.hooks.contains(hook) {
+                return Ok(Some(msg.clone()));
+            }
+            let arg = rhai::serde::to_dynamic(msg).map_err(|err| err.to_string())?;
+            let result: Dynamic = self
+                .engine
+                .call_fn(&mut Scope::new(), &self.ast, hook, (arg,))
+                .map_err(|err| err.to_string())?;
+            if result.is::<()>() {
+                return Ok(None);
+            }
+            rhai::serde::from_dynamic(&result)
+                .map(Some)
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
+#[cfg(not(feature = "script"))]
+mod engine {
+    use std::path::Path;
+
+    use crate::lsp::Message;
+
+    pub(super) struct Compiled;
+
+    impl Compiled {
+        pub(super) fn load(_path: &Path) -> Result<Self, String> {
+            Err("built without the script feature".to_owned())
+        }
+
+        pub(super) fn call(&self, _hook: &str, msg: &Message) -> Result<Option<Message>, String> {
+            Ok(Some(msg.clone()))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "script"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let path = std::env::temp_dir().join(format!("lsp-ws-proxy-{}.rhai", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &path,
+            r#"
+fn on_server_message(msg) {
+    if msg.method == "telemetry/event" {
+        return ();
+    }
+    msg
+}
+"#,
+        )
+        .unwrap();
+        let compiled = engine::Compiled::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let telemetry =
+            Message::from_str(r#"{"jsonrpc":"2.0","method":"telemetry/event","params":{}}"#)
+                .unwrap();
+        assert_eq!(compiled.call("on_server_message", &telemetry), Ok(None));
+        // Not defined
+        assert_eq!(
+            compiled.call("on_client_message", &telemetry),
+            Ok(Some(telemetry.clone()))
+        );
+
+        let log = Message::from_str(
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{"type":3,"message":"hi"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            compiled.call("on_server_message", &log),
+            Ok(Some(log.clone()))
+        );
+    }
+}
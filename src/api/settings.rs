@@ -0,0 +1,144 @@
+//! Answering `workspace/configuration` of a server from its settings in the config file.
+//!
+//! Thin web clients often don't implement `workspace/configuration`, leaving servers that ask for
+//! their settings with errors or defaults. With `settings` of a server, the proxy responds to the
+//! requests itself without round-tripping to the client, and tells the server the client supports
+//! them in `initialize`.
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Add a middleware answering `workspace/configuration` from `settings` to `middlewares`.
+pub(super) fn apply(settings: Option<&Value>, middlewares: &mut Middlewares) {
+    if let Some(settings) = settings {
+        middlewares.push(Settings(settings.clone()));
+    }
+}
+
+struct Settings(Value);
+
+impl Settings {
+    /// Advertise `workspace.configuration` in the capabilities of `initialize` in `msg`.
+    fn on_client(&self, msg: &mut Value) -> bool {
+        if msg.get("method").and_then(Value::as_str) != Some("initialize") {
+            return false;
+        }
+        match msg.pointer_mut("/params/capabilities") {
+            Some(Value::Object(capabilities)) => {
+                let workspace = capabilities.entry("workspace").or_insert_with(|| json!({}));
+                if let Value::Object(workspace) = workspace {
+                    workspace.insert("configuration".to_owned(), Value::Bool(true));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The response to `msg` if it's `workspace/configuration`.
+    fn on_server(&self, msg: &Value) -> Option<Value> {
+        if msg.get("method").and_then(Value::as_str) != Some("workspace/configuration") {
+            return None;
+        }
+        let id = msg.get("id")?;
+        let items = msg
+            .pointer("/params/items")
+            .and_then(Value::as_array)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let result = items
+            .iter()
+            .map(|item| match item.get("section").and_then(Value::as_str) {
+                Some(section) => self.section(section).cloned().unwrap_or(Value::Null),
+                None => self.0.clone(),
+            })
+            .collect::<Vec<_>>();
+        Some(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+    }
+
+    /// The settings of `section`, like `python.analysis`, either nested or with the dotted key.
+    fn section(&self, section: &str) -> Option<&Value> {
+        if let Some(value) = self.0.get(section) {
+            return Some(value);
+        }
+        section
+            .split('.')
+            .try_fold(&self.0, |value, key| value.get(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Settings {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Request(_)) {
+            return Some(msg);
+        }
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        if self.on_client(&mut value) {
+            return Some(serde_json::from_value(value).unwrap_or(msg));
+        }
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Request(_) | Message::Unknown(_)) {
+            return Some(msg);
+        }
+        let response = serde_json::to_value(&msg)
+            .ok()
+            .and_then(|value| self.on_server(&value))
+            .and_then(|response| serde_json::from_value(response).ok());
+        match response {
+            Some(response) => {
+                tracing::debug!("answered workspace/configuration from the settings");
+                outbox.send_to_server(response);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let settings = Settings(json!({
+            "python": {"analysis": {"typeCheckingMode": "basic"}},
+            "editor.tabSize": 4,
+        }));
+        let mut initialize = json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {
+            "capabilities": {},
+        }});
+        assert!(settings.on_client(&mut initialize));
+        assert_eq!(
+            initialize["params"]["capabilities"],
+            json!({"workspace": {"configuration": true}})
+        );
+
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "workspace/configuration", "params": {
+            "items": [
+                {"section": "python.analysis"},
+                {"scopeUri": "file:///a.py", "section": "editor.tabSize"},
+                {"section": "rust-analyzer"},
+            ],
+        }});
+        assert_eq!(
+            settings.on_server(&request),
+            Some(json!({"jsonrpc": "2.0", "id": 1, "result": [
+                {"typeCheckingMode": "basic"},
+                4,
+                null,
+            ]}))
+        );
+        let hover = json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover"});
+        assert_eq!(settings.on_server(&hover), None);
+    }
+}
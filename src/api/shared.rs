@@ -0,0 +1,774 @@
+//! Share one server process between connections.
+//!
+//! The first `initialize` is sent to the server and the result is reused for later connections.
+//! Requests from the clients are namespaced (`"<client>:<id>"`) so the responses can be routed
+//! back, notifications from the server are sent to every client, and requests from the server
+//! are sent to the oldest client. Documents are opened once, and closed when the last client
+//! with the document open closes it or disconnects.
+//!
+//! With `--process shared`, connections use the process one at a time. Later connections
+//! receive the result of the first `initialize`, and the workspace folder is replaced with
+//! `workspace/didChangeWorkspaceFolders` if the workspace is different.
+//!
+//! With replicas, each server has several processes and connections are distributed between them.
+//! A replica that crashed is taken out of rotation for a while.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{future, stream, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{backend, lsp};
+
+use super::proxy::{self, Context, Query};
+
+/// How connections share servers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Share {
+    /// One process for each server, kept running.
+    Server,
+    /// One process for each server and workspace (`rootUri` in `initialize`),
+    /// stopped when the last connection closes.
+    Workspace,
+}
+
+impl FromStr for Share {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "server" => Ok(Self::Server),
+            "workspace" => Ok(Self::Workspace),
+            _ => Err(format!("expected server or workspace, got {}", s)),
+        }
+    }
+}
+
+/// Lifetime of the server processes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Process {
+    /// Start a process for each connection.
+    PerConnection,
+    /// Reuse one process for each server, used by one connection at a time.
+    Shared,
+}
+
+impl FromStr for Process {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "per-connection" => Ok(Self::PerConnection),
+            "shared" => Ok(Self::Shared),
+            _ => Err(format!("expected per-connection or shared, got {}", s)),
+        }
+    }
+}
+
+/// How connections are distributed between the replicas of a server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Balance {
+    RoundRobin,
+    LeastConnections,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-connections" => Ok(Self::LeastConnections),
+            _ => Err(format!(
+                "expected round-robin or least-connections, got {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Time a crashed replica is out of rotation.
+const REPLICA_DOWN_FOR: Duration = Duration::from_secs(10);
+
+/// Running shared servers.
+#[derive(Debug, Clone)]
+pub struct Hubs {
+    share: Share,
+    replicas: usize,
+    balance: Balance,
+    /// Connections wait for their turn to use the hub.
+    exclusive: bool,
+    turns: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    hubs: Arc<Mutex<HashMap<String, Running>>>,
+    /// When each crashed replica stopped.
+    down: Arc<Mutex<HashMap<String, Instant>>>,
+    next_client: Arc<AtomicUsize>,
+    next_replica: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+struct Running {
+    /// ID of the client that started the hub.
+    started_by: usize,
+    tx: mpsc::UnboundedSender<Command>,
+    /// Number of attached connections.
+    sessions: Arc<AtomicUsize>,
+}
+
+impl Hubs {
+    pub fn new(share: Share) -> Self {
+        Self {
+            share,
+            replicas: 1,
+            balance: Balance::RoundRobin,
+            exclusive: false,
+            turns: Arc::new(Mutex::new(HashMap::new())),
+            hubs: Arc::new(Mutex::new(HashMap::new())),
+            down: Arc::new(Mutex::new(HashMap::new())),
+            next_client: Arc::new(AtomicUsize::new(0)),
+            next_replica: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Run `replicas` processes for each hub and distribute connections with `balance`.
+    pub fn with_replicas(mut self, replicas: usize, balance: Balance) -> Self {
+        self.replicas = replicas.max(1);
+        self.balance = balance;
+        self
+    }
+
+    /// Let one connection at a time use each hub.
+    pub fn exclusive(mut self) -> Self {
+        self.exclusive = true;
+        self
+    }
+
+    // Wait for the turn of the connection to use the hub, if exclusive.
+    async fn turn(&self, key: &str) -> Option<tokio::sync::OwnedMutexGuard<()>> {
+        if !self.exclusive {
+            return None;
+        }
+        let turn = self
+            .turns
+            .lock()
+            .expect("lock turns")
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        Some(turn.lock_owned().await)
+    }
+
+    // Key of the hub for the connection starting with `first` message.
+    fn key(&self, ctx: &Context, query: Option<&Query>, first: &Value) -> String {
+        let name = query.map_or_else(|| ctx.servers[0].name.clone(), |q| q.name.clone());
+        let key = match self.share {
+            Share::Server => name,
+            Share::Workspace => format!("{} {}", name, root_uri(first).unwrap_or_default()),
+        };
+        self.select_replica(key)
+    }
+
+    // Key of the replica of the hub to attach the next connection to.
+    fn select_replica(&self, key: String) -> String {
+        if self.replicas == 1 {
+            return key;
+        }
+        let replicas: Vec<String> = (0..self.replicas)
+            .map(|index| format!("{}#{}", key, index))
+            .collect();
+        let mut down = self.down.lock().expect("lock down");
+        down.retain(|_, since| since.elapsed() < REPLICA_DOWN_FOR);
+        let mut healthy: Vec<&String> =
+            replicas.iter().filter(|r| !down.contains_key(*r)).collect();
+        // Try them anyway if every replica is down.
+        if healthy.is_empty() {
+            healthy = replicas.iter().collect();
+        }
+        let selected = match self.balance {
+            Balance::RoundRobin => {
+                let next = self.next_replica.fetch_add(1, Ordering::Relaxed);
+                healthy[next % healthy.len()]
+            }
+            Balance::LeastConnections => {
+                let hubs = self.hubs.lock().expect("lock hubs");
+                let sessions = |replica: &String| {
+                    hubs.get(replica)
+                        .map_or(0, |running| running.sessions.load(Ordering::Relaxed))
+                };
+                healthy
+                    .into_iter()
+                    .min_by_key(|replica| sessions(replica))
+                    .expect("at least one replica")
+            }
+        };
+        selected.clone()
+    }
+
+    // Attach a client to the hub, starting the server if necessary.
+    fn attach(
+        &self,
+        key: &str,
+        ctx: &Context,
+        query: Option<&Query>,
+        client_tx: mpsc::UnboundedSender<String>,
+    ) -> Result<(usize, mpsc::UnboundedSender<Command>, Arc<AtomicUsize>), std::io::Error> {
+        let client = self.next_client.fetch_add(1, Ordering::Relaxed);
+        let mut hubs = self.hubs.lock().expect("lock hubs");
+        if let Some(running) = hubs.get(key) {
+            // Fails if the hub stopped.
+            if running
+                .tx
+                .send(Command::Attach(client, client_tx.clone()))
+                .is_ok()
+            {
+                running.sessions.fetch_add(1, Ordering::Relaxed);
+                return Ok((client, running.tx.clone(), running.sessions.clone()));
+            }
+        }
+
+        tracing::info!("starting shared server {}", key);
+        let server = proxy::spawn_server(ctx, query)?;
+        let (hub_tx, hub_rx) = mpsc::unbounded_channel();
+        hub_tx
+            .send(Command::Attach(client, client_tx))
+            .expect("hub receiver");
+        let sessions = Arc::new(AtomicUsize::new(1));
+        hubs.insert(
+            key.to_owned(),
+            Running {
+                started_by: client,
+                tx: hub_tx.clone(),
+                sessions: sessions.clone(),
+            },
+        );
+        let key = key.to_owned();
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut hub = Hub::new(this.share == Share::Workspace);
+            hub.replace_workspace = this.exclusive;
+            if let Err(err) = hub.run(server, hub_rx).await {
+                tracing::error!("shared server error: {}", err);
+                this.down
+                    .lock()
+                    .expect("lock down")
+                    .insert(key.clone(), Instant::now());
+            }
+            tracing::info!("shared server {} stopped", key);
+            let mut hubs = this.hubs.lock().expect("lock hubs");
+            // Another hub may have been started for the key already.
+            if matches!(hubs.get(&key), Some(running) if running.started_by == client) {
+                hubs.remove(&key);
+            }
+        });
+        Ok((client, hub_tx, sessions))
+    }
+}
+
+#[derive(Debug)]
+enum Command {
+    Attach(usize, mpsc::UnboundedSender<String>),
+    Message(usize, Value),
+    Detach(usize),
+}
+
+/// Attach to the shared server for the connection.
+pub(super) fn connect(
+    hubs: &Hubs,
+    ctx: &Context,
+    query: Option<&Query>,
+) -> Result<backend::Connection, std::io::Error> {
+    let (front, back) = tokio::io::duplex(64 * 1024);
+    let (reader, writer) = tokio::io::split(back);
+    let hubs = hubs.clone();
+    let ctx = ctx.clone();
+    let query = query.cloned();
+    tokio::spawn(async move {
+        if let Err(err) = client(hubs, ctx, query, reader, writer).await {
+            tracing::error!("shared connection error: {}", err);
+        }
+    });
+    let (reader, writer) = tokio::io::split(front);
+    Ok(backend::Connection {
+        reader: Box::new(reader),
+        writer: Box::new(writer),
+        child: None,
+    })
+}
+
+enum Event {
+    Client(Option<Value>),
+    Hub(Option<String>),
+}
+
+async fn client<R, W>(
+    hubs: Hubs,
+    ctx: Context,
+    query: Option<Query>,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut client_send = lsp::framed::writer(writer);
+    let mut client_recv = lsp::framed::reader(reader).filter_map(|msg| {
+        future::ready(msg.ok().and_then(|text| {
+            serde_json::from_str::<Value>(&text)
+                .map_err(|_| tracing::warn!("ignoring invalid message {}", text))
+                .ok()
+        }))
+    });
+    // The first message (`initialize`) can determine the hub.
+    let first = match client_recv.next().await {
+        Some(msg) => msg,
+        None => return Ok(()),
+    };
+    let key = hubs.key(&ctx, query.as_ref(), &first);
+    let _turn = hubs.turn(&key).await;
+    let (client_tx, client_rx) = mpsc::unbounded_channel();
+    let (id, hub, sessions) = hubs.attach(&key, &ctx, query.as_ref(), client_tx)?;
+    hub.send(Command::Message(id, first))?;
+
+    let client_recv = client_recv
+        .map(|msg| Event::Client(Some(msg)))
+        .chain(stream::once(future::ready(Event::Client(None))));
+    let hub_recv = stream::unfold(client_rx, |mut rx| async move {
+        let text = rx.recv().await?;
+        Some((Event::Hub(Some(text)), rx))
+    })
+    .chain(stream::once(future::ready(Event::Hub(None))));
+    let mut events = stream::select(client_recv, hub_recv);
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Client(Some(msg)) => {
+                if hub.send(Command::Message(id, msg)).is_err() {
+                    break;
+                }
+            }
+            Event::Hub(Some(text)) => client_send.send(text).await?,
+            Event::Client(None) | Event::Hub(None) => break,
+        }
+    }
+    // The hub may have stopped already.
+    let _ = hub.send(Command::Detach(id));
+    sessions.fetch_sub(1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// State of a shared server.
+struct Hub {
+    clients: BTreeMap<usize, mpsc::UnboundedSender<String>>,
+    /// The result of the first `initialize`.
+    result: Option<Value>,
+    /// Namespaced ID of the first `initialize` while waiting for the result.
+    initializing: Option<String>,
+    /// Clients waiting for the result of `initialize`, and the IDs of their requests.
+    waiting: Vec<(usize, Value)>,
+    initialized: bool,
+    /// Clients with each document open.
+    documents: HashMap<String, HashSet<usize>>,
+    /// Stop the server when the last client detaches.
+    exit_when_empty: bool,
+    /// Replace the workspace folder when a client initializes with another workspace.
+    replace_workspace: bool,
+    /// Workspace of the last client to initialize.
+    root: Option<String>,
+}
+
+impl Hub {
+    fn new(exit_when_empty: bool) -> Self {
+        Self {
+            exit_when_empty,
+            replace_workspace: false,
+            root: None,
+            clients: BTreeMap::new(),
+            result: None,
+            initializing: None,
+            waiting: Vec::new(),
+            initialized: false,
+            documents: HashMap::new(),
+        }
+    }
+
+    async fn run(
+        mut self,
+        server: backend::Connection,
+        commands: mpsc::UnboundedReceiver<Command>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let backend::Connection {
+            reader,
+            writer,
+            child: _child,
+        } = server;
+        let mut server_send = lsp::framed::writer(writer);
+        let server_recv = lsp::framed::reader(reader)
+            .filter_map(|msg| future::ready(msg.ok()))
+            .map(|text| HubEvent::Server(Some(text)))
+            .chain(stream::once(future::ready(HubEvent::Server(None))));
+        let commands = stream::unfold(commands, |mut rx| async move {
+            let command = rx.recv().await?;
+            Some((HubEvent::Command(command), rx))
+        });
+        let mut events = stream::select(commands, server_recv);
+
+        while let Some(event) = events.next().await {
+            match event {
+                HubEvent::Command(Command::Attach(client, tx)) => {
+                    tracing::debug!("client {} attached", client);
+                    self.clients.insert(client, tx);
+                }
+
+                HubEvent::Command(Command::Message(client, msg)) => {
+                    for msg in self.handle_client(client, msg) {
+                        server_send.send(msg.to_string()).await?;
+                    }
+                }
+
+                HubEvent::Command(Command::Detach(client)) => {
+                    tracing::debug!("client {} detached", client);
+                    self.clients.remove(&client);
+                    for msg in self.close_documents(client) {
+                        server_send.send(msg.to_string()).await?;
+                    }
+                    if self.exit_when_empty && self.clients.is_empty() {
+                        tracing::info!("last client detached, stopping shared server");
+                        let exit = json!({"jsonrpc": "2.0", "method": "exit"});
+                        server_send.send(exit.to_string()).await?;
+                        break;
+                    }
+                }
+
+                HubEvent::Server(Some(text)) => match serde_json::from_str::<Value>(&text) {
+                    Ok(msg) => self.handle_server(msg),
+                    Err(_) => tracing::warn!("ignoring invalid message from server"),
+                },
+
+                HubEvent::Server(None) => return Err("server exited".into()),
+            }
+        }
+        Ok(())
+    }
+
+    // Handle message from the client, and return messages to send to the server.
+    fn handle_client(&mut self, client: usize, mut msg: Value) -> Vec<Value> {
+        let method = msg.get("method").and_then(Value::as_str).map(String::from);
+        let id = msg.get("id").cloned();
+        match (method.as_deref(), id) {
+            (Some("initialize"), Some(id)) => {
+                let root = root_uri(&msg);
+                if let Some(result) = &self.result {
+                    self.send(
+                        client,
+                        json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    );
+                    self.change_workspace(root).into_iter().collect()
+                } else if self.initializing.is_some() {
+                    self.waiting.push((client, id));
+                    vec![]
+                } else {
+                    self.root = root;
+                    let namespaced = namespace(client, &id);
+                    self.initializing = Some(namespaced.clone());
+                    msg["id"] = Value::String(namespaced);
+                    vec![msg]
+                }
+            }
+
+            (Some("initialized"), None) => {
+                if self.initialized {
+                    vec![]
+                } else {
+                    self.initialized = true;
+                    vec![msg]
+                }
+            }
+
+            // The server is kept running for other clients.
+            (Some("shutdown"), Some(id)) => {
+                self.send(client, json!({"jsonrpc": "2.0", "id": id, "result": null}));
+                vec![]
+            }
+            (Some("exit"), None) => vec![],
+
+            (Some("textDocument/didOpen"), None) => match document_uri(&msg) {
+                Some(uri) => {
+                    let clients = self.documents.entry(uri).or_default();
+                    let first = clients.is_empty();
+                    clients.insert(client);
+                    if first {
+                        vec![msg]
+                    } else {
+                        vec![]
+                    }
+                }
+                None => vec![msg],
+            },
+
+            (Some("textDocument/didClose"), None) => match document_uri(&msg) {
+                Some(uri) => self.close_document(client, &uri).into_iter().collect(),
+                None => vec![msg],
+            },
+
+            // Request from the client
+            (Some(_), Some(id)) => {
+                msg["id"] = Value::String(namespace(client, &id));
+                vec![msg]
+            }
+
+            // Notification from the client, or response to a request from the server
+            _ => vec![msg],
+        }
+    }
+
+    // Handle message from the server.
+    fn handle_server(&mut self, mut msg: Value) {
+        let is_request = msg.get("method").is_some();
+        match msg.get("id").cloned() {
+            // Response to a request from a client
+            Some(Value::String(namespaced)) if !is_request => {
+                if let Some((client, id)) = split_id(&namespaced) {
+                    if self.initializing.as_deref() == Some(namespaced.as_str()) {
+                        self.initializing = None;
+                        self.result = msg.get("result").cloned();
+                        for (waiting, id) in std::mem::take(&mut self.waiting) {
+                            let mut response = msg.clone();
+                            response["id"] = id;
+                            self.send(waiting, response);
+                        }
+                    }
+                    msg["id"] = id;
+                    self.send(client, msg);
+                }
+            }
+
+            // Request from the server
+            Some(_) if is_request => {
+                if let Some(&client) = self.clients.keys().next() {
+                    self.send(client, msg);
+                }
+            }
+
+            // Notification from the server
+            _ => {
+                let text = msg.to_string();
+                for tx in self.clients.values() {
+                    let _ = tx.send(text.clone());
+                }
+            }
+        }
+    }
+
+    // Replace the workspace folder with `root`, if different and enabled.
+    fn change_workspace(&mut self, root: Option<String>) -> Option<Value> {
+        if !self.replace_workspace || root.is_none() || root == self.root {
+            return None;
+        }
+        let folder = |uri: &str| {
+            let name = uri.trim_end_matches('/').rsplit('/').next().unwrap_or(uri);
+            json!({"uri": uri, "name": name})
+        };
+        let added: Vec<Value> = root.iter().map(|uri| folder(uri)).collect();
+        let removed: Vec<Value> = self.root.iter().map(|uri| folder(uri)).collect();
+        self.root = root;
+        Some(json!({
+            "jsonrpc": "2.0",
+            "method": "workspace/didChangeWorkspaceFolders",
+            "params": {"event": {"added": added, "removed": removed}},
+        }))
+    }
+
+    // Close document for the client, and return `didClose` if no other client has it open.
+    fn close_document(&mut self, client: usize, uri: &str) -> Option<Value> {
+        let clients = self.documents.get_mut(uri)?;
+        if !clients.remove(&client) || !clients.is_empty() {
+            return None;
+        }
+        self.documents.remove(uri);
+        Some(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didClose",
+            "params": {"textDocument": {"uri": uri}},
+        }))
+    }
+
+    // Close documents left open by the disconnected client.
+    fn close_documents(&mut self, client: usize) -> Vec<Value> {
+        let uris: Vec<String> = self
+            .documents
+            .iter()
+            .filter(|(_, clients)| clients.contains(&client))
+            .map(|(uri, _)| uri.clone())
+            .collect();
+        uris.iter()
+            .filter_map(|uri| self.close_document(client, uri))
+            .collect()
+    }
+
+    fn send(&self, client: usize, msg: Value) {
+        if let Some(tx) = self.clients.get(&client) {
+            let _ = tx.send(msg.to_string());
+        }
+    }
+}
+
+enum HubEvent {
+    Command(Command),
+    Server(Option<String>),
+}
+
+// Workspace of the client from `initialize`.
+fn root_uri(msg: &Value) -> Option<String> {
+    if msg.get("method").and_then(Value::as_str) != Some("initialize") {
+        return None;
+    }
+    [
+        "/params/rootUri",
+        "/params/workspaceFolders/0/uri",
+        "/params/rootPath",
+    ]
+    .iter()
+    .find_map(|pointer| msg.pointer(pointer).and_then(Value::as_str))
+    .map(String::from)
+}
+
+fn document_uri(msg: &Value) -> Option<String> {
+    msg.pointer("/params/textDocument/uri")
+        .and_then(Value::as_str)
+        .map(String::from)
+}
+
+fn namespace(client: usize, id: &Value) -> String {
+    format!("{}:{}", client, id)
+}
+
+// Split namespaced ID `"<client>:<id>"` into the client and the original ID.
+fn split_id(id: &str) -> Option<(usize, Value)> {
+    let (client, id) = id.split_once(':')?;
+    Some((client.parse().ok()?, serde_json::from_str(id).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::FutureExt;
+
+    use super::*;
+
+    fn hub_with_clients() -> (Hub, Vec<mpsc::UnboundedReceiver<String>>) {
+        let mut hub = Hub::new(false);
+        let mut receivers = Vec::new();
+        for client in 0..2 {
+            let (tx, rx) = mpsc::unbounded_channel();
+            hub.clients.insert(client, tx);
+            receivers.push(rx);
+        }
+        (hub, receivers)
+    }
+
+    #[test]
+    fn test_balance_replicas() {
+        let hubs = Hubs::new(Share::Server).with_replicas(2, Balance::RoundRobin);
+        assert_eq!(hubs.select_replica("a".to_owned()), "a#0");
+        assert_eq!(hubs.select_replica("a".to_owned()), "a#1");
+        assert_eq!(hubs.select_replica("a".to_owned()), "a#0");
+
+        hubs.down
+            .lock()
+            .unwrap()
+            .insert("a#1".to_owned(), Instant::now());
+        assert_eq!(hubs.select_replica("a".to_owned()), "a#0");
+        assert_eq!(hubs.select_replica("a".to_owned()), "a#0");
+    }
+
+    #[test]
+    fn test_balance_least_connections() {
+        let hubs = Hubs::new(Share::Server).with_replicas(2, Balance::LeastConnections);
+        let (tx, _rx) = mpsc::unbounded_channel();
+        hubs.hubs.lock().unwrap().insert(
+            "a#0".to_owned(),
+            Running {
+                started_by: 0,
+                tx,
+                sessions: Arc::new(AtomicUsize::new(3)),
+            },
+        );
+        assert_eq!(hubs.select_replica("a".to_owned()), "a#1");
+    }
+
+    #[test]
+    fn test_root_uri() {
+        let initialize = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {"rootUri": null, "workspaceFolders": [{"uri": "file:///a", "name": "a"}]}
+        });
+        assert_eq!(root_uri(&initialize), Some("file:///a".to_owned()));
+        assert_eq!(root_uri(&json!({"jsonrpc": "2.0", "method": "exit"})), None);
+    }
+
+    #[test]
+    fn test_reuse_initialize_result() {
+        let (mut hub, mut receivers) = hub_with_clients();
+        let initialize = json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}});
+        let sent = hub.handle_client(0, initialize.clone());
+        assert_eq!(sent[0]["id"], json!("0:1"));
+        assert!(hub.handle_client(1, initialize).is_empty());
+
+        hub.handle_server(json!({"jsonrpc": "2.0", "id": "0:1", "result": {"capabilities": {}}}));
+        for rx in &mut receivers {
+            let response: Value =
+                serde_json::from_str(&rx.recv().now_or_never().unwrap().unwrap()).unwrap();
+            assert_eq!(response["id"], json!(1));
+            assert_eq!(response["result"], json!({"capabilities": {}}));
+        }
+    }
+
+    #[test]
+    fn test_replace_workspace() {
+        let (mut hub, _receivers) = hub_with_clients();
+        hub.replace_workspace = true;
+        let initialize = |root: &str| json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {"rootUri": root}});
+        hub.handle_client(0, initialize("file:///a"));
+        hub.handle_server(json!({"jsonrpc": "2.0", "id": "0:1", "result": {}}));
+        assert!(hub.handle_client(1, initialize("file:///a")).is_empty());
+
+        let sent = hub.handle_client(1, initialize("file:///b/"));
+        assert_eq!(
+            sent,
+            vec![json!({
+                "jsonrpc": "2.0",
+                "method": "workspace/didChangeWorkspaceFolders",
+                "params": {"event": {
+                    "added": [{"uri": "file:///b/", "name": "b"}],
+                    "removed": [{"uri": "file:///a", "name": "a"}],
+                }},
+            })]
+        );
+    }
+
+    #[test]
+    fn test_open_and_close_documents_once() {
+        let (mut hub, _receivers) = hub_with_clients();
+        let open = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {"textDocument": {"uri": "file:///a.rs", "languageId": "rust", "version": 1, "text": ""}}
+        });
+        assert_eq!(hub.handle_client(0, open.clone()).len(), 1);
+        assert!(hub.handle_client(1, open).is_empty());
+
+        assert!(hub.close_documents(0).is_empty());
+        let closed = hub.close_documents(1);
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0]["method"], json!("textDocument/didClose"));
+    }
+}
@@ -0,0 +1,248 @@
+//! Shut down the servers gracefully when the proxy is stopped.
+//!
+//! Each session sends `shutdown` and `exit` to its server, waits for it to exit within the grace
+//! period, and closes the connection with 1001 Going Away. Servers still running after the grace
+//! period are killed.
+//!
+//! While draining, or shutting down, new sessions are rejected with 503 Service Unavailable and
+//! the sessions already started continue.
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures_util::{future, stream, SinkExt, Stream, StreamExt};
+use serde_json::{json, Value};
+use tokio::{sync::watch, time::Instant};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use warp::{Filter, Rejection};
+
+use crate::{backend, lsp::framed::LspFrameCodec};
+
+use super::proxy::SharedContext;
+
+/// ID of the `shutdown` request sent by the proxy.
+const SHUTDOWN_ID: &str = "lsp-ws-proxy/shutdown";
+
+/// Signal to shut down the sessions, and the number of sessions still running.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    tx: watch::Sender<bool>,
+    rx: watch::Receiver<bool>,
+    grace: Duration,
+    active: AtomicUsize,
+    draining: AtomicBool,
+}
+
+/// Rejection for a new session while draining.
+#[derive(Debug)]
+pub(super) struct Draining;
+
+impl warp::reject::Reject for Draining {}
+
+/// Held by a running session.
+#[derive(Debug)]
+pub(super) struct Guard {
+    inner: Arc<Inner>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.inner.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Shutdown {
+    pub fn new(grace: Duration) -> Self {
+        let (tx, rx) = watch::channel(false);
+        Self {
+            inner: Arc::new(Inner {
+                tx,
+                rx,
+                grace,
+                active: AtomicUsize::new(0),
+                draining: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Time given to the servers to exit.
+    pub(super) fn grace(&self) -> Duration {
+        self.inner.grace
+    }
+
+    /// Number of sessions running.
+    pub fn sessions(&self) -> usize {
+        self.inner.active.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting new sessions.
+    pub fn drain(&self) {
+        if !self.inner.draining.swap(true, Ordering::SeqCst) {
+            tracing::info!("draining {} sessions", self.sessions());
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.inner.draining.load(Ordering::SeqCst)
+    }
+
+    /// The shutdown started.
+    pub(super) fn is_stopping(&self) -> bool {
+        *self.inner.rx.borrow()
+    }
+
+    /// Track a session until the guard is dropped.
+    pub(super) fn track(&self) -> Guard {
+        self.inner.active.fetch_add(1, Ordering::SeqCst);
+        Guard {
+            inner: self.inner.clone(),
+        }
+    }
+
+    /// Stream yielding once when the shutdown starts.
+    pub(super) fn signaled(&self) -> impl Stream<Item = ()> + Send + Unpin {
+        let mut rx = self.inner.rx.clone();
+        Box::pin(stream::once(async move {
+            while !*rx.borrow() {
+                if rx.changed().await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }
+        }))
+    }
+
+    /// Signal the sessions to shut down, and wait for them to end within the grace period.
+    pub async fn shutdown(&self) {
+        tracing::info!("shutting down {} sessions", self.sessions());
+        self.inner.draining.store(true, Ordering::SeqCst);
+        self.inner.tx.send(true).ok();
+        let ended = async {
+            while self.sessions() > 0 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        };
+        // Allow a little more than the grace period for the sessions to kill their servers.
+        let wait = self.inner.grace + Duration::from_secs(1);
+        if tokio::time::timeout(wait, ended).await.is_err() {
+            tracing::warn!("sessions did not end within the grace period");
+        }
+    }
+}
+
+/// Reject new sessions with `Draining` while draining.
+pub(super) fn with_accepting(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let draining = ctx.get().shutdown.is_draining();
+            async move {
+                if draining {
+                    Err(warp::reject::custom(Draining))
+                } else {
+                    Ok(())
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Send `shutdown` and `exit` to the server, and wait for it to exit within `grace`.
+/// The server is killed if it's still running.
+pub(super) async fn stop_server(
+    send: &mut FramedWrite<backend::Writer, LspFrameCodec>,
+    recv: &mut FramedRead<backend::Reader, LspFrameCodec>,
+    child: Option<&mut backend::Process>,
+    grace: Duration,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let deadline = Instant::now() + grace;
+    let request = json!({"jsonrpc": "2.0", "id": SHUTDOWN_ID, "method": "shutdown"});
+    send.send(request.to_string()).await?;
+    let responded = tokio::time::timeout_at(deadline, async {
+        while let Some(msg) = recv.next().await {
+            if msg.map_or(false, |text| is_shutdown_response(&text)) {
+                return true;
+            }
+        }
+        false
+    })
+    .await
+    .unwrap_or(false);
+    if !responded {
+        tracing::warn!("server did not respond to shutdown");
+    }
+    // The server may have exited already.
+    let _ = send
+        .send(json!({"jsonrpc": "2.0", "method": "exit"}).to_string())
+        .await;
+
+    if let Some(child) = child {
+        match tokio::time::timeout_at(deadline, child.wait()).await {
+            Ok(status) => tracing::info!("server exited: {:?}", status),
+            Err(_) => {
+                tracing::warn!("killing server after the grace period");
+                child.kill().await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_shutdown_response(text: &str) -> bool {
+    serde_json::from_str::<Value>(text).map_or(false, |msg| {
+        msg.get("method").is_none() && msg.get("id") == Some(&json!(SHUTDOWN_ID))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown_response() {
+        assert!(is_shutdown_response(
+            r#"{"jsonrpc":"2.0","id":"lsp-ws-proxy/shutdown","result":null}"#
+        ));
+        assert!(!is_shutdown_response(
+            r#"{"jsonrpc":"2.0","id":1,"result":null}"#
+        ));
+        assert!(!is_shutdown_response(
+            r#"{"jsonrpc":"2.0","method":"window/logMessage","params":{}}"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_sessions() {
+        let shutdown = Shutdown::new(Duration::from_secs(5));
+        let guard = shutdown.track();
+        let mut signaled = shutdown.signaled();
+        let session = tokio::spawn(async move {
+            signaled.next().await;
+            drop(guard);
+        });
+        tokio::time::timeout(Duration::from_secs(1), shutdown.shutdown())
+            .await
+            .unwrap();
+        session.await.unwrap();
+        assert!(shutdown.is_draining());
+    }
+
+    #[test]
+    fn test_drain() {
+        let shutdown = Shutdown::new(Duration::from_secs(5));
+        let _guard = shutdown.track();
+        assert!(!shutdown.is_draining());
+        shutdown.drain();
+        assert!(shutdown.is_draining());
+        assert_eq!(shutdown.sessions(), 1);
+    }
+}
@@ -0,0 +1,185 @@
+//! Maximum sizes of the messages in each direction.
+//!
+//! Messages over the limits are dropped instead of closing the connection. Requests are answered
+//! with an error, so the sender doesn't wait for a response that never comes. With
+//! `--oversized-responses error` (the default), a response of the server over the limit is replaced
+//! with an error for the request, protecting browsers from huge payloads like semantic tokens of a
+//! generated file. With `drop`, it's dropped.
+use std::{io, str::FromStr};
+
+use serde_json::{json, Value};
+
+/// Error code of `InvalidRequest`.
+const INVALID_REQUEST: i64 = -32600;
+/// Error code of `RequestFailed`.
+const REQUEST_FAILED: i64 = -32803;
+
+/// What to do with the responses from the server over the limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Oversized {
+    /// Send an error response to the client instead.
+    Error,
+    /// Drop it.
+    Drop,
+}
+
+impl Default for Oversized {
+    fn default() -> Self {
+        Self::Error
+    }
+}
+
+impl FromStr for Oversized {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "drop" => Ok(Self::Drop),
+            _ => Err(format!("expected error or drop, got {}", s)),
+        }
+    }
+}
+
+/// Maximum sizes of the messages in bytes.
+#[derive(Debug, Clone, Default)]
+pub struct SizeLimits {
+    /// Maximum size of the messages from the client.
+    pub client: Option<usize>,
+    /// Maximum size of the messages from the server.
+    pub server: Option<usize>,
+    /// What to do with the responses from the server over the limit.
+    pub oversized: Oversized,
+}
+
+impl SizeLimits {
+    /// Whether the serialized `msg` from the client is over the limit.
+    pub(super) fn client_exceeds<T: serde::Serialize>(&self, msg: &T) -> bool {
+        self.client.map_or(false, |max| {
+            let mut counter = Counter(0);
+            serde_json::to_writer(&mut counter, msg).is_ok() && counter.0 > max
+        })
+    }
+
+    /// Whether `text` from the client that isn't a valid message is over the limit.
+    pub(super) fn client_text_exceeds(&self, text: &str) -> bool {
+        self.client.map_or(false, |max| text.len() > max)
+    }
+
+    /// Whether `text` from the server is over the limit.
+    pub(super) fn server_exceeds(&self, text: &str) -> bool {
+        self.server.map_or(false, |max| text.len() > max)
+    }
+
+    /// Error response to the client for the response `text` from the server over the limit, or
+    /// `None` if it should be dropped.
+    pub(super) fn replacement(&self, text: &str) -> Option<String> {
+        if self.oversized == Oversized::Drop {
+            return None;
+        }
+        let head = Head::parse(text)?;
+        if head.method.is_some() {
+            return None;
+        }
+        let id = head.id?;
+        let message = format!(
+            "response of {} bytes is over the limit of {} bytes",
+            text.len(),
+            self.server.unwrap_or_default()
+        );
+        Some(error_response(&id, REQUEST_FAILED, &message))
+    }
+}
+
+/// Error response to the request `text` over the limit of `max` bytes, or `None` if it's not a
+/// request.
+pub(super) fn rejection(text: &str, max: Option<usize>) -> Option<String> {
+    let head = Head::parse(text)?;
+    let method = head.method?;
+    let id = head.id?;
+    let message = format!(
+        "{} of {} bytes is over the limit of {} bytes",
+        method,
+        text.len(),
+        max.unwrap_or_default()
+    );
+    Some(error_response(&id, INVALID_REQUEST, &message))
+}
+
+fn error_response(id: &Value, code: i64, message: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+    .to_string()
+}
+
+/// The fields of a message needed to answer it, without the large params or result.
+#[derive(serde::Deserialize)]
+struct Head {
+    id: Option<Value>,
+    method: Option<String>,
+}
+
+impl Head {
+    fn parse(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+}
+
+/// Writer counting the bytes, to measure messages without keeping them.
+struct Counter(usize);
+
+impl io::Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_limits() {
+        let limits = SizeLimits {
+            client: Some(64),
+            server: Some(64),
+            oversized: Oversized::Error,
+        };
+        let hover = json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/hover"});
+        let change = json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {
+            "contentChanges": [{"text": "fn main() { println!(\"Hello, world!\"); }"}],
+        }});
+        assert!(!limits.client_exceeds(&hover));
+        assert!(limits.client_exceeds(&change));
+        assert!(limits.client_text_exceeds(&"x".repeat(65)));
+        assert!(!limits.client_text_exceeds("not json"));
+        assert_eq!(rejection(&change.to_string(), limits.client), None);
+        let response =
+            serde_json::from_str::<Value>(&rejection(&hover.to_string(), limits.client).unwrap())
+                .unwrap();
+        assert_eq!(response["id"], json!(1));
+        assert_eq!(response["error"]["code"], json!(INVALID_REQUEST));
+
+        let tokens = json!({"jsonrpc": "2.0", "id": 3, "result": {"data": vec![0; 64]}});
+        let tokens = tokens.to_string();
+        assert!(limits.server_exceeds(&tokens));
+        let error = serde_json::from_str::<Value>(&limits.replacement(&tokens).unwrap()).unwrap();
+        assert_eq!(error["id"], json!(3));
+        assert_eq!(error["error"]["code"], json!(REQUEST_FAILED));
+
+        let limits = SizeLimits {
+            oversized: Oversized::Drop,
+            ..limits
+        };
+        assert_eq!(limits.replacement(&tokens), None);
+        assert!("truncate".parse::<Oversized>().is_err());
+    }
+}
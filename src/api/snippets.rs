@@ -0,0 +1,311 @@
+//! Downgrading snippet completions for clients without snippet support.
+//!
+//! Some servers send `insertTextFormat: Snippet` even when the client didn't advertise
+//! `snippetSupport`, leaving minimal clients inserting `${1:name}` literally. With
+//! `--strip-snippets`, the completion items of those clients are converted to plain text, with
+//! the placeholders replaced by their default text and the tab stops removed.
+use std::{collections::HashSet, iter::Peekable, str::Chars, sync::Mutex};
+
+use serde_json::Value;
+
+use crate::lsp::Message;
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// `InsertTextFormat.PlainText`
+const PLAIN_TEXT: u64 = 1;
+/// `InsertTextFormat.Snippet`
+const SNIPPET: u64 = 2;
+
+/// Add a middleware stripping snippets from the completions to `middlewares` if `enabled`.
+pub(super) fn apply(enabled: bool, middlewares: &mut Middlewares) {
+    if enabled {
+        middlewares.push(Stripper::default());
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // Set from `initialize`, until then the snippets are kept.
+    supported: Option<bool>,
+    // Completion requests from the client, by id.
+    pending: HashSet<String>,
+}
+
+#[derive(Default)]
+struct Stripper {
+    state: Mutex<State>,
+}
+
+impl Stripper {
+    fn on_client(&self, msg: &Value) {
+        let method = msg.get("method").and_then(Value::as_str);
+        let mut state = self.state.lock().expect("lock snippets");
+        match (method, msg.get("id")) {
+            (Some("initialize"), _) => {
+                let supported = msg
+                    .pointer("/params/capabilities/textDocument/completion/completionItem/snippetSupport")
+                    .and_then(Value::as_bool)
+                    .unwrap_or(false);
+                state.supported = Some(supported);
+            }
+            (Some("textDocument/completion"), Some(id))
+            | (Some("completionItem/resolve"), Some(id))
+                if state.supported == Some(false) =>
+            {
+                state.pending.insert(id.to_string());
+            }
+            _ => {}
+        }
+    }
+
+    /// Strip the snippets in the response `msg` if it's for a completion request, returning
+    /// whether it changed.
+    fn on_server(&self, msg: &mut Value) -> bool {
+        if msg.get("method").is_some() {
+            return false;
+        }
+        let id = match msg.get("id") {
+            Some(id) => id.to_string(),
+            None => return false,
+        };
+        if !self
+            .state
+            .lock()
+            .expect("lock snippets")
+            .pending
+            .remove(&id)
+        {
+            return false;
+        }
+        let result = match msg.get_mut("result") {
+            Some(result) => result,
+            None => return false,
+        };
+        // CompletionItem[]
+        if let Some(items) = result.as_array_mut() {
+            return strip_items(items, false);
+        }
+        // CompletionItem from resolve
+        if result.get("items").is_none() {
+            return strip_item(result, false);
+        }
+        strip_list(result)
+    }
+}
+
+/// Strip the snippets of the items of `CompletionList`, including the items with the format of
+/// `itemDefaults`.
+fn strip_list(list: &mut Value) -> bool {
+    let by_default = match list.pointer_mut("/itemDefaults/insertTextFormat") {
+        Some(format) if format.as_u64() == Some(SNIPPET) => {
+            *format = Value::from(PLAIN_TEXT);
+            true
+        }
+        _ => false,
+    };
+    let changed = match list.get_mut("items").and_then(Value::as_array_mut) {
+        Some(items) => strip_items(items, by_default),
+        None => false,
+    };
+    changed || by_default
+}
+
+fn strip_items(items: &mut [Value], by_default: bool) -> bool {
+    items.iter_mut().fold(false, |changed, item| {
+        strip_item(item, by_default) || changed
+    })
+}
+
+/// Convert the completion item to plain text if it's a snippet, or if it has no format and
+/// `by_default`.
+fn strip_item(item: &mut Value, by_default: bool) -> bool {
+    let format = item.get("insertTextFormat").and_then(Value::as_u64);
+    if format != Some(SNIPPET) && !(format.is_none() && by_default) {
+        return false;
+    }
+    if format.is_some() {
+        item["insertTextFormat"] = Value::from(PLAIN_TEXT);
+    }
+    if let Some(Value::String(text)) = item.get_mut("insertText") {
+        *text = strip_snippet(text);
+    }
+    if let Some(Value::String(text)) = item.pointer_mut("/textEdit/newText") {
+        *text = strip_snippet(text);
+    }
+    true
+}
+
+/// The text inserted by the `snippet` without the tab stops, with the placeholders and
+/// variables replaced by their defaults, and the first of the choices.
+pub(super) fn strip_snippet(snippet: &str) -> String {
+    let mut text = String::with_capacity(snippet.len());
+    strip_until(&mut snippet.chars().peekable(), &mut text, false);
+    text
+}
+
+/// Strip the snippet into `text`, until the `}` closing a placeholder if `nested`.
+fn strip_until(chars: &mut Peekable<Chars<'_>>, text: &mut String, nested: bool) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(&escaped) if "$}\\".contains(escaped) => {
+                    text.push(escaped);
+                    chars.next();
+                }
+                _ => text.push('\\'),
+            },
+            '}' if nested => return,
+            '$' => match chars.peek() {
+                Some('{') => {
+                    chars.next();
+                    placeholder(chars, text);
+                }
+                Some(&c) if c.is_ascii_alphanumeric() || c == '_' => {
+                    // Tab stop or variable without a default
+                    while chars
+                        .peek()
+                        .map_or(false, |c| c.is_ascii_alphanumeric() || *c == '_')
+                    {
+                        chars.next();
+                    }
+                }
+                _ => text.push('$'),
+            },
+            c => text.push(c),
+        }
+    }
+}
+
+/// Strip the rest of `${...}` into `text`.
+fn placeholder(chars: &mut Peekable<Chars<'_>>, text: &mut String) {
+    while chars
+        .peek()
+        .map_or(false, |c| c.is_ascii_alphanumeric() || *c == '_')
+    {
+        chars.next();
+    }
+    match chars.next() {
+        // `${1:default}` or `${name:default}`
+        Some(':') => strip_until(chars, text, true),
+        // `${1|one,two|}`, the first choice
+        Some('|') => {
+            let mut first = true;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            if first {
+                                text.push(escaped);
+                            }
+                        }
+                    }
+                    ',' => first = false,
+                    '|' => break,
+                    c if first => text.push(c),
+                    _ => {}
+                }
+            }
+            skip_to_close(chars);
+        }
+        // `${1}`
+        Some('}') | None => {}
+        // Transforms like `${TM_FILENAME/(.*)/$1/}`, replaced by nothing
+        Some(_) => skip_to_close(chars),
+    }
+}
+
+fn skip_to_close(chars: &mut Peekable<Chars<'_>>) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '}' => return,
+            _ => {}
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Stripper {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if matches!(&msg, Message::Request(_) | Message::Unknown(_)) {
+            if let Ok(value) = serde_json::to_value(&msg) {
+                self.on_client(&value);
+            }
+        }
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        if !matches!(&msg, Message::Response(_) | Message::Unknown(_)) {
+            return Some(msg);
+        }
+        let mut value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        if self.on_server(&mut value) {
+            tracing::debug!("stripped snippets from completions");
+            return Some(serde_json::from_value(value).unwrap_or(msg));
+        }
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_strip_snippet() {
+        assert_eq!(
+            strip_snippet("println!(\"{}\", ${1:x})$0"),
+            "println!(\"{}\", x)"
+        );
+        assert_eq!(
+            strip_snippet("fn ${1:name}(${2:arg: ${3:i32}})"),
+            "fn name(arg: i32)"
+        );
+        assert_eq!(strip_snippet("${1|public,private|} class"), "public class");
+        assert_eq!(
+            strip_snippet("cost \\$5 ${TM_FILENAME/(.*)/$1/}\\}"),
+            "cost $5 }"
+        );
+        assert_eq!(strip_snippet("$ ${TM_SELECTED_TEXT:none}"), "$ none");
+    }
+
+    #[test]
+    fn test_stripper() {
+        let stripper = Stripper::default();
+        stripper.on_client(
+            &json!({"jsonrpc": "2.0", "id": 0, "method": "initialize", "params": {
+                "capabilities": {"textDocument": {"completion": {"completionItem": {}}}},
+            }}),
+        );
+        stripper
+            .on_client(&json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/completion"}));
+        let mut response = json!({"jsonrpc": "2.0", "id": 1, "result": {
+            "isIncomplete": false,
+            "items": [
+                {"label": "main", "insertText": "main()$0", "insertTextFormat": 2},
+                {"label": "x", "insertText": "x$", "insertTextFormat": 1},
+                {"label": "vec!", "textEdit": {"newText": "vec![$1]", "range": {
+                    "start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 1},
+                }}, "insertTextFormat": 2},
+            ],
+        }});
+        assert!(stripper.on_server(&mut response));
+        let items = &response["result"]["items"];
+        assert_eq!(items[0]["insertText"], json!("main()"));
+        assert_eq!(items[0]["insertTextFormat"], json!(PLAIN_TEXT));
+        assert_eq!(items[1]["insertText"], json!("x$"));
+        assert_eq!(items[2]["textEdit"]["newText"], json!("vec![]"));
+
+        // Only the responses to completion requests
+        assert!(!stripper.on_server(&mut response));
+    }
+}
@@ -0,0 +1,170 @@
+//! Fallback transport for clients that can't use WebSocket.
+//!
+//! `GET /events` starts a session and streams messages from the server as Server-Sent Events.
+//! The first event is `session` with the session id as data, followed by `message` events
+//! with the serialized messages. Messages to the server are sent with `POST /events/{id}`.
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{future, sink, stream, StreamExt};
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use warp::{http::StatusCode, reply, sse::Event, Filter, Rejection, Reply};
+
+use super::{
+    json_error_response, limit,
+    proxy::{self, Message, Outgoing, Query},
+    shutdown, with_context, workspace,
+};
+
+/// Senders of the client messages for each session.
+type Sessions = Arc<Mutex<HashMap<String, mpsc::Sender<Message>>>>;
+
+#[derive(Debug, Clone)]
+pub struct Context {
+    proxy: proxy::SharedContext,
+    sessions: Sessions,
+}
+
+impl Context {
+    pub fn new(proxy: proxy::SharedContext) -> Self {
+        Self {
+            proxy,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+/// Handler for `GET /events` and `POST /events/{id}`.
+pub fn handler(ctx: Context) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let events = warp::get()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(with_context(ctx.clone()))
+        .and(proxy::with_valid_query(ctx.proxy.clone()))
+        .and(shutdown::with_accepting(ctx.proxy.clone()))
+        .and(limit::with_session(ctx.proxy.clone()))
+        .map(start_session);
+    let send = warp::post()
+        .and(warp::path!("events" / String))
+        .and(with_context(ctx))
+        .and(warp::body::content_length_limit(2 * 1024 * 1024))
+        .and(warp::body::bytes())
+        .and_then(send_message);
+    events.or(send)
+}
+
+fn start_session(ctx: Context, query: Option<Query>, session: limit::Session) -> impl Reply {
+    let id = proxy::new_session_id();
+    let (client_tx, client_rx) = mpsc::channel(16);
+    let (server_tx, server_rx) = mpsc::channel(16);
+    ctx.sessions
+        .lock()
+        .expect("lock sessions")
+        .insert(id.clone(), client_tx);
+    tokio::spawn(
+        on_start(ctx, id.clone(), query, client_rx, server_tx, session)
+            .instrument(proxy::session_span(&id)),
+    );
+
+    let session = stream::once(future::ok::<_, Infallible>(
+        Event::default().event("session").data(id),
+    ));
+    let messages = stream::unfold(server_rx, |mut rx| async move {
+        let msg = rx.recv().await?;
+        Some((msg, rx))
+    })
+    .take_while(|msg| {
+        future::ready(!matches!(
+            msg,
+            Outgoing::Close
+                | Outgoing::Shutdown
+                | Outgoing::ServerFailed
+                | Outgoing::Exited(_)
+                | Outgoing::Ended
+        ))
+    })
+    .filter_map(|msg| {
+        future::ready(match msg {
+            Outgoing::Text(text) => Some(Ok(Event::default().event("message").data(text))),
+            // The connection is kept alive with comments instead.
+            _ => None,
+        })
+    });
+    warp::sse::reply(warp::sse::keep_alive().stream(session.chain(messages)))
+}
+
+async fn on_start(
+    ctx: Context,
+    id: String,
+    query: Option<Query>,
+    client_rx: mpsc::Receiver<Message>,
+    server_tx: mpsc::Sender<Outgoing>,
+    session: limit::Session,
+) {
+    tracing::info!("connected with server-sent events");
+    if let Err(err) = connected(&ctx, &id, query, client_rx, server_tx).await {
+        tracing::error!("connection error: {}", err);
+    }
+    ctx.sessions.lock().expect("lock sessions").remove(&id);
+    drop(session);
+    tracing::info!("disconnected");
+}
+
+async fn connected(
+    ctx: &Context,
+    id: &str,
+    query: Option<Query>,
+    client_rx: mpsc::Receiver<Message>,
+    server_tx: mpsc::Sender<Outgoing>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let proxy = ctx.proxy.get().for_query(query.as_ref(), id);
+    let (proxy, _workspace) = workspace::prepare(proxy, query.as_ref(), id).await?;
+    let server = proxy::connect_server(&proxy, query.as_ref()).await?;
+    let closed_tx = server_tx.clone();
+    let client_recv = stream::unfold(client_rx, |mut rx| async move {
+        let msg = rx.recv().await?;
+        Some((Ok::<_, Infallible>(msg), rx))
+    })
+    .chain(stream::once(async { Ok(Message::Done) }));
+    // The client disconnected when the event stream is dropped.
+    let closed = stream::once(async move {
+        closed_tx.closed().await;
+        Ok(Message::Done)
+    });
+    let client_recv = stream::select(client_recv, closed).boxed();
+    let client_send = sink::unfold(server_tx, |tx, msg: Outgoing| async move {
+        tx.send(msg).await.map(|_| tx)
+    });
+    proxy::run(&proxy, server, client_recv, Box::pin(client_send)).await
+}
+
+async fn send_message(
+    id: String,
+    ctx: Context,
+    body: bytes::Bytes,
+) -> Result<reply::Response, Rejection> {
+    let tx = ctx
+        .sessions
+        .lock()
+        .expect("lock sessions")
+        .get(&id)
+        .cloned();
+    let tx = tx.ok_or_else(warp::reject::not_found)?;
+    let text = match std::str::from_utf8(&body) {
+        Ok(text) => text,
+        Err(_) => {
+            return Ok(json_error_response(
+                "message must be UTF-8",
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    for msg in proxy::parse_client_messages(text) {
+        tx.send(msg).await.map_err(|_| warp::reject::not_found())?;
+    }
+    Ok(StatusCode::ACCEPTED.into_response())
+}
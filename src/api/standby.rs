@@ -0,0 +1,119 @@
+//! Warm standby servers for `--restart`.
+//!
+//! A started server is kept ready for each server used by the sessions. It replaces the server of
+//! a session when it crashes, or when the sessions are handed over to new servers after upgrading
+//! it. The standby receives the state of the session like any restarted server, without waiting
+//! for a new process to start first.
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::{stream, Stream};
+use tokio::sync::broadcast;
+
+use crate::backend;
+
+use super::proxy::{self, Context, Query};
+
+/// Started servers by name, and the names of the servers to hand over.
+#[derive(Clone)]
+pub struct Standby {
+    ready: Arc<Mutex<HashMap<String, backend::Connection>>>,
+    handovers: broadcast::Sender<String>,
+}
+
+impl fmt::Debug for Standby {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ready = self.ready.lock().expect("lock standby").len();
+        f.debug_struct("Standby").field("ready", &ready).finish()
+    }
+}
+
+impl Default for Standby {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Standby {
+    pub fn new() -> Self {
+        let (handovers, _) = broadcast::channel(16);
+        Self {
+            ready: Arc::new(Mutex::new(HashMap::new())),
+            handovers,
+        }
+    }
+
+    /// Start a standby for the server selected by `query` unless one is running.
+    pub(super) fn prepare(&self, ctx: &Context, query: Option<&Query>) {
+        let name = match proxy::select_server(&ctx.servers, query) {
+            Ok(server) => server.name.clone(),
+            Err(_) => return,
+        };
+        let mut ready = self.ready.lock().expect("lock standby");
+        if ready.get_mut(&name).map_or(false, is_running) {
+            return;
+        }
+        match proxy::spawn_server(ctx, query) {
+            Ok(conn) => {
+                tracing::debug!("started standby {}", name);
+                ready.insert(name, conn);
+            }
+            Err(err) => tracing::warn!("failed to start standby {}: {}", name, err),
+        }
+    }
+
+    /// Take the standby for the server selected by `query`, or start one if there's none, and
+    /// start the next standby.
+    pub(super) fn take(
+        &self,
+        ctx: &Context,
+        query: Option<&Query>,
+    ) -> Result<backend::Connection, std::io::Error> {
+        let name = proxy::select_server(&ctx.servers, query)?.name.clone();
+        let standby = self.ready.lock().expect("lock standby").remove(&name);
+        let conn = match standby {
+            Some(mut conn) if is_running(&mut conn) => {
+                tracing::info!("using standby {}", name);
+                conn
+            }
+            _ => proxy::spawn_server(ctx, query)?,
+        };
+        self.prepare(ctx, query);
+        Ok(conn)
+    }
+
+    /// Hand the sessions using the server `name` over to new servers, replacing the standby
+    /// started before the upgrade.
+    pub fn handover(&self, ctx: &Context, name: &str) {
+        self.ready.lock().expect("lock standby").remove(name);
+        let query = Query {
+            name: name.to_owned(),
+        };
+        self.prepare(ctx, Some(&query));
+        tracing::info!("handing over sessions of {}", name);
+        self.handovers.send(name.to_owned()).ok();
+    }
+
+    /// Stream of the names of the servers to hand over.
+    pub(super) fn handovers(&self) -> impl Stream<Item = String> + Send + Unpin {
+        let rx = self.handovers.subscribe();
+        Box::pin(stream::unfold(rx, |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(name) => return Some((name, rx)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+}
+
+fn is_running(conn: &mut backend::Connection) -> bool {
+    conn.child
+        .as_mut()
+        .map_or(true, |child| matches!(child.try_wait(), Ok(None)))
+}
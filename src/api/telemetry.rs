@@ -0,0 +1,212 @@
+//! Collecting `telemetry/event` of the servers.
+//!
+//! Browsers have no use for the telemetry of the servers, but operators do. With
+//! `--telemetry <sink>`, `telemetry/event` isn't forwarded to the client, and is instead posted to
+//! an HTTP endpoint or appended to a file as a line of JSON, with the session it's from:
+//! `{"session":"...","server":"rust-analyzer","timestamp":1634212345678,"event":{...}}`.
+use std::{
+    convert::TryFrom,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hyper::{client::HttpConnector, Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+
+use crate::lsp::{self, Message};
+
+use super::middleware::{Middleware, Middlewares, Outbox};
+
+/// Time the endpoint has to respond before the event is dropped.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the events are sent, an `http` or `https` URL, or the path of a file.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum Sink {
+    Http(hyper::Uri),
+    File(PathBuf),
+}
+
+impl FromStr for Sink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            let url = s
+                .parse::<hyper::Uri>()
+                .map_err(|err| format!("invalid url {}: {}", s, err))?;
+            return Ok(Self::Http(url));
+        }
+        if s.is_empty() {
+            return Err("expected url or path".to_owned());
+        }
+        Ok(Self::File(PathBuf::from(s)))
+    }
+}
+
+impl TryFrom<String> for Sink {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Sink with the client posting to it, shared by the sessions.
+#[derive(Debug, Clone)]
+pub struct Telemetry {
+    sink: Arc<Sink>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Telemetry {
+    pub fn new(sink: Sink) -> Self {
+        Self {
+            sink: Arc::new(sink),
+            client: Client::builder().build(HttpsConnector::with_webpki_roots()),
+        }
+    }
+
+    /// Add a middleware collecting the events of the session `id` with `server` to
+    /// `middlewares`.
+    pub(super) fn apply(&self, id: &str, server: &str, middlewares: &mut Middlewares) {
+        middlewares.push(Collector {
+            telemetry: self.clone(),
+            session: id.to_owned(),
+            server: server.to_owned(),
+        });
+    }
+
+    async fn send(&self, event: Value) -> Result<(), String> {
+        match &*self.sink {
+            Sink::Http(url) => {
+                let req = Request::post(url.clone())
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(event.to_string()))
+                    .map_err(|err| err.to_string())?;
+                let res = tokio::time::timeout(TIMEOUT, self.client.request(req))
+                    .await
+                    .map_err(|_| "timed out".to_owned())?
+                    .map_err(|err| err.to_string())?;
+                if !res.status().is_success() {
+                    return Err(format!("responded with {}", res.status()));
+                }
+                Ok(())
+            }
+            Sink::File(path) => {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .map_err(|err| format!("failed to open {}: {}", path.display(), err))?;
+                // A single write of the line with the newline, so lines of sessions don't mix.
+                let line = format!("{}\n", event);
+                file.write_all(line.as_bytes())
+                    .await
+                    .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+            }
+        }
+    }
+}
+
+struct Collector {
+    telemetry: Telemetry,
+    session: String,
+    server: String,
+}
+
+impl Collector {
+    fn event(&self, params: Value) -> Value {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        json!({
+            "session": self.session,
+            "server": self.server,
+            "timestamp": timestamp,
+            "event": params,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Collector {
+    async fn on_client_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        Some(msg)
+    }
+
+    async fn on_server_message(&self, msg: Message, _outbox: &mut Outbox) -> Option<Message> {
+        match msg {
+            Message::Notification(lsp::Notification::TelemetryEvent { params }) => {
+                let event = self.event(params);
+                let telemetry = self.telemetry.clone();
+                // Without holding up the messages after it
+                tokio::spawn(async move {
+                    if let Err(err) = telemetry.send(event).await {
+                        tracing::warn!("failed to send telemetry: {}", err);
+                    }
+                });
+                None
+            }
+            msg => Some(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sink() {
+        assert_eq!(
+            "https://telemetry.example.com/lsp".parse::<Sink>(),
+            Ok(Sink::Http(
+                "https://telemetry.example.com/lsp".parse().unwrap()
+            ))
+        );
+        assert_eq!(
+            "/var/log/lsp-telemetry.jsonl".parse::<Sink>(),
+            Ok(Sink::File(PathBuf::from("/var/log/lsp-telemetry.jsonl")))
+        );
+        assert!("".parse::<Sink>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_collect_to_file() {
+        let path =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-{}.jsonl", uuid::Uuid::new_v4()));
+        let collector = Collector {
+            telemetry: Telemetry::new(Sink::File(path.clone())),
+            session: "abc".to_owned(),
+            server: "rust-analyzer".to_owned(),
+        };
+        let log = json!({"jsonrpc": "2.0", "method": "window/logMessage", "params": {
+            "type": 3,
+            "message": "indexing",
+        }});
+        let log = serde_json::from_value::<Message>(log).unwrap();
+        let forwarded = collector
+            .on_server_message(log, &mut Outbox::default())
+            .await;
+        assert!(forwarded.is_some());
+
+        collector
+            .telemetry
+            .send(collector.event(json!({"name": "indexed"})))
+            .await
+            .unwrap();
+        let line = std::fs::read_to_string(&path).unwrap();
+        let event = serde_json::from_str::<Value>(line.trim_end()).unwrap();
+        assert_eq!(event["session"], json!("abc"));
+        assert_eq!(event["server"], json!("rust-analyzer"));
+        assert_eq!(event["event"], json!({"name": "indexed"}));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Fill placeholders in the command with query parameters.
+//!
+//! `{name}` in the arguments is replaced with the query parameter `name` if the parameter is
+//! allowed with `--param`. Values are restricted to a safe set of characters so they can't
+//! inject options or traverse paths.
+use std::{collections::HashMap, str::FromStr};
+
+use warp::{Filter, Rejection};
+
+use super::proxy::SharedContext;
+
+/// Query parameter allowed in the command, with the value to use if not given.
+///
+/// ```text
+/// tsVersion
+/// tsVersion=4.3.5
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub name: String,
+    pub default: Option<String>,
+}
+
+impl FromStr for Param {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, default) = match s.split_once('=') {
+            Some((name, default)) => (name, Some(default)),
+            None => (s, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("expected <name>[=<default>], got {}", s));
+        }
+        if let Some(default) = default.filter(|default| !is_valid_value(default)) {
+            return Err(format!("invalid default value {}", default));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            default: default.map(String::from),
+        })
+    }
+}
+
+/// Rejection for a query parameter with a value that can't be used in the command.
+#[derive(Debug)]
+pub(super) struct InvalidParam(pub(super) String);
+
+impl warp::reject::Reject for InvalidParam {}
+
+/// Values of the allowed query parameters, rejected with `InvalidParam` if invalid.
+pub(super) fn with_params(
+    ctx: SharedContext,
+) -> impl Filter<Extract = (HashMap<String, String>,), Error = Rejection> + Clone {
+    warp::query::<HashMap<String, String>>()
+        .or_else(|_| async { Ok::<_, Rejection>((HashMap::new(),)) })
+        .and_then(move |query: HashMap<String, String>| {
+            let values = select(&ctx.get().params, query);
+            async move { values.map_err(|name| warp::reject::custom(InvalidParam(name))) }
+        })
+}
+
+// Keep the allowed parameters, or return the name of the first invalid one.
+fn select(
+    params: &[Param],
+    mut query: HashMap<String, String>,
+) -> Result<HashMap<String, String>, String> {
+    let mut values = HashMap::new();
+    for param in params {
+        if let Some(value) = query.remove(&param.name) {
+            if !is_valid_value(&value) {
+                return Err(param.name.clone());
+            }
+            values.insert(param.name.clone(), value);
+        }
+    }
+    Ok(values)
+}
+
+/// Replace the placeholders in `command` with `values`, or the defaults of `params`.
+pub(super) fn expand(
+    command: &[String],
+    params: &[Param],
+    values: &HashMap<String, String>,
+) -> Result<Vec<String>, std::io::Error> {
+    command
+        .iter()
+        .map(|arg| {
+            let mut arg = arg.clone();
+            for param in params {
+                let placeholder = format!("{{{}}}", param.name);
+                if !arg.contains(&placeholder) {
+                    continue;
+                }
+                let value = values
+                    .get(&param.name)
+                    .or_else(|| param.default.as_ref())
+                    .ok_or_else(|| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("missing query parameter {}", param.name),
+                        )
+                    })?;
+                arg = arg.replace(&placeholder, value);
+            }
+            Ok(arg)
+        })
+        .collect()
+}
+
+// Letters, digits, `.`, `_`, `-`, `+` and `@`, not starting with `-` or `.`.
+fn is_valid_value(value: &str) -> bool {
+    !value.is_empty()
+        && !value.starts_with('-')
+        && !value.starts_with('.')
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+' | '@'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> Vec<Param> {
+        vec![
+            "tsVersion".parse().unwrap(),
+            "target=es2020".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn test_parse_param() {
+        assert_eq!(
+            "tsVersion=4.3.5".parse::<Param>(),
+            Ok(Param {
+                name: "tsVersion".to_owned(),
+                default: Some("4.3.5".to_owned()),
+            })
+        );
+        assert!("ts-version".parse::<Param>().is_err());
+        assert!("tsVersion=../x".parse::<Param>().is_err());
+    }
+
+    #[test]
+    fn test_expand() {
+        let command: Vec<String> = vec![
+            "typescript-language-server".to_owned(),
+            "--tsserver-path=/opt/ts/{tsVersion}/tsserver.js".to_owned(),
+            "--target={target}".to_owned(),
+        ];
+        let mut values = HashMap::new();
+        values.insert("tsVersion".to_owned(), "4.3.5".to_owned());
+        assert_eq!(
+            expand(&command, &params(), &values).unwrap(),
+            vec![
+                "typescript-language-server",
+                "--tsserver-path=/opt/ts/4.3.5/tsserver.js",
+                "--target=es2020",
+            ]
+        );
+        assert!(expand(&command, &params(), &HashMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_select_valid_values() {
+        let mut query = HashMap::new();
+        query.insert("tsVersion".to_owned(), "4.3.5".to_owned());
+        query.insert("other".to_owned(), "../../etc".to_owned());
+        let values = select(&params(), query).unwrap();
+        assert_eq!(values.len(), 1);
+
+        for invalid in &["../4.3.5", "--help", "4.3.5/../x", "a b", ""] {
+            let mut query = HashMap::new();
+            query.insert("tsVersion".to_owned(), (*invalid).to_owned());
+            assert_eq!(select(&params(), query), Err("tsVersion".to_owned()));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Editing the text of documents with the positions of LSP.
+use serde_json::Value;
+
+// Apply the change from `didChange` to the text, or return false if invalid.
+pub(super) fn apply_change(text: &mut String, change: &Value) -> bool {
+    let new_text = match change.get("text").and_then(Value::as_str) {
+        Some(new_text) => new_text,
+        None => return false,
+    };
+    let range = match change.get("range") {
+        Some(range) => range,
+        None => {
+            *text = new_text.to_owned();
+            return true;
+        }
+    };
+    let start = range.get("start").and_then(|p| offset_at(text, p));
+    let end = range.get("end").and_then(|p| offset_at(text, p));
+    match (start, end) {
+        (Some(start), Some(end)) if start <= end => {
+            text.replace_range(start..end, new_text);
+            true
+        }
+        _ => false,
+    }
+}
+
+// Byte offset of the position in UTF-16 code units.
+pub(super) fn offset_at(text: &str, position: &Value) -> Option<usize> {
+    let line = position.get("line")?.as_u64()?;
+    let character = position.get("character")?.as_u64()? as usize;
+    let mut start = 0;
+    for _ in 0..line {
+        start += text[start..].find('\n')? + 1;
+    }
+    let rest = &text[start..];
+    let line_text = &rest[..rest.find('\n').unwrap_or(rest.len())];
+    let mut units = 0;
+    for (i, c) in line_text.char_indices() {
+        if units >= character {
+            return Some(start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(start + line_text.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_apply_change() {
+        let mut text = "fn main() {\n    let a = 1;\n}\n".to_owned();
+        let change = json!({
+            "range": {"start": {"line": 1, "character": 8}, "end": {"line": 1, "character": 9}},
+            "text": "b"
+        });
+        assert!(apply_change(&mut text, &change));
+        assert_eq!(text, "fn main() {\n    let b = 1;\n}\n");
+
+        // UTF-16 code units
+        let mut text = "a😀b".to_owned();
+        let change = json!({
+            "range": {"start": {"line": 0, "character": 3}, "end": {"line": 0, "character": 4}},
+            "text": "c"
+        });
+        assert!(apply_change(&mut text, &change));
+        assert_eq!(text, "a😀c");
+
+        assert!(apply_change(&mut text, &json!({"text": "full"})));
+        assert_eq!(text, "full");
+    }
+}
@@ -0,0 +1,239 @@
+//! Watching the workspace for the files the servers registered watchers for.
+//!
+//! Servers register watchers for `workspace/didChangeWatchedFiles` with `client/registerCapability`
+//! to learn about the files changed outside of the editor, like the ones generated by a build.
+//! Browser clients can't watch the files on the host, so with `--watch`, each session watches its
+//! workspace and sends the changes matching the watchers of its server. The registrations are
+//! still forwarded to the client.
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use futures_util::{stream, Stream};
+use globset::{GlobBuilder, GlobMatcher};
+use lsp_types::{FileChangeType, FileEvent};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher as _};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use url::Url;
+
+/// Time to wait for more events of a file before sending the change.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+const METHOD: &str = "workspace/didChangeWatchedFiles";
+
+/// Files changed on the disk.
+pub(super) type Changes = Vec<(PathBuf, FileChangeType)>;
+
+/// Watch `dir` recursively, returning the watcher to keep while streaming the changes.
+pub(super) fn watch(
+    dir: &Path,
+) -> Result<
+    (
+        RecommendedWatcher,
+        impl Stream<Item = Changes> + Send + Unpin,
+    ),
+    notify::Error,
+> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE)?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+
+    let (changes_tx, changes_rx) = mpsc::unbounded_channel();
+    // Ends when the watcher is dropped.
+    std::thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            let changes = match event {
+                DebouncedEvent::Create(path) => vec![(path, FileChangeType::Created)],
+                DebouncedEvent::Write(path) => vec![(path, FileChangeType::Changed)],
+                DebouncedEvent::Remove(path) => vec![(path, FileChangeType::Deleted)],
+                DebouncedEvent::Rename(from, to) => vec![
+                    (from, FileChangeType::Deleted),
+                    (to, FileChangeType::Created),
+                ],
+                DebouncedEvent::Error(err, path) => {
+                    tracing::warn!("failed to watch {:?}: {}", path, err);
+                    continue;
+                }
+                _ => continue,
+            };
+            if changes_tx.send(changes).is_err() {
+                break;
+            }
+        }
+    });
+    Ok((
+        watcher,
+        Box::pin(stream::unfold(changes_rx, |mut rx| async move {
+            rx.recv().await.map(|changes| (changes, rx))
+        })),
+    ))
+}
+
+/// Watcher registered by the server.
+#[derive(Debug)]
+struct FileWatcher {
+    glob: GlobMatcher,
+    // `WatchKind` bits of the changes to send.
+    kind: u64,
+}
+
+impl FileWatcher {
+    fn from_value(value: &Value) -> Option<Self> {
+        let pattern = value.get("globPattern")?.as_str()?;
+        let glob = GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| tracing::warn!("invalid glob pattern {}: {}", pattern, err))
+            .ok()?
+            .compile_matcher();
+        // Create, change, and delete
+        let kind = value.get("kind").and_then(Value::as_u64).unwrap_or(7);
+        Some(Self { glob, kind })
+    }
+
+    /// Whether the change of the file at `path`, relative to the workspace `root`, is watched.
+    /// Patterns are matched against both absolute and relative paths.
+    fn matches(&self, root: &Path, path: &Path, change: FileChangeType) -> bool {
+        let bit = match change {
+            FileChangeType::Created => 1,
+            FileChangeType::Changed => 2,
+            FileChangeType::Deleted => 4,
+        };
+        self.kind & bit != 0
+            && (self.glob.is_match(path)
+                || path
+                    .strip_prefix(root)
+                    .map_or(false, |relative| self.glob.is_match(relative)))
+    }
+}
+
+/// Watchers registered by the server of a session, by the id of the registration.
+#[derive(Debug, Default)]
+pub(super) struct Watchers {
+    registrations: HashMap<String, Vec<FileWatcher>>,
+}
+
+impl Watchers {
+    /// Track the watchers registered and unregistered by `text` from the server.
+    pub(super) fn on_server_text(&mut self, text: &str) {
+        // Most messages don't have them, so they're not parsed.
+        if !text.contains(METHOD) {
+            return;
+        }
+        let msg = match serde_json::from_str::<Value>(text) {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let items = |pointer: &str| {
+            msg.pointer(pointer)
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter(|item| item.get("method").and_then(Value::as_str) == Some(METHOD))
+                .filter_map(|item| Some((item.get("id")?.as_str()?, item)))
+        };
+        match msg.get("method").and_then(Value::as_str) {
+            Some("client/registerCapability") => {
+                for (id, registration) in items("/params/registrations") {
+                    let watchers = registration
+                        .pointer("/registerOptions/watchers")
+                        .and_then(Value::as_array)
+                        .into_iter()
+                        .flatten()
+                        .filter_map(FileWatcher::from_value)
+                        .collect();
+                    self.registrations.insert(id.to_owned(), watchers);
+                }
+            }
+            // Misspelled in the specification.
+            Some("client/unregisterCapability") => {
+                for (id, _) in items("/params/unregisterations") {
+                    self.registrations.remove(id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The notification of the `changes` in the workspace `root` matching the watchers, if any.
+    pub(super) fn notification(&self, root: &Path, changes: Changes) -> Option<String> {
+        let changes = changes
+            .into_iter()
+            // Changes in `.git` are noise for the servers.
+            .filter(|(path, _)| {
+                !path
+                    .components()
+                    .any(|c| c == Component::Normal(".git".as_ref()))
+            })
+            .filter(|(path, change)| {
+                self.registrations
+                    .values()
+                    .flatten()
+                    .any(|watcher| watcher.matches(root, path, *change))
+            })
+            .filter_map(|(path, change)| {
+                Url::from_file_path(&path)
+                    .ok()
+                    .map(|uri| FileEvent::new(uri, change))
+            })
+            .collect::<Vec<_>>();
+        if changes.is_empty() {
+            return None;
+        }
+        Some(
+            json!({
+                "jsonrpc": "2.0",
+                "method": METHOD,
+                "params": {"changes": changes},
+            })
+            .to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchers() {
+        let root = Path::new("/workspace");
+        let mut watchers = Watchers::default();
+        watchers.on_server_text(
+            &json!({"jsonrpc": "2.0", "id": 1, "method": "client/registerCapability", "params": {
+                "registrations": [{"id": "rs", "method": METHOD, "registerOptions": {"watchers": [
+                    {"globPattern": "**/*.rs"},
+                    {"globPattern": "Cargo.toml", "kind": 2},
+                ]}}],
+            }})
+            .to_string(),
+        );
+        let changes = vec![
+            (root.join("src/gen.rs"), FileChangeType::Created),
+            (root.join("README.md"), FileChangeType::Changed),
+            (root.join("Cargo.toml"), FileChangeType::Changed),
+            (root.join("nested/Cargo.toml"), FileChangeType::Changed),
+            (root.join("Cargo.toml"), FileChangeType::Deleted),
+            (root.join(".git/hooks/x.rs"), FileChangeType::Created),
+        ];
+        let notification = watchers.notification(root, changes.clone()).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&notification).unwrap(),
+            json!({"jsonrpc": "2.0", "method": METHOD, "params": {"changes": [
+                {"uri": "file:///workspace/src/gen.rs", "type": 1},
+                {"uri": "file:///workspace/Cargo.toml", "type": 2},
+            ]}})
+        );
+
+        watchers.on_server_text(
+            &json!({"jsonrpc": "2.0", "id": 2, "method": "client/unregisterCapability", "params": {
+                "unregisterations": [{"id": "rs", "method": METHOD}],
+            }})
+            .to_string(),
+        );
+        assert_eq!(watchers.notification(root, changes), None);
+    }
+}
@@ -0,0 +1,250 @@
+//! Webhooks intercepting the messages with selected methods.
+//!
+//! A webhook `client:textDocument/codeAction=http://policy/hook` receives the code action
+//! requests from the client, and the responses to them from the server. Each message is posted as
+//! JSON, `{"from":"server","method":"textDocument/codeAction","message":{...}}`, and the webhook
+//! responds with the message to forward, modified or not, or `null` to drop it.
+//!
+//! A message is dropped if the webhook fails or doesn't respond in time, so a policy service
+//! being down doesn't let messages through.
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hyper::{client::HttpConnector, Body, Client, Request};
+use hyper_rustls::HttpsConnector;
+use serde_json::{json, Value};
+
+use crate::lsp::Message;
+
+use super::{
+    filter::{self, Direction, Rule},
+    middleware::{Middleware, Middlewares, Outbox},
+};
+
+/// Time the webhook has to respond before the message is dropped.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Messages matching `rule` are posted to `url`, given with `--webhook`.
+///
+/// ```text
+/// client:textDocument/codeAction=https://policy.example.com/lsp
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Webhook {
+    rule: Rule,
+    url: hyper::Uri,
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rule, url) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <rule>=<url>, got {}", s))?;
+        let url = url
+            .parse::<hyper::Uri>()
+            .map_err(|err| format!("invalid url {}: {}", url, err))?;
+        match url.scheme_str() {
+            Some("http") | Some("https") => Ok(Self {
+                rule: rule.parse()?,
+                url,
+            }),
+            _ => Err(format!("expected http or https url: {}", url)),
+        }
+    }
+}
+
+impl TryFrom<String> for Webhook {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Webhooks with the client posting to them, shared by the sessions.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    webhooks: Arc<Vec<Webhook>>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl Webhooks {
+    pub fn new(webhooks: Vec<Webhook>) -> Self {
+        Self {
+            webhooks: Arc::new(webhooks),
+            client: Client::builder().build(HttpsConnector::with_webpki_roots()),
+        }
+    }
+
+    /// Add a middleware posting the messages of a session to the webhooks to `middlewares`.
+    pub(super) fn apply(&self, middlewares: &mut Middlewares) {
+        if !self.webhooks.is_empty() {
+            middlewares.push(Interceptor {
+                webhooks: self.clone(),
+                pending: Default::default(),
+            });
+        }
+    }
+}
+
+/// Interceptor of a session, keeping the methods of the requests to match their responses.
+struct Interceptor {
+    webhooks: Webhooks,
+    pending: Mutex<HashMap<(Direction, String), String>>,
+}
+
+impl Interceptor {
+    /// The webhook for `msg` from `from` and the method of the message or its request.
+    fn select(&self, from: Direction, msg: &Value) -> Option<(&Webhook, String)> {
+        let to = match from {
+            Direction::Client => Direction::Server,
+            Direction::Server => Direction::Client,
+        };
+        let id = msg.get("id").map(Value::to_string);
+        let (requester, method) = match msg.get("method").and_then(Value::as_str) {
+            Some(method) => (from, method.to_owned()),
+            // Response to a request from the other side.
+            None => {
+                let id = id.clone()?;
+                let mut pending = self.pending.lock().expect("lock pending");
+                (to, pending.remove(&(to, id))?)
+            }
+        };
+        let webhook = self
+            .webhooks
+            .webhooks
+            .iter()
+            .find(|webhook| webhook.rule.matches(requester, &method))?;
+        if let (Some(id), true) = (id, requester == from) {
+            self.pending
+                .lock()
+                .expect("lock pending")
+                .insert((from, id), method.clone());
+        }
+        Some((webhook, method))
+    }
+
+    async fn run(&self, from: Direction, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        let value = match serde_json::to_value(&msg) {
+            Ok(value) => value,
+            Err(_) => return Some(msg),
+        };
+        let (webhook, method) = match self.select(from, &value) {
+            Some(selected) => selected,
+            None => return Some(msg),
+        };
+        let body = json!({
+            "from": match from {
+                Direction::Client => "client",
+                Direction::Server => "server",
+            },
+            "method": method,
+            "message": value,
+        });
+        match tokio::time::timeout(TIMEOUT, self.post(&webhook.url, &body)).await {
+            Ok(Ok(Some(msg))) => return Some(msg),
+            Ok(Ok(None)) => tracing::debug!("webhook {} dropped {}", webhook.url, method),
+            Ok(Err(err)) => tracing::error!("webhook {} failed: {}", webhook.url, err),
+            Err(_) => tracing::error!("webhook {} timed out", webhook.url),
+        }
+        self.reject(from, &body["message"], outbox);
+        None
+    }
+
+    /// Answer the dropped request `msg` from `from` with an error, so the sender isn't left
+    /// waiting.
+    fn reject(&self, from: Direction, msg: &Value, outbox: &mut Outbox) {
+        if let (Some(id), Some(_)) = (msg.get("id"), msg.get("method")) {
+            self.pending
+                .lock()
+                .expect("lock pending")
+                .remove(&(from, id.to_string()));
+            let rejection =
+                filter::rejection(&msg.to_string()).and_then(|text| Message::from_str(&text).ok());
+            if let Some(rejection) = rejection {
+                match from {
+                    Direction::Client => outbox.send_to_client(rejection),
+                    Direction::Server => outbox.send_to_server(rejection),
+                }
+            }
+        }
+    }
+
+    async fn post(&self, url: &hyper::Uri, body: &Value) -> Result<Option<Message>, String> {
+        let req = Request::post(url.clone())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .map_err(|err| err.to_string())?;
+        let res = self
+            .webhooks
+            .client
+            .request(req)
+            .await
+            .map_err(|err| err.to_string())?;
+        if !res.status().is_success() {
+            return Err(format!("responded with {}", res.status()));
+        }
+        let body = hyper::body::to_bytes(res.into_body())
+            .await
+            .map_err(|err| err.to_string())?;
+        serde_json::from_slice(&body).map_err(|err| format!("invalid message: {}", err))
+    }
+}
+
+#[async_trait::async_trait]
+impl Middleware for Interceptor {
+    async fn on_client_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        self.run(Direction::Client, msg, outbox).await
+    }
+
+    async fn on_server_message(&self, msg: Message, outbox: &mut Outbox) -> Option<Message> {
+        self.run(Direction::Server, msg, outbox).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let interceptor = Interceptor {
+            webhooks: Webhooks::new(vec![
+                "client:textDocument/codeAction=http://localhost:8080/hook"
+                    .parse()
+                    .unwrap(),
+            ]),
+            pending: Default::default(),
+        };
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": "textDocument/codeAction"});
+        let response = json!({"jsonrpc": "2.0", "id": 1, "result": []});
+        let other = json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/hover"});
+
+        assert!(interceptor.select(Direction::Server, &response).is_none());
+        let (_, method) = interceptor.select(Direction::Client, &request).unwrap();
+        assert_eq!(method, "textDocument/codeAction");
+        assert!(interceptor.select(Direction::Client, &other).is_none());
+        let (_, method) = interceptor.select(Direction::Server, &response).unwrap();
+        assert_eq!(method, "textDocument/codeAction");
+        // Only once
+        assert!(interceptor.select(Direction::Server, &response).is_none());
+
+        assert!("client:initialize=ftp://localhost"
+            .parse::<Webhook>()
+            .is_err());
+    }
+}
@@ -0,0 +1,179 @@
+//! Workspaces of the sessions, cloned from a repository with `--repo`, or empty with `--isolate`.
+//!
+//! Anonymous users connecting to the same proxy see each other's files in a shared workspace.
+//! With `--isolate`, each session gets a new directory under `--workspace-dir` instead. The
+//! servers are started in it, `source://` is relative to it, and `/files` requests with the
+//! `x-session-id` header of the session are in it. The client receives the ID with the
+//! notification `$/lsp-ws-proxy/workspace` when it connects. The workspace is removed when the
+//! session ends, or `--workspace-retention` seconds after, and stays available to `/files` until
+//! then.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use serde_json::json;
+use url::Url;
+
+use super::{
+    clone,
+    proxy::{Context, Query},
+};
+
+/// Method of the notification with the ID of the session to select its workspace with.
+const WORKSPACE_METHOD: &str = "$/lsp-ws-proxy/workspace";
+
+/// Directories of the workspaces of the sessions by their ID.
+#[derive(Debug, Clone, Default)]
+pub struct Workspaces {
+    dirs: Arc<RwLock<HashMap<String, PathBuf>>>,
+    /// Time to keep the workspaces after the sessions end.
+    retention: Duration,
+}
+
+impl Workspaces {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            dirs: Arc::default(),
+            retention,
+        }
+    }
+
+    /// Directory of the workspace of the session `id`.
+    pub(super) fn get(&self, id: &str) -> Option<PathBuf> {
+        self.dirs.read().expect("lock workspaces").get(id).cloned()
+    }
+
+    /// Register `dir` as the workspace of the session `id` until the returned guard is dropped
+    /// and the retention passed.
+    pub(super) fn register(&self, id: &str, dir: PathBuf) -> Workspace {
+        self.dirs
+            .write()
+            .expect("lock workspaces")
+            .insert(id.to_owned(), dir.clone());
+        Workspace {
+            id: id.to_owned(),
+            dir,
+            workspaces: self.clone(),
+        }
+    }
+
+    fn remove(&self, id: &str, dir: &std::path::Path) {
+        self.dirs.write().expect("lock workspaces").remove(id);
+        tracing::info!("removing workspace {}", dir.display());
+        if let Err(err) = std::fs::remove_dir_all(dir) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                tracing::warn!("failed to remove workspace {}: {}", dir.display(), err);
+            }
+        }
+    }
+}
+
+/// Workspace of a session, removed when dropped after the retention.
+#[derive(Debug)]
+pub(super) struct Workspace {
+    id: String,
+    dir: PathBuf,
+    workspaces: Workspaces,
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let (id, dir) = (std::mem::take(&mut self.id), std::mem::take(&mut self.dir));
+        let workspaces = self.workspaces.clone();
+        let retention = workspaces.retention;
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) if retention > Duration::from_secs(0) => {
+                handle.spawn(async move {
+                    tokio::time::sleep(retention).await;
+                    workspaces.remove(&id, &dir);
+                });
+            }
+            _ => workspaces.remove(&id, &dir),
+        }
+    }
+}
+
+/// Create the workspace for the session `id`, cloning the repository of `ctx` into it if any,
+/// returning the context with the servers started in it.
+pub(super) async fn prepare(
+    mut ctx: Context,
+    query: Option<&Query>,
+    id: &str,
+) -> Result<(Context, Option<Workspace>), std::io::Error> {
+    let dir = match (&ctx.repo, ctx.isolate) {
+        (Some(_), _) => ctx.clone_dir.join(id),
+        (None, true) => ctx.workspace_dir.join(id),
+        (None, false) => return Ok((ctx, None)),
+    };
+    // Fails for unknown servers before cloning.
+    super::proxy::select_server(&ctx.servers, query)?;
+    let cwd = Url::from_directory_path(&dir).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not an absolute path", dir.display()),
+        )
+    })?;
+    // Removes the partial clone if it fails.
+    let workspace = ctx.workspaces.register(id, dir.clone());
+    match &ctx.repo {
+        Some(repo) => clone::clone(repo, &dir, ctx.clone_depth).await?,
+        None => {
+            tracing::info!("creating workspace {}", dir.display());
+            tokio::fs::create_dir_all(&dir).await?;
+        }
+    }
+
+    for server in &mut ctx.servers {
+        server.cwd = Some(dir.clone());
+    }
+    ctx.remap_rules = ctx.remap_rules.with_root(&cwd);
+    ctx.cwd = cwd;
+    ctx.workspace_session = Some(id.to_owned());
+    Ok((ctx, Some(workspace)))
+}
+
+/// Notification to the client with the ID of the session `id` with its own workspace.
+pub(super) fn notification(id: &str) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": WORKSPACE_METHOD,
+        "params": {"session": id},
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lsp-ws-proxy-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_workspaces() {
+        let workspaces = Workspaces::new(Duration::from_secs(0));
+        let dir = temp_dir();
+        let workspace = workspaces.register("a", dir.clone());
+        assert_eq!(workspaces.get("a"), Some(dir.clone()));
+        assert_eq!(workspaces.get("b"), None);
+        drop(workspace);
+        assert_eq!(workspaces.get("a"), None);
+        assert!(!dir.exists());
+
+        // Kept for the retention
+        let workspaces = Workspaces::new(Duration::from_millis(50));
+        let dir = temp_dir();
+        drop(workspaces.register("a", dir.clone()));
+        assert_eq!(workspaces.get("a"), Some(dir.clone()));
+        assert!(dir.exists());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(workspaces.get("a"), None);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+/// Run the Language Server in a Docker container using the `docker` CLI.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Docker {
+    /// Start a new container from the image for each session, removed on exit.
+    /// The project root is mounted at the same path in the container.
+    Image(String),
+    /// Execute in a running container.
+    Exec(String),
+}
+
+impl Docker {
+    /// The command to run `command` in the container with `cwd` as the working directory.
+    pub fn command<P: AsRef<Path>>(&self, command: &[String], cwd: P) -> Vec<String> {
+        let cwd = cwd.as_ref().to_string_lossy();
+        let mut args: Vec<String> = match self {
+            Self::Image(image) => vec![
+                "docker".to_owned(),
+                "run".to_owned(),
+                "--rm".to_owned(),
+                "--interactive".to_owned(),
+                "--volume".to_owned(),
+                format!("{}:{}", cwd, cwd),
+                "--workdir".to_owned(),
+                cwd.into_owned(),
+                image.to_owned(),
+            ],
+            Self::Exec(container) => vec![
+                "docker".to_owned(),
+                "exec".to_owned(),
+                "--interactive".to_owned(),
+                "--workdir".to_owned(),
+                cwd.into_owned(),
+                container.to_owned(),
+            ],
+        };
+        args.extend(command.iter().cloned());
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_command() {
+        let docker = Docker::Image("rust:latest".to_owned());
+        let command = vec!["rust-analyzer".to_owned()];
+        assert_eq!(
+            docker.command(&command, "/workspace"),
+            vec![
+                "docker",
+                "run",
+                "--rm",
+                "--interactive",
+                "--volume",
+                "/workspace:/workspace",
+                "--workdir",
+                "/workspace",
+                "rust:latest",
+                "rust-analyzer",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_exec_command() {
+        let docker = Docker::Exec("dev".to_owned());
+        let command = vec!["gopls".to_owned(), "serve".to_owned()];
+        assert_eq!(
+            docker.command(&command, "/workspace"),
+            vec![
+                "docker",
+                "exec",
+                "--interactive",
+                "--workdir",
+                "/workspace",
+                "dev",
+                "gopls",
+                "serve",
+            ]
+        );
+    }
+}
@@ -0,0 +1,162 @@
+//! Resource limits of spawned servers.
+use std::str::FromStr;
+
+use tokio::process::Command;
+
+/// Limits applied to a spawned server with `setrlimit`.
+///
+/// ```toml
+/// [servers.limits]
+/// memory = 4096
+/// cpu = 3600
+/// files = 1024
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum address space in MiB.
+    pub memory: Option<u64>,
+    /// Maximum CPU time in seconds.
+    pub cpu: Option<u64>,
+    /// Maximum number of open files.
+    pub files: Option<u64>,
+}
+
+/// One of the limits, `<resource>=<value>`.
+///
+/// ```text
+/// memory=4096
+/// cpu=3600
+/// files=1024
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Memory(u64),
+    Cpu(u64),
+    Files(u64),
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (resource, value) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <resource>=<value>, got {}", s))?;
+        let value: u64 = value
+            .parse()
+            .map_err(|_| format!("invalid value of {}: {}", resource, value))?;
+        match resource {
+            // Must fit in bytes.
+            "memory" if value.checked_mul(1024 * 1024).is_none() => {
+                Err(format!("memory is too large: {}", value))
+            }
+            "memory" => Ok(Self::Memory(value)),
+            "cpu" => Ok(Self::Cpu(value)),
+            "files" => Ok(Self::Files(value)),
+            _ => Err(format!(
+                "unknown resource {}, expected memory, cpu, or files",
+                resource
+            )),
+        }
+    }
+}
+
+impl Limits {
+    pub fn set(&mut self, limit: Limit) {
+        match limit {
+            Limit::Memory(mib) => self.memory = Some(mib),
+            Limit::Cpu(secs) => self.cpu = Some(secs),
+            Limit::Files(files) => self.files = Some(files),
+        }
+    }
+
+    /// Limits of `self`, or of `other` if not set.
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            memory: self.memory.or(other.memory),
+            cpu: self.cpu.or(other.cpu),
+            files: self.files.or(other.files),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Apply the limits to the process started by `cmd`.
+    #[cfg(unix)]
+    pub(super) fn apply(&self, cmd: &mut Command) {
+        if self.is_empty() {
+            return;
+        }
+        // Computed before forking, so nothing can panic in the child. The memory from the config
+        // file isn't parsed as a `Limit`, and is unlimited if too large.
+        let limits = [
+            (
+                libc::RLIMIT_AS,
+                self.memory.map(|mib| mib.saturating_mul(1024 * 1024)),
+            ),
+            (libc::RLIMIT_CPU, self.cpu),
+            (libc::RLIMIT_NOFILE, self.files),
+        ];
+        // SAFETY: only calls `setrlimit`, which is async-signal-safe.
+        unsafe {
+            cmd.pre_exec(move || {
+                for (resource, value) in limits.iter() {
+                    if let Some(value) = value {
+                        let rlimit = libc::rlimit {
+                            rlim_cur: *value as libc::rlim_t,
+                            rlim_max: *value as libc::rlim_t,
+                        };
+                        if libc::setrlimit(*resource, &rlimit) != 0 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(super) fn apply(&self, _cmd: &mut Command) {
+        if !self.is_empty() {
+            tracing::warn!("resource limits are not supported on this platform");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_limit() {
+        assert_eq!("memory=4096".parse::<Limit>(), Ok(Limit::Memory(4096)));
+        assert_eq!("files=1024".parse::<Limit>(), Ok(Limit::Files(1024)));
+        assert!("memory".parse::<Limit>().is_err());
+        assert!("memory=4G".parse::<Limit>().is_err());
+        assert!("threads=4".parse::<Limit>().is_err());
+        assert!("memory=18446744073709551615".parse::<Limit>().is_err());
+    }
+
+    #[test]
+    fn test_merge_limits() {
+        let mut global = Limits::default();
+        global.set(Limit::Memory(4096));
+        global.set(Limit::Cpu(60));
+        let server = Limits {
+            memory: Some(1024),
+            ..Limits::default()
+        };
+        assert_eq!(
+            server.or(global),
+            Limits {
+                memory: Some(1024),
+                cpu: Some(60),
+                files: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Connections to Language Servers.
+//!
+//! A Language Server is either spawned as a child process communicating over stdio,
+//! or already running somewhere and connected to with [`Remote`].
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    io,
+    path::Path,
+    process::Stdio,
+    str::FromStr,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    process::Command,
+};
+use tracing::Instrument;
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+mod docker;
+mod limits;
+mod process;
+mod ssh;
+
+pub use docker::Docker;
+pub use limits::{Limit, Limits};
+pub use process::{Process, StopSignals};
+pub use ssh::Ssh;
+
+pub type Reader = Box<dyn AsyncRead + Send + Unpin>;
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Address of a running Language Server to connect to instead of spawning one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Remote {
+    /// `tcp://host:port`
+    Tcp(String),
+    /// `unix:///path/to/socket`
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// `pipe:\\.\pipe\name`
+    #[cfg(windows)]
+    Pipe(String),
+    /// `ws://host/path` or `wss://host/path` of another proxy.
+    WebSocket(String),
+    /// `ssh://user@host[:port][/path/to/project]`
+    ///
+    /// Unlike others, the server is started on the remote machine for each connection.
+    Ssh(Ssh),
+}
+
+impl Remote {
+    /// Whether the command to start the server is required.
+    pub fn requires_command(&self) -> bool {
+        matches!(self, Self::Ssh(_))
+    }
+}
+
+impl FromStr for Remote {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            let addr = addr.trim_end_matches('/');
+            if addr.is_empty() {
+                return Err(format!("{} is missing host:port", s));
+            }
+            Ok(Self::Tcp(addr.to_owned()))
+        } else if let Some(path) = s
+            .strip_prefix("unix://")
+            .or_else(|| s.strip_prefix("unix:"))
+        {
+            parse_unix(s, path)
+        } else if let Some(name) = s.strip_prefix("pipe:") {
+            parse_pipe(s, name)
+        } else if s.starts_with("ws://") || s.starts_with("wss://") {
+            Ok(Self::WebSocket(s.to_owned()))
+        } else if let Some(ssh) = s.strip_prefix("ssh://") {
+            Ssh::parse(ssh).map(Self::Ssh)
+        } else {
+            Err(format!(
+                "{} is not supported, expected tcp://host:port, unix:///path, ws://host or ssh://host",
+                s
+            ))
+        }
+    }
+}
+
+#[cfg(unix)]
+fn parse_unix(s: &str, path: &str) -> Result<Remote, String> {
+    if path.is_empty() {
+        return Err(format!("{} is missing the socket path", s));
+    }
+    Ok(Remote::Unix(PathBuf::from(path)))
+}
+
+#[cfg(not(unix))]
+fn parse_unix(s: &str, _path: &str) -> Result<Remote, String> {
+    Err(format!("{} is not supported on this platform", s))
+}
+
+#[cfg(windows)]
+fn parse_pipe(s: &str, name: &str) -> Result<Remote, String> {
+    if name.is_empty() {
+        return Err(format!("{} is missing the pipe name", s));
+    }
+    Ok(Remote::Pipe(name.to_owned()))
+}
+
+#[cfg(not(windows))]
+fn parse_pipe(s: &str, _name: &str) -> Result<Remote, String> {
+    Err(format!("{} is not supported on this platform", s))
+}
+
+impl Display for Remote {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp://{}", addr),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix://{}", path.display()),
+            #[cfg(windows)]
+            Self::Pipe(name) => write!(f, "pipe:{}", name),
+            Self::WebSocket(url) => f.write_str(url),
+            Self::Ssh(ssh) => Display::fmt(ssh, f),
+        }
+    }
+}
+
+/// Connection to a Language Server.
+pub struct Connection {
+    /// Messages from the server.
+    pub reader: Reader,
+    /// Messages to the server.
+    pub writer: Writer,
+    /// The server process if spawned. The process is stopped when this is dropped.
+    pub child: Option<Process>,
+}
+
+impl Connection {
+    /// Spawn a Language Server with `command` and communicate over its stdio.
+    pub fn spawn(command: &[String]) -> io::Result<Self> {
+        Self::spawn_with(
+            command,
+            &HashMap::new(),
+            None,
+            &Limits::default(),
+            &StopSignals::default(),
+        )
+    }
+
+    /// Like [`Connection::spawn`], with additional environment variables, working directory,
+    /// resource limits, and signals to stop the process with.
+    pub fn spawn_with(
+        command: &[String],
+        env: &HashMap<String, String>,
+        cwd: Option<&Path>,
+        limits: &Limits,
+        stop: &StopSignals,
+    ) -> io::Result<Self> {
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..])
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(cwd) = cwd {
+            cmd.current_dir(cwd);
+        }
+        limits.apply(&mut cmd);
+        process::new_group(&mut cmd);
+        let mut child = cmd.spawn()?;
+        let writer = child.stdin.take().expect("piped stdin");
+        let reader = child.stdout.take().expect("piped stdout");
+        Ok(Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            child: Some(Process::new(child, stop.clone())),
+        })
+    }
+
+    /// Connect to a running Language Server.
+    ///
+    /// Use [`Ssh::command`] to start one on the remote machine instead.
+    pub async fn connect(remote: &Remote) -> io::Result<Self> {
+        match remote {
+            Remote::Ssh(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ssh requires a command to start the server",
+            )),
+
+            Remote::Tcp(addr) => {
+                let stream = TcpStream::connect(addr.as_str()).await?;
+                let (reader, writer) = stream.into_split();
+                Ok(Self {
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                    child: None,
+                })
+            }
+
+            Remote::WebSocket(url) => {
+                let (ws, _) = tokio_tungstenite::connect_async(url.as_str())
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+                // Adapt messages to a byte stream of framed messages like other servers.
+                let (local, bridged) = tokio::io::duplex(64 * 1024);
+                tokio::spawn(
+                    async move {
+                        let (reader, writer) = tokio::io::split(bridged);
+                        if let Err(err) = crate::bridge::forward(ws, reader, writer).await {
+                            tracing::error!("upstream error: {}", err);
+                        }
+                    }
+                    .in_current_span(),
+                );
+                let (reader, writer) = tokio::io::split(local);
+                Ok(Self {
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                    child: None,
+                })
+            }
+
+            #[cfg(windows)]
+            Remote::Pipe(name) => {
+                let pipe = tokio::net::windows::named_pipe::ClientOptions::new().open(name)?;
+                let (reader, writer) = tokio::io::split(pipe);
+                Ok(Self {
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                    child: None,
+                })
+            }
+
+            #[cfg(unix)]
+            Remote::Unix(path) => {
+                let stream = tokio::net::UnixStream::connect(path).await?;
+                let (reader, writer) = stream.into_split();
+                Ok(Self {
+                    reader: Box::new(reader),
+                    writer: Box::new(writer),
+                    child: None,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tcp() {
+        assert_eq!(
+            "tcp://127.0.0.1:8080".parse::<Remote>(),
+            Ok(Remote::Tcp("127.0.0.1:8080".to_owned()))
+        );
+        assert_eq!(
+            "tcp://localhost:8080/".parse::<Remote>(),
+            Ok(Remote::Tcp("localhost:8080".to_owned()))
+        );
+        assert!("tcp://".parse::<Remote>().is_err());
+        assert!("http://localhost:8080".parse::<Remote>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_unix() {
+        assert_eq!(
+            "unix:///tmp/lsp.sock".parse::<Remote>(),
+            Ok(Remote::Unix(PathBuf::from("/tmp/lsp.sock")))
+        );
+        assert_eq!(
+            "unix:/tmp/lsp.sock".parse::<Remote>(),
+            Ok(Remote::Unix(PathBuf::from("/tmp/lsp.sock")))
+        );
+        assert!("unix://".parse::<Remote>().is_err());
+    }
+
+    #[test]
+    fn test_parse_websocket() {
+        assert_eq!(
+            "wss://example.com/lsp?name=rust-analyzer".parse::<Remote>(),
+            Ok(Remote::WebSocket(
+                "wss://example.com/lsp?name=rust-analyzer".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_display_tcp() {
+        let remote = Remote::Tcp("localhost:8080".to_owned());
+        assert_eq!(remote.to_string(), "tcp://localhost:8080");
+    }
+}
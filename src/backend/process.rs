@@ -0,0 +1,348 @@
+//! Spawned server processes, stopped with a sequence of signals when dropped.
+//!
+//! On Unix, the process is started in its own process group, and the processes it started that
+//! are still running when it's stopped are killed with it.
+//!
+//! The stderr of the process is copied to the stderr of the proxy, keeping the end of it to
+//! report why the server exited.
+use std::{
+    collections::VecDeque,
+    convert::TryFrom,
+    ops::{Deref, DerefMut},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    process::{Child, ChildStderr},
+    sync::OwnedSemaphorePermit,
+    task::JoinHandle,
+};
+use tracing::Instrument;
+
+/// Bytes kept from the end of stderr.
+const STDERR_TAIL: usize = 8 * 1024;
+/// Time to wait for the rest of stderr after the process exited.
+const STDERR_WAIT: Duration = Duration::from_millis(500);
+
+/// Number of server processes running.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Time to wait after a signal if not given.
+const DEFAULT_WAIT: Duration = Duration::from_secs(5);
+
+/// Signals to send to stop the server, each followed by the time to wait for it to exit.
+/// The server is killed if it's still running after the last one.
+///
+/// ```text
+/// INT:5,TERM:5
+/// SIGTERM
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct StopSignals {
+    steps: Vec<(i32, Duration)>,
+}
+
+impl FromStr for StopSignals {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let steps = s
+            .split(',')
+            .map(|step| {
+                let (name, wait) = match step.split_once(':') {
+                    Some((name, secs)) => {
+                        let secs = secs
+                            .parse()
+                            .map_err(|_| format!("invalid seconds to wait: {}", secs))?;
+                        (name, Duration::from_secs(secs))
+                    }
+                    None => (step, DEFAULT_WAIT),
+                };
+                Ok((parse_signal(name)?, wait))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { steps })
+    }
+}
+
+impl TryFrom<String> for StopSignals {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl StopSignals {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+#[cfg(unix)]
+fn parse_signal(name: &str) -> Result<i32, String> {
+    match name.trim_start_matches("SIG") {
+        "HUP" => Ok(libc::SIGHUP),
+        "INT" => Ok(libc::SIGINT),
+        "QUIT" => Ok(libc::SIGQUIT),
+        "TERM" => Ok(libc::SIGTERM),
+        "USR1" => Ok(libc::SIGUSR1),
+        "USR2" => Ok(libc::SIGUSR2),
+        "KILL" => Ok(libc::SIGKILL),
+        _ => Err(format!("unknown signal {}", name)),
+    }
+}
+
+#[cfg(not(unix))]
+fn parse_signal(name: &str) -> Result<i32, String> {
+    Err(format!("signal {} is not supported on this platform", name))
+}
+
+/// Server process. The process is stopped with its signals when this is dropped.
+#[derive(Debug)]
+pub struct Process {
+    child: Option<Child>,
+    stop: StopSignals,
+    running: Option<Running>,
+    stderr: Tail,
+    /// Task copying stderr until it's closed.
+    copy_stderr: Option<JoinHandle<()>>,
+    /// The process group led by the process.
+    group: Option<u32>,
+}
+
+/// End of the output, dropping the oldest bytes over the limit.
+#[derive(Debug, Clone, Default)]
+struct Tail {
+    bytes: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl Tail {
+    fn push(&self, data: &[u8]) {
+        let mut bytes = self.bytes.lock().expect("lock tail");
+        bytes.extend(data);
+        let over = bytes.len().saturating_sub(STDERR_TAIL);
+        bytes.drain(..over);
+    }
+
+    fn to_string_lossy(&self) -> String {
+        let bytes = self.bytes.lock().expect("lock tail");
+        let (front, back) = bytes.as_slices();
+        String::from_utf8_lossy(&[front, back].concat()).into_owned()
+    }
+}
+
+async fn copy_stderr(mut stderr: ChildStderr, tail: Tail) {
+    let mut out = tokio::io::stderr();
+    let mut buf = [0; 4096];
+    loop {
+        match stderr.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                tail.push(&buf[..n]);
+                let _ = out.write_all(&buf[..n]).await;
+            }
+        }
+    }
+}
+
+/// Counted as running until dropped after the process exits.
+#[derive(Debug)]
+struct Running {
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Process {
+    /// The process must be started with [`new_group`].
+    pub(super) fn new(mut child: Child, stop: StopSignals) -> Self {
+        RUNNING.fetch_add(1, Ordering::SeqCst);
+        let stderr = Tail::default();
+        let copy_stderr = child
+            .stderr
+            .take()
+            .map(|out| tokio::spawn(copy_stderr(out, stderr.clone())));
+        let group = if cfg!(unix) { child.id() } else { None };
+        Self {
+            child: Some(child),
+            stop,
+            running: Some(Running { permit: None }),
+            stderr,
+            copy_stderr,
+            group,
+        }
+    }
+
+    /// The end of stderr, waiting a little for the rest of it if the process exited.
+    pub async fn stderr_tail(&mut self) -> String {
+        if let Some(copy) = self.copy_stderr.take() {
+            let _ = tokio::time::timeout(STDERR_WAIT, copy).await;
+        }
+        self.stderr.to_string_lossy().trim_end().to_owned()
+    }
+
+    /// Number of server processes running.
+    pub fn running() -> usize {
+        RUNNING.load(Ordering::SeqCst)
+    }
+
+    /// Hold `permit` until the process exits.
+    pub fn hold(&mut self, permit: OwnedSemaphorePermit) {
+        if let Some(running) = self.running.as_mut() {
+            running.permit = Some(permit);
+        }
+    }
+}
+
+impl Deref for Process {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        self.child.as_ref().expect("child")
+    }
+}
+
+impl DerefMut for Process {
+    fn deref_mut(&mut self) -> &mut Child {
+        self.child.as_mut().expect("child")
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let child = match self.child.take() {
+            Some(child) => child,
+            None => return,
+        };
+        let group = self.group;
+        // Without signals, the child is killed when dropped.
+        if self.stop.is_empty() {
+            kill_group(group);
+            return;
+        }
+        let steps = std::mem::take(&mut self.stop.steps);
+        let running = self.running.take();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(
+                async move {
+                    stop(child, steps, group).await;
+                    drop(running);
+                }
+                .in_current_span(),
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn stop(mut child: Child, steps: Vec<(i32, Duration)>, group: Option<u32>) {
+    let mut stopped = false;
+    for (signal, wait) in steps {
+        let pid = match child.id() {
+            Some(pid) => pid,
+            // Already exited
+            None => {
+                stopped = true;
+                break;
+            }
+        };
+        tracing::debug!("sending signal {} to {}", signal, pid);
+        if !send_signal(group.unwrap_or(pid), group.is_some(), signal) {
+            break;
+        }
+        if tokio::time::timeout(wait, child.wait()).await.is_ok() {
+            stopped = true;
+            break;
+        }
+    }
+    if !stopped {
+        tracing::warn!("killing server still running after the stop signals");
+        let _ = child.kill().await;
+    }
+    kill_group(group);
+}
+
+#[cfg(not(unix))]
+async fn stop(mut child: Child, _steps: Vec<(i32, Duration)>, _group: Option<u32>) {
+    let _ = child.kill().await;
+}
+
+/// Send `signal` to the process `pid`, or to all processes in the group `pid`.
+#[cfg(unix)]
+fn send_signal(pid: u32, group: bool, signal: i32) -> bool {
+    let pid = pid as libc::pid_t;
+    let pid = if group { -pid } else { pid };
+    // SAFETY: `kill` has no memory safety requirements.
+    unsafe { libc::kill(pid, signal) == 0 }
+}
+
+/// Kill the processes the server started that are still running in its group.
+#[cfg(unix)]
+fn kill_group(group: Option<u32>) {
+    if let Some(group) = group {
+        send_signal(group, true, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_group(_group: Option<u32>) {}
+
+/// Start the process in a new process group, so it can be stopped with the processes it starts.
+#[cfg(unix)]
+pub(super) fn new_group(cmd: &mut tokio::process::Command) {
+    // SAFETY: only calls `setpgid`, which is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub(super) fn new_group(_cmd: &mut tokio::process::Command) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stderr_tail() {
+        let tail = Tail::default();
+        tail.push(&[b'a'; STDERR_TAIL]);
+        tail.push(b"panicked");
+        let text = tail.to_string_lossy();
+        assert_eq!(text.len(), STDERR_TAIL);
+        assert!(text.ends_with("apanicked"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_stop_signals() {
+        assert_eq!(
+            "INT:2,SIGTERM".parse::<StopSignals>(),
+            Ok(StopSignals {
+                steps: vec![
+                    (libc::SIGINT, Duration::from_secs(2)),
+                    (libc::SIGTERM, DEFAULT_WAIT),
+                ],
+            })
+        );
+        assert!("INT:soon".parse::<StopSignals>().is_err());
+        assert!("STOP".parse::<StopSignals>().is_err());
+    }
+}
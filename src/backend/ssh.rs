@@ -0,0 +1,158 @@
+use std::fmt::{self, Display, Formatter};
+
+/// Run the Language Server on a remote machine over SSH using the `ssh` CLI.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ssh {
+    /// `user@host` or `host`.
+    pub destination: String,
+    pub port: Option<u16>,
+    /// Directory on the remote machine to start the server in.
+    /// This is the project root when remapping URIs.
+    pub cwd: Option<String>,
+}
+
+impl Ssh {
+    /// Parse `user@host[:port][/path]` after `ssh://`.
+    pub(super) fn parse(s: &str) -> Result<Self, String> {
+        let (authority, cwd) = match s.find('/') {
+            Some(i) if i + 1 < s.len() => (&s[..i], Some(s[i..].to_owned())),
+            Some(i) => (&s[..i], None),
+            None => (s, None),
+        };
+        let parse_port = |port: &str| {
+            port.parse::<u16>()
+                .map_err(|_| format!("{} has an invalid port", s))
+        };
+        // Avoid splitting IPv6 addresses like `[::1]:22`.
+        let (destination, port) = match authority.find(']') {
+            Some(end) if end + 1 < authority.len() => {
+                let port = authority[end + 1..]
+                    .strip_prefix(':')
+                    .ok_or_else(|| format!("{} has an invalid port", s))?;
+                (&authority[..=end], Some(parse_port(port)?))
+            }
+            Some(_) => (authority, None),
+            None => match authority.rsplit_once(':') {
+                Some((destination, port)) => (destination, Some(parse_port(port)?)),
+                None => (authority, None),
+            },
+        };
+        if destination.is_empty() {
+            return Err(format!("{} is missing the host", s));
+        }
+        Ok(Self {
+            destination: destination.to_owned(),
+            port,
+            cwd,
+        })
+    }
+
+    /// The command to run `command` on the remote machine.
+    pub fn command(&self, command: &[String]) -> Vec<String> {
+        let mut args = vec![
+            "ssh".to_owned(),
+            // No pseudo-terminal, the server speaks LSP over stdio.
+            "-T".to_owned(),
+            // Fail instead of prompting for passwords.
+            "-o".to_owned(),
+            "BatchMode=yes".to_owned(),
+        ];
+        if let Some(port) = self.port {
+            args.push("-p".to_owned());
+            args.push(port.to_string());
+        }
+        args.push(self.destination.clone());
+
+        // The remote shell parses the command, so quote everything.
+        let command = command
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ");
+        args.push(if let Some(cwd) = &self.cwd {
+            format!("cd {} && exec {}", shell_quote(cwd), command)
+        } else {
+            format!("exec {}", command)
+        });
+        args
+    }
+}
+
+impl Display for Ssh {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "ssh://{}", self.destination)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        if let Some(cwd) = &self.cwd {
+            write!(f, "{}", cwd)?;
+        }
+        Ok(())
+    }
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ssh::parse("user@host"),
+            Ok(Ssh {
+                destination: "user@host".to_owned(),
+                port: None,
+                cwd: None,
+            })
+        );
+        assert_eq!(
+            Ssh::parse("user@host:2222/home/user/project"),
+            Ok(Ssh {
+                destination: "user@host".to_owned(),
+                port: Some(2222),
+                cwd: Some("/home/user/project".to_owned()),
+            })
+        );
+        assert_eq!(
+            Ssh::parse("[::1]"),
+            Ok(Ssh {
+                destination: "[::1]".to_owned(),
+                port: None,
+                cwd: None,
+            })
+        );
+        assert_eq!(
+            Ssh::parse("[::1]:22"),
+            Ok(Ssh {
+                destination: "[::1]".to_owned(),
+                port: Some(22),
+                cwd: None,
+            })
+        );
+        assert!(Ssh::parse("host:abc").is_err());
+        assert!(Ssh::parse("").is_err());
+    }
+
+    #[test]
+    fn test_command() {
+        let ssh = Ssh::parse("user@host:2222/home/user/it's").unwrap();
+        let command = vec!["rust-analyzer".to_owned()];
+        assert_eq!(
+            ssh.command(&command),
+            vec![
+                "ssh",
+                "-T",
+                "-o",
+                "BatchMode=yes",
+                "-p",
+                "2222",
+                "user@host",
+                r#"cd '/home/user/it'\''s' && exec 'rust-analyzer'"#,
+            ]
+        );
+    }
+}
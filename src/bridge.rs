@@ -0,0 +1,95 @@
+//! Bridge mode: speak LSP over stdio and forward messages to a remote proxy over WebSocket.
+//!
+//! This lets a desktop editor start `lsp-ws-proxy --bridge ws://host:9999` as its Language Server.
+use futures_util::{
+    future::{select, Either},
+    SinkExt, StreamExt,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+
+use crate::lsp;
+
+/// Forward messages between stdio and the WebSocket at `url` until either of them closes.
+pub async fn run(url: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    tracing::info!("connecting to {}", url);
+    let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+    tracing::info!("connected");
+    forward(ws, tokio::io::stdin(), tokio::io::stdout()).await
+}
+
+/// Forward framed LSP messages from `reader` to `ws`, and messages from `ws` to `writer`,
+/// until either of them closes.
+pub async fn forward<S, R, W>(
+    ws: WebSocketStream<S>,
+    reader: R,
+    writer: W,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut remote_send, mut remote_recv) = ws.split();
+    let mut local_send = lsp::framed::writer(writer);
+    let mut local_recv = lsp::framed::reader(reader);
+
+    let mut local_msg = local_recv.next();
+    let mut remote_msg = remote_recv.next();
+    loop {
+        match select(local_msg, remote_msg).await {
+            // From the editor, or the proxy when chaining
+            Either::Left((from_local, p_remote_msg)) => {
+                match from_local {
+                    Some(Ok(text)) => {
+                        tracing::debug!("-> {}", text);
+                        remote_send.send(Message::Text(text)).await?;
+                    }
+
+                    // Codec Error
+                    Some(Err(err)) => {
+                        tracing::error!("{}", err);
+                    }
+
+                    // Editor exited
+                    None => {
+                        tracing::info!("local connection closed");
+                        remote_send.send(Message::Close(None)).await?;
+                        break;
+                    }
+                }
+
+                local_msg = local_recv.next();
+                remote_msg = p_remote_msg;
+            }
+
+            // From the remote proxy
+            Either::Right((from_remote, p_local_msg)) => {
+                match from_remote {
+                    Some(Ok(Message::Text(text))) => {
+                        tracing::debug!("<- {}", text);
+                        local_send.send(text).await?;
+                    }
+
+                    Some(Ok(Message::Close(_))) | None => {
+                        tracing::info!("connection closed");
+                        break;
+                    }
+
+                    // Pings are answered automatically
+                    Some(Ok(_)) => {}
+
+                    Some(Err(err)) => {
+                        tracing::error!("websocket error: {}", err);
+                        break;
+                    }
+                }
+
+                local_msg = p_local_msg;
+                remote_msg = remote_recv.next();
+            }
+        }
+    }
+
+    Ok(())
+}
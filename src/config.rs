@@ -0,0 +1,307 @@
+//! Configuration file describing the servers and the listener.
+//!
+//! ```toml
+//! listen = ["0.0.0.0:9999"]
+//! sync = true
+//!
+//! [[servers]]
+//! command = "rust-analyzer"
+//! route = "/rust"
+//! languages = ["rust"]
+//! extensions = ["rs"]
+//!
+//! [[servers]]
+//! name = "pyright"
+//! command = "pyright-langserver"
+//! args = ["--stdio"]
+//! env = { NODE_OPTIONS = "--max-old-space-size=4096" }
+//! remap = true
+//! limits = { memory = 4096, files = 1024 }
+//! ```
+//!
+//! YAML is used if the file extension is `.yaml` or `.yml`.
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    api::{
+        clone,
+        diagnostics::DiagnosticRules,
+        filter, interactive,
+        multiplex::{Extension, Language},
+        proxy, quota, rate_limit, telemetry, webhook,
+    },
+    backend::{Limits, StopSignals},
+    lsp::ext::RemapRule,
+};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("failed to read {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("invalid config {path}: {source}")]
+    Toml {
+        path: String,
+        source: toml::de::Error,
+    },
+
+    #[error("invalid config {path}: {source}")]
+    Yaml {
+        path: String,
+        source: serde_yaml::Error,
+    },
+}
+
+/// Options of the proxy. Options given on the command line take precedence.
+#[derive(Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub listen: Vec<String>,
+    pub socket_mode: Option<u32>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub sync: bool,
+    pub apply_edits: bool,
+    pub watch: bool,
+    pub memory: bool,
+    pub memory_dir: Option<PathBuf>,
+    pub isolate: bool,
+    pub workspace_dir: Option<PathBuf>,
+    pub workspace_retention: Option<u64>,
+    pub quotas: Vec<quota::Limit>,
+    pub sync_roots: Vec<PathBuf>,
+    pub remap: bool,
+    pub remap_rules: Vec<RemapRule>,
+    pub deep_remap: bool,
+    pub redact_paths: bool,
+    pub strip_snippets: bool,
+    pub answer_unsupported: bool,
+    pub message_action: Option<interactive::MessageAction>,
+    pub normalize_line_endings: bool,
+    pub sse: bool,
+    pub drop: Vec<filter::Rule>,
+    pub allow: Vec<filter::Rule>,
+    pub webhooks: Vec<webhook::Webhook>,
+    pub telemetry: Option<telemetry::Sink>,
+    pub repo: Option<clone::Repo>,
+    pub allow_repos: Vec<String>,
+    pub rate_limits: Vec<rate_limit::RateLimit>,
+    pub cache: Vec<String>,
+    pub prefix: Option<String>,
+    pub ws_path: Vec<String>,
+    pub discover: bool,
+    pub servers: Vec<ServerConfig>,
+}
+
+/// Definition of a Language Server.
+#[derive(Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Name to select the server with. Defaults to `command`.
+    pub name: Option<String>,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    pub cwd: Option<PathBuf>,
+    /// Start the server in the workspace of the client if it's a local directory.
+    #[serde(default, rename = "cwd-from-root")]
+    pub cwd_from_root: bool,
+    pub remap: Option<bool>,
+    pub sync: Option<bool>,
+    /// Name of the server to start if this one fails to start or initialize.
+    pub fallback: Option<String>,
+    /// Path to start the server on.
+    pub route: Option<String>,
+    /// Languages to multiplex the server for.
+    #[serde(default)]
+    pub languages: Vec<String>,
+    /// File extensions to multiplex the server for.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Resource limits of the process, overriding `--limit`.
+    #[serde(default)]
+    pub limits: Limits,
+    /// Signals to stop the process with, overriding `--stop-signals`.
+    #[serde(default, rename = "stop-signals")]
+    pub stop_signals: StopSignals,
+    /// Merged into `initializationOptions` of `initialize`.
+    #[serde(rename = "initialization-options")]
+    pub initialization_options: Option<serde_json::Value>,
+    /// Merge patch applied to `capabilities` of `initialize`.
+    pub capabilities: Option<serde_json::Value>,
+    /// WebAssembly plugins run on the messages.
+    #[serde(default)]
+    pub plugins: Vec<PathBuf>,
+    /// Rhai script run on the messages.
+    pub script: Option<PathBuf>,
+    /// Command the messages are piped through.
+    pub hook: Option<Vec<String>>,
+    /// Rules transforming the published diagnostics.
+    pub diagnostics: Option<DiagnosticRules>,
+    /// Settings to answer `workspace/configuration` with.
+    pub settings: Option<serde_json::Value>,
+    /// Commands allowed in `workspace/executeCommand`, ending with `*` to match the prefix.
+    pub commands: Option<Vec<String>>,
+}
+
+impl ServerConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.command)
+    }
+
+    pub fn to_server(&self) -> proxy::Server {
+        let mut command = vec![self.command.clone()];
+        command.extend(self.args.iter().cloned());
+        proxy::Server {
+            name: self.name().to_owned(),
+            command,
+            env: self.env.clone(),
+            cwd: self.cwd.clone(),
+            cwd_from_root: self.cwd_from_root,
+            remap: self.remap,
+            sync: self.sync,
+            fallback: self.fallback.clone(),
+            limits: self.limits,
+            stop_signals: self.stop_signals.clone(),
+            initialization_options: self.initialization_options.clone(),
+            capabilities: self.capabilities.clone(),
+            plugins: self.plugins.clone(),
+            script: self.script.clone(),
+            hook: self.hook.clone(),
+            diagnostics: self.diagnostics.clone(),
+            settings: self.settings.clone(),
+            commands: self.commands.clone(),
+        }
+    }
+
+    pub fn to_route(&self) -> Option<proxy::Route> {
+        self.route.as_ref().map(|path| proxy::Route {
+            path: path.clone(),
+            name: self.name().to_owned(),
+        })
+    }
+
+    pub fn to_languages(&self) -> impl Iterator<Item = Language> + '_ {
+        self.languages.iter().map(move |id| Language {
+            id: id.clone(),
+            name: self.name().to_owned(),
+        })
+    }
+
+    pub fn to_extensions(&self) -> impl Iterator<Item = Extension> + '_ {
+        self.extensions.iter().map(move |extension| Extension {
+            extension: extension.trim_start_matches('.').to_owned(),
+            name: self.name().to_owned(),
+        })
+    }
+}
+
+/// Load the config file at `path`.
+pub fn load(path: &Path) -> Result<Config, Error> {
+    let display = path.display().to_string();
+    let contents = std::fs::read_to_string(path).map_err(|source| Error::Read {
+        path: display.clone(),
+        source,
+    })?;
+    parse(&contents, path)
+}
+
+fn parse(contents: &str, path: &Path) -> Result<Config, Error> {
+    let display = path.display().to_string();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml") | Some("yml") => {
+            serde_yaml::from_str(contents).map_err(|source| Error::Yaml {
+                path: display,
+                source,
+            })
+        }
+        _ => toml::from_str(contents).map_err(|source| Error::Toml {
+            path: display,
+            source,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_toml() {
+        let config = parse(
+            r#"
+listen = ["8888"]
+sync = true
+
+[[servers]]
+command = "rust-analyzer"
+route = "/rust"
+
+[[servers]]
+name = "pyright"
+command = "pyright-langserver"
+args = ["--stdio"]
+env = { FOO = "bar" }
+"#,
+            Path::new("proxy.toml"),
+        )
+        .unwrap();
+        assert_eq!(config.listen, vec!["8888".to_owned()]);
+        assert!(config.sync);
+        assert_eq!(config.servers[0].name(), "rust-analyzer");
+        assert_eq!(
+            config.servers[0].to_route(),
+            Some(proxy::Route {
+                path: "/rust".to_owned(),
+                name: "rust-analyzer".to_owned(),
+            })
+        );
+        let pyright = config.servers[1].to_server();
+        assert_eq!(pyright.name, "pyright");
+        assert_eq!(pyright.command, vec!["pyright-langserver", "--stdio"]);
+        assert_eq!(pyright.env.get("FOO").map(String::as_str), Some("bar"));
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let config = parse(
+            "
+remap: true
+remap-rules: ['source://web/=/srv/web']
+servers:
+  - command: gopls
+    languages: [go]
+",
+            Path::new("proxy.yaml"),
+        )
+        .unwrap();
+        assert!(config.remap);
+        assert_eq!(
+            config.remap_rules,
+            vec!["source://web/=/srv/web".parse().unwrap()]
+        );
+        assert_eq!(
+            config.servers[0].to_languages().collect::<Vec<_>>(),
+            vec![Language {
+                id: "go".to_owned(),
+                name: "gopls".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reject_unknown_fields() {
+        assert!(parse("listne = []", Path::new("proxy.toml")).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! Discover well-known Language Servers installed in `PATH`.
+use std::path::{Path, PathBuf};
+
+use crate::api::proxy::{Route, Server};
+
+/// Well-known servers as `(route, command)`.
+const KNOWN_SERVERS: &[(&str, &[&str])] = &[
+    ("/rust", &["rust-analyzer"]),
+    ("/go", &["gopls"]),
+    ("/python", &["pyright-langserver", "--stdio"]),
+    ("/c", &["clangd"]),
+    ("/typescript", &["typescript-language-server", "--stdio"]),
+    ("/lua", &["lua-language-server"]),
+    ("/haskell", &["haskell-language-server-wrapper", "--lsp"]),
+    ("/ruby", &["solargraph", "stdio"]),
+    ("/java", &["jdtls"]),
+    ("/zig", &["zls"]),
+    ("/bash", &["bash-language-server", "start"]),
+    ("/yaml", &["yaml-language-server", "--stdio"]),
+];
+
+/// Servers found in `PATH`, with the route for each.
+pub fn discover() -> Vec<(Server, Route)> {
+    let paths: Vec<PathBuf> = std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).collect())
+        .unwrap_or_default();
+    discover_in(&paths)
+}
+
+fn discover_in(paths: &[PathBuf]) -> Vec<(Server, Route)> {
+    KNOWN_SERVERS
+        .iter()
+        .filter(|(_, command)| paths.iter().any(|dir| is_executable(dir, command[0])))
+        .map(|(path, command)| {
+            let server = Server::from(command.iter().map(|s| (*s).to_owned()).collect::<Vec<_>>());
+            let route = Route {
+                path: (*path).to_owned(),
+                name: server.name.clone(),
+            };
+            (server, route)
+        })
+        .collect()
+}
+
+fn is_executable(dir: &Path, program: &str) -> bool {
+    if cfg!(windows) {
+        ["exe", "cmd", "bat"]
+            .iter()
+            .any(|ext| dir.join(program).with_extension(ext).is_file())
+    } else {
+        is_executable_file(&dir.join(program))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable_file(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).map_or(false, |m| {
+        m.is_file() && m.permissions().mode() & 0o111 != 0
+    })
+}
+
+#[cfg(not(unix))]
+fn is_executable_file(path: &Path) -> bool {
+    path.is_file()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    #[test]
+    fn test_discover_in() {
+        let dir =
+            std::env::temp_dir().join(format!("lsp-ws-proxy-discover-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gopls = dir.join("gopls");
+        std::fs::write(&gopls, "").unwrap();
+        std::fs::set_permissions(&gopls, std::fs::Permissions::from_mode(0o755)).unwrap();
+        // Not executable
+        std::fs::write(dir.join("clangd"), "").unwrap();
+
+        let found = discover_in(&[dir.clone()]);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0.command, vec!["gopls"]);
+        assert_eq!(
+            found[0].1,
+            Route {
+                path: "/go".to_owned(),
+                name: "gopls".to_owned(),
+            }
+        );
+    }
+}
@@ -0,0 +1,12 @@
+//! WebSocket proxy for Language Servers.
+//!
+//! The `lsp-ws-proxy` binary is built on these modules. Custom integrations can build their own
+//! with [`api::proxy::handler`], adding [`api::middleware::Middleware`]s to the context of the
+//! proxy to intercept the messages.
+pub mod api;
+pub mod backend;
+pub mod bridge;
+pub mod config;
+pub mod discover;
+pub mod listener;
+pub mod lsp;
@@ -0,0 +1,589 @@
+//! Addresses the proxy listens on.
+use std::{
+    convert::Infallible,
+    fmt::{self, Display, Formatter},
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+#[cfg(any(unix, windows))]
+use futures_util::{stream::BoxStream, StreamExt};
+use hyper::{
+    header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    Body, Method, Request, Response, StatusCode,
+};
+#[cfg(unix)]
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile, AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore,
+        ServerConfig, ServerSession, Session,
+    },
+    TlsAcceptor,
+};
+use warp::{Filter, Rejection, Reply};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Listen {
+    /// `host:port`, or just `port` to listen on all interfaces.
+    Tcp(SocketAddr),
+    /// `unix:/path/to/socket`
+    #[cfg(unix)]
+    Unix(PathBuf),
+    /// `pipe:\\.\pipe\name`
+    #[cfg(windows)]
+    Pipe(String),
+    /// Listening socket inherited from systemd.
+    #[cfg(unix)]
+    Systemd(std::os::unix::io::RawFd),
+}
+
+impl Default for Listen {
+    fn default() -> Self {
+        Self::Tcp(SocketAddr::from(([0, 0, 0, 0], 9999)))
+    }
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = value
+            .strip_prefix("unix://")
+            .or_else(|| value.strip_prefix("unix:"))
+        {
+            return parse_unix(value, path);
+        }
+        if let Some(name) = value.strip_prefix("pipe:") {
+            return parse_pipe(value, name);
+        }
+
+        // Allow specifying only a port number.
+        let addr = if value.chars().all(|c| c.is_ascii_digit()) {
+            format!("0.0.0.0:{}", value)
+        } else {
+            value.to_owned()
+        };
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => Ok(Self::Tcp(addr)),
+            Err(_) => Err(format!("{} cannot be parsed as SocketAddr", value)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn parse_unix(value: &str, path: &str) -> Result<Listen, String> {
+    if path.is_empty() {
+        return Err(format!("{} is missing the socket path", value));
+    }
+    Ok(Listen::Unix(PathBuf::from(path)))
+}
+
+#[cfg(not(unix))]
+fn parse_unix(value: &str, _path: &str) -> Result<Listen, String> {
+    Err(format!("{} is not supported on this platform", value))
+}
+
+#[cfg(windows)]
+fn parse_pipe(value: &str, name: &str) -> Result<Listen, String> {
+    if name.is_empty() {
+        return Err(format!("{} is missing the pipe name", value));
+    }
+    Ok(Listen::Pipe(name.to_owned()))
+}
+
+#[cfg(not(windows))]
+fn parse_pipe(value: &str, _name: &str) -> Result<Listen, String> {
+    Err(format!("{} is not supported on this platform", value))
+}
+
+impl Display for Listen {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => Display::fmt(addr, f),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            #[cfg(windows)]
+            Self::Pipe(name) => write!(f, "pipe:{}", name),
+            #[cfg(unix)]
+            Self::Systemd(fd) => write!(f, "systemd socket (fd {})", fd),
+        }
+    }
+}
+
+/// The first socket passed by systemd socket activation.
+#[cfg(unix)]
+pub fn systemd() -> Result<Listen, String> {
+    // See sd_listen_fds(3).
+    const SD_LISTEN_FDS_START: std::os::unix::io::RawFd = 3;
+
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<u32>().ok())
+        .unwrap_or(0);
+    let listen_pid = std::env::var("LISTEN_PID").ok();
+    // Not passed on to the servers, like sd_listen_fds(3).
+    for var in &["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    if fds == 0 {
+        return Err("no socket was passed by systemd (LISTEN_FDS is not set)".to_owned());
+    }
+    if let Some(pid) = listen_pid.and_then(|pid| pid.parse::<u32>().ok()) {
+        if pid != std::process::id() {
+            return Err(format!("sockets were passed to another process ({})", pid));
+        }
+    }
+    if fds > 1 {
+        tracing::warn!("using the first of {} sockets passed by systemd", fds);
+    }
+    // Passed without FD_CLOEXEC. Set before any server is started, like the pool, so they don't
+    // inherit the sockets.
+    for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds as std::os::unix::io::RawFd {
+        // SAFETY: Only sets the flags of the file descriptor.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+            return Err(format!(
+                "failed to set FD_CLOEXEC on the socket passed by systemd: {}",
+                io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(Listen::Systemd(SD_LISTEN_FDS_START))
+}
+
+#[cfg(not(unix))]
+pub fn systemd() -> Result<Listen, String> {
+    Err("systemd socket activation is not supported on this platform".to_owned())
+}
+
+/// Parse the permission bits for the Unix socket in octal.
+pub fn parse_socket_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{} is not a valid octal file mode", value))
+}
+
+/// Certificate and private key to serve over TLS.
+#[derive(Debug, Clone)]
+pub struct Tls {
+    /// Path to the PEM encoded certificate chain.
+    pub cert: PathBuf,
+    /// Path to the PEM encoded private key.
+    pub key: PathBuf,
+    /// Path to the PEM encoded CA bundle to verify client certificates against.
+    /// Clients without a valid certificate are rejected during the handshake.
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Subject of the certificate a client authenticated with `--tls-client-ca`, like
+/// `CN=alice, O=Example`. In the extensions of the requests of its connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSubject(pub String);
+
+/// Options for listening.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// Permissions of the Unix socket.
+    pub socket_mode: Option<u32>,
+    /// Serve over TLS if set.
+    pub tls: Option<Tls>,
+}
+
+/// Serve `routes` on all of `listens` until any of them stops.
+pub async fn serve_all<F>(routes: F, listens: &[Listen], config: &Config) -> io::Result<()>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    futures_util::future::try_join_all(
+        listens
+            .iter()
+            .map(|listen| serve(routes.clone(), listen, config)),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Serve `routes` on `listen` until the server stops.
+pub async fn serve<F>(routes: F, listen: &Listen, config: &Config) -> io::Result<()>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    match listen {
+        Listen::Tcp(addr) => {
+            if config.socket_mode.is_some() {
+                tracing::warn!("ignoring socket mode for {}", addr);
+            }
+            if let Some(tls) = &config.tls {
+                tracing::info!("listening on {} with TLS", addr);
+                if let Some(ca) = &tls.client_ca {
+                    tracing::info!("requiring client certificates signed by {:?}", ca);
+                }
+                serve_tls(routes, *addr, tls).await?;
+            } else {
+                tracing::info!("listening on {}", addr);
+                warp::serve(routes).run(*addr).await;
+            }
+        }
+
+        #[cfg(unix)]
+        Listen::Unix(path) => {
+            reject_tls(config, listen)?;
+            let listener = bind_unix(path, config.socket_mode)?;
+            tracing::info!("listening on {}", listen);
+            warp::serve(routes)
+                .run_incoming(unix_incoming(listener))
+                .await;
+        }
+
+        #[cfg(windows)]
+        Listen::Pipe(name) => {
+            reject_tls(config, listen)?;
+            let incoming = pipe_incoming(name)?;
+            tracing::info!("listening on {}", listen);
+            warp::serve(routes).run_incoming(incoming).await;
+        }
+
+        #[cfg(unix)]
+        Listen::Systemd(fd) => {
+            reject_tls(config, listen)?;
+            tracing::info!("listening on {}", listen);
+            match inherit(*fd)? {
+                Inherited::Tcp(listener) => {
+                    warp::serve(routes)
+                        .run_incoming(tcp_incoming(listener))
+                        .await;
+                }
+                Inherited::Unix(listener) => {
+                    warp::serve(routes)
+                        .run_incoming(unix_incoming(listener))
+                        .await;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Unlike `warp::serve(routes).tls()`, the connections are accepted here so the requests can have
+// the `ClientSubject` of the certificate, and HTTP/2 connections can bootstrap WebSockets.
+async fn serve_tls<F>(routes: F, addr: SocketAddr, tls: &Tls) -> io::Result<()>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    use hyper::service::Service;
+
+    let acceptor = tls_acceptor(tls)?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let service = warp::service(routes);
+    let mut http = hyper::server::conn::Http::new();
+    http.http2_enable_connect_protocol();
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                // Like running out of file descriptors, wait instead of spinning.
+                tracing::error!("failed to accept connection: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        let http = http.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    tracing::debug!("TLS handshake failed: {}", err);
+                    return;
+                }
+            };
+            let subject = client_subject(stream.get_ref().1);
+            let service = hyper::service::service_fn(move |mut req: Request<Body>| {
+                if let Some(subject) = &subject {
+                    req.extensions_mut().insert(subject.clone());
+                }
+                let extended_connect = from_extended_connect(&mut req);
+                let res = service.clone().call(req);
+                async move {
+                    let mut res = res.await?;
+                    if extended_connect {
+                        into_extended_connect(&mut res);
+                    }
+                    Ok::<_, Infallible>(res)
+                }
+            });
+            let served = http.serve_connection(stream, service).with_upgrades().await;
+            if let Err(err) = served {
+                tracing::debug!("failed to serve connection: {}", err);
+            }
+        });
+    }
+}
+
+// Turn the extended `CONNECT` of RFC 8441 bootstrapping a WebSocket over HTTP/2 into the upgrade
+// request of HTTP/1.1 the routes accept. Returns whether it was one.
+fn from_extended_connect(req: &mut Request<Body>) -> bool {
+    let websocket = req.method() == Method::CONNECT
+        && req
+            .extensions()
+            .get::<hyper::ext::Protocol>()
+            .map_or(false, |protocol| {
+                protocol.as_str().eq_ignore_ascii_case("websocket")
+            });
+    if websocket {
+        *req.method_mut() = Method::GET;
+        let headers = req.headers_mut();
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        // The key isn't used over HTTP/2, the response is accepted without the hash of it.
+        headers.insert(
+            SEC_WEBSOCKET_KEY,
+            HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="),
+        );
+    }
+    websocket
+}
+
+// Turn the response switching an upgrade request to WebSocket into the response accepting an
+// extended `CONNECT`, which opens the stream with any 2xx status.
+fn into_extended_connect(res: &mut Response<Body>) {
+    if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+        *res.status_mut() = StatusCode::OK;
+        let headers = res.headers_mut();
+        headers.remove(CONNECTION);
+        headers.remove(UPGRADE);
+        headers.remove(SEC_WEBSOCKET_ACCEPT);
+    }
+}
+
+fn tls_acceptor(tls: &Tls) -> io::Result<TlsAcceptor> {
+    let verifier = match &tls.client_ca {
+        Some(ca) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut read(ca)?.as_slice()) {
+                Ok((added, _)) if added > 0 => {}
+                _ => return Err(invalid_pem(ca, "no valid CA certificates")),
+            }
+            AllowAnyAuthenticatedClient::new(roots)
+        }
+        None => NoClientAuth::new(),
+    };
+    let certs = pemfile::certs(&mut read(&tls.cert)?.as_slice())
+        .map_err(|_| invalid_pem(&tls.cert, "invalid certificate chain"))?;
+    let key = private_key(&tls.key)?;
+    let mut config = ServerConfig::new(verifier);
+    config
+        .set_single_cert(certs, key)
+        .map_err(|err| invalid_pem(&tls.cert, &err.to_string()))?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn read(path: &Path) -> io::Result<Vec<u8>> {
+    std::fs::read(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))
+}
+
+fn invalid_pem(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), reason),
+    )
+}
+
+// PKCS #8 or RSA, like warp.
+fn private_key(path: &Path) -> io::Result<PrivateKey> {
+    let pem = read(path)?;
+    let pkcs8 = pemfile::pkcs8_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    let rsa = pemfile::rsa_private_keys(&mut pem.as_slice()).unwrap_or_default();
+    pkcs8
+        .into_iter()
+        .chain(rsa)
+        .next()
+        .ok_or_else(|| invalid_pem(path, "no private key"))
+}
+
+// Subject of the first certificate of the client, the one it authenticated with.
+fn client_subject(session: &ServerSession) -> Option<ClientSubject> {
+    let certs = session.get_peer_certificates()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&certs.first()?.0).ok()?;
+    Some(ClientSubject(cert.subject().to_string()))
+}
+
+// The TLS server can only bind TCP addresses.
+#[cfg(any(unix, windows))]
+fn reject_tls(config: &Config, listen: &Listen) -> io::Result<()> {
+    if config.tls.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("TLS is not supported when listening on {}", listen),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn tcp_incoming(listener: TcpListener) -> BoxStream<'static, io::Result<TcpStream>> {
+    futures_util::stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    })
+    .boxed()
+}
+
+#[cfg(unix)]
+fn unix_incoming(listener: UnixListener) -> BoxStream<'static, io::Result<UnixStream>> {
+    futures_util::stream::unfold(listener, |listener| async move {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    })
+    .boxed()
+}
+
+#[cfg(windows)]
+fn pipe_incoming(
+    name: &str,
+) -> io::Result<BoxStream<'static, io::Result<tokio::net::windows::named_pipe::NamedPipeServer>>> {
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    // Each pipe instance serves one client, so create the next one after each connection.
+    let first = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(name)?;
+    Ok(futures_util::stream::unfold(
+        (name.to_owned(), Some(first)),
+        |(name, server)| async move {
+            let server = server?;
+            if let Err(err) = server.connect().await {
+                return Some((Err(err), (name, None)));
+            }
+            match ServerOptions::new().create(&name) {
+                Ok(next) => Some((Ok(server), (name, Some(next)))),
+                Err(err) => Some((Err(err), (name, None))),
+            }
+        },
+    )
+    .boxed())
+}
+
+#[cfg(unix)]
+enum Inherited {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+#[cfg(unix)]
+fn inherit(fd: std::os::unix::io::RawFd) -> io::Result<Inherited> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    // SAFETY: The socket was passed to this process by systemd, and nothing else owns it.
+    let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    // Fails if the socket is not a Unix socket.
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(Inherited::Unix(UnixListener::from_std(unix)?));
+    }
+
+    // SAFETY: Ownership is transferred from `unix`.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+    tcp.set_nonblocking(true)?;
+    Ok(Inherited::Tcp(TcpListener::from_std(tcp)?))
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // Remove the socket left behind by the previous run, but never anything else.
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if meta.file_type().is_socket() {
+            tracing::debug!("removing stale socket {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_port() {
+        assert_eq!(
+            "8888".parse::<Listen>(),
+            Ok(Listen::Tcp(SocketAddr::from(([0, 0, 0, 0], 8888))))
+        );
+    }
+
+    #[test]
+    fn test_parse_addr() {
+        assert_eq!(
+            "127.0.0.1:8888".parse::<Listen>(),
+            Ok(Listen::Tcp(SocketAddr::from(([127, 0, 0, 1], 8888))))
+        );
+        assert!("localhost".parse::<Listen>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_parse_unix() {
+        assert_eq!(
+            "unix:/run/lsp-ws-proxy.sock".parse::<Listen>(),
+            Ok(Listen::Unix(PathBuf::from("/run/lsp-ws-proxy.sock")))
+        );
+        assert!("unix:".parse::<Listen>().is_err());
+    }
+
+    #[test]
+    fn test_extended_connect() {
+        let mut req = Request::builder()
+            .method(Method::CONNECT)
+            .uri("https://localhost:9999/?name=rust-analyzer")
+            .header("sec-websocket-version", "13")
+            .extension(hyper::ext::Protocol::from_static("websocket"))
+            .body(Body::empty())
+            .unwrap();
+        assert!(from_extended_connect(&mut req));
+        assert_eq!(req.method(), Method::GET);
+        assert_eq!(req.headers()[UPGRADE], "websocket");
+        assert!(req.headers().contains_key(SEC_WEBSOCKET_KEY));
+
+        let mut res = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(CONNECTION, "upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_ACCEPT, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+            .header("sec-websocket-protocol", "msgpack")
+            .body(Body::empty())
+            .unwrap();
+        into_extended_connect(&mut res);
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(CONNECTION));
+        assert!(!res.headers().contains_key(SEC_WEBSOCKET_ACCEPT));
+        assert_eq!(res.headers()["sec-websocket-protocol"], "msgpack");
+
+        let mut get = Request::get("/").body(Body::empty()).unwrap();
+        assert!(!from_extended_connect(&mut get));
+    }
+
+    #[test]
+    fn test_parse_socket_mode() {
+        assert_eq!(parse_socket_mode("660"), Ok(0o660));
+        assert_eq!(parse_socket_mode("0600"), Ok(0o600));
+        assert!(parse_socket_mode("888").is_err());
+        assert!(parse_socket_mode("7777").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use serde_json::Value;
+
+/// Apply `patch` to `capabilities` of the `initialize` request `msg` as a JSON Merge Patch
+/// ([RFC 7386]): objects are merged, `null` removes the capability, and other values replace it.
+///
+/// The message is patched as JSON because the capabilities may be newer than the ones known to
+/// the proxy, like `general.positionEncodings`.
+///
+/// [RFC 7386]: https://datatracker.ietf.org/doc/html/rfc7386
+pub fn patch_client_capabilities(msg: &mut Value, patch: &Value) {
+    if msg.get("method").and_then(Value::as_str) != Some("initialize") {
+        return;
+    }
+    if let Some(params) = msg.get_mut("params").and_then(Value::as_object_mut) {
+        let capabilities = params
+            .entry("capabilities")
+            .or_insert_with(|| Value::Object(Default::default()));
+        merge_patch(capabilities, patch);
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Default::default());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_patch_client_capabilities() {
+        let mut msg = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {"capabilities": {
+                "general": {"positionEncodings": ["utf-8", "utf-16"]},
+                "window": {"workDoneProgress": true, "showDocument": {"support": true}},
+            }},
+        });
+        let patch = json!({
+            "general": {"positionEncodings": ["utf-16"]},
+            "window": {"workDoneProgress": false, "showDocument": null},
+        });
+        patch_client_capabilities(&mut msg, &patch);
+        assert_eq!(
+            msg["params"]["capabilities"],
+            json!({
+                "general": {"positionEncodings": ["utf-16"]},
+                "window": {"workDoneProgress": false},
+            })
+        );
+
+        let mut other = json!({"jsonrpc": "2.0", "method": "initialized", "params": {}});
+        patch_client_capabilities(&mut other, &patch);
+        assert_eq!(other["params"], json!({}));
+    }
+}
@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::lsp::{Message, Request};
+
+/// `initializationOptions` to merge into the `initialize` request of the client to the server
+/// `name`.
+///
+/// ```text
+/// rust-analyzer={"cargo":{"targetDir":"/tmp/ra-target"}}
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct InitializationOptions {
+    pub name: String,
+    pub options: Value,
+}
+
+impl FromStr for InitializationOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, options) = s
+            .split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| format!("expected <name>=<json>, got {}", s))?;
+        let options: Value = serde_json::from_str(options)
+            .map_err(|err| format!("invalid initializationOptions of {}: {}", name, err))?;
+        if !options.is_object() {
+            return Err(format!(
+                "initializationOptions of {} must be an object",
+                name
+            ));
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            options,
+        })
+    }
+}
+
+/// Merge `options` into `initializationOptions` of `initialize`, replacing the values set by the
+/// client and keeping the others.
+pub fn merge_initialization_options(msg: &mut Message, options: &Value) {
+    if let Message::Request(Request::Initialize { id: _, params: p }) = msg {
+        let merged = p.initialization_options.get_or_insert_with(|| Value::Null);
+        merge(merged, options);
+    }
+}
+
+fn merge(target: &mut Value, value: &Value) {
+    match (target, value) {
+        (Value::Object(target), Value::Object(value)) => {
+            for (key, value) in value {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, value) => *target = value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_merge_initialization_options() {
+        let mut msg: Message = serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "initialize",
+            "params": {
+                "capabilities": {},
+                "initializationOptions": {
+                    "cargo": {"targetDir": "target", "features": ["a"]},
+                    "checkOnSave": true,
+                },
+            },
+        }))
+        .unwrap();
+        let options: InitializationOptions = r#"rust-analyzer={"cargo":{"targetDir":"/tmp/ra"}}"#
+            .parse()
+            .unwrap();
+        merge_initialization_options(&mut msg, &options.options);
+        let msg = serde_json::to_value(&msg).unwrap();
+        assert_eq!(
+            msg["params"]["initializationOptions"],
+            json!({
+                "cargo": {"targetDir": "/tmp/ra", "features": ["a"]},
+                "checkOnSave": true,
+            })
+        );
+
+        assert!("rust-analyzer=[]".parse::<InitializationOptions>().is_err());
+        assert!("={}".parse::<InitializationOptions>().is_err());
+    }
+}
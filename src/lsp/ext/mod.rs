@@ -1,4 +1,14 @@
 //! Nonstandard LSP features.
+mod capabilities;
+mod initialization_options;
+mod redact_paths;
 mod relative_uri;
+mod remap_rules;
 
-pub use relative_uri::remap_relative_uri;
+pub use capabilities::patch_client_capabilities;
+pub use initialization_options::{merge_initialization_options, InitializationOptions};
+pub use redact_paths::redact_paths;
+pub use relative_uri::{
+    remap_relative_uri, remap_relative_uri_deep_to_file, remap_relative_uri_deep_to_source,
+};
+pub use remap_rules::{RemapRule, RemapRules};
@@ -0,0 +1,89 @@
+use serde_json::Value;
+
+/// Rewrite the absolute paths under `root` in the strings of `value` to paths relative to it, like
+/// in diagnostics and log messages, so the layout of the host isn't leaked to the client.
+///
+/// `file://` URIs are kept, so the client can still open the documents. The root itself becomes
+/// `.`.
+pub fn redact_paths(value: &mut Value, root: &str) {
+    let root = root.trim_end_matches('/');
+    if root.is_empty() {
+        return;
+    }
+    redact_strings(value, root);
+}
+
+fn redact_strings(value: &mut Value, root: &str) {
+    match value {
+        Value::String(s) => {
+            if let Some(redacted) = redact(s, root) {
+                *s = redacted;
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                redact_strings(value, root);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                redact_strings(value, root);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+fn redact(s: &str, root: &str) -> Option<String> {
+    if !s.contains(root) {
+        return None;
+    }
+    let mut redacted = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(root) {
+        let (before, after) = (&rest[..i], &rest[i + root.len()..]);
+        redacted.push_str(before);
+        if before.ends_with("file://") {
+            redacted.push_str(root);
+            rest = after;
+        } else if let Some(after) = after.strip_prefix('/') {
+            rest = after;
+        } else if after.starts_with(|c: char| c.is_alphanumeric() || "-_.".contains(c)) {
+            // Another directory starting with the root
+            redacted.push_str(root);
+            rest = after;
+        } else {
+            redacted.push('.');
+            rest = after;
+        }
+    }
+    redacted.push_str(rest);
+    Some(redacted)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_redact_paths() {
+        let mut msg = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": {
+                "uri": "file:///srv/ws/src/main.rs",
+                "diagnostics": [{
+                    "message": "can't find /srv/ws/src/lib.rs in /srv/ws, or /srv/ws2/lib.rs",
+                }],
+            },
+        });
+        redact_paths(&mut msg, "/srv/ws/");
+        assert_eq!(msg["params"]["uri"], json!("file:///srv/ws/src/main.rs"));
+        assert_eq!(
+            msg["params"]["diagnostics"][0]["message"],
+            json!("can't find src/lib.rs in ., or /srv/ws2/lib.rs")
+        );
+    }
+}
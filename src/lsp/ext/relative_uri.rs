@@ -1,69 +1,112 @@
 use std::collections::HashMap;
 
+use serde_json::Value;
 use url::Url;
 
 use crate::lsp::{Message, Notification, Request, Response, ResponseResult};
 
+use super::RemapRules;
+
 /// Remap URI relative to current directory (`source://`) to absolute URI (`file://`).  
 /// `source://` was chosen because it's used by [Metals Remote Language Server].
 ///
 /// [Metals Remote Language Server]: https://scalameta.org/metals/docs/contributors/remote-language-server.html
-pub fn remap_relative_uri(msg: &mut Message, cwd: &Url) -> Result<(), std::io::Error> {
+pub fn remap_relative_uri(msg: &mut Message, rules: &RemapRules) -> Result<(), std::io::Error> {
     match msg {
-        Message::Notification(notification) => remap_notification(notification, cwd)?,
-        Message::Request(request) => remap_request(request, cwd)?,
-        Message::Response(response) => remap_response(response, cwd)?,
+        Message::Notification(notification) => remap_notification(notification, rules)?,
+        Message::Request(request) => remap_request(request, rules)?,
+        Message::Response(response) => remap_response(response, rules)?,
         Message::Unknown(_) => {}
     }
     Ok(())
 }
 
-fn remap_notification(notification: &mut Notification, cwd: &Url) -> Result<(), std::io::Error> {
+/// Remap any string in `value` starting with a client prefix of the rules (`source://`) to the
+/// server, including object keys, for servers embedding URIs in fields not remapped by
+/// [`remap_relative_uri`].
+pub fn remap_relative_uri_deep_to_file(value: &mut Value, rules: &RemapRules) {
+    remap_strings(value, &|s| rules.to_server(s));
+}
+
+/// Remap any string in `value` starting with a server directory of the rules (`file://`) to the
+/// client, including object keys.
+pub fn remap_relative_uri_deep_to_source(value: &mut Value, rules: &RemapRules) {
+    remap_strings(value, &|s| rules.to_client(s));
+}
+
+fn remap_strings(value: &mut Value, remap: &dyn Fn(&str) -> Option<String>) {
+    match value {
+        Value::String(s) => {
+            if let Some(remapped) = remap(s) {
+                *s = remapped;
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                remap_strings(value, remap);
+            }
+        }
+        Value::Object(map) => {
+            // Keys are URIs in `WorkspaceEdit.changes`.
+            let entries = std::mem::take(map);
+            for (key, mut value) in entries {
+                remap_strings(&mut value, remap);
+                map.insert(remap(&key).unwrap_or(key), value);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+fn remap_notification(
+    notification: &mut Notification,
+    rules: &RemapRules,
+) -> Result<(), std::io::Error> {
     match notification {
         Notification::DidSave { params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Notification::DidChangeWorkspaceFolders { params: p } => {
             for folder in &mut p.event.added {
-                remap_workspace_folder(folder, cwd)?;
+                remap_workspace_folder(folder, rules)?;
             }
             for folder in &mut p.event.removed {
-                remap_workspace_folder(folder, cwd)?;
+                remap_workspace_folder(folder, rules)?;
             }
         }
 
         Notification::DidChangeWatchedFiles { params: p } => {
             for event in &mut p.changes {
-                if let Some(uri) = to_file(&event.uri, cwd)? {
+                if let Some(uri) = to_file(&event.uri, rules)? {
                     event.uri = uri;
                 }
             }
         }
 
         Notification::DidOpen { params: p } => {
-            if let Some(uri) = to_file(&p.text_document.uri, cwd)? {
+            if let Some(uri) = to_file(&p.text_document.uri, rules)? {
                 p.text_document.uri = uri;
             }
         }
 
         Notification::DidChange { params: p } => {
-            if let Some(uri) = to_file(&p.text_document.uri, cwd)? {
+            if let Some(uri) = to_file(&p.text_document.uri, rules)? {
                 p.text_document.uri = uri;
             }
         }
 
         Notification::WillSave { params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Notification::DidClose { params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Notification::PublishDiagnostics { params: p } => {
             // `to_source` because this goes to client
-            if let Some(uri) = to_source(&p.uri, cwd)? {
+            if let Some(uri) = to_source(&p.uri, rules)? {
                 p.uri = uri;
             }
         }
@@ -81,44 +124,44 @@ fn remap_notification(notification: &mut Notification, cwd: &Url) -> Result<(),
     Ok(())
 }
 
-fn remap_request(request: &mut Request, cwd: &Url) -> Result<(), std::io::Error> {
+fn remap_request(request: &mut Request, rules: &RemapRules) -> Result<(), std::io::Error> {
     match request {
         Request::Initialize { id: _, params: p } => {
             if let Some(root_uri) = &p.root_uri {
-                if let Some(root_uri) = to_file(root_uri, cwd)? {
+                if let Some(root_uri) = to_file(root_uri, rules)? {
                     p.root_uri = Some(root_uri);
                 }
             }
             if let Some(folders) = &mut p.workspace_folders {
                 for folder in folders {
-                    remap_workspace_folder(folder, cwd)?;
+                    remap_workspace_folder(folder, rules)?;
                 }
             }
         }
 
         Request::DocumentSymbol { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::WillSaveWaitUntil { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::Completion { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, rules)?;
         }
 
         Request::Hover { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                rules,
             )?;
         }
 
         Request::SignatureHelp { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                rules,
             )?;
         }
 
@@ -128,87 +171,87 @@ fn remap_request(request: &mut Request, cwd: &Url) -> Result<(), std::io::Error>
         | Request::GotoImplementation { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                rules,
             )?;
         }
 
         Request::References { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, rules)?;
         }
 
         Request::DocumentHighlight { id: _, params: p } => {
             remap_text_document_identifier(
                 &mut p.text_document_position_params.text_document,
-                cwd,
+                rules,
             )?;
         }
 
         Request::CodeAction { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::CodeLens { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::DocumentLink { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::DocumentLinkResolve { id: _, params: p } => {
             if let Some(target) = &p.target {
-                if let Some(target) = to_file(target, cwd)? {
+                if let Some(target) = to_file(target, rules)? {
                     p.target = Some(target);
                 }
             }
         }
 
         Request::DocumentColor { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::ColorPresentation { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::Formatting { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::RangeFormatting { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::OnTypeFormatting { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, rules)?;
         }
 
         Request::Rename { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document_position.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document_position.text_document, rules)?;
         }
 
         Request::PrepareRename { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::FoldingRange { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         Request::SelectionRange { id: _, params: p } => {
-            remap_text_document_identifier(&mut p.text_document, cwd)?;
+            remap_text_document_identifier(&mut p.text_document, rules)?;
         }
 
         // To Client
         Request::ApplyEdit { id: _, params: p } => {
-            remap_workspace_edit(&mut p.edit, cwd)?;
+            remap_workspace_edit(&mut p.edit, rules)?;
         }
 
         // To Client
         Request::Configuration { id: _, params: p } => {
             for item in &mut p.items {
                 if let Some(scope_uri) = &item.scope_uri {
-                    if let Some(scope_uri) = to_source(scope_uri, cwd)? {
+                    if let Some(scope_uri) = to_source(scope_uri, rules)? {
                         item.scope_uri = Some(scope_uri);
                     }
                 }
@@ -231,20 +274,20 @@ fn remap_request(request: &mut Request, cwd: &Url) -> Result<(), std::io::Error>
     Ok(())
 }
 
-fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Error> {
+fn remap_response(response: &mut Response, rules: &RemapRules) -> Result<(), std::io::Error> {
     match response {
         Response::Success { id: _, result } => {
             match result {
                 ResponseResult::DocumentLinkWithTarget(links) => {
                     for link in links {
-                        if let Some(target) = to_source(&link.target, cwd)? {
+                        if let Some(target) = to_source(&link.target, rules)? {
                             link.target = target;
                         }
                     }
                 }
 
                 ResponseResult::DocumentLinkWithTargetResolve(link) => {
-                    if let Some(target) = to_source(&link.target, cwd)? {
+                    if let Some(target) = to_source(&link.target, rules)? {
                         link.target = target;
                     }
                 }
@@ -255,7 +298,7 @@ fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Err
                             lsp_types::CodeActionOrCommand::Command(_) => {}
                             lsp_types::CodeActionOrCommand::CodeAction(action) => {
                                 if let Some(workspace_edit) = &mut action.edit {
-                                    remap_workspace_edit(workspace_edit, cwd)?;
+                                    remap_workspace_edit(workspace_edit, rules)?;
                                 }
                             }
                         }
@@ -263,18 +306,18 @@ fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Err
                 }
 
                 ResponseResult::Location(location) => {
-                    remap_location(location, cwd)?;
+                    remap_location(location, rules)?;
                 }
 
                 ResponseResult::Locations(locations) => {
                     for location in locations {
-                        remap_location(location, cwd)?;
+                        remap_location(location, rules)?;
                     }
                 }
 
                 ResponseResult::LocationLinks(links) => {
                     for link in links {
-                        if let Some(target_uri) = to_source(&link.target_uri, cwd)? {
+                        if let Some(target_uri) = to_source(&link.target_uri, rules)? {
                             link.target_uri = target_uri;
                         }
                     }
@@ -282,30 +325,30 @@ fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Err
 
                 ResponseResult::SymbolInfos(syms) => {
                     for sym in syms {
-                        remap_location(&mut sym.location, cwd)?;
+                        remap_location(&mut sym.location, rules)?;
                     }
                 }
 
                 ResponseResult::WorkspaceFolders(folders) => {
                     for folder in folders {
                         // `to_file` because this is a response from Client.
-                        if let Some(uri) = to_file(&folder.uri, cwd)? {
+                        if let Some(uri) = to_file(&folder.uri, rules)? {
                             folder.uri = uri;
                         }
                     }
                 }
 
                 ResponseResult::WorkspaceEditWithBoth(edit) => {
-                    remap_workspace_edit_changes(&mut edit.changes, cwd)?;
-                    remap_document_changes(&mut edit.document_changes, cwd)?;
+                    remap_workspace_edit_changes(&mut edit.changes, rules)?;
+                    remap_document_changes(&mut edit.document_changes, rules)?;
                 }
 
                 ResponseResult::WorkspaceEditWithChanges(edit) => {
-                    remap_workspace_edit_changes(&mut edit.changes, cwd)?;
+                    remap_workspace_edit_changes(&mut edit.changes, rules)?;
                 }
 
                 ResponseResult::WorkspaceEditWithDocumentChanges(edit) => {
-                    remap_document_changes(&mut edit.document_changes, cwd)?;
+                    remap_document_changes(&mut edit.document_changes, rules)?;
                 }
 
                 ResponseResult::Any(_) => {}
@@ -318,24 +361,21 @@ fn remap_response(response: &mut Response, cwd: &Url) -> Result<(), std::io::Err
     Ok(())
 }
 
-fn to_file(uri: &Url, cwd: &Url) -> Result<Option<Url>, std::io::Error> {
-    if uri.scheme() == "source" {
-        cwd.join(uri.as_str().strip_prefix("source://").unwrap())
-            .map_err(map_parse_error)
-            .map(Some)
-    } else {
-        Ok(None)
-    }
+fn to_file(uri: &Url, rules: &RemapRules) -> Result<Option<Url>, std::io::Error> {
+    rules
+        .to_server(uri.as_str())
+        .map(|uri| Url::parse(&uri))
+        .transpose()
+        .map_err(map_parse_error)
 }
 
-fn to_source(uri: &Url, cwd: &Url) -> Result<Option<Url>, std::io::Error> {
+fn to_source(uri: &Url, rules: &RemapRules) -> Result<Option<Url>, std::io::Error> {
     if uri.scheme() == "file" {
-        if let Some(rel) = uri.as_str().strip_prefix(cwd.as_str()) {
-            let source_uri = format!("source://{}", rel);
-            Url::parse(&source_uri).map_err(map_parse_error).map(Some)
-        } else {
-            Ok(None)
-        }
+        rules
+            .to_client(uri.as_str())
+            .map(|uri| Url::parse(&uri))
+            .transpose()
+            .map_err(map_parse_error)
     } else {
         Ok(None)
     }
@@ -348,14 +388,14 @@ fn map_parse_error(err: url::ParseError) -> std::io::Error {
 /// Remap `DocumentUri` in `WorkspaceEdit` to use `source://`
 fn remap_workspace_edit(
     workspace_edit: &mut lsp_types::WorkspaceEdit,
-    cwd: &Url,
+    rules: &RemapRules,
 ) -> Result<(), std::io::Error> {
     if let Some(changes) = &mut workspace_edit.changes {
-        remap_workspace_edit_changes(changes, cwd)?;
+        remap_workspace_edit_changes(changes, rules)?;
     }
 
     if let Some(doc_changes) = &mut workspace_edit.document_changes {
-        remap_document_changes(doc_changes, cwd)?;
+        remap_document_changes(doc_changes, rules)?;
     }
     Ok(())
 }
@@ -363,11 +403,11 @@ fn remap_workspace_edit(
 /// Remap keys of `WorkspaceEdit.changes`
 fn remap_workspace_edit_changes(
     changes: &mut HashMap<Url, Vec<lsp_types::TextEdit>>,
-    cwd: &Url,
+    rules: &RemapRules,
 ) -> Result<(), std::io::Error> {
     let mut tmp = Vec::with_capacity(changes.len());
     for (key, val) in changes.drain() {
-        if let Some(rel) = to_source(&key, cwd)? {
+        if let Some(rel) = to_source(&key, rules)? {
             tmp.push((rel, val));
         } else {
             tmp.push((key, val));
@@ -381,12 +421,12 @@ fn remap_workspace_edit_changes(
 
 fn remap_document_changes(
     document_changes: &mut lsp_types::DocumentChanges,
-    cwd: &Url,
+    rules: &RemapRules,
 ) -> Result<(), std::io::Error> {
     match document_changes {
         lsp_types::DocumentChanges::Edits(edits) => {
             for edit in edits {
-                if let Some(uri) = to_source(&edit.text_document.uri, cwd)? {
+                if let Some(uri) = to_source(&edit.text_document.uri, rules)? {
                     edit.text_document.uri = uri;
                 }
             }
@@ -397,27 +437,27 @@ fn remap_document_changes(
                 match op {
                     lsp_types::DocumentChangeOperation::Op(op) => match op {
                         lsp_types::ResourceOp::Create(c) => {
-                            if let Some(uri) = to_source(&c.uri, cwd)? {
+                            if let Some(uri) = to_source(&c.uri, rules)? {
                                 c.uri = uri;
                             }
                         }
                         lsp_types::ResourceOp::Rename(r) => {
-                            if let Some(uri) = to_source(&r.old_uri, cwd)? {
+                            if let Some(uri) = to_source(&r.old_uri, rules)? {
                                 r.old_uri = uri;
                             }
-                            if let Some(uri) = to_source(&r.new_uri, cwd)? {
+                            if let Some(uri) = to_source(&r.new_uri, rules)? {
                                 r.new_uri = uri;
                             }
                         }
                         lsp_types::ResourceOp::Delete(d) => {
-                            if let Some(uri) = to_source(&d.uri, cwd)? {
+                            if let Some(uri) = to_source(&d.uri, rules)? {
                                 d.uri = uri;
                             }
                         }
                     },
 
                     lsp_types::DocumentChangeOperation::Edit(e) => {
-                        if let Some(uri) = to_source(&e.text_document.uri, cwd)? {
+                        if let Some(uri) = to_source(&e.text_document.uri, rules)? {
                             e.text_document.uri = uri;
                         }
                     }
@@ -430,8 +470,11 @@ fn remap_document_changes(
 }
 
 /// Remap `Location.uri` to use `source://`
-fn remap_location(location: &mut lsp_types::Location, cwd: &Url) -> Result<(), std::io::Error> {
-    if let Some(uri) = to_source(&location.uri, cwd)? {
+fn remap_location(
+    location: &mut lsp_types::Location,
+    rules: &RemapRules,
+) -> Result<(), std::io::Error> {
+    if let Some(uri) = to_source(&location.uri, rules)? {
         location.uri = uri;
     }
     Ok(())
@@ -440,9 +483,9 @@ fn remap_location(location: &mut lsp_types::Location, cwd: &Url) -> Result<(), s
 /// Remap `TextDocumentIdentifier.uri` to use `file://`
 fn remap_text_document_identifier(
     text_document: &mut lsp_types::TextDocumentIdentifier,
-    cwd: &Url,
+    rules: &RemapRules,
 ) -> Result<(), std::io::Error> {
-    if let Some(uri) = to_file(&text_document.uri, cwd)? {
+    if let Some(uri) = to_file(&text_document.uri, rules)? {
         text_document.uri = uri;
     }
     Ok(())
@@ -450,9 +493,9 @@ fn remap_text_document_identifier(
 
 fn remap_workspace_folder(
     folder: &mut lsp_types::WorkspaceFolder,
-    cwd: &Url,
+    rules: &RemapRules,
 ) -> Result<(), std::io::Error> {
-    if let Some(uri) = to_file(&folder.uri, cwd)? {
+    if let Some(uri) = to_file(&folder.uri, rules)? {
         folder.uri = uri;
     }
     Ok(())
@@ -467,16 +510,46 @@ mod tests {
     #[test]
     fn test_to_file() {
         let cwd = Url::from_directory_path(Path::new("/workspace")).unwrap();
+        let rules = RemapRules::new(&cwd, Vec::new());
         let uri = Url::parse("source://src/main.rs").unwrap();
-        let remapped = to_file(&uri, &cwd).unwrap().unwrap();
+        let remapped = to_file(&uri, &rules).unwrap().unwrap();
         assert_eq!(remapped.as_str(), "file:///workspace/src/main.rs");
     }
 
     #[test]
     fn test_to_source() {
         let cwd = Url::from_directory_path(Path::new("/workspace")).unwrap();
+        let rules = RemapRules::new(&cwd, Vec::new());
         let uri = Url::from_file_path(Path::new("/workspace/src/main.rs")).unwrap();
-        let remapped = to_source(&uri, &cwd).unwrap().unwrap();
+        let remapped = to_source(&uri, &rules).unwrap().unwrap();
         assert_eq!(remapped.as_str(), "source://src/main.rs");
     }
+
+    #[test]
+    fn test_remap_deep() {
+        let cwd = Url::from_directory_path(Path::new("/workspace")).unwrap();
+        let rules = RemapRules::new(&cwd, Vec::new());
+        let mut value = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": [{
+                "title": "Fix",
+                "data": {"file": "file:///workspace/src/main.rs", "other": "file:///tmp/a.rs"},
+                "edit": {"changes": {"file:///workspace/src/lib.rs": []}},
+            }],
+        });
+        remap_relative_uri_deep_to_source(&mut value, &rules);
+        assert_eq!(value["result"][0]["data"]["file"], "source://src/main.rs");
+        assert_eq!(value["result"][0]["data"]["other"], "file:///tmp/a.rs");
+        assert!(value["result"][0]["edit"]["changes"]
+            .get("source://src/lib.rs")
+            .is_some());
+
+        remap_relative_uri_deep_to_file(&mut value, &rules);
+        assert_eq!(
+            value["result"][0]["data"]["file"],
+            "file:///workspace/src/main.rs"
+        );
+        assert_eq!(value["result"][0]["title"], "Fix");
+    }
 }
@@ -0,0 +1,140 @@
+use std::{convert::TryFrom, path::Path, str::FromStr};
+
+use url::Url;
+
+/// URIs starting with `client` on the client are under the directory `server` on the server.
+///
+/// ```text
+/// source://web/=/srv/web
+/// file:///Users/me/api/=/home/dev/api
+/// ```
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct RemapRule {
+    client: String,
+    server: Url,
+}
+
+impl FromStr for RemapRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (client, path) = s
+            .rsplit_once('=')
+            .ok_or_else(|| format!("expected <client-prefix>=<server-path>: {}", s))?;
+        if client.is_empty() {
+            return Err(format!("empty client prefix: {}", s));
+        }
+        let server = Url::from_directory_path(Path::new(path))
+            .map_err(|_| format!("server path must be absolute: {}", path))?;
+        let mut client = client.to_owned();
+        // Only match whole path segments.
+        if !client.ends_with('/') {
+            client.push('/');
+        }
+        Ok(Self { client, server })
+    }
+}
+
+impl TryFrom<String> for RemapRule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Rules to remap URIs between the client and the server. The rule with the longest matching
+/// prefix is used, and `source://` is relative to the project root unless a rule overrides it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemapRules {
+    rules: Vec<RemapRule>,
+}
+
+impl RemapRules {
+    pub fn new(root: &Url, rules: Vec<RemapRule>) -> Self {
+        let mut rules = rules;
+        rules.push(RemapRule {
+            client: "source://".to_owned(),
+            server: root.clone(),
+        });
+        Self { rules }
+    }
+
+    /// The rules with `source://` relative to `root` instead.
+    pub fn with_root(&self, root: &Url) -> Self {
+        let mut rules = self.rules.clone();
+        // Added last by `new`
+        rules.pop();
+        Self::new(root, rules)
+    }
+
+    /// The URI on the server for `uri` from the client, or `None` if no rule matches.
+    pub fn to_server(&self, uri: &str) -> Option<String> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| uri.starts_with(&rule.client))
+            .max_by_key(|rule| rule.client.len())?;
+        let rel = &uri[rule.client.len()..];
+        rule.server.join(rel).ok().map(String::from)
+    }
+
+    /// The URI on the client for `uri` from the server, or `None` if no rule matches.
+    pub fn to_client(&self, uri: &str) -> Option<String> {
+        let rule = self
+            .rules
+            .iter()
+            .filter(|rule| uri.starts_with(rule.server.as_str()))
+            .max_by_key(|rule| rule.server.as_str().len())?;
+        let rel = &uri[rule.server.as_str().len()..];
+        Some(format!("{}{}", rule.client, rel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remap_rules() {
+        let root = Url::from_directory_path(Path::new("/workspace")).unwrap();
+        let rules = RemapRules::new(
+            &root,
+            vec![
+                "source://web=/srv/web".parse().unwrap(),
+                "file:///Users/me/api/=/home/dev/api".parse().unwrap(),
+            ],
+        );
+        assert_eq!(
+            rules.to_server("source://src/main.rs").as_deref(),
+            Some("file:///workspace/src/main.rs")
+        );
+        assert_eq!(
+            rules.to_server("source://web/index.ts").as_deref(),
+            Some("file:///srv/web/index.ts")
+        );
+        assert_eq!(
+            rules.to_server("source://website/index.ts").as_deref(),
+            Some("file:///workspace/website/index.ts")
+        );
+        assert_eq!(
+            rules.to_server("file:///Users/me/api/main.go").as_deref(),
+            Some("file:///home/dev/api/main.go")
+        );
+        assert_eq!(rules.to_server("file:///tmp/a.rs"), None);
+
+        assert_eq!(
+            rules.to_client("file:///srv/web/index.ts").as_deref(),
+            Some("source://web/index.ts")
+        );
+        assert_eq!(
+            rules.to_client("file:///home/dev/api/main.go").as_deref(),
+            Some("file:///Users/me/api/main.go")
+        );
+        assert_eq!(rules.to_client("file:///tmp/a.rs"), None);
+
+        assert!("source://web".parse::<RemapRule>().is_err());
+        assert!("source://web/=srv/web".parse::<RemapRule>().is_err());
+    }
+}
@@ -10,6 +10,7 @@ use std::{
 };
 
 use bytes::{Buf, BufMut, BytesMut};
+use encoding_rs::{Encoding, UTF_8};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
 
@@ -36,6 +37,10 @@ pub enum CodecError {
     Encode(IoError),
     /// The frame contains invalid UTF8.
     Utf8(Utf8Error),
+    /// The charset in the `Content-Type` header is not supported.
+    UnsupportedCharset(String),
+    /// The frame contains bytes invalid in the declared charset.
+    Malformed(&'static str),
 }
 
 impl Display for CodecError {
@@ -46,6 +51,10 @@ impl Display for CodecError {
             Self::InvalidType => write!(fmt, "unable to parse content type"),
             Self::Encode(ref e) => write!(fmt, "failed to encode frame: {}", e),
             Self::Utf8(ref e) => write!(fmt, "frame contains invalid UTF8: {}", e),
+            Self::UnsupportedCharset(ref charset) => {
+                write!(fmt, "unsupported charset `{}`", charset)
+            }
+            Self::Malformed(name) => write!(fmt, "frame contains invalid {}", name),
         }
     }
 }
@@ -101,11 +110,13 @@ impl Decoder for LspFrameCodec {
         }
 
         match parser::parse_message(src) {
-            Ok((remaining, message)) => {
-                let message = str::from_utf8(message)?.to_string();
+            Ok((remaining, frame)) => {
+                // Decode before advancing, but don't leave an undecodable frame in the buffer.
+                let message = decode_content(frame.charset, frame.content);
                 let len = src.len() - remaining.len();
                 src.advance(len);
                 self.remaining_bytes = 0;
+                let message = message?;
                 // Ignore empty frame
                 if message.is_empty() {
                     Ok(None)
@@ -143,6 +154,26 @@ impl Decoder for LspFrameCodec {
     }
 }
 
+// Decode the content to UTF-8 using the charset declared in the `Content-Type` header.
+fn decode_content(charset: Option<&[u8]>, content: &[u8]) -> Result<String, CodecError> {
+    let encoding = match charset {
+        Some(label) => Encoding::for_label(label).ok_or_else(|| {
+            CodecError::UnsupportedCharset(String::from_utf8_lossy(label).into_owned())
+        })?,
+        None => UTF_8,
+    };
+    if encoding == UTF_8 {
+        return Ok(str::from_utf8(content)?.to_string());
+    }
+
+    let (text, had_errors) = encoding.decode_without_bom_handling(content);
+    if had_errors {
+        Err(CodecError::Malformed(encoding.name()))
+    } else {
+        Ok(text.into_owned())
+    }
+}
+
 #[inline]
 fn number_of_digits(mut n: usize) -> usize {
     let mut num_digits = 0;
@@ -195,6 +226,32 @@ mod tests {
         assert_eq!(message, Some(decoded));
     }
 
+    #[test]
+    fn decodes_legacy_charset() {
+        let content_type = "Content-Type: application/vscode-jsonrpc; charset=iso-8859-1";
+        let mut encoded = format!("Content-Length: 33\r\n{}\r\n\r\n", content_type).into_bytes();
+        encoded.extend_from_slice(b"{\"jsonrpc\":\"2.0\",\"method\":\"caf\xe9\"}");
+        // Followed by a frame with an unknown charset.
+        encoded.extend_from_slice(b"Content-Length: 2\r\nContent-Type: a; charset=x\r\n\r\n{}");
+
+        let mut codec = LspFrameCodec::default();
+        let mut buffer = BytesMut::from(&encoded[..]);
+        let message = codec.decode(&mut buffer).unwrap();
+        assert_eq!(
+            message,
+            Some(r#"{"jsonrpc":"2.0","method":"café"}"#.to_string())
+        );
+
+        match codec.decode(&mut buffer) {
+            Err(CodecError::UnsupportedCharset(charset)) => assert_eq!(charset, "x"),
+            other => panic!(
+                "expected `Err(CodecError::UnsupportedCharset)`, got {:?}",
+                other
+            ),
+        }
+        assert!(buffer.is_empty());
+    }
+
     #[test]
     fn recovers_from_parse_error() {
         let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#.to_string();
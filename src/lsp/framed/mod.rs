@@ -2,4 +2,4 @@
 mod codec;
 mod parser;
 
-pub use codec::{reader, writer};
+pub use codec::{reader, writer, LspFrameCodec};
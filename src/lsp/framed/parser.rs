@@ -5,30 +5,47 @@
 use std::str;
 
 use nom::{
-    branch::alt,
-    bytes::streaming::{is_not, tag, take_until},
+    bytes::streaming::{is_not, tag, take, take_until},
     character::streaming::{char, crlf, digit1, space0},
     combinator::{map, map_res, opt},
-    multi::length_data,
-    sequence::{delimited, terminated, tuple},
+    sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
 
-// Get JSON message from input using the Content-Length header.
-pub fn parse_message(input: &[u8]) -> IResult<&[u8], &[u8]> {
-    let content_len = delimited(tag("Content-Length: "), digit1, crlf);
-
-    let utf8 = alt((tag("utf-8"), tag("utf8")));
-    let charset = tuple((char(';'), space0, tag("charset="), utf8));
-    let content_type = tuple((tag("Content-Type: "), is_not(";\r"), opt(charset), crlf));
+/// Frame parsed from the input.
+#[derive(Debug, PartialEq)]
+pub struct Frame<'a> {
+    /// The `charset` parameter of the `Content-Type` header, if any.
+    pub charset: Option<&'a [u8]>,
+    /// The content of the frame.
+    pub content: &'a [u8],
+}
 
-    let header = terminated(terminated(content_len, opt(content_type)), crlf);
+// Get JSON message from input using the Content-Length header.
+pub fn parse_message(input: &[u8]) -> IResult<&[u8], Frame<'_>> {
+    let length = map_res(map_res(digit1, str::from_utf8), |s: &str| {
+        s.parse::<usize>()
+    });
+    let content_len = delimited(tag("Content-Length: "), length, crlf);
 
-    let header = map_res(header, str::from_utf8);
-    let length = map_res(header, |s: &str| s.parse::<usize>());
-    let mut message = length_data(length);
+    // The value can be quoted. Older servers may declare encodings other than UTF-8.
+    let value = delimited(opt(char('"')), is_not(";\r\""), opt(char('"')));
+    let charset = preceded(tuple((char(';'), space0, tag("charset="))), value);
+    let content_type = map(
+        tuple((tag("Content-Type: "), is_not(";\r"), opt(charset), crlf)),
+        |(_, _, charset, _)| charset,
+    );
 
-    message(input)
+    let mut header = terminated(tuple((content_len, opt(content_type))), crlf);
+    let (input, (length, charset)) = header(input)?;
+    let (input, content) = take(length)(input)?;
+    Ok((
+        input,
+        Frame {
+            charset: charset.flatten(),
+            content,
+        },
+    ))
 }
 
 pub fn find_next_message(input: &[u8]) -> IResult<&[u8], usize> {
@@ -46,7 +63,13 @@ mod tests {
         let sample = format!("Content-Length: {}\r\n\r\n{}", decoded.len(), decoded);
         assert_eq!(
             parse_message(sample.as_bytes()),
-            Ok(("".as_bytes(), decoded.as_bytes()))
+            Ok((
+                "".as_bytes(),
+                Frame {
+                    charset: None,
+                    content: decoded.as_bytes()
+                }
+            ))
         );
     }
 
@@ -64,7 +87,36 @@ mod tests {
         );
         assert_eq!(
             parse_message(sample.as_bytes()),
-            Ok(("".as_bytes(), decoded.as_bytes()))
+            Ok((
+                "".as_bytes(),
+                Frame {
+                    charset: Some("utf-8".as_bytes()),
+                    content: decoded.as_bytes()
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_legacy_charset() {
+        let decoded = r#"{"jsonrpc":"2.0","method":"exit"}"#;
+        let content_type = "Content-Type: application/vscode-jsonrpc; charset=\"ISO-8859-1\"";
+
+        let sample = format!(
+            "Content-Length: {}\r\n{}\r\n\r\n{}",
+            decoded.len(),
+            content_type,
+            decoded
+        );
+        assert_eq!(
+            parse_message(sample.as_bytes()),
+            Ok((
+                "".as_bytes(),
+                Frame {
+                    charset: Some("ISO-8859-1".as_bytes()),
+                    content: decoded.as_bytes()
+                }
+            ))
         );
     }
 
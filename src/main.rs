@@ -1,13 +1,12 @@
-use std::net::SocketAddr;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
 
 use argh::FromArgs;
 use url::Url;
 use warp::{http, Filter};
 
-mod api;
-mod lsp;
+use lsp_ws_proxy::{api, backend, bridge, config, discover, listener, lsp};
 
-#[derive(FromArgs)]
+#[derive(FromArgs, Clone)]
 // Using block doc comments so that `argh` preserves newlines in help output.
 // We need to also write block doc comments without leading space.
 /**
@@ -15,36 +14,453 @@ Start WebSocket proxy for the LSP Server.
 Anything after the option delimiter is used to start the server.
 
 Multiple servers can be registered by separating each with an option delimiter,
-and using the query parameter `name` to specify the command name on connection.
-If no query parameter is present, the first one is started.
+and using the query parameter `name` (or `server`) to specify the command name
+on connection. If no query parameter is present, the first one is started.
+Connections selecting an unknown server are rejected.
+
+Use `--connect` to proxy a Language Server that is already running
+instead of starting one for each connection, or to start one over SSH.
 
 Examples:
   lsp-ws-proxy -- rust-analyzer
   lsp-ws-proxy -- typescript-language-server --stdio
   lsp-ws-proxy --listen 8888 -- rust-analyzer
   lsp-ws-proxy --listen 0.0.0.0:8888 -- rust-analyzer
+  lsp-ws-proxy --listen unix:/run/lsp-ws-proxy.sock -- rust-analyzer
+  lsp-ws-proxy --listen 127.0.0.1:9999 --listen [::1]:9999 -- rust-analyzer
+  lsp-ws-proxy --tls-cert cert.pem --tls-key key.pem -- rust-analyzer
+  # Serve under /lsp/ with WebSocket at /lsp/ws behind path-based routing.
+  lsp-ws-proxy --prefix /lsp/ --ws-path /ws -- rust-analyzer
+  # Started by systemd with socket activation.
+  lsp-ws-proxy --systemd-socket -- rust-analyzer
   # Register multiple servers.
   # Choose the server with query parameter `name` when connecting.
   lsp-ws-proxy --listen 9999 --sync --remap \
     -- typescript-language-server --stdio \
     -- css-languageserver --stdio \
     -- html-languageserver --stdio
+  # Start rust-analyzer on /rust and pyright on /python.
+  lsp-ws-proxy --route /rust=rust-analyzer --route /python=pyright-langserver \
+    -- rust-analyzer \
+    -- pyright-langserver --stdio
+  # Select the server with the header set by the front proxy.
+  lsp-ws-proxy --server-header X-LSP-Server -- gopls -- rust-analyzer
+  # Choose the TypeScript version with query parameter `tsVersion`.
+  lsp-ws-proxy --param tsVersion=4.3.5 \
+    -- typescript-language-server --stdio --tsserver-path=/opt/ts/{tsVersion}/tsserver.js
+  # Multiplex both on one connection by languageId.
+  lsp-ws-proxy --language rust=rust-analyzer --language python=pyright-langserver \
+    -- rust-analyzer \
+    -- pyright-langserver --stdio
+  # Route documents by file extension instead.
+  lsp-ws-proxy --extension rs=rust-analyzer --extension py=pyright-langserver \
+    -- rust-analyzer \
+    -- pyright-langserver --stdio
+  # Start rls if rust-analyzer is missing or fails to initialize.
+  lsp-ws-proxy --fallback rust-analyzer=rls -- rust-analyzer -- rls
+  # Set the target directory of rust-analyzer whatever the client sends.
+  lsp-ws-proxy --initialization-options 'rust-analyzer={"cargo":{"targetDir":"/tmp/ra"}}' \
+    -- rust-analyzer
+  # Rewrite or veto the messages of rust-analyzer with a WebAssembly plugin.
+  lsp-ws-proxy --plugin rust-analyzer=/etc/lsp-ws-proxy/policy.wasm -- rust-analyzer
+  # Modify the messages of rust-analyzer with a Rhai script.
+  lsp-ws-proxy --script rust-analyzer=/etc/lsp-ws-proxy/rust.rhai -- rust-analyzer
+  # Pipe the messages of rust-analyzer through an external command.
+  lsp-ws-proxy --hook 'rust-analyzer=python3 /etc/lsp-ws-proxy/hook.py' -- rust-analyzer
+  # Reuse one process for each server instead of starting one for each connection.
+  lsp-ws-proxy --process shared -- jdtls
+  # Distribute connections between 4 shared processes.
+  lsp-ws-proxy --replicas 4 --balance least-connections -- gopls
+  # Allow 8 sessions, and wait up to 30s for one to end before rejecting.
+  lsp-ws-proxy --max-sessions 8 --session-wait 30 -- rust-analyzer
+  # Run at most 16 server processes.
+  lsp-ws-proxy --max-processes 16 -- rust-analyzer
+  # Keep the server for 60s after the client disconnects so it can resume the session.
+  lsp-ws-proxy --resume-timeout 60 -- rust-analyzer
+  # Also resume sessions after the proxy restarts.
+  lsp-ws-proxy --resume-timeout 60 --state-dir /var/lib/lsp-ws-proxy -- rust-analyzer
+  # Stop servers of sessions idle for 30 minutes.
+  lsp-ws-proxy --idle-timeout 30 -- rust-analyzer
+  # Restart the server if it crashes.
+  lsp-ws-proxy --restart -- rust-analyzer
+  # Also restart it if it stops responding for 3 minutes.
+  lsp-ws-proxy --restart --probe-interval 60 -- rust-analyzer
+  # Keep a started server ready to replace a crashed one.
+  lsp-ws-proxy --restart --standby -- jdtls
+  # End sessions after 90 minutes, e.g. for exams.
+  lsp-ws-proxy --max-session-duration 90 -- rust-analyzer
+  # Fail requests rust-analyzer doesn't respond to within 30 seconds.
+  lsp-ws-proxy --request-timeout 30 -- rust-analyzer
+  # Fail responses over 10 MiB instead of sending them to the browser.
+  lsp-ws-proxy --max-server-message 10485760 -- rust-analyzer
+  # Limit each server to 4 GiB of memory and 1024 open files.
+  lsp-ws-proxy --limit memory=4096 --limit files=1024 -- rust-analyzer
+  # Stop servers with SIGINT, then SIGTERM 5s later, then SIGKILL 5s later.
+  lsp-ws-proxy --stop-signals INT:5,TERM:5 -- rust-analyzer
+  # Start the server in a new container for each connection.
+  lsp-ws-proxy --docker-image rust:latest -- rust-analyzer
+  # Connect to a running server instead of starting one.
+  lsp-ws-proxy --connect tcp://127.0.0.1:8080
+  lsp-ws-proxy --connect pipe:\\.\pipe\rust-analyzer
+  # Forward to another proxy.
+  lsp-ws-proxy --connect ws://internal:9999/?name=rust-analyzer
+  # Start the server on a remote machine in /home/user/project.
+  lsp-ws-proxy --connect ssh://user@host/home/user/project -- rust-analyzer
+  # Load servers and options from a file.
+  lsp-ws-proxy --config proxy.toml
+  # Proxy every well-known server installed, e.g. gopls on /go.
+  lsp-ws-proxy --discover
+  # Register servers at runtime with PUT /admin/servers/{name}.
+  lsp-ws-proxy --admin-token secret -- rust-analyzer
+  # Start as the Language Server of a desktop editor and forward to a remote proxy.
+  lsp-ws-proxy --bridge wss://example.com/lsp
 */
 struct Options {
-    /// address or port to listen on (default: 0.0.0.0:9999)
-    #[argh(
-        option,
-        short = 'l',
-        default = "String::from(\"0.0.0.0:9999\")",
-        from_str_fn(parse_listen)
-    )]
-    listen: String,
+    /// address or port to listen on, unix:/path/to/socket, or pipe:\\.\pipe\name
+    /// (default: 0.0.0.0:9999).
+    /// can be repeated to listen on multiple addresses
+    #[argh(option, short = 'l')]
+    listen: Vec<listener::Listen>,
+    /// permissions of the unix socket in octal (e.g. 660)
+    #[argh(option, from_str_fn(listener::parse_socket_mode))]
+    socket_mode: Option<u32>,
+    /// listen on the socket passed by systemd socket activation
+    #[argh(switch)]
+    systemd_socket: bool,
+    /// path to the TLS certificate chain to serve over wss:// (PEM)
+    #[argh(option)]
+    tls_cert: Option<PathBuf>,
+    /// path to the TLS private key (PEM)
+    #[argh(option)]
+    tls_key: Option<PathBuf>,
+    /// require client certificates signed by the CA bundle (PEM)
+    #[argh(option)]
+    tls_client_ca: Option<PathBuf>,
     /// write text document to disk on save, and enable `/files` endpoint
     #[argh(switch, short = 's')]
     sync: bool,
+    /// with --sync, apply `workspace/applyEdit` from the server to the files
+    /// of the documents the client hasn't opened
+    #[argh(switch)]
+    apply_edits: bool,
+    /// watch the workspace and send `workspace/didChangeWatchedFiles` to the
+    /// servers for the changed files matching the watchers they registered
+    #[argh(switch)]
+    watch: bool,
+    /// keep the workspace in a new directory in memory removed on exit,
+    /// start the servers in it, and only write documents in it on save
+    #[argh(switch)]
+    memory: bool,
+    /// memory filesystem (tmpfs) to create the --memory workspace in
+    /// (default: /dev/shm)
+    #[argh(option)]
+    memory_dir: Option<PathBuf>,
+    /// require `Authorization: Bearer <token>` for the `/files` endpoint
+    #[argh(option)]
+    files_token: Option<String>,
+    /// leave files and directories with the name, or ending with the rest of
+    /// `*<end>`, out of `/files/list` (default: .git). can be repeated
+    #[argh(option)]
+    files_ignore: Vec<String>,
+    /// maximum megabytes of the archives uploaded to `/files/archive`, and of
+    /// the files extracted from them (default: 100)
+    #[argh(option)]
+    max_upload: Option<u64>,
+    /// limit the files of each workspace written with `/files` and on save:
+    /// size=<MiB>, files=<count>, or file-size=<KiB>. can be repeated
+    #[argh(option)]
+    quota: Vec<api::quota::Limit>,
+    /// only sync the files of the workspaces in the directory, and documents
+    /// in the workspace of the session. can be repeated
+    #[argh(option)]
+    sync_root: Vec<PathBuf>,
     /// remap relative uri (source://)
     #[argh(switch, short = 'r')]
     remap: bool,
+    /// remap uris starting with the prefix on the client to the path on the
+    /// server (e.g. source://web/=/srv/web), implies --remap. can be
+    /// repeated
+    #[argh(option)]
+    remap_rule: Vec<lsp::ext::RemapRule>,
+    /// with --remap, remap uris in any field of the messages, not only the
+    /// known ones
+    #[argh(switch)]
+    deep_remap: bool,
+    /// rewrite the absolute paths in the workspace to relative ones in the
+    /// messages from the server, like in diagnostics and logs
+    #[argh(switch)]
+    redact_paths: bool,
+    /// convert snippet completions from the server to plain text for clients
+    /// without snippet support
+    #[argh(switch)]
+    strip_snippets: bool,
+    /// answer `window/showDocument` and `window/showMessageRequest` of the
+    /// servers when the client doesn't support them
+    #[argh(switch)]
+    answer_unsupported: bool,
+    /// with --answer-unsupported, the action to answer
+    /// `window/showMessageRequest` with: none or first (default: none)
+    #[argh(option)]
+    message_action: Option<api::interactive::MessageAction>,
+    /// normalize the line endings of the documents to LF for the servers,
+    /// restoring CRLF in their edits
+    #[argh(switch)]
+    normalize_line_endings: bool,
+    /// translate positions between this encoding of the clients and UTF-16 of
+    /// the servers: utf-8 or utf-32
+    #[argh(option)]
+    position_encoding: Option<api::positions::PositionEncoding>,
+    /// enable server-sent events fallback for clients without WebSocket (`/events`)
+    #[argh(switch)]
+    sse: bool,
+    /// drop messages with the method from the client or the server (e.g.
+    /// server:telemetry/event, client:workspace/*), answering requests with an
+    /// error. can be repeated
+    #[argh(option)]
+    drop: Vec<api::filter::Rule>,
+    /// only forward messages with the methods from the client or the server,
+    /// dropping the others like --drop. can be repeated
+    #[argh(option)]
+    allow: Vec<api::filter::Rule>,
+    /// post the messages with the method, and the responses to them, to the
+    /// webhook, forwarding what it responds with (e.g.
+    /// client:textDocument/codeAction=http://localhost:8080/lsp). can be repeated
+    #[argh(option)]
+    webhook: Vec<api::webhook::Webhook>,
+    /// send `telemetry/event` of the servers to the http(s) url or append it
+    /// to the file, instead of forwarding it to the clients
+    #[argh(option)]
+    telemetry: Option<api::telemetry::Sink>,
+    /// answer the requests with the method from the client over the rate of
+    /// each session with an error (e.g. textDocument/completion=10/s). can be
+    /// repeated
+    #[argh(option)]
+    rate_limit: Vec<api::rate_limit::RateLimit>,
+    /// answer repeated requests with the method for the same version of the
+    /// document from the cache (e.g. textDocument/documentSymbol). can be
+    /// repeated
+    #[argh(option)]
+    cache: Vec<String>,
+    /// disable permessage-deflate compression
+    #[argh(switch)]
+    no_compression: bool,
+    /// path prefix of all routes (e.g. /lsp/)
+    #[argh(option)]
+    prefix: Option<String>,
+    /// path to accept WebSocket connections on under the prefix (default: /).
+    /// can be repeated
+    #[argh(option)]
+    ws_path: Vec<String>,
+    /// start the named server on connections to the path under the prefix
+    /// (e.g. /rust=rust-analyzer). can be repeated
+    #[argh(option)]
+    route: Vec<api::proxy::Route>,
+    /// select the server with the header of the upgrade request instead of
+    /// the query parameter (e.g. X-LSP-Server)
+    #[argh(option)]
+    server_header: Option<String>,
+    /// allow the query parameter to fill `{name}` in the commands, with
+    /// optional default (e.g. tsVersion=4.3.5). can be repeated
+    #[argh(option)]
+    param: Vec<api::template::Param>,
+    /// multiplex servers on connections without a selected server, routing
+    /// documents by languageId (e.g. rust=rust-analyzer). can be repeated
+    #[argh(option)]
+    language: Vec<api::multiplex::Language>,
+    /// multiplex servers on connections without a selected server, routing
+    /// documents by file extension (e.g. rs=rust-analyzer). can be repeated
+    #[argh(option)]
+    extension: Vec<api::multiplex::Extension>,
+    /// start the other server when the named server fails to start or
+    /// initialize (e.g. rust-analyzer=rls). can be repeated
+    #[argh(option)]
+    fallback: Vec<api::fallback::Fallback>,
+    /// merge the json into `initializationOptions` of `initialize` to the
+    /// named server (e.g. rust-analyzer={"cargo":{"targetDir":"/tmp/ra"}}),
+    /// overriding the client. can be repeated
+    #[argh(option)]
+    initialization_options: Vec<lsp::ext::InitializationOptions>,
+    /// run the webassembly plugin on the messages of the named server (e.g.
+    /// rust-analyzer=/etc/lsp-ws-proxy/policy.wasm). can be repeated
+    #[argh(option)]
+    plugin: Vec<api::plugin::PluginOption>,
+    /// run the rhai script on the messages of the named server (e.g.
+    /// rust-analyzer=/etc/lsp-ws-proxy/rust.rhai)
+    #[argh(option)]
+    script: Vec<api::script::ScriptOption>,
+    /// pipe the messages of the named server through the command, a line of
+    /// json for each (e.g. rust-analyzer=python3 hook.py)
+    #[argh(option)]
+    hook: Vec<api::hook::HookOption>,
+    /// keep the number of default servers started and initialized ahead of
+    /// connections
+    #[argh(option)]
+    pool_size: Option<usize>,
+    /// start a process for each connection (per-connection, default),
+    /// or reuse one process for each server, one connection at a time (shared)
+    #[argh(option)]
+    process: Option<api::shared::Process>,
+    /// share one process between connections for each server (server),
+    /// or for each server and workspace until the last one closes (workspace)
+    #[argh(option)]
+    share: Option<api::shared::Share>,
+    /// number of processes to share for each server, implies `--share server`
+    #[argh(option)]
+    replicas: Option<usize>,
+    /// how connections are distributed between replicas: round-robin
+    /// (default) or least-connections
+    #[argh(option)]
+    balance: Option<api::shared::Balance>,
+    /// maximum number of concurrent sessions. new connections are rejected
+    /// with 503 Service Unavailable
+    #[argh(option)]
+    max_sessions: Option<usize>,
+    /// seconds to wait for a session to end before rejecting connections
+    /// over `--max-sessions`
+    #[argh(option)]
+    session_wait: Option<u64>,
+    /// maximum number of server processes. sessions needing another one
+    /// fail to connect
+    #[argh(option)]
+    max_processes: Option<usize>,
+    /// seconds to keep the server after the client disconnects, so it can
+    /// reconnect with the query parameter `session`
+    #[argh(option)]
+    resume_timeout: Option<u64>,
+    /// maximum bytes of server messages buffered for a disconnected client
+    /// and replayed when it resumes (default: 1048576)
+    #[argh(option)]
+    resume_buffer: Option<usize>,
+    /// directory to save resumable sessions to, so clients can resume them
+    /// with a new server after the proxy restarts
+    #[argh(option)]
+    state_dir: Option<PathBuf>,
+    /// minutes without messages from the client before closing the session
+    /// and stopping its server
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+    /// seconds given to the servers to exit after `shutdown` and `exit` on
+    /// SIGTERM or SIGINT before they're killed (default: 10)
+    #[argh(option)]
+    shutdown_grace: Option<u64>,
+    /// restart servers that crash during the session, and reopen the
+    /// documents of the client
+    #[argh(switch)]
+    restart: bool,
+    /// crashes in a row before `--restart` gives up, showing an error and
+    /// closing the connection with 4000 (default: 5)
+    #[argh(option)]
+    max_restarts: Option<u32>,
+    /// seconds between requests checking the server still responds with
+    /// `--restart`. it's restarted after 3 without a response
+    #[argh(option)]
+    probe_interval: Option<u64>,
+    /// keep a started server ready for each server used with `--restart`,
+    /// replacing crashed servers without waiting for a new one to start
+    #[argh(switch)]
+    standby: bool,
+    /// minutes after which sessions are closed, warning the user a minute
+    /// before
+    #[argh(option)]
+    max_session_duration: Option<u64>,
+    /// seconds to wait for the server to respond to a request before
+    /// answering it with an error and cancelling it
+    #[argh(option)]
+    request_timeout: Option<u64>,
+    /// milliseconds to buffer changes to a document from the client, merging
+    /// them into one `textDocument/didChange`
+    #[argh(option)]
+    coalesce_changes: Option<u64>,
+    /// bytes over which messages from the client are dropped, answering
+    /// requests with an error
+    #[argh(option)]
+    max_client_message: Option<usize>,
+    /// bytes over which messages from the server are dropped, answering
+    /// requests with an error
+    #[argh(option)]
+    max_server_message: Option<usize>,
+    /// what to do with responses over `--max-server-message`: error to answer
+    /// the request with an error, or drop (default: error)
+    #[argh(option)]
+    oversized_responses: Option<api::size::Oversized>,
+    /// bytes over which array results of requests with `partialResultToken`
+    /// are streamed to the client as partial results
+    #[argh(option)]
+    partial_results: Option<usize>,
+    /// seconds between pings to WebSocket clients, 0 to disable (default: 30)
+    #[argh(option)]
+    ping_interval: Option<u64>,
+    /// seconds to wait for a pong before closing the connection and
+    /// stopping its server (default: the ping interval)
+    #[argh(option)]
+    ping_timeout: Option<u64>,
+    /// also serve the gRPC service on the address (e.g. 127.0.0.1:9998)
+    #[argh(option)]
+    grpc: Option<SocketAddr>,
+    /// enable `/admin/servers` endpoint to register servers at runtime,
+    /// authenticated with `Authorization: Bearer <token>`
+    #[argh(option)]
+    admin_token: Option<String>,
+    /// connect to a running server instead of starting one (tcp://host:port, unix:///path,
+    /// pipe:\\.\pipe\name, ws://host/path to chain proxies),
+    /// or start on a remote machine (ssh://user@host[:port][/path])
+    #[argh(option, short = 'c')]
+    connect: Option<backend::Remote>,
+    /// start the servers in the workspace of the client (rootUri) if it's a
+    /// local directory
+    #[argh(switch)]
+    cwd_from_root: bool,
+    /// clone the git repository at the url, followed by `#<branch>` to
+    /// select a branch, into a workspace for each session to start the server
+    /// in
+    #[argh(option)]
+    repo: Option<api::clone::Repo>,
+    /// allow the clients to clone repositories with urls starting with the
+    /// prefix with the query parameters `repo` and `branch`. can be repeated
+    #[argh(option)]
+    allow_repo: Vec<String>,
+    /// directory to clone the repositories of the sessions into (default: a
+    /// directory in the temporary directory)
+    #[argh(option)]
+    clone_dir: Option<PathBuf>,
+    /// number of commits to clone, or 0 for the whole history (default: 1)
+    #[argh(option)]
+    clone_depth: Option<u32>,
+    /// start each session in a new empty workspace, removed when it ends
+    #[argh(switch)]
+    isolate: bool,
+    /// directory to create the workspaces of the sessions in (default: a
+    /// directory in the temporary directory)
+    #[argh(option)]
+    workspace_dir: Option<PathBuf>,
+    /// seconds to keep the workspaces of the sessions after they end
+    /// (default: 0)
+    #[argh(option)]
+    workspace_retention: Option<u64>,
+    /// limit a resource of the spawned servers: memory=<MiB>, cpu=<seconds>,
+    /// or files=<count>. can be repeated
+    #[argh(option)]
+    limit: Vec<backend::Limit>,
+    /// signals to stop the servers with, each followed by seconds to wait
+    /// (e.g. INT:5,TERM:5). servers still running are killed
+    #[argh(option)]
+    stop_signals: Option<backend::StopSignals>,
+    /// start the server in a new container from the image for each connection
+    #[argh(option)]
+    docker_image: Option<String>,
+    /// start the server in the running container
+    #[argh(option)]
+    docker_exec: Option<String>,
+    /// load servers and options from the TOML or YAML file
+    #[argh(option)]
+    config: Option<PathBuf>,
+    /// proxy well-known servers found in PATH on conventional routes
+    /// (e.g. /rust, /go, /python)
+    #[argh(switch)]
+    discover: bool,
+    /// speak LSP over stdio and forward to the proxy at the WebSocket url
+    #[argh(option)]
+    bridge: Option<String>,
     /// show version and exit
     #[argh(switch, short = 'v')]
     version: bool,
@@ -52,45 +468,385 @@ struct Options {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned()))
-        .init();
+    let (mut opts, commands) = get_opts_and_commands();
+
+    let env_filter = std::env::var("RUST_LOG").unwrap_or_else(|_| "info".to_owned());
+    if let Some(url) = &opts.bridge {
+        // stdout is used for LSP messages
+        tracing_subscriber::fmt()
+            .with_env_filter(env_filter)
+            .with_writer(std::io::stderr)
+            .init();
+        return bridge::run(url)
+            .await
+            .map_err(|err| -> Box<dyn std::error::Error> { err });
+    }
+    tracing_subscriber::fmt().with_env_filter(env_filter).init();
+
+    let cli_opts = opts.clone();
+    let mut servers = to_servers(&commands);
+    if let Some(path) = &cli_opts.config {
+        let config = config::load(path).unwrap_or_else(|err| panic!("{}", err));
+        apply_config(&mut opts, &mut servers, config).unwrap_or_else(|err| panic!("{}", err));
+    }
+    opts.remap |= !opts.remap_rule.is_empty();
+    if opts.discover {
+        apply_discovered(&mut opts, &mut servers);
+    }
+    apply_fallbacks(&opts, &mut servers).unwrap_or_else(|err| panic!("{}", err));
+    apply_initialization_options(&opts, &mut servers).unwrap_or_else(|err| panic!("{}", err));
+    apply_plugins(&opts, &mut servers).unwrap_or_else(|err| panic!("{}", err));
+    apply_scripts(&opts, &mut servers).unwrap_or_else(|err| panic!("{}", err));
+    apply_hooks(&opts, &mut servers).unwrap_or_else(|err| panic!("{}", err));
+    apply_cwd_from_root(&opts, &mut servers);
+    apply_limits(&opts, &mut servers);
+    apply_stop_signals(&opts, &mut servers);
+    validate_servers(&opts, &servers).unwrap_or_else(|err| panic!("{}", err));
 
-    let (opts, commands) = get_opts_and_commands();
+    let config = listener::Config {
+        socket_mode: opts.socket_mode,
+        tls: match (&opts.tls_cert, &opts.tls_key) {
+            (Some(cert), Some(key)) => Some(listener::Tls {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: opts.tls_client_ca.clone(),
+            }),
+            (None, None) => None,
+            _ => panic!("--tls-cert and --tls-key must be used together"),
+        },
+    };
+    if config.tls.is_none() && opts.tls_client_ca.is_some() {
+        panic!("--tls-client-ca requires --tls-cert and --tls-key");
+    }
+
+    let mut listens = opts.listen.clone();
+    if opts.systemd_socket {
+        listens.push(listener::systemd().unwrap_or_else(|err| panic!("{}", err)));
+    }
+    if listens.is_empty() {
+        listens.push(listener::Listen::default());
+    }
 
     let cwd = std::env::current_dir()?;
+    // Removed when the proxy stops.
+    let memory = if opts.memory {
+        if opts.connect.is_some() || opts.cwd_from_root {
+            panic!("--memory cannot be used with --connect or --cwd-from-root");
+        }
+        if opts.state_dir.is_some() {
+            panic!("--memory cannot be used with --state-dir, it saves the documents to the disk");
+        }
+        let dir = opts.memory_dir.as_ref().map_or_else(
+            || PathBuf::from(api::memory::DEFAULT_DIR),
+            |dir| cwd.join(dir),
+        );
+        Some(api::memory::Memory::create(&dir).unwrap_or_else(|err| panic!("{}", err)))
+    } else {
+        None
+    };
+    let clone_dir = match (&opts.clone_dir, &memory) {
+        (Some(dir), _) => cwd.join(dir),
+        (None, Some(memory)) => memory.clones(),
+        (None, None) => std::env::temp_dir().join("lsp-ws-proxy-clones"),
+    };
+    let workspace_dir = match (&opts.workspace_dir, &memory) {
+        (Some(dir), _) => cwd.join(dir),
+        (None, Some(memory)) => memory.sessions(),
+        (None, None) => std::env::temp_dir().join("lsp-ws-proxy-workspaces"),
+    };
+    let workspaces =
+        api::workspace::Workspaces::new(Duration::from_secs(opts.workspace_retention.unwrap_or(0)));
+    let sync_roots = opts
+        .sync_root
+        .iter()
+        .map(|dir| cwd.join(dir))
+        .collect::<Vec<_>>();
+    let sync_roots = api::sandbox::Roots::new(&sync_roots)
+        .unwrap_or_else(|err| panic!("invalid --sync-root {}", err));
+    let cwd = memory.as_ref().map_or(cwd, api::memory::Memory::workspace);
     // TODO Move these to `api` module.
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(&[http::header::CONTENT_TYPE])
-        .allow_methods(&[http::Method::GET, http::Method::OPTIONS, http::Method::POST]);
-    // TODO Limit concurrent connection. Can get messy when `sync` is used.
+        .allow_headers(&[
+            http::header::CONTENT_TYPE,
+            http::header::AUTHORIZATION,
+            http::header::IF_NONE_MATCH,
+            http::header::HeaderName::from_static("x-session-id"),
+        ])
+        .expose_headers(&[http::header::ETAG])
+        .allow_methods(&[
+            http::Method::GET,
+            http::Method::OPTIONS,
+            http::Method::POST,
+            http::Method::PUT,
+            http::Method::DELETE,
+        ]);
+    let shutdown =
+        api::shutdown::Shutdown::new(Duration::from_secs(opts.shutdown_grace.unwrap_or(10)));
+    let root = match &opts.connect {
+        // URIs are remapped relative to the project on the remote machine.
+        Some(backend::Remote::Ssh(backend::Ssh { cwd: Some(dir), .. })) => {
+            Url::from_directory_path(dir).expect("valid url from remote dir")
+        }
+        _ => Url::from_directory_path(&cwd).expect("valid url from current dir"),
+    };
+    let plugins = api::plugin::Plugins::load(&servers).unwrap_or_else(|err| panic!("{}", err));
+    let scripts = api::script::Scripts::load(&servers).unwrap_or_else(|err| panic!("{}", err));
+    let hooks = api::hook::Hooks::new(&servers);
+    // Notify the sessions of the files removed and renamed with `/files`.
+    let file_operations = api::file_operations::FileOperations::new();
     // TODO? Keep track of added files and remove them on disconnect?
-    let proxy = api::proxy::handler(api::proxy::Context {
-        commands,
+    let mut proxy_ctx = api::proxy::Context {
+        servers,
         sync: opts.sync,
+        apply_edits: opts.apply_edits,
+        watch: opts.watch,
+        memory: memory.is_some(),
+        quota: api::quota::Quota::new(&opts.quota),
+        sync_roots: sync_roots.clone(),
+        file_operations: file_operations.clone(),
+        repo: opts.repo.clone(),
+        allowed_repos: opts.allow_repo.clone(),
+        clone_dir,
+        isolate: opts.isolate,
+        workspace_dir,
+        workspaces: workspaces.clone(),
+        workspace_session: None,
+        clone_depth: Some(opts.clone_depth.unwrap_or(1)).filter(|depth| *depth > 0),
+        connect: opts.connect.clone(),
+        docker: match (&opts.docker_image, &opts.docker_exec) {
+            (Some(image), None) => Some(backend::Docker::Image(image.clone())),
+            (None, Some(container)) => Some(backend::Docker::Exec(container.clone())),
+            (None, None) => None,
+            _ => panic!("--docker-image and --docker-exec cannot be used together"),
+        },
+        languages: opts.language.clone(),
+        extensions: opts.extension.clone(),
+        pool: None,
+        shared: match (opts.share, opts.replicas) {
+            (None, None) if opts.process == Some(api::shared::Process::Shared) => {
+                Some(api::shared::Hubs::new(api::shared::Share::Server).exclusive())
+            }
+            (share, Some(replicas)) => Some(
+                api::shared::Hubs::new(share.unwrap_or(api::shared::Share::Server)).with_replicas(
+                    replicas,
+                    opts.balance.unwrap_or(api::shared::Balance::RoundRobin),
+                ),
+            ),
+            (share, None) => share.map(api::shared::Hubs::new),
+        },
+        sessions: opts.max_sessions.map(|max| {
+            api::limit::SessionLimit::new(max, opts.session_wait.map(Duration::from_secs))
+        }),
+        processes: opts.max_processes.map(api::limit::ProcessLimit::new),
+        resume: match opts.resume_timeout {
+            Some(secs) => {
+                let sessions = api::resume::Sessions::new(
+                    Duration::from_secs(secs),
+                    opts.resume_buffer.unwrap_or(1024 * 1024),
+                );
+                match &opts.state_dir {
+                    Some(dir) => Some(
+                        sessions
+                            .with_store(api::persist::Store::new(dir.clone())?, shutdown.clone()),
+                    ),
+                    None => Some(sessions),
+                }
+            }
+            None => None,
+        },
+        session_token: None,
+        idle_timeout: opts
+            .idle_timeout
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| Duration::from_secs(60 * minutes)),
+        shutdown: shutdown.clone(),
+        restart: opts.restart,
+        max_restarts: opts.max_restarts.unwrap_or(5),
+        probe_interval: opts
+            .probe_interval
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+        standby: if opts.standby {
+            Some(api::standby::Standby::new())
+        } else {
+            None
+        },
+        max_session_duration: opts
+            .max_session_duration
+            .filter(|minutes| *minutes > 0)
+            .map(|minutes| Duration::from_secs(60 * minutes)),
+        request_timeout: opts
+            .request_timeout
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+        coalesce_changes: opts
+            .coalesce_changes
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis),
+        size_limits: api::size::SizeLimits {
+            client: opts.max_client_message,
+            server: opts.max_server_message,
+            oversized: opts.oversized_responses.unwrap_or_default(),
+        },
+        partial_results: opts.partial_results.filter(|bytes| *bytes > 0),
+        ping_interval: Some(opts.ping_interval.unwrap_or(30))
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs),
+        ping_timeout: Duration::from_secs(
+            opts.ping_timeout
+                .or(opts.ping_interval)
+                .filter(|secs| *secs > 0)
+                .unwrap_or(30),
+        ),
         remap: opts.remap,
-        cwd: Url::from_directory_path(&cwd).expect("valid url from current dir"),
-    });
-    let healthz = warp::path::end().and(warp::get()).map(|| "OK");
-    let addr = opts.listen.parse::<SocketAddr>().expect("valid addr");
+        deep_remap: opts.deep_remap,
+        redact_paths: opts.redact_paths,
+        strip_snippets: opts.strip_snippets,
+        answer_unsupported: opts.answer_unsupported,
+        message_action: opts.message_action.unwrap_or_default(),
+        normalize_line_endings: opts.normalize_line_endings,
+        position_encoding: opts.position_encoding,
+        initialization_options: None,
+        capabilities: None,
+        middlewares: Default::default(),
+        plugins,
+        scripts,
+        hooks,
+        filters: api::filter::Filters {
+            drop: opts.drop.clone(),
+            allow: opts.allow.clone(),
+        },
+        webhooks: api::webhook::Webhooks::new(opts.webhook.clone()),
+        telemetry: opts.telemetry.clone().map(api::telemetry::Telemetry::new),
+        rate_limits: opts.rate_limit.clone(),
+        cache: opts.cache.clone(),
+        compression: !opts.no_compression,
+        server_header: opts.server_header.clone(),
+        params: opts.param.clone(),
+        param_values: HashMap::new(),
+        client_subject: None,
+        remap_rules: lsp::ext::RemapRules::new(&root, opts.remap_rule.clone()),
+        cwd: root,
+    };
+    if let Some(size) = opts.pool_size.filter(|size| *size > 0) {
+        if proxy_ctx.connect.is_some() {
+            panic!("--pool-size cannot be used with --connect");
+        }
+        proxy_ctx.pool = Some(api::pool::Pool::new(proxy_ctx.clone(), size));
+    }
+    if opts.process == Some(api::shared::Process::Shared)
+        && (opts.share.is_some() || opts.replicas.is_some())
+    {
+        panic!("--process shared cannot be used with --share or --replicas");
+    }
+    if proxy_ctx.shared.is_some() && proxy_ctx.connect.is_some() {
+        panic!("--share cannot be used with --connect");
+    }
+    if opts.standby && !opts.restart {
+        panic!("--standby requires --restart");
+    }
+    // The workspace is removed when the connection closes.
+    let workspace = opts.isolate || opts.repo.is_some() || !opts.allow_repo.is_empty();
+    if proxy_ctx.resume.is_some() && workspace {
+        panic!("--isolate, --repo, and --allow-repo cannot be used with --resume-timeout");
+    }
+    // The servers would be started outside of the workspace.
+    if workspace
+        && (proxy_ctx.connect.is_some() || proxy_ctx.pool.is_some() || proxy_ctx.shared.is_some())
+    {
+        panic!("--isolate and --repo cannot be used with --connect, --pool-size, or --share");
+    }
+    let max_upload = opts
+        .max_upload
+        .unwrap_or(100)
+        .checked_mul(1024 * 1024)
+        .unwrap_or_else(|| panic!("--max-upload is too large"));
+    let proxy_ctx = api::proxy::SharedContext::new(proxy_ctx);
+    if let Some(path) = cli_opts.config.clone() {
+        tokio::spawn(watch_config(path, cli_opts, commands, proxy_ctx.clone()));
+    }
+    let proxy = api::proxy::handler(proxy_ctx.clone(), &opts.ws_path, &opts.route);
+    // Report unhealthy while draining so load balancers stop sending connections.
+    let healthz = {
+        let shutdown = shutdown.clone();
+        warp::path::end().and(warp::get()).map(move || {
+            if shutdown.is_draining() {
+                warp::reply::with_status("Draining", http::StatusCode::SERVICE_UNAVAILABLE)
+            } else {
+                warp::reply::with_status("OK", http::StatusCode::OK)
+            }
+        })
+    };
     // Enable `/files` endpoint if sync
-    if opts.sync {
-        let files = api::files::handler(api::files::Context {
-            cwd,
-            remap: opts.remap,
-        });
-        warp::serve(proxy.or(healthz).or(files).recover(api::recover).with(cors))
-            .run(addr)
-            .await;
-    } else {
-        warp::serve(proxy.or(healthz).recover(api::recover).with(cors))
-            .run(addr)
-            .await;
+    let files = api::enabled(opts.sync).and(api::files::handler(api::files::Context {
+        cwd,
+        remap: opts.remap,
+        token: opts.files_token.clone(),
+        ignore: if opts.files_ignore.is_empty() {
+            vec![".git".to_owned()]
+        } else {
+            opts.files_ignore.clone()
+        },
+        file_operations,
+        max_upload,
+        quota: api::quota::Quota::new(&opts.quota),
+        workspaces,
+        isolate: opts.isolate,
+        temp_dir: memory
+            .as_ref()
+            .map_or_else(std::env::temp_dir, api::memory::Memory::temp),
+        sync_roots,
+    }));
+    // Enable `/events` endpoint if sse
+    let sse =
+        api::enabled(opts.sse).and(api::sse::handler(api::sse::Context::new(proxy_ctx.clone())));
+    // Enable `/admin/servers` endpoint if admin token
+    let admin =
+        api::enabled(opts.admin_token.is_some()).and(api::admin::handler(api::admin::Context {
+            proxy: proxy_ctx.clone(),
+            token: opts.admin_token.clone().unwrap_or_default(),
+        }));
+    let http = listener::serve_all(
+        api::path_prefix(opts.prefix.as_deref().unwrap_or("/"))
+            .and(proxy.or(healthz).or(files).or(sse).or(admin))
+            .recover(api::recover)
+            .with(cors),
+        &listens,
+        &config,
+    );
+    let serve = async {
+        if let Some(addr) = opts.grpc {
+            futures_util::try_join!(http, api::grpc::serve(proxy_ctx, addr)).map(|_| ())
+        } else {
+            http.await
+        }
+    };
+    tokio::select! {
+        served = serve => served?,
+        _ = stop_signal() => shutdown.shutdown().await,
     }
     Ok(())
 }
 
+// Wait for SIGINT, or SIGTERM on Unix.
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("listen for SIGTERM");
+        tokio::select! {
+            _ = terminate.recv() => tracing::info!("received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => tracing::info!("received SIGINT"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.expect("listen for ctrl-c");
+        tracing::info!("received ctrl-c");
+    }
+}
+
 fn get_opts_and_commands() -> (Options, Vec<Vec<String>>) {
     let args: Vec<String> = std::env::args().collect();
     let splitted: Vec<Vec<String>> = args.split(|s| *s == "--").map(|s| s.to_vec()).collect();
@@ -111,22 +867,334 @@ fn get_opts_and_commands() -> (Options, Vec<Vec<String>>) {
         std::process::exit(0);
     }
 
-    if splitted.len() < 2 {
-        panic!("Command to start the server is required. See --help for examples.");
+    let commands: Vec<Vec<String>> = splitted[1..]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect();
+    (opts, commands)
+}
+
+fn to_servers(commands: &[Vec<String>]) -> Vec<api::proxy::Server> {
+    commands
+        .iter()
+        .cloned()
+        .map(api::proxy::Server::from)
+        .collect()
+}
+
+// Options from the command line take precedence over the config file.
+fn apply_config(
+    opts: &mut Options,
+    servers: &mut Vec<api::proxy::Server>,
+    config: config::Config,
+) -> Result<(), String> {
+    if opts.listen.is_empty() {
+        opts.listen = config
+            .listen
+            .iter()
+            .map(|listen| listen.parse())
+            .collect::<Result<_, _>>()?;
+    }
+    opts.socket_mode = opts.socket_mode.or(config.socket_mode);
+    opts.tls_cert = opts.tls_cert.take().or(config.tls_cert);
+    opts.tls_key = opts.tls_key.take().or(config.tls_key);
+    opts.tls_client_ca = opts.tls_client_ca.take().or(config.tls_client_ca);
+    opts.sync |= config.sync;
+    opts.apply_edits |= config.apply_edits;
+    opts.watch |= config.watch;
+    opts.memory |= config.memory;
+    opts.memory_dir = opts.memory_dir.take().or(config.memory_dir);
+    opts.isolate |= config.isolate;
+    opts.workspace_dir = opts.workspace_dir.take().or(config.workspace_dir);
+    opts.workspace_retention = opts.workspace_retention.or(config.workspace_retention);
+    if opts.quota.is_empty() {
+        opts.quota = config.quotas;
+    }
+    if opts.sync_root.is_empty() {
+        opts.sync_root = config.sync_roots;
+    }
+    opts.remap |= config.remap;
+    if opts.remap_rule.is_empty() {
+        opts.remap_rule = config.remap_rules;
     }
+    opts.deep_remap |= config.deep_remap;
+    opts.redact_paths |= config.redact_paths;
+    opts.strip_snippets |= config.strip_snippets;
+    opts.answer_unsupported |= config.answer_unsupported;
+    opts.message_action = opts.message_action.take().or(config.message_action);
+    opts.normalize_line_endings |= config.normalize_line_endings;
+    if opts.drop.is_empty() {
+        opts.drop = config.drop;
+    }
+    if opts.allow.is_empty() {
+        opts.allow = config.allow;
+    }
+    if opts.webhook.is_empty() {
+        opts.webhook = config.webhooks;
+    }
+    opts.telemetry = opts.telemetry.take().or(config.telemetry);
+    opts.repo = opts.repo.take().or(config.repo);
+    if opts.allow_repo.is_empty() {
+        opts.allow_repo = config.allow_repos;
+    }
+    if opts.rate_limit.is_empty() {
+        opts.rate_limit = config.rate_limits;
+    }
+    if opts.cache.is_empty() {
+        opts.cache = config.cache;
+    }
+    opts.sse |= config.sse;
+    opts.prefix = opts.prefix.take().or(config.prefix);
+    if opts.ws_path.is_empty() {
+        opts.ws_path = config.ws_path;
+    }
+    opts.discover |= config.discover;
+    for server in &config.servers {
+        opts.route.extend(server.to_route());
+        opts.language.extend(server.to_languages());
+        opts.extension.extend(server.to_extensions());
+        servers.push(server.to_server());
+    }
+    Ok(())
+}
 
-    let commands = splitted[1..].iter().map(|s| s.to_owned()).collect();
-    (opts, commands)
+fn apply_initialization_options(
+    opts: &Options,
+    servers: &mut [api::proxy::Server],
+) -> Result<(), String> {
+    for options in &opts.initialization_options {
+        let server = servers
+            .iter_mut()
+            .find(|server| server.name == options.name)
+            .ok_or_else(|| {
+                format!(
+                    "--initialization-options {} refers to an unknown server",
+                    options.name
+                )
+            })?;
+        server.initialization_options = Some(options.options.clone());
+    }
+    Ok(())
+}
+
+fn apply_plugins(opts: &Options, servers: &mut [api::proxy::Server]) -> Result<(), String> {
+    for plugin in &opts.plugin {
+        let server = servers
+            .iter_mut()
+            .find(|server| server.name == plugin.name)
+            .ok_or_else(|| format!("--plugin {} refers to an unknown server", plugin.name))?;
+        server.plugins.push(plugin.path.clone());
+    }
+    Ok(())
+}
+
+fn apply_scripts(opts: &Options, servers: &mut [api::proxy::Server]) -> Result<(), String> {
+    for script in &opts.script {
+        let server = servers
+            .iter_mut()
+            .find(|server| server.name == script.name)
+            .ok_or_else(|| format!("--script {} refers to an unknown server", script.name))?;
+        server.script = Some(script.path.clone());
+    }
+    Ok(())
+}
+
+fn apply_hooks(opts: &Options, servers: &mut [api::proxy::Server]) -> Result<(), String> {
+    for hook in &opts.hook {
+        let server = servers
+            .iter_mut()
+            .find(|server| server.name == hook.name)
+            .ok_or_else(|| format!("--hook {} refers to an unknown server", hook.name))?;
+        server.hook = Some(hook.command.clone());
+    }
+    Ok(())
+}
+
+fn apply_fallbacks(opts: &Options, servers: &mut [api::proxy::Server]) -> Result<(), String> {
+    for fallback in &opts.fallback {
+        let server = servers
+            .iter_mut()
+            .find(|server| server.name == fallback.name)
+            .ok_or_else(|| format!("--fallback {} refers to an unknown server", fallback.name))?;
+        server.fallback = Some(fallback.fallback.clone());
+    }
+    Ok(())
+}
+
+fn apply_cwd_from_root(opts: &Options, servers: &mut [api::proxy::Server]) {
+    if opts.cwd_from_root {
+        for server in servers {
+            server.cwd_from_root = true;
+        }
+    }
+}
+
+// Limits from the command line apply to the servers without their own.
+fn apply_limits(opts: &Options, servers: &mut [api::proxy::Server]) {
+    let mut limits = backend::Limits::default();
+    for limit in &opts.limit {
+        limits.set(*limit);
+    }
+    for server in servers {
+        server.limits = server.limits.or(limits);
+    }
+}
+
+fn apply_stop_signals(opts: &Options, servers: &mut [api::proxy::Server]) {
+    if let Some(stop_signals) = &opts.stop_signals {
+        for server in servers.iter_mut().filter(|s| s.stop_signals.is_empty()) {
+            server.stop_signals = stop_signals.clone();
+        }
+    }
 }
 
-fn parse_listen(value: &str) -> Result<String, String> {
-    // Allow specifying only a port number.
-    if value.chars().all(|c| c.is_ascii_digit()) {
-        return Ok(format!("0.0.0.0:{}", value));
+// Add the discovered servers that are not registered yet, and the routes not taken.
+fn apply_discovered(opts: &mut Options, servers: &mut Vec<api::proxy::Server>) {
+    for (server, route) in discover::discover() {
+        if !opts.route.iter().any(|r| r.path == route.path) {
+            tracing::info!("discovered {} on {}", server.name, route.path);
+            opts.route.push(route);
+        }
+        if !servers.iter().any(|s| s.name == server.name) {
+            servers.push(server);
+        }
     }
+}
 
-    match value.parse::<SocketAddr>() {
-        Ok(_) => Ok(String::from(value)),
-        Err(_) => Err(format!("{} cannot be parsed as SocketAddr", value)),
+fn validate_servers(opts: &Options, servers: &[api::proxy::Server]) -> Result<(), String> {
+    let requires_command = opts
+        .connect
+        .as_ref()
+        .map_or(true, backend::Remote::requires_command);
+    if servers.is_empty() && requires_command {
+        return Err("Command to start the server is required. See --help for examples.".into());
     }
+    let is_registered = |name: &str| servers.iter().any(|server| server.name == name);
+    for fallback in servers.iter().filter_map(|server| server.fallback.as_ref()) {
+        if !is_registered(fallback) {
+            return Err(format!("fallback {} refers to an unknown server", fallback));
+        }
+    }
+    for route in &opts.route {
+        if !is_registered(&route.name) {
+            return Err(format!(
+                "--route {} refers to an unknown server",
+                route.name
+            ));
+        }
+    }
+    for language in &opts.language {
+        if !is_registered(&language.name) {
+            return Err(format!(
+                "--language {} refers to an unknown server",
+                language.name
+            ));
+        }
+    }
+    for extension in &opts.extension {
+        if !is_registered(&extension.name) {
+            return Err(format!(
+                "--extension {} refers to an unknown server",
+                extension.name
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Reload the config file when it's modified, or on SIGHUP.
+// Only the servers and the options `remap`, `remap-rules`, `deep-remap`, and `sync` are reloaded.
+async fn watch_config(
+    path: PathBuf,
+    cli_opts: Options,
+    commands: Vec<Vec<String>>,
+    ctx: api::proxy::SharedContext,
+) {
+    #[cfg(unix)]
+    {
+        let (path, cli_opts, commands, ctx) = (
+            path.clone(),
+            cli_opts.clone(),
+            commands.clone(),
+            ctx.clone(),
+        );
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut hangup = signal(SignalKind::hangup()).expect("listen for SIGHUP");
+            while hangup.recv().await.is_some() {
+                tracing::info!("received SIGHUP");
+                reload_config(&path, &cli_opts, &commands, &ctx);
+            }
+        });
+    }
+
+    let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let mut last_modified = modified(&path);
+    let mut interval = tokio::time::interval(Duration::from_secs(2));
+    loop {
+        interval.tick().await;
+        let current = modified(&path);
+        if current != last_modified {
+            last_modified = current;
+            reload_config(&path, &cli_opts, &commands, &ctx);
+        }
+    }
+}
+
+fn reload_config(
+    path: &std::path::Path,
+    cli_opts: &Options,
+    commands: &[Vec<String>],
+    ctx: &api::proxy::SharedContext,
+) {
+    let mut opts = cli_opts.clone();
+    let mut servers = to_servers(commands);
+    let applied = config::load(path)
+        .map_err(|err| err.to_string())
+        .and_then(|config| apply_config(&mut opts, &mut servers, config))
+        .map(|_| {
+            if opts.discover {
+                apply_discovered(&mut opts, &mut servers);
+            }
+        })
+        .and_then(|_| apply_fallbacks(&opts, &mut servers))
+        .and_then(|_| apply_initialization_options(&opts, &mut servers))
+        .and_then(|_| apply_plugins(&opts, &mut servers))
+        .and_then(|_| apply_scripts(&opts, &mut servers))
+        .and_then(|_| apply_hooks(&opts, &mut servers))
+        .map(|_| apply_cwd_from_root(&opts, &mut servers))
+        .map(|_| apply_limits(&opts, &mut servers))
+        .map(|_| apply_stop_signals(&opts, &mut servers))
+        .and_then(|_| validate_servers(&opts, &servers));
+    if let Err(err) = applied {
+        tracing::error!("failed to reload config: {}", err);
+        return;
+    }
+
+    let loaded = api::plugin::Plugins::load(&servers)
+        .and_then(|plugins| Ok((plugins, api::script::Scripts::load(&servers)?)));
+    let (plugins, scripts) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            tracing::error!("failed to reload config: {}", err);
+            return;
+        }
+    };
+    let hooks = api::hook::Hooks::new(&servers);
+    // Keeps the servers registered with the admin API.
+    ctx.reload(servers, |next| {
+        next.plugins = plugins;
+        next.scripts = scripts;
+        next.hooks = hooks;
+        next.remap = opts.remap || !opts.remap_rule.is_empty();
+        next.remap_rules = lsp::ext::RemapRules::new(&next.cwd, opts.remap_rule);
+        next.deep_remap = opts.deep_remap;
+        next.sync = opts.sync;
+        next.apply_edits = opts.apply_edits;
+        next.watch = opts.watch;
+        next.repo = opts.repo;
+        next.allowed_repos = opts.allow_repo;
+    });
+    tracing::info!("reloaded {}", path.display());
 }